@@ -0,0 +1,152 @@
+// Crates ---------------------------------------------------------------------
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+extern crate bincode;
+extern crate cobalt_two;
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+
+// External Dependencies ------------------------------------------------------
+use bincode::{serialize, Infinite};
+use cobalt_two::{Client, ClientState, Error, SymmetricServer, TCP};
+
+
+// Counts every allocation made by the process, growing a buffer included
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, size)
+    }
+
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+static TICKS_PER_SECOND: u8 = 60;
+static WARMUP_TICKS: u64 = 10;
+static MEASURED_TICKS: u64 = 120;
+static MESSAGES_PER_TICK: usize = 16;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Message {
+    State { id: u32, position: (f32, f32), velocity: (f32, f32), name: String }
+}
+
+fn message(id: u32) -> Message {
+    Message::State {
+        id,
+        position: (1.0, 2.0),
+        velocity: (0.5, -0.5),
+        name: "player".to_string()
+    }
+}
+
+// Only what happens inside of f is counted
+fn measure<F: FnOnce()>(f: F) -> (usize, Duration) {
+    let start = Instant::now();
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    f();
+    (ALLOCATIONS.load(Ordering::Relaxed) - before, start.elapsed())
+}
+
+fn report(name: &str, messages: usize, (allocations, elapsed): (usize, Duration)) {
+    println!(
+        "{:<28} {:>8} messages {:>8.3} allocations/message {:>8} ns/message",
+        name,
+        messages,
+        allocations as f64 / messages as f64,
+        elapsed.as_nanos() / messages as u128
+    );
+}
+
+fn main() -> Result<(), Error> {
+
+    let messages: Vec<Message> = (0..MESSAGES_PER_TICK as u32).map(message).collect();
+    let count = MEASURED_TICKS as usize * MESSAGES_PER_TICK;
+
+    // What every send used to cost before the payload buffers were reused
+    report("bincode::serialize", count, measure(|| {
+        for _ in 0..MEASURED_TICKS {
+            for m in &messages {
+                serialize(m, Infinite).ok();
+            }
+        }
+    }));
+
+    let mut server = SymmetricServer::<TCP, Message, ()>::new(TICKS_PER_SECOND);
+    server.bind("127.0.0.1:0")?;
+
+    let mut client = Client::<TCP, Message, Message>::new(TICKS_PER_SECOND);
+    client.connect(server.local_addr()?, Duration::from_millis(500))?;
+    while client.state() != ClientState::Connected {
+        for _ in server.accepted_with(|_| Ok(())) {}
+        for _ in client.receive()? {}
+        server.sleep();
+        client.sleep();
+    }
+
+    let (mut client_sends, mut server_sends, mut broadcasts) = ((0, Duration::default()), (0, Duration::default()), (0, Duration::default()));
+    for tick in 0..WARMUP_TICKS + MEASURED_TICKS {
+
+        // Cloning the messages is not part of the send path
+        let (a, b, c) = (messages.clone(), messages.clone(), messages.clone());
+        let client_tick = measure(|| {
+            for m in a {
+                client.send(m).ok();
+            }
+        });
+
+        let server_tick = measure(|| {
+            if let Some(&mut (ref mut remote, _)) = server.connected().next() {
+                for m in b {
+                    remote.send(m).ok();
+                }
+            }
+        });
+
+        let broadcast_tick = measure(|| {
+            for m in c {
+                server.broadcast(m).ok();
+            }
+        });
+
+        if tick >= WARMUP_TICKS {
+            client_sends = (client_sends.0 + client_tick.0, client_sends.1 + client_tick.1);
+            server_sends = (server_sends.0 + server_tick.0, server_sends.1 + server_tick.1);
+            broadcasts = (broadcasts.0 + broadcast_tick.0, broadcasts.1 + broadcast_tick.1);
+        }
+
+        for _ in client.receive()? {}
+        server.sleep();
+        client.sleep();
+
+    }
+
+    report("Client::send", count, client_sends);
+    report("Remote::send", count, server_sends);
+    report("Server::broadcast", count, broadcasts);
+
+    client.disconnect()
+
+}
@@ -0,0 +1,158 @@
+// Crates ---------------------------------------------------------------------
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+extern crate cobalt_two;
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+
+// External Dependencies ------------------------------------------------------
+use cobalt_two::{Client, ClientState, Error, SymmetricServer, TCP};
+
+
+// Counts the allocations of the process along with their size
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(size, Ordering::Relaxed);
+        System.realloc(ptr, layout, size)
+    }
+
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+static TICKS_PER_SECOND: u8 = 60;
+static WARMUP_TICKS: u64 = 10;
+static MEASURED_TICKS: u64 = 60;
+static MESSAGES_PER_TICK: usize = 8;
+static PAYLOAD_SIZE: usize = 64 * 1024;
+static MAX_MESSAGE_SIZE: usize = 128 * 1024;
+
+// Both encode the samples the same way, so either one can decode the other
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Chunk {
+    samples: Vec<u8>
+}
+
+#[derive(Debug, Deserialize)]
+struct ChunkRef<'a> {
+    #[serde(borrow)]
+    samples: &'a [u8]
+}
+
+#[derive(Default)]
+struct Measurement {
+    messages: usize,
+    bytes: usize,
+    allocations: usize,
+    allocated: usize,
+    elapsed: Duration
+}
+
+impl Measurement {
+
+    // Only what happens inside of f is counted, it returns the messages and
+    // payload bytes it went through
+    fn add<F: FnOnce() -> (usize, usize)>(&mut self, f: F) {
+        let start = Instant::now();
+        let (allocations, allocated) = (ALLOCATIONS.load(Ordering::Relaxed), ALLOCATED_BYTES.load(Ordering::Relaxed));
+        let (messages, bytes) = f();
+        self.allocations += ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+        self.allocated += ALLOCATED_BYTES.load(Ordering::Relaxed) - allocated;
+        self.elapsed += start.elapsed();
+        self.messages += messages;
+        self.bytes += bytes;
+    }
+
+    fn report(&self, name: &str) {
+        let messages = self.messages.max(1);
+        println!(
+            "{:<28} {:>8} messages {:>8} KiB {:>8.3} allocations/message {:>10} bytes allocated/message {:>8} ns/message",
+            name,
+            self.messages,
+            self.bytes / 1024,
+            self.allocations as f64 / messages as f64,
+            self.allocated / messages,
+            self.elapsed.as_nanos() / messages as u128
+        );
+    }
+
+}
+
+fn main() -> Result<(), Error> {
+
+    let payload: Vec<u8> = (0..PAYLOAD_SIZE).map(|i| (i % 251) as u8).collect();
+
+    let mut server = SymmetricServer::<TCP, Chunk, ()>::new(TICKS_PER_SECOND);
+    server.set_max_message_size(MAX_MESSAGE_SIZE);
+    server.set_max_incoming_size(MAX_MESSAGE_SIZE * MESSAGES_PER_TICK * 4);
+    server.bind("127.0.0.1:0")?;
+
+    // Fragments would be copied when they are reassembled
+    let mut client = Client::<TCP, Chunk, Chunk>::new(TICKS_PER_SECOND);
+    client.set_max_message_size(MAX_MESSAGE_SIZE);
+    client.set_fragment_size(MAX_MESSAGE_SIZE);
+    client.set_max_outgoing_size(MAX_MESSAGE_SIZE * MESSAGES_PER_TICK * 4);
+    client.connect(server.local_addr()?, Duration::from_millis(500))?;
+    while client.state() != ClientState::Connected {
+        for _ in server.accepted_with(|_| Ok(())) {}
+        for _ in client.receive()? {}
+        server.sleep();
+        client.sleep();
+    }
+
+    // The first half of the ticks receives owned messages, the second half
+    // borrowed ones, each starting with a few ticks which are not counted
+    let (mut owned, mut borrowed, mut warmup) = (Measurement::default(), Measurement::default(), Measurement::default());
+    let half = WARMUP_TICKS + MEASURED_TICKS;
+    for tick in 0..half * 2 {
+
+        for _ in 0..MESSAGES_PER_TICK {
+            client.send(Chunk {
+                samples: payload.clone()
+            })?;
+        }
+        client.sleep();
+        server.sleep();
+
+        let measurement = if tick % half < WARMUP_TICKS { &mut warmup } else if tick < half { &mut owned } else { &mut borrowed };
+        if let Some(&mut (ref mut remote, _)) = server.connected().next() {
+            if tick < half {
+                measurement.add(|| remote.receive().fold((0, 0), |(messages, bytes), chunk| (messages + 1, bytes + chunk.samples.len())));
+
+            } else {
+                measurement.add(|| remote.receive_borrowed::<ChunkRef>().fold((0, 0), |(messages, bytes), chunk| (messages + 1, bytes + chunk.samples.len())));
+            }
+        }
+
+    }
+
+    owned.report("Remote::receive");
+    borrowed.report("Remote::receive_borrowed");
+
+    client.disconnect()
+
+}
@@ -0,0 +1,144 @@
+// Crates ---------------------------------------------------------------------
+extern crate cobalt_two;
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+
+// External Dependencies ------------------------------------------------------
+use cobalt_two::{Client, ClientState, ConnectionId, Error, Memory, MockClock, SymmetricServer};
+
+
+// Every client and server runs on its own mock clock, only the time spent in
+// the server is measured
+static TICKS_PER_SECOND: f64 = 60.0;
+static REMOTES: [usize; 3] = [1000, 2000, 5000];
+static ROUNDS: usize = 10;
+static CHURN: usize = 10;
+
+type Server = SymmetricServer<Memory, (), ()>;
+
+#[derive(Default)]
+struct Measurement {
+    lookups: usize,
+    lookup_time: Duration,
+    // Remotes which were around during the calls to closed(), as it writes to
+    // all of them before it removes the closed ones
+    remotes: usize,
+    closed_time: Duration,
+    accepted: usize,
+    accepted_time: Duration
+}
+
+impl Measurement {
+
+    fn report(&self, remotes: usize) {
+        println!(
+            "{:>6} remotes {:>8} ns/lookup {:>8} ns/remote in closed() {:>8} ns/accepted remote",
+            remotes,
+            self.lookup_time.as_nanos() / self.lookups as u128,
+            self.closed_time.as_nanos() / self.remotes as u128,
+            self.accepted_time.as_nanos() / self.accepted as u128
+        );
+    }
+
+}
+
+fn connect(server: &Server, count: usize) -> Result<Vec<Client<Memory, ()>>, Error> {
+    (0..count).map(|_| {
+        let mut client = Client::with_clock(TICKS_PER_SECOND, Arc::new(MockClock::new()));
+        client.connect(server.local_addr()?, Duration::from_millis(500))?;
+        Ok(client)
+
+    }).collect()
+}
+
+// Ticks everything once, returning the remotes the server accepted and closed
+fn tick(server: &mut Server, clients: &mut [Client<Memory, ()>], measurement: &mut Measurement) -> (usize, usize) {
+
+    let start = Instant::now();
+    let accepted = server.accepted_with(|_| Ok(())).count();
+    measurement.accepted_time += start.elapsed();
+    measurement.accepted += accepted;
+
+    for _ in server.connected() {}
+
+    measurement.remotes += server.remotes().len();
+    let start = Instant::now();
+    let closed = server.closed().count();
+    measurement.closed_time += start.elapsed();
+
+    server.sleep();
+    for client in clients.iter_mut() {
+        client.receive().map(|messages| messages.count()).ok();
+        client.sleep();
+    }
+
+    (accepted, closed)
+
+}
+
+fn main() -> Result<(), Error> {
+
+    for &remotes in &REMOTES {
+
+        let mut server = Server::with_clock(TICKS_PER_SECOND, Arc::new(MockClock::new()));
+        server.set_max_connections(remotes * 2);
+        server.set_accept_limit_per_tick(remotes);
+        server.bind("127.0.0.1:0")?;
+
+        let mut clients = connect(&server, remotes)?;
+        let mut warmup = Measurement::default();
+        while server.remotes().len() < remotes {
+            tick(&mut server, &mut clients, &mut warmup);
+        }
+
+        // Every round a tenth of the remotes, spread over all of them, goes
+        // away and is replaced by new ones
+        let mut measurement = Measurement::default();
+        for round in 0..ROUNDS {
+
+            let ids: Vec<ConnectionId> = server.remote_ids().collect();
+            let start = Instant::now();
+            for &id in &ids {
+                server.remote_mut(id);
+            }
+            measurement.lookup_time += start.elapsed();
+            measurement.lookups += ids.len();
+
+            let mut index = 0;
+            clients.retain_mut(|client| {
+                index += 1;
+                if index % CHURN == round {
+                    client.disconnect().ok();
+                    false
+
+                } else {
+                    true
+                }
+            });
+
+            let churned = remotes - clients.len();
+            clients.extend(connect(&server, churned)?);
+
+            let (mut accepted, mut closed) = (0, 0);
+            while accepted < churned || closed < churned {
+                let (a, c) = tick(&mut server, &mut clients, &mut measurement);
+                accepted += a;
+                closed += c;
+            }
+            while clients.iter().any(|client| client.state() != ClientState::Connected) {
+                tick(&mut server, &mut clients, &mut measurement);
+            }
+
+        }
+
+        measurement.report(remotes);
+
+    }
+
+    Ok(())
+
+}
@@ -0,0 +1,158 @@
+// Crates ---------------------------------------------------------------------
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+extern crate bincode;
+extern crate cobalt_two;
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use std::thread;
+
+
+// External Dependencies ------------------------------------------------------
+use bincode::{deserialize, serialize, Infinite};
+use cobalt_two::{Client, ClientState, Error, SymmetricServer, TCP};
+
+
+// Counts every allocation made by the process, growing a buffer included
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, size)
+    }
+
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+static TICKS_PER_SECOND: u8 = 60;
+static ROUNDS: usize = 20;
+static QUEUED_MESSAGES: usize = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Message {
+    Input { id: u32, tick: u32, buttons: u8 }
+}
+
+fn message(id: u32) -> Message {
+    Message::Input {
+        id,
+        tick: id * 2,
+        buttons: 1
+    }
+}
+
+// Only what happens inside of f is counted, it returns the number of
+// messages it went through
+fn measure<F: FnOnce() -> usize>(f: F) -> (usize, usize, Duration) {
+    let start = Instant::now();
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let messages = f();
+    (messages, ALLOCATIONS.load(Ordering::Relaxed) - before, start.elapsed())
+}
+
+fn report(name: &str, (messages, allocations, elapsed): (usize, usize, Duration)) {
+    println!(
+        "{:<28} {:>8} messages {:>8.3} allocations/message {:>8} ns/message",
+        name,
+        messages,
+        allocations as f64 / messages as f64,
+        elapsed.as_nanos() / messages as u128
+    );
+}
+
+fn add(a: (usize, usize, Duration), b: (usize, usize, Duration)) -> (usize, usize, Duration) {
+    (a.0 + b.0, a.1 + b.1, a.2 + b.2)
+}
+
+// How the iterator used to work, copying the rest of the buffer into a new
+// one after every single message
+fn copy_per_message(mut buffer: Vec<u8>) -> usize {
+    let mut messages = 0;
+    while buffer.len() >= 4 {
+        let length = u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize;
+        if deserialize::<Message>(&buffer[5..4 + length]).is_ok() {
+            messages += 1;
+        }
+        buffer = buffer[4 + length..].to_vec();
+    }
+    messages
+}
+
+fn main() -> Result<(), Error> {
+
+    let messages: Vec<Message> = (0..QUEUED_MESSAGES as u32).map(message).collect();
+
+    // The same frames the client sends, one per message
+    let mut frames = Vec::new();
+    for m in &messages {
+        let payload = serialize(m, Infinite).unwrap();
+        frames.extend_from_slice(&(payload.len() as u32 + 1).to_le_bytes());
+        frames.push(1);
+        frames.extend_from_slice(&payload);
+    }
+
+    let mut server = SymmetricServer::<TCP, Message, ()>::new(TICKS_PER_SECOND);
+    server.bind("127.0.0.1:0")?;
+
+    let mut client = Client::<TCP, Message, Message>::new(TICKS_PER_SECOND);
+    client.set_batching(false);
+    client.connect(server.local_addr()?, Duration::from_millis(500))?;
+    while client.state() != ClientState::Connected {
+        for _ in server.accepted_with(|_| Ok(())) {}
+        for _ in client.receive()? {}
+        server.sleep();
+        client.sleep();
+    }
+
+    let (mut copied, mut cursor) = ((0, 0, Duration::default()), (0, 0, Duration::default()));
+    for _ in 0..ROUNDS {
+
+        // Cloning the frames is not part of the old path either
+        let buffer = frames.clone();
+        copied = add(copied, measure(|| copy_per_message(buffer)));
+
+        for m in messages.iter().cloned() {
+            client.send(m)?;
+        }
+        client.flush()?;
+
+        // Give all messages time to arrive, so they are queued up at once
+        thread::sleep(Duration::from_millis(20));
+        if let Some(&mut (ref mut remote, _)) = server.connected().next() {
+            let tick = measure(|| remote.receive().count());
+            assert_eq!(tick.0, QUEUED_MESSAGES);
+            cursor = add(cursor, tick);
+        }
+
+        for _ in client.receive()? {}
+        server.sleep();
+        client.sleep();
+
+    }
+
+    report("copy per message", copied);
+    report("Remote::receive", cursor);
+
+    client.disconnect()
+
+}
@@ -0,0 +1,191 @@
+// Crates ---------------------------------------------------------------------
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+extern crate bincode;
+extern crate cobalt_two;
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+
+// External Dependencies ------------------------------------------------------
+use bincode::{serialize, Infinite};
+use cobalt_two::{Client, ClientState, Error, SymmetricServer, TCP};
+
+
+// Counts every allocation made by the process, growing a buffer included
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, size)
+    }
+
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+static TICKS_PER_SECOND: u8 = 60;
+static WARMUP_TICKS: u64 = 5;
+static MEASURED_TICKS: u64 = 30;
+static MESSAGES_PER_TICK: usize = 10_000;
+static MAX_OUTGOING_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Message {
+    Input { id: u32, tick: u32, buttons: u8 }
+}
+
+fn message(id: u32) -> Message {
+    Message::Input {
+        id,
+        tick: id * 2,
+        buttons: 1
+    }
+}
+
+#[derive(Default)]
+struct Measurement {
+    messages: usize,
+    bytes: usize,
+    allocations: usize,
+    elapsed: Duration
+}
+
+impl Measurement {
+
+    // Only what happens inside of f is counted, it returns the bytes written
+    fn add<F: FnOnce() -> usize>(&mut self, messages: usize, f: F) {
+        let start = Instant::now();
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+        let bytes = f();
+        self.allocations += ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+        self.elapsed += start.elapsed();
+        self.messages += messages;
+        self.bytes += bytes;
+    }
+
+    fn report(&self, name: &str) {
+        println!(
+            "{:<28} {:>8} messages {:>8} KiB {:>8.3} allocations/message {:>8} ns/message {:>8.1} MiB/s",
+            name,
+            self.messages,
+            self.bytes / 1024,
+            self.allocations as f64 / self.messages as f64,
+            self.elapsed.as_nanos() / self.messages as u128,
+            self.bytes as f64 / 1024.0 / 1024.0 / self.elapsed.as_secs_f64()
+        );
+    }
+
+}
+
+// How frames used to be written, each one built in its own buffer and
+// copied into a single one for the write
+fn copy_into_buffer(stream: &mut TcpStream, messages: &[Message]) -> usize {
+    let mut buffer = Vec::new();
+    for m in messages {
+        let payload = serialize(m, Infinite).unwrap();
+        let mut frame = vec![1];
+        frame.extend_from_slice(&payload);
+        buffer.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        buffer.extend_from_slice(&frame);
+    }
+    stream.write_all(&buffer).unwrap();
+    buffer.len()
+}
+
+fn main() -> Result<(), Error> {
+
+    let messages: Vec<Message> = (0..MESSAGES_PER_TICK as u32).map(message).collect();
+
+    // The bytes of the old path are discarded by a thread which allocates
+    // nothing once it is running
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let mut stream = TcpStream::connect(listener.local_addr()?)?;
+    let mut sink = listener.accept()?.0;
+    thread::spawn(move || {
+        let mut buffer = [0; 64 * 1024];
+        while let Ok(bytes) = sink.read(&mut buffer) {
+            if bytes == 0 {
+                break;
+            }
+        }
+    });
+
+    let (mut copied, mut warmup) = (Measurement::default(), Measurement::default());
+    for tick in 0..WARMUP_TICKS + MEASURED_TICKS {
+        let measurement = if tick < WARMUP_TICKS { &mut warmup } else { &mut copied };
+        measurement.add(MESSAGES_PER_TICK, || copy_into_buffer(&mut stream, &messages));
+    }
+
+    // Batches would copy the payloads, which is not what is measured here
+    let mut server = SymmetricServer::<TCP, Message, ()>::new(TICKS_PER_SECOND);
+    server.set_batching(false);
+    server.set_max_outgoing_size(MAX_OUTGOING_SIZE);
+    server.bind("127.0.0.1:0")?;
+
+    let mut client = Client::<TCP, Message, Message>::new(TICKS_PER_SECOND);
+    client.set_max_incoming_size(MAX_OUTGOING_SIZE);
+    client.connect(server.local_addr()?, Duration::from_millis(500))?;
+    while client.state() != ClientState::Connected {
+        for _ in server.accepted_with(|_| Ok(())) {}
+        for _ in client.receive()? {}
+        server.sleep();
+        client.sleep();
+    }
+
+    let mut vectored = Measurement::default();
+    for tick in 0..WARMUP_TICKS + MEASURED_TICKS {
+
+        if let Some(&mut (ref mut remote, _)) = server.connected().next() {
+
+            // Cloning the messages is not part of the send path
+            let tick_messages = messages.clone();
+            let measurement = if tick < WARMUP_TICKS { &mut warmup } else { &mut vectored };
+            measurement.add(MESSAGES_PER_TICK, || {
+                for m in tick_messages {
+                    remote.send(m).ok();
+                }
+                remote.flush().unwrap_or(0)
+            });
+
+            // Whatever the socket did not take is written outside of the measurement
+            while remote.outgoing_len() > 0 {
+                for _ in client.receive()? {}
+                remote.flush()?;
+            }
+
+        }
+        for _ in client.receive()? {}
+        server.sleep();
+        client.sleep();
+
+    }
+
+    copied.report("copy into one buffer");
+    vectored.report("Remote::send + flush");
+
+    client.disconnect()
+
+}
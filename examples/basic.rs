@@ -8,111 +8,95 @@ extern crate cobalt_two;
 
 // STD Dependencies -----------------------------------------------------------
 use std::thread;
-use std::io::Error as IOError;
+use std::net::SocketAddr;
 use std::time::Duration;
 
 
 // External Dependencies ------------------------------------------------------
-use cobalt_two::{Client, Server, TCP};
+use cobalt_two::{Client, Error, Server, TCP};
 
 
+// Messages sent from the client to the server
 #[derive(Debug, Serialize, Deserialize)]
-enum Message {
-    Hello,
+enum ClientMessage {
     Text
 }
 
-fn client() -> Result<(), IOError> {
-
-    // TODO expose tick rate set by server via client
+// Messages sent from the server to the client
+#[derive(Debug, Serialize, Deserialize)]
+enum ServerMessage {
+    Hello
+}
 
-    // TODO
-    // client.time() -> f64
-    // client.ticks() -> usize
-    // client.ticks_per_second() -> u8
+fn client(addr: SocketAddr) -> Result<(), Error> {
 
-    // client.bytes_total() -> (usize, usize)
-    // client.bytes_per_second() -> (f64, f64);
-    let mut client = Client::<TCP, Message>::new(30);
-    client.connect("127.0.0.1:7564", Duration::from_millis(500))?;
+    let mut client = Client::<TCP, ClientMessage, ServerMessage>::new(30);
+    client.connect(addr, Duration::from_millis(500))?;
 
-    let mut ticks = 0;
     loop {
 
-        println!("[Client] RTT: {} Clock: {} (behind)", client.rtt().round(), client.clock().round());
+        println!(
+            "[Client] Tick: {} ({:.2}s) Ticks/s: {} RTT: {} Clock: {} (behind) Sent: {} bytes ({}/s) Received: {} bytes ({}/s)",
+            client.ticks(), client.time(), client.ticks_per_second(), client.rtt().round(), client.clock().round(),
+            client.bytes_sent(), client.bytes_sent_per_second().round(),
+            client.bytes_received(), client.bytes_received_per_second().round()
+        );
         for m in client.receive()? {
             println!("[Client] [Remote] [Message] {:?}", m);
         }
 
-        if ticks % 3 == 0 {
-            client.send(Message::Text)?;
+        if client.ticks().is_multiple_of(3) {
+            client.send(ClientMessage::Text)?;
         }
-        ticks += 1;
 
         client.sleep();
 
     }
 
-    Ok(())
-
 }
 
-fn run_server() -> Result<(), IOError> {
-
-    #[derive(Default)]
-    struct Data;
-
-    // TODO configure tick rate only on server and transmit to client behind the scenes
-
-    // TODO
-    // server.time() -> f64
-    // server.ticks() -> usize
-    // server.ticks_per_second() -> u8
-
-    // server.bytes_total() -> (usize, usize)
-    // server.bytes_per_second() -> (f64, f64);
-
-    // remote.bytes_total() -> (usize, usize)
-    // remote.bytes_per_second() -> (f64, f64);
-
-    let mut server = Server::<TCP, Message, Data>::new(30);
-    server.bind("0.0.0.0:7564")?;
+#[derive(Default)]
+struct Data;
 
+fn run_server(mut server: Server<TCP, ClientMessage, ServerMessage, Data>) -> Result<(), Error> {
 
     loop {
 
-        for &mut (ref mut r, _) in server.accepted_with(|_| Some(Data::default())) {
+        for &mut (ref mut r, _) in server.accepted_with(|_| Ok(Data)) {
             println!("[Server] [Remote] Accepted");
-            r.send(Message::Hello);
+            r.send(ServerMessage::Hello)?;
         }
 
         for &mut (ref mut r, _) in server.connected() {
             //println!("[Server] [Remote] Connected {:?} ({}ms rtt)", r.peer_addr(), r.rtt());
-            for m in r.receive() {
+            for _ in r.receive() {
                 //println!("[Server] [Remote] [Message] {:?}", m);
             }
         }
 
-        for (r, _) in server.closed() {
-            println!("[Server] [Remote] Closed");
+        for (id, _, _) in server.closed() {
+            println!("[Server] [Remote] Closed {:?}", id);
         }
 
         server.sleep();
 
     }
 
-    server.shutdown();
-
 }
 
 fn main() {
 
-    let server_handle = thread::spawn(|| {
-        run_server()
+    // Let the OS pick a free port and tell the client about it
+    let mut server = Server::<TCP, ClientMessage, ServerMessage, Data>::new(30);
+    server.bind("127.0.0.1:0").expect("Failed to bind server");
+    let addr = server.local_addr().expect("Failed to get server address");
+
+    let server_handle = thread::spawn(move || {
+        run_server(server)
     });
 
-    let client_handle = thread::spawn(|| {
-        client().ok();
+    let client_handle = thread::spawn(move || {
+        client(addr).ok();
     });
 
     client_handle.join().ok();
@@ -0,0 +1,273 @@
+// Crates ---------------------------------------------------------------------
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+extern crate cobalt_two;
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::thread;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+
+// External Dependencies ------------------------------------------------------
+use cobalt_two::{BincodeCodec, CborCodec, Client, Codec, CodecError, Error, Server, TCP};
+
+
+// Wire Format ----------------------------------------------------------------
+// Every frame is its length as a little endian u32, followed by a prefix byte
+// and the payload; the length covers both the prefix and the payload.
+//
+// Prefix 0 frames carry the internal messages of the crate and prefix 1 frames
+// the messages of the default channel. With the CborCodec each payload is a
+// single CBOR item and enums are maps from the variant name to its fields:
+//
+//   {"Hello": {"protocol_version": 4, "version": 0, "ticks_per_second": 30.0,
+//              "compression": false, "stamping": false}}
+//   {"Ping": [sequence, time]}
+//   {"Close": reason}
+//
+// A peer must send its Hello before anything else. Everything else may be
+// skipped, though peers which never answer pings are dropped eventually.
+//
+// The messages of this example are sent as raw bytes instead, the very first
+// one picking the format of all which follow it, 0 for bincode and 1 for
+// CBOR, so clients can move from one to the other one at a time. The internal
+// messages stay CBOR for all of them, as they are encoded by the server codec.
+static TICKS_PER_SECOND: u8 = 30;
+static SESSION_TICKS: u64 = 60;
+
+static FLAG_BINCODE: u8 = 0;
+static FLAG_CBOR: u8 = 1;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+enum Message {
+    Move(i32, i32),
+    Chat(String)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Bincode,
+    Cbor
+}
+
+impl Format {
+
+    fn from_flag(flag: u8) -> Option<Format> {
+        match flag {
+            flag if flag == FLAG_BINCODE => Some(Format::Bincode),
+            flag if flag == FLAG_CBOR => Some(Format::Cbor),
+            _ => None
+        }
+    }
+
+    fn flag(self) -> u8 {
+        match self {
+            Format::Bincode => FLAG_BINCODE,
+            Format::Cbor => FLAG_CBOR
+        }
+    }
+
+    fn encode(self, message: &Message) -> Result<Vec<u8>, CodecError> {
+        match self {
+            Format::Bincode => BincodeCodec::encode(message),
+            Format::Cbor => CborCodec::encode(message)
+        }
+    }
+
+    fn decode(self, bytes: &[u8]) -> Result<Message, CodecError> {
+        match self {
+            Format::Bincode => BincodeCodec::decode(bytes),
+            Format::Cbor => CborCodec::decode(bytes)
+        }.map(|(message, _)| message)
+    }
+
+}
+
+
+// Hand Built CBOR ------------------------------------------------------------
+// Only what the example needs: short strings, small integers and floats
+fn cbor_map(bytes: &mut Vec<u8>, len: u8) {
+    bytes.push(0xa0 | len);
+}
+
+fn cbor_array(bytes: &mut Vec<u8>, len: u8) {
+    bytes.push(0x80 | len);
+}
+
+fn cbor_text(bytes: &mut Vec<u8>, text: &str) {
+    bytes.push(0x60 | text.len() as u8);
+    bytes.extend_from_slice(text.as_bytes());
+}
+
+fn cbor_int(bytes: &mut Vec<u8>, value: i8) {
+    if value < 0 {
+        bytes.push(0x20 | (-1 - value) as u8);
+
+    } else {
+        bytes.push(value as u8);
+    }
+}
+
+fn cbor_bool(bytes: &mut Vec<u8>, value: bool) {
+    bytes.push(if value { 0xf5 } else { 0xf4 });
+}
+
+fn cbor_f64(bytes: &mut Vec<u8>, value: f64) {
+    bytes.push(0xfb);
+    bytes.extend_from_slice(&value.to_bits().to_be_bytes());
+}
+
+fn hand_built_hello() -> Vec<u8> {
+    let mut bytes = Vec::new();
+    cbor_map(&mut bytes, 1);
+    cbor_text(&mut bytes, "Hello");
+    cbor_map(&mut bytes, 5);
+    cbor_text(&mut bytes, "protocol_version");
+    cbor_int(&mut bytes, 4);
+    cbor_text(&mut bytes, "version");
+    cbor_int(&mut bytes, 0);
+    cbor_text(&mut bytes, "ticks_per_second");
+    cbor_f64(&mut bytes, f64::from(TICKS_PER_SECOND));
+    cbor_text(&mut bytes, "compression");
+    cbor_bool(&mut bytes, false);
+    cbor_text(&mut bytes, "stamping");
+    cbor_bool(&mut bytes, false);
+    bytes
+}
+
+// Message::Move(1, -2), encoded as the shortest form just like the codec does
+fn hand_built_move() -> Vec<u8> {
+    let mut bytes = Vec::new();
+    cbor_map(&mut bytes, 1);
+    cbor_text(&mut bytes, "Move");
+    cbor_array(&mut bytes, 2);
+    cbor_int(&mut bytes, 1);
+    cbor_int(&mut bytes, -2);
+    bytes
+}
+
+fn write_frame(stream: &mut TcpStream, prefix: u8, payload: &[u8]) -> Result<(), Error> {
+    let mut frame = ((payload.len() + 1) as u32).to_le_bytes().to_vec();
+    frame.push(prefix);
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame)?;
+    Ok(())
+}
+
+fn read_frame(stream: &mut TcpStream) -> Result<(u8, Vec<u8>), Error> {
+    let mut header = [0; 4];
+    stream.read_exact(&mut header)?;
+    let mut body = vec![0; u32::from_le_bytes(header) as usize];
+    stream.read_exact(&mut body)?;
+    Ok((body[0], body.split_off(1)))
+}
+
+
+// Clients --------------------------------------------------------------------
+// Speaks the wire format directly, like a peer in another language would
+fn foreign_client(addr: SocketAddr) -> Result<bool, Error> {
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+    write_frame(&mut stream, 0, &hand_built_hello())?;
+    write_frame(&mut stream, 1, &[FLAG_CBOR])?;
+    write_frame(&mut stream, 1, &hand_built_move())?;
+
+    // The server echoes the message, which must come back byte for byte
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_secs(1) {
+        let (prefix, payload) = read_frame(&mut stream)?;
+        if prefix == 1 {
+            println!("[Foreign] Received {:02x?}", payload);
+            return Ok(payload == hand_built_move());
+        }
+    }
+
+    Ok(false)
+
+}
+
+fn client(addr: SocketAddr, format: Format) -> Result<(), Error> {
+
+    let mut client = Client::<TCP, Message, Message, CborCodec>::new(TICKS_PER_SECOND);
+    client.connect(addr, Duration::from_millis(500))?;
+    client.send_bytes(&[format.flag()])?;
+
+    while client.ticks() < 30 {
+        for bytes in client.receive_raw()? {
+            println!("[Client] {:?} received {:?}", format, format.decode(&bytes)?);
+        }
+        if client.ticks() == 5 {
+            client.send_bytes(&format.encode(&Message::Chat(format!("{:?}", format)))?)?;
+        }
+        client.sleep();
+    }
+
+    client.disconnect()
+
+}
+
+
+// Server ---------------------------------------------------------------------
+fn main() -> Result<(), Error> {
+
+    // Hand built bytes and the codec must agree in both directions
+    let (decoded, size): (Message, usize) = CborCodec::decode(&hand_built_move())?;
+    println!("[Interop] Decoded {:?} from {} bytes", decoded, size);
+    println!("[Interop] Encoding matches: {}", CborCodec::encode(&decoded)? == hand_built_move());
+
+    let mut server = Server::<TCP, Message, Message, Option<Format>, CborCodec>::new(TICKS_PER_SECOND);
+    server.bind("127.0.0.1:0")?;
+
+    let addr = server.local_addr()?;
+    let handles = vec![
+        thread::spawn(move || client(addr, Format::Bincode)),
+        thread::spawn(move || client(addr, Format::Cbor))
+    ];
+    let foreign = thread::spawn(move || foreign_client(addr));
+
+    while server.ticks() < SESSION_TICKS {
+
+        for _ in server.accepted_with(|_| Ok(None)) {}
+
+        for &mut (ref mut r, ref mut format) in server.connected() {
+            let payloads: Vec<Vec<u8>> = r.receive_raw().collect();
+            for bytes in payloads {
+                match *format {
+                    Some(format) => match format.decode(&bytes) {
+                        Ok(message) => {
+                            println!("[Server] {:?} message {:?}", format, message);
+                            r.send_bytes(&format.encode(&message)?)?;
+                        },
+                        Err(err) => println!("[Server] {}", err)
+                    },
+                    None => {
+                        *format = bytes.first().cloned().and_then(Format::from_flag);
+                        println!("[Server] Remote uses {:?}", format);
+                    }
+                }
+            }
+        }
+
+        for _ in server.closed() {}
+        server.sleep();
+
+    }
+
+    for handle in handles {
+        handle.join().ok();
+    }
+
+    if let Ok(result) = foreign.join() {
+        println!("[Foreign] Echo matches: {}", result?);
+    }
+
+    Ok(())
+
+}
+
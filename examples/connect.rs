@@ -0,0 +1,67 @@
+// Crates ---------------------------------------------------------------------
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+extern crate cobalt_two;
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::thread;
+use std::io::{self, Write};
+use std::time::Duration;
+
+
+// External Dependencies ------------------------------------------------------
+use cobalt_two::{Client, ClientEvent, ClientState, Error, SymmetricServer, TCP};
+
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Message {
+    Welcome
+}
+
+fn main() -> Result<(), Error> {
+
+    let mut server = SymmetricServer::<TCP, Message, ()>::new(30);
+    server.bind("127.0.0.1:0")?;
+    let addr = server.local_addr()?;
+
+    thread::spawn(move || {
+        loop {
+            for &mut (ref mut r, _) in server.accepted_with(|_| Ok(())) {
+                r.send(Message::Welcome).ok();
+            }
+            server.sleep();
+        }
+    });
+
+    // The game loop keeps running while the connection is being established
+    let mut client = Client::<TCP, Message>::new(30);
+    client.connect_nonblocking(addr, Duration::from_secs(5))?;
+
+    let spinner = ['|', '/', '-', '\\'];
+    while client.ticks() < 30 {
+
+        if client.state() == ClientState::Connecting {
+            print!("\r[Client] Connecting {}", spinner[(client.ticks() % 4) as usize]);
+            io::stdout().flush().ok();
+        }
+
+        for event in client.events() {
+            match event {
+                ClientEvent::Message(m) => println!("\r[Client] [Message] {:?}", m),
+                ClientEvent::Failed => {
+                    println!("\r[Client] Failed to connect");
+                    return Ok(());
+                },
+                _ => {}
+            }
+        }
+
+        client.sleep();
+
+    }
+
+    client.disconnect()
+
+}
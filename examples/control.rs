@@ -0,0 +1,121 @@
+// Crates ---------------------------------------------------------------------
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+extern crate cobalt_two;
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::thread;
+use std::net::SocketAddr;
+use std::sync::mpsc;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+
+// External Dependencies ------------------------------------------------------
+use cobalt_two::{Client, ConnectionId, Error, SymmetricServer, TCP};
+
+
+// Probes are echoed by the server on their own control prefix, so they never
+// show up among the application messages
+static BANDWIDTH_ECHO: u8 = 16;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Probe {
+    sequence: u32,
+    padding: Vec<u8>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Message {
+    Chat(String)
+}
+
+fn client(addr: SocketAddr) -> Result<(), Error> {
+
+    let (sender, echoes) = mpsc::channel();
+    let mut client = Client::<TCP, Message, Message>::new(30);
+    client.register_control(BANDWIDTH_ECHO, move |probe: Probe| {
+        sender.send(probe).ok();
+    })?;
+    client.connect(addr, Duration::from_millis(500))?;
+
+    let mut sent = HashMap::new();
+    let mut sequence = 0;
+    while client.ticks() < 90 {
+
+        for m in client.receive()? {
+            println!("[Client] Message {:?}", m);
+        }
+
+        for probe in echoes.try_iter() {
+            if let Some(start) = sent.remove(&probe.sequence) {
+                let elapsed = Instant::now().duration_since(start);
+                let bytes = probe.padding.len() * 2;
+                println!(
+                    "[Client] Probe {} with {} bytes echoed in {:.1}ms, {:.0} KiB/s",
+                    probe.sequence, probe.padding.len(), elapsed.as_secs_f64() * 1000.0,
+                    bytes as f64 / 1024.0 / elapsed.as_secs_f64()
+                );
+            }
+        }
+
+        // Probes double in size every time, staying below the maximum message size
+        if client.ticks().is_multiple_of(10) && sequence < 6 {
+            client.send_control(BANDWIDTH_ECHO, Probe {
+                sequence,
+                padding: vec![0; 1024 << sequence]
+            })?;
+            sent.insert(sequence, Instant::now());
+            sequence += 1;
+        }
+
+        if client.ticks() == 45 {
+            client.send(Message::Chat("still here".to_string()))?;
+        }
+
+        client.sleep();
+
+    }
+
+    client.disconnect()
+
+}
+
+fn main() -> Result<(), Error> {
+
+    let mut server = SymmetricServer::<TCP, Message, ()>::new(30);
+
+    // Handlers only get the connection id, replies are sent from the game loop
+    let (sender, probes) = mpsc::channel::<(ConnectionId, Probe)>();
+    server.register_control(BANDWIDTH_ECHO, move |id, probe: Probe| {
+        sender.send((id, probe)).ok();
+    })?;
+    server.bind("127.0.0.1:7156")?;
+
+    let handle = thread::spawn(move || client("127.0.0.1:7156".parse().unwrap()));
+    while server.ticks() < 120 {
+
+        for _ in server.accepted_with(|_| Ok(())) {}
+        for &mut (ref mut remote, _) in server.connected() {
+            for m in remote.receive() {
+                println!("[Server] Message {:?}", m);
+            }
+        }
+
+        for (id, probe) in probes.try_iter() {
+            if let Some(&mut (ref mut remote, _)) = server.remote_mut(id) {
+                remote.send_control(BANDWIDTH_ECHO, probe).ok();
+            }
+        }
+
+        for _ in server.closed() {}
+        server.sleep();
+
+    }
+
+    handle.join().ok();
+    Ok(())
+
+}
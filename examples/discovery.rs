@@ -0,0 +1,72 @@
+// Crates ---------------------------------------------------------------------
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+extern crate cobalt_two;
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::thread;
+use std::time::Duration;
+
+
+// External Dependencies ------------------------------------------------------
+use cobalt_two::{Client, Error, SymmetricServer, TCP};
+
+
+static DISCOVERY_PORT: u16 = 28_015;
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Message {
+    Join(String),
+    Welcome(String)
+}
+
+fn main() -> Result<(), Error> {
+
+    // The game port is picked by the OS, only the discovery port is well known
+    let mut server = SymmetricServer::<TCP, Message, ()>::new(30);
+    server.bind("0.0.0.0:0")?;
+    server.enable_discovery(DISCOVERY_PORT, b"Capture the Flag".to_vec())?;
+
+    thread::spawn(move || {
+        loop {
+            for _ in server.accepted_with(|_| Ok(())) {}
+            for &mut (ref mut r, _) in server.connected() {
+                let joins: Vec<Message> = r.receive().collect();
+                for m in joins {
+                    if let Message::Join(name) = m {
+                        r.send(Message::Welcome(name)).ok();
+                    }
+                }
+            }
+            server.sleep();
+        }
+    });
+
+    let servers = Client::<TCP, Message, Message>::discover(DISCOVERY_PORT, Duration::from_millis(500))?;
+    for s in &servers {
+        println!("[Client] Found \"{}\" at {}", String::from_utf8_lossy(&s.info), s.addr);
+    }
+
+    if let Some(s) = servers.first() {
+
+        let mut client = Client::<TCP, Message, Message>::new(30);
+        client.connect(s.addr, Duration::from_millis(500))?;
+        client.send(Message::Join("Player".to_string()))?;
+
+        while client.ticks() < 30 {
+            for m in client.receive()? {
+                println!("[Client] {:?}", m);
+            }
+            client.sleep();
+        }
+
+        client.disconnect()?;
+
+    }
+
+    Ok(())
+
+}
+
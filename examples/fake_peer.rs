@@ -0,0 +1,106 @@
+// Crates ---------------------------------------------------------------------
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+extern crate cobalt_two;
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::sync::Arc;
+use std::time::Duration;
+
+
+// External Dependencies ------------------------------------------------------
+use cobalt_two::{Client, ClientState, FakePeer, Memory, MockClock, ReconnectPolicy, ScriptError, SymmetricServer};
+
+
+// Every scenario runs on a mock clock, so none of them takes any real time
+static TICKS_PER_SECOND: f64 = 30.0;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+enum Message {
+    Ping(u32),
+    Pong(u32),
+    Kick
+}
+
+// The server echoes pings and kicks whoever asks for it
+fn connect_and_kick() -> Result<(), ScriptError> {
+
+    let clock = Arc::new(MockClock::new());
+    let mut server = SymmetricServer::<Memory, Message, ()>::with_clock(TICKS_PER_SECOND, clock.clone());
+    server.bind("127.0.0.1:0").map_err(ScriptError::Failed)?;
+
+    let mut peer = FakePeer::<Message>::new(TICKS_PER_SECOND, clock);
+    peer.connect(server.local_addr().map_err(ScriptError::Failed)?).map_err(ScriptError::Failed)?;
+    peer.expect_connect()
+        .send(Message::Ping(1))
+        .expect_message(|m| *m == Message::Pong(1))
+        .send(Message::Kick)
+        .expect_close();
+
+    peer.run(|| {
+        for _ in server.accepted_with(|_| Ok(())) {}
+        for &mut (ref mut remote, _) in server.connected() {
+            let messages: Vec<Message> = remote.receive().collect();
+            for m in messages {
+                match m {
+                    Message::Ping(n) => remote.send(Message::Pong(n)).ok(),
+                    Message::Kick => remote.close_with_code(42).ok(),
+                    Message::Pong(_) => None
+                };
+            }
+        }
+        for _ in server.closed() {}
+        server.sleep();
+    })?;
+
+    println!("[Kick] Closed with code {:?} after {} ticks", peer.close_code(), peer.ticks());
+    Ok(())
+
+}
+
+// The peer plays the server, answering the pings of the client
+fn rtt_and_reconnect() -> Result<(), ScriptError> {
+
+    let clock = Arc::new(MockClock::new());
+    let mut peer = FakePeer::<Message>::new(TICKS_PER_SECOND, clock.clone());
+    peer.listen("127.0.0.1:0").map_err(ScriptError::Failed)?;
+
+    let mut client = Client::<Memory, Message>::with_clock(TICKS_PER_SECOND, clock);
+    client.set_auto_reconnect(Some(ReconnectPolicy {
+        initial_delay: Duration::from_millis(100),
+        ..ReconnectPolicy::default()
+    }));
+    client.connect(peer.local_addr().map_err(ScriptError::Failed)?, Duration::from_millis(100)).map_err(ScriptError::Failed)?;
+
+    peer.expect_connect().advance_ticks(30);
+    peer.run(|| {
+        client.receive().map(|messages| messages.count()).ok();
+        client.sleep();
+    })?;
+    println!("[Reconnect] Connected with a RTT of {:.1}ms", client.rtt());
+
+    peer.drop_connection().advance_ticks(1).expect_connect().send(Message::Ping(7)).advance_ticks(2);
+    let mut states = Vec::new();
+    let mut received = Vec::new();
+    peer.run(|| {
+        if let Ok(messages) = client.receive() {
+            received.extend(messages);
+        }
+        if states.last() != Some(&client.state()) {
+            states.push(client.state());
+        }
+        client.sleep();
+    })?;
+
+    println!("[Reconnect] States {:?}, received {:?}", states, received);
+    println!("[Reconnect] Reconnected: {}", client.state() == ClientState::Connected && received == vec![Message::Ping(7)]);
+    Ok(())
+
+}
+
+fn main() -> Result<(), ScriptError> {
+    connect_and_kick()?;
+    rtt_and_reconnect()
+}
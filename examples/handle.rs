@@ -0,0 +1,75 @@
+// Crates ---------------------------------------------------------------------
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+extern crate cobalt_two;
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::thread;
+use std::time::{Duration, Instant};
+
+
+// External Dependencies ------------------------------------------------------
+use cobalt_two::{Client, ClientEvent, Error, SymmetricServer, TCP};
+
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Message {
+    Input(u32),
+    Echo(u32)
+}
+
+fn main() -> Result<(), Error> {
+
+    let mut server = SymmetricServer::<TCP, Message, ()>::new(30);
+    server.bind("127.0.0.1:0")?;
+    let addr = server.local_addr()?;
+
+    thread::spawn(move || {
+        loop {
+            for _ in server.accepted_with(|_| Ok(())) {}
+            for &mut (ref mut r, _) in server.connected() {
+                let inputs: Vec<Message> = r.receive().collect();
+                for m in inputs {
+                    if let Message::Input(n) = m {
+                        r.send(Message::Echo(n)).ok();
+                    }
+                }
+            }
+            server.sleep();
+        }
+    });
+
+    // The client ticks on its own thread, the render loop below never sleeps
+    let handle = Client::<TCP, Message>::spawn(addr, 30)?;
+
+    let start = Instant::now();
+    let mut frames = 0u32;
+    let mut last_input = Instant::now();
+    while start.elapsed() < Duration::from_secs(2) {
+
+        while let Some(event) = handle.try_recv() {
+            match event {
+                ClientEvent::Connected => println!("[Render] Connected"),
+                ClientEvent::Message(m) => println!("[Render] {:?} at frame {} (RTT: {}ms)", m, frames, handle.rtt().round()),
+                ClientEvent::Lost | ClientEvent::Failed => println!("[Render] Connection lost")
+            }
+        }
+
+        if last_input.elapsed() > Duration::from_millis(250) {
+            handle.send(Message::Input(frames))?;
+            last_input = Instant::now();
+        }
+
+        // Draw the frame...
+        frames += 1;
+        thread::yield_now();
+
+    }
+
+    println!("[Render] {} frames rendered", frames);
+    handle.shutdown();
+    Ok(())
+
+}
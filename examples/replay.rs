@@ -0,0 +1,105 @@
+// Crates ---------------------------------------------------------------------
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+extern crate cobalt_two;
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::env;
+use std::thread;
+use std::sync::Arc;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+
+// External Dependencies ------------------------------------------------------
+use cobalt_two::{Capture, Client, Error, FileTap, MockClock, Replay, ReplayConfig, SymmetricServer, TCP};
+
+
+static TICKS_PER_SECOND: u8 = 30;
+static SESSION_TICKS: u64 = 60;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+enum Message {
+    Move(i32, i32),
+    Chat(String)
+}
+
+fn client(addr: SocketAddr) -> Result<(), Error> {
+
+    let mut client = Client::<TCP, Message, Message>::new(TICKS_PER_SECOND);
+    client.connect(addr, Duration::from_millis(500))?;
+
+    while client.ticks() < 30 {
+        for _ in client.receive()? {}
+        if client.ticks().is_multiple_of(4) {
+            client.send(Message::Move(client.ticks() as i32, -1))?;
+        }
+        if client.ticks() == 15 {
+            client.send(Message::Chat("gg".to_string()))?;
+        }
+        client.sleep();
+    }
+
+    client.disconnect()
+
+}
+
+fn main() -> Result<(), Error> {
+
+    let path = env::temp_dir().join("cobalt_replay.cap");
+
+    // Record a short session over TCP with the clients on their own thread
+    let mut recorded = Vec::new();
+    {
+        let mut server = SymmetricServer::<TCP, Message, ()>::new(TICKS_PER_SECOND);
+        server.set_tap(FileTap::create(&path)?);
+        server.bind("127.0.0.1:0")?;
+
+        let addr = server.local_addr()?;
+        let handle = thread::spawn(move || client(addr));
+
+        while server.ticks() < SESSION_TICKS {
+            for _ in server.accepted_with(|_| Ok(())) {}
+            let ticks = server.ticks();
+            for &mut (ref mut r, _) in server.connected() {
+                recorded.extend(r.receive().map(|m| (ticks, m)));
+            }
+            for _ in server.closed() {}
+            server.sleep();
+        }
+
+        handle.join().ok();
+        server.clear_tap();
+    }
+
+    // Play the capture back without a network, as fast as the mock clock allows
+    let capture = Capture::load(&path)?;
+    println!("[Replay] {} connection(s) recorded", capture.connections());
+
+    let clock = Arc::new(MockClock::new());
+    let mut server = SymmetricServer::<Replay, Message, ()>::with_clock(f64::from(TICKS_PER_SECOND), clock.clone());
+    server.set_protocol_config(ReplayConfig::new(capture, f64::from(TICKS_PER_SECOND), clock));
+    server.bind("127.0.0.1:0")?;
+
+    let mut replayed = Vec::new();
+    while server.ticks() < SESSION_TICKS {
+        for _ in server.accepted_with(|_| Ok(())) {}
+        let ticks = server.ticks();
+        for &mut (ref mut r, _) in server.connected() {
+            replayed.extend(r.receive().map(|m| (ticks, m)));
+        }
+        for _ in server.closed() {}
+        server.sleep();
+    }
+
+    for &(tick, ref m) in &replayed {
+        println!("[Replay] Tick {}: {:?}", tick, m);
+    }
+    println!("[Replay] Matches recording: {}", replayed == recorded);
+
+    Ok(())
+
+}
+
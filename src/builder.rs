@@ -0,0 +1,277 @@
+// Copyright (c) 2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::sync::Arc;
+use std::time::Duration;
+use std::marker::PhantomData;
+
+
+// External Dependencies ------------------------------------------------------
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+
+// Internal Dependencies ------------------------------------------------------
+use ::error::Error;
+use ::client::Client;
+use ::server::Server;
+use ::codec::{Codec, BincodeCodec};
+use ::message::FRAME_HEADER_SIZE;
+use ::protocol::{Config, Protocol};
+use ::time::{Clock, SystemClock, MIN_TICKS_PER_SECOND, MAX_TICKS_PER_SECOND};
+
+
+// Statics --------------------------------------------------------------------
+static DEFAULT_TICKS_PER_SECOND: f64 = 60.0;
+
+
+// Server Builder -------------------------------------------------------------
+// Everything which is not set keeps the defaults of Server::new(), invalid
+// settings are reported by build() instead of surfacing in bind() later on
+pub struct ServerBuilder<P: Protocol, In: Serialize + DeserializeOwned, Out: Serialize + DeserializeOwned, D, E: Codec = BincodeCodec> {
+    tick_rate: f64,
+    clock: Arc<dyn Clock>,
+    connection_timeout: Option<Duration>,
+    max_message_size: Option<usize>,
+    max_connections: Option<usize>,
+    ping_interval: Option<u64>,
+    socket_config: Option<Config<P>>,
+    server: PhantomData<fn() -> Server<P, In, Out, D, E>>
+}
+
+impl<P: Protocol, In: Serialize + DeserializeOwned, Out: Serialize + DeserializeOwned, D, E: Codec> ServerBuilder<P, In, Out, D, E> {
+
+    pub fn new() -> Self {
+        Self {
+            tick_rate: DEFAULT_TICKS_PER_SECOND,
+            clock: Arc::new(SystemClock),
+            connection_timeout: None,
+            max_message_size: None,
+            max_connections: None,
+            ping_interval: None,
+            socket_config: None,
+            server: PhantomData
+        }
+    }
+
+    pub fn tick_rate(mut self, ticks_per_second: f64) -> Self {
+        self.tick_rate = ticks_per_second;
+        self
+    }
+
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn connection_timeout(mut self, timeout: Duration) -> Self {
+        self.connection_timeout = Some(timeout);
+        self
+    }
+
+    pub fn max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = Some(max_message_size);
+        self
+    }
+
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = Some(max_connections);
+        self
+    }
+
+    // Ticks between two pings once the warm up is over
+    pub fn ping_interval(mut self, ticks: u64) -> Self {
+        self.ping_interval = Some(ticks);
+        self
+    }
+
+    // Applies to the listener created by bind()
+    pub fn socket_config(mut self, config: Config<P>) -> Self {
+        self.socket_config = Some(config);
+        self
+    }
+
+    pub fn build(self) -> Result<Server<P, In, Out, D, E>, Error> {
+        check_tick_rate(self.tick_rate)?;
+        check_connection_timeout(self.connection_timeout)?;
+        check_max_message_size(self.max_message_size)?;
+        check_ping_interval(self.ping_interval)?;
+        if self.max_connections == Some(0) {
+            return Err(invalid_config("max connections must be at least 1".to_string()));
+        }
+        Ok(self.assemble())
+    }
+
+    // Builds without validating, for the constructors of the server itself
+    pub fn assemble(self) -> Server<P, In, Out, D, E> {
+        let mut server = Server::with_clock(self.tick_rate, self.clock);
+        if let Some(timeout) = self.connection_timeout {
+            server.set_connection_timeout(timeout);
+        }
+        if let Some(max_message_size) = self.max_message_size {
+            server.set_max_message_size(max_message_size);
+        }
+        if let Some(max_connections) = self.max_connections {
+            server.set_max_connections(max_connections);
+        }
+        if let Some(ticks) = self.ping_interval {
+            server.set_ping_interval(ticks);
+        }
+        if let Some(config) = self.socket_config {
+            server.set_protocol_config(config);
+        }
+        server
+    }
+
+}
+
+impl<P: Protocol, In: Serialize + DeserializeOwned, Out: Serialize + DeserializeOwned, D, E: Codec> Default for ServerBuilder<P, In, Out, D, E> {
+    fn default() -> Self {
+        ServerBuilder::new()
+    }
+}
+
+
+// Client Builder -------------------------------------------------------------
+pub struct ClientBuilder<P: Protocol, Out: Serialize + DeserializeOwned, In: Serialize + DeserializeOwned = Out, E: Codec = BincodeCodec> {
+    tick_rate: f64,
+    clock: Arc<dyn Clock>,
+    connection_timeout: Option<Duration>,
+    max_message_size: Option<usize>,
+    ping_interval: Option<u64>,
+    socket_config: Option<Config<P>>,
+    client: PhantomData<fn() -> Client<P, Out, In, E>>
+}
+
+impl<P: Protocol, Out: Serialize + DeserializeOwned, In: Serialize + DeserializeOwned, E: Codec> ClientBuilder<P, Out, In, E> {
+
+    pub fn new() -> Self {
+        Self {
+            tick_rate: DEFAULT_TICKS_PER_SECOND,
+            clock: Arc::new(SystemClock),
+            connection_timeout: None,
+            max_message_size: None,
+            ping_interval: None,
+            socket_config: None,
+            client: PhantomData
+        }
+    }
+
+    pub fn tick_rate(mut self, ticks_per_second: f64) -> Self {
+        self.tick_rate = ticks_per_second;
+        self
+    }
+
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn connection_timeout(mut self, timeout: Duration) -> Self {
+        self.connection_timeout = Some(timeout);
+        self
+    }
+
+    pub fn max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = Some(max_message_size);
+        self
+    }
+
+    // Ticks between two pings once the warm up is over
+    pub fn ping_interval(mut self, ticks: u64) -> Self {
+        self.ping_interval = Some(ticks);
+        self
+    }
+
+    // Applies to every connection attempt
+    pub fn socket_config(mut self, config: Config<P>) -> Self {
+        self.socket_config = Some(config);
+        self
+    }
+
+    pub fn build(self) -> Result<Client<P, Out, In, E>, Error> {
+        check_tick_rate(self.tick_rate)?;
+        check_connection_timeout(self.connection_timeout)?;
+        check_max_message_size(self.max_message_size)?;
+        check_ping_interval(self.ping_interval)?;
+        Ok(self.assemble())
+    }
+
+    // Builds without validating, for the constructors of the client itself
+    pub fn assemble(self) -> Client<P, Out, In, E> {
+        let mut client = Client::with_clock(self.tick_rate, self.clock);
+        if let Some(timeout) = self.connection_timeout {
+            client.set_connection_timeout(timeout);
+        }
+        if let Some(max_message_size) = self.max_message_size {
+            client.set_max_message_size(max_message_size);
+        }
+        if let Some(ticks) = self.ping_interval {
+            client.set_ping_interval(ticks);
+        }
+        if let Some(config) = self.socket_config {
+            client.set_protocol_config(config);
+        }
+        client
+    }
+
+}
+
+impl<P: Protocol, Out: Serialize + DeserializeOwned, In: Serialize + DeserializeOwned, E: Codec> Default for ClientBuilder<P, Out, In, E> {
+    fn default() -> Self {
+        ClientBuilder::new()
+    }
+}
+
+
+// Validation -----------------------------------------------------------------
+fn check_tick_rate(ticks_per_second: f64) -> Result<(), Error> {
+    if (MIN_TICKS_PER_SECOND..=MAX_TICKS_PER_SECOND).contains(&ticks_per_second) {
+        Ok(())
+
+    } else {
+        Err(invalid_config(format!(
+            "tick rate of {} is outside of {} to {} ticks per second",
+            ticks_per_second, MIN_TICKS_PER_SECOND, MAX_TICKS_PER_SECOND
+        )))
+    }
+}
+
+fn check_connection_timeout(timeout: Option<Duration>) -> Result<(), Error> {
+    if timeout == Some(Duration::from_secs(0)) {
+        Err(invalid_config("connection timeout must not be zero".to_string()))
+
+    } else {
+        Ok(())
+    }
+}
+
+fn check_max_message_size(max_message_size: Option<usize>) -> Result<(), Error> {
+    match max_message_size {
+        Some(size) if size < FRAME_HEADER_SIZE => Err(invalid_config(format!(
+            "max message size of {} bytes is smaller than the frame header of {} bytes",
+            size, FRAME_HEADER_SIZE
+        ))),
+        _ => Ok(())
+    }
+}
+
+fn check_ping_interval(ticks: Option<u64>) -> Result<(), Error> {
+    if ticks == Some(0) {
+        Err(invalid_config("ping interval must be at least 1 tick".to_string()))
+
+    } else {
+        Ok(())
+    }
+}
+
+fn invalid_config(reason: String) -> Error {
+    Error::InvalidConfig(reason)
+}
@@ -8,123 +8,1611 @@
 
 
 // STD Dependencies -----------------------------------------------------------
-use std::time::Duration;
-use std::marker::PhantomData;
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::cmp;
+use std::fmt;
+use std::mem;
+use std::slice;
 use std::io::{Error as IOError, ErrorKind};
+use std::thread::{self, JoinHandle};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::time::{Duration, Instant};
+use std::marker::PhantomData;
+use std::collections::HashMap;
 
 
 // External Dependencies ------------------------------------------------------
 use serde::Serialize;
-use serde::de::DeserializeOwned;
-use bincode::{serialize, Infinite};
+use serde::de::{Deserialize, DeserializeOwned};
 
 
 // Internal Dependencies ------------------------------------------------------
-use ::time::Timer;
-use ::protocol::{Protocol, Connection};
-use ::message::{MessageIterator, InternalMessage, create_message_iterator};
+use ::error::Error;
+use ::builder::ClientBuilder;
+use ::time::{CatchUpPolicy, Clock, ConnectionQuality, TickReport, Timer, TimingConfig, Throughput, RateLimit};
+use ::codec::{BorrowCodec, Codec, BincodeCodec};
+use ::discovery::{self, DiscoveredServer};
+use ::replay::Tap;
+use ::server::{ConnectionId, UpdateResult};
+use ::compression::{self, DEFAULT_COMPRESSION_THRESHOLD};
+use ::encryption::KEY_SIZE;
+use ::protocol::{Addr, Address, Config, Protocol, Connection, ToAddrs};
+use ::message::{
+    AckId, BorrowedIterator, ChannelIterator, MessageIterator, RawIterator, StampedIterator, InternalMessage, Incoming, Outgoing, PriorityChannels, QueuePolicies, QueuePolicy, RequestId, DecodeError, LatePolicy,
+    DEFAULT_MAX_MESSAGE_SIZE, DEFAULT_MAX_OUTGOING_SIZE, DEFAULT_CONNECTION_TIMEOUT, DEFAULT_FRAGMENT_SIZE,
+    DEFAULT_REQUEST_TIMEOUT, CLOSE_PROTOCOL_ERROR, CLOSE_VERSION_MISMATCH, PROTOCOL_VERSION, CloseReason, io_close_reason,
+    check_control_prefix, create_borrowed_iterator, create_channel_iterator, create_message_iterator, create_raw_iterator, create_stamped_iterator, from_bytes
+};
+
+
+// Statics --------------------------------------------------------------------
+const TAP_CONNECTION_ID: ConnectionId = ConnectionId(0);
 
 
 // Client Abstraction ---------------------------------------------------------
-pub struct Client<P: Protocol, M: Serialize + DeserializeOwned> {
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ClientState {
+    Disconnected,
+    Connecting,
+    Connected,
+    Lost,
+    Failed
+}
+
+#[derive(Debug)]
+pub enum ClientEvent<M> {
+    Connected,
+    Message(M),
+    Lost,
+    Failed
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ReconnectPolicy {
+    pub max_attempts: Option<u32>,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub timeout: Duration,
+    // Number of messages kept for replay while reconnecting, with 0 making send() fail instead
+    pub queue_size: usize
+}
+
+impl ReconnectPolicy {
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt);
+        self.initial_delay.checked_mul(factor).map_or(self.max_delay, |delay| delay.min(self.max_delay))
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: None,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            timeout: Duration::from_secs(2),
+            queue_size: 0
+        }
+    }
+}
+
+pub struct Client<P: Protocol, Out: Serialize + DeserializeOwned, In: Serialize + DeserializeOwned = Out, E: Codec = BincodeCodec> {
     connection: Option<P::Connection>,
-    incoming: Vec<u8>,
-    internal_messages: Vec<InternalMessage>,
+    connecting: Option<Connecting<P>>,
+    connected_addr: Option<Addr<P>>,
+    addrs: Vec<Addr<P>>,
+    protocol_config: Config<P>,
+    reconnect: Option<ReconnectPolicy>,
+    reconnect_attempts: u32,
+    next_reconnect: Option<Instant>,
+    queued: Vec<(u8, Vec<u8>)>,
+    preconnect: Vec<(u8, Out)>,
+    preconnect_size: usize,
+    next_request: u32,
+    next_ack: u32,
+    last_acked: Option<AckId>,
+    acks_reported: u32,
+    pending_requests: Vec<(RequestId, Instant)>,
+    request_timeout: Duration,
+    state: ClientState,
+    close_reason: Option<u8>,
+    closing: Option<CloseReason>,
+    disconnect_reason: Option<CloseReason>,
+    connected_event: bool,
+    lost_event: bool,
+    failed_event: bool,
+    incoming: Incoming<InternalMessage>,
+    outgoing: Outgoing,
+    held: Outgoing,
+    handshaking: bool,
+    version: u32,
+    version_mismatch: Option<u32>,
+    priority_channels: PriorityChannels,
+    queue_policies: QueuePolicies,
+    fragment_size: usize,
+    batching: bool,
+    stamping: bool,
+    compression_threshold: Option<usize>,
+    peer_compression: bool,
+    psk: Option<[u8; KEY_SIZE]>,
+    send_limit: RateLimit,
+    max_outgoing_size: usize,
+    max_message_size: usize,
+    connection_timeout: Duration,
     timer: Timer,
-    message: PhantomData<M>
+    ticks: u64,
+    messages_sent: u64,
+    sent: Throughput,
+    received: Throughput,
+    tap: Option<Box<dyn Tap>>,
+    controls: HashMap<u8, ControlHandler>,
+    outgoing_message: PhantomData<Out>,
+    incoming_message: PhantomData<In>,
+    // Codecs are never instantiated, so they have no say in whether this is Send
+    codec: PhantomData<fn() -> E>
+}
+
+// A connection which is being established in the background, the remaining
+// addresses are tried in order should it fail
+struct Connecting<P: Protocol> {
+    connection: P::Connection,
+    addr: Addr<P>,
+    attempt_deadline: Instant,
+    remaining: Vec<Addr<P>>,
+    deadline: Instant
 }
 
-impl<P: Protocol, M: Serialize + DeserializeOwned> Client<P, M> {
+// Decodes the payload of a control message and hands it to the application
+type ControlHandler = Box<dyn FnMut(&[u8]) -> Result<(), DecodeError> + Send>;
+
+// Client which sends and receives the same message type
+pub type SymmetricClient<P, M, E = BincodeCodec> = Client<P, M, M, E>;
+
+impl<P: Protocol, Out: Serialize + DeserializeOwned, In: Serialize + DeserializeOwned, E: Codec> Client<P, Out, In, E> {
 
     pub fn new(ticks_per_second: u8) -> Self {
+        Client::with_tick_rate(f64::from(ticks_per_second))
+    }
+
+    // Supports fractional rates and rates above 255 ticks per second
+    pub fn with_tick_rate(ticks_per_second: f64) -> Self {
+        ClientBuilder::new().tick_rate(ticks_per_second).assemble()
+    }
+
+    pub fn with_timing(ticks_per_second: f64, timing: TimingConfig) -> Self {
+        let mut client = Client::with_tick_rate(ticks_per_second);
+        client.set_timing(timing);
+        client
+    }
+
+    pub fn with_clock(ticks_per_second: f64, clock: Arc<dyn Clock>) -> Self {
         Self {
             connection: None,
-            incoming: Vec::new(),
-            internal_messages: Vec::new(),
-            timer: Timer::new(ticks_per_second),
-            message: PhantomData
+            connecting: None,
+            connected_addr: None,
+            addrs: Vec::new(),
+            protocol_config: Config::<P>::default(),
+            reconnect: None,
+            reconnect_attempts: 0,
+            next_reconnect: None,
+            queued: Vec::new(),
+            preconnect: Vec::new(),
+            preconnect_size: 0,
+            next_request: 0,
+            next_ack: 0,
+            last_acked: None,
+            acks_reported: 0,
+            pending_requests: Vec::new(),
+            request_timeout: Duration::from_secs(DEFAULT_REQUEST_TIMEOUT),
+            state: ClientState::Disconnected,
+            close_reason: None,
+            closing: None,
+            disconnect_reason: None,
+            connected_event: false,
+            lost_event: false,
+            failed_event: false,
+            incoming: Incoming::new(DEFAULT_MAX_MESSAGE_SIZE),
+            outgoing: Outgoing::new(),
+            held: Outgoing::new(),
+            handshaking: false,
+            version: 0,
+            version_mismatch: None,
+            priority_channels: PriorityChannels::default(),
+            queue_policies: QueuePolicies::default(),
+            fragment_size: DEFAULT_FRAGMENT_SIZE,
+            batching: true,
+            stamping: false,
+            compression_threshold: Some(DEFAULT_COMPRESSION_THRESHOLD),
+            peer_compression: false,
+            psk: None,
+            send_limit: RateLimit::new(),
+            max_outgoing_size: DEFAULT_MAX_OUTGOING_SIZE,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            connection_timeout: Duration::from_secs(DEFAULT_CONNECTION_TIMEOUT),
+            timer: Timer::new(ticks_per_second, clock),
+            ticks: 0,
+            messages_sent: 0,
+            sent: Throughput::new(ticks_per_second),
+            received: Throughput::new(ticks_per_second),
+            tap: None,
+            controls: HashMap::new(),
+            outgoing_message: PhantomData,
+            incoming_message: PhantomData,
+            codec: PhantomData
         }
     }
 
+    // Looks for servers with discovery enabled on the given UDP port, blocking
+    // for the whole timeout while replies are collected
+    pub fn discover(port: u16, timeout: Duration) -> Result<Vec<DiscoveredServer>, Error> {
+        discovery::discover(port, timeout)
+    }
+
+    pub fn state(&self) -> ClientState {
+        self.state
+    }
+
+    pub fn close_reason(&self) -> Option<u8> {
+        self.close_reason
+    }
+
+    // Why the last connection ended, reset once a new one is established
+    pub fn disconnect_reason(&self) -> Option<CloseReason> {
+        self.disconnect_reason
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    // Servers with a different version refuse the connection, which then
+    // fails with Error::VersionMismatch
+    pub fn set_version(&mut self, version: u32) {
+        self.version = version;
+    }
+
+    pub fn ticks_per_second(&self) -> f64 {
+        self.timer.ticks_per_second()
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    // Timing of the last completed tick, see TickReport
+    pub fn last_tick_report(&self) -> TickReport {
+        self.timer.last_tick_report()
+    }
+
+    // Number of ticks which took longer than their budget
+    pub fn overrun_ticks(&self) -> u64 {
+        self.timer.overrun_ticks()
+    }
+
+    // How to make up for ticks which took longer than their budget
+    pub fn set_catch_up(&mut self, policy: CatchUpPolicy) {
+        self.timer.set_catch_up(policy);
+    }
+
+    pub fn time(&self) -> f64 {
+        self.ticks as f64 / self.timer.ticks_per_second()
+    }
+
+    // Time passed since the client connected, unlike time() this includes
+    // overruns
+    pub fn wall_time(&self) -> Duration {
+        self.timer.elapsed()
+    }
+
+    // Whether the server told us that it paused its ticks, see Server::pause()
+    pub fn server_paused(&self) -> bool {
+        self.timer.paused()
+    }
+
     pub fn rtt(&self) -> f64 {
         self.timer.rtt()
     }
 
+    // Whether enough pings were answered for rtt() to be meaningful
+    pub fn rtt_is_stable(&self) -> bool {
+        self.timer.rtt_is_stable()
+    }
+
+    pub fn jitter(&self) -> f64 {
+        self.timer.jitter()
+    }
+
+    pub fn rtt_bounds(&self) -> (f64, f64) {
+        self.timer.rtt_bounds()
+    }
+
+    pub fn quality(&self) -> Option<ConnectionQuality> {
+        self.timer.quality()
+    }
+
     pub fn clock(&self) -> f64 {
         self.timer.clock()
     }
 
-    pub fn peer_addr(&self) -> Result<SocketAddr, IOError> {
+    // Spread of the clock shift samples, which bounds the accuracy of the ages
+    // yielded by receive_stamped()
+    pub fn clock_deviation(&self) -> f64 {
+        self.timer.clock_deviation()
+    }
+
+    // Whether rtt() and clock() have settled after the warm up
+    pub fn clock_synchronized(&self) -> bool {
+        self.timer.synchronized()
+    }
+
+    pub fn timing(&self) -> TimingConfig {
+        self.timer.timing()
+    }
+
+    // Changing a window size discards the samples averaged so far
+    pub fn set_timing(&mut self, timing: TimingConfig) {
+        self.timer.set_timing(timing);
+    }
+
+    // Pings are sent every tick for this many ticks after connecting
+    pub fn set_ping_warmup(&mut self, ticks: u64) {
+        self.timer.set_ping_warmup(ticks);
+    }
+
+    // Ticks between two pings once the warm up is over
+    pub fn set_ping_interval(&mut self, ticks: u64) {
+        self.timer.set_ping_interval(ticks);
+    }
+
+    // Estimate of the server's wall clock in milliseconds since the epoch, off by
+    // up to half the RTT when the network delay is asymmetric; None until the
+    // clock shift has been measured
+    pub fn server_time(&self) -> Option<f64> {
+        self.timer.remote_time()
+    }
+
+    // Estimate of the server's current tick based on the last pong, which is
+    // usually accurate to within one tick; None until the first pong arrived
+    pub fn estimated_server_tick(&self) -> Option<u64> {
+        self.timer.remote_tick()
+    }
+
+    // Number of ticks until the server reaches the given tick, negative once
+    // it has passed; None until the first pong arrived
+    pub fn ticks_until(&self, server_tick: u64) -> Option<i64> {
+        self.timer.ticks_until_remote(server_tick)
+    }
+
+    pub fn messages_sent(&self) -> u64 {
+        self.messages_sent
+    }
+
+    // Frames written so far, below messages_sent() when messages were batched
+    pub fn frames_sent(&self) -> u64 {
+        self.outgoing.total_frames_sent()
+    }
+
+    // Outgoing messages replaced by newer ones according to their queue policy
+    pub fn dropped_frames(&self) -> u64 {
+        self.outgoing.dropped_frames() + self.held.dropped_frames()
+    }
+
+    pub fn messages_received(&self) -> u64 {
+        self.incoming.messages()
+    }
+
+    pub fn bytes_sent(&self) -> usize {
+        self.sent.total()
+    }
+
+    pub fn bytes_received(&self) -> usize {
+        self.received.total()
+    }
+
+    pub fn bytes_sent_per_second(&self) -> f64 {
+        self.sent.per_second(self.timer.ticks_per_second())
+    }
+
+    pub fn bytes_received_per_second(&self) -> f64 {
+        self.received.per_second(self.timer.ticks_per_second())
+    }
+
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.max_message_size = max_message_size;
+        self.incoming.set_max_message_size(max_message_size);
+    }
+
+    pub fn max_outgoing_size(&self) -> usize {
+        self.max_outgoing_size
+    }
+
+    // Takes effect with the next connection attempt
+    pub fn set_protocol_config(&mut self, config: Config<P>) {
+        self.protocol_config = config;
+    }
+
+    pub fn set_max_outgoing_size(&mut self, max_outgoing_size: usize) {
+        self.max_outgoing_size = max_outgoing_size;
+    }
+
+    // Includes messages which are held back until the handshake completed
+    pub fn outgoing_len(&self) -> usize {
+        self.outgoing.len() + self.held.len()
+    }
+
+    // Bytes which were received but not yet handed out by receive()
+    pub fn pending_incoming_bytes(&self) -> usize {
+        self.incoming.pending_bytes()
+    }
+
+    // Pings, acks and other internal messages waiting to be handled on the next tick
+    pub fn pending_internal_messages(&self) -> usize {
+        self.incoming.pending_internal()
+    }
+
+    // Caps the bytes written to the connection per second, everything above
+    // the limit stays buffered until send() reports a full buffer
+    pub fn set_send_limit(&mut self, bytes_per_second: Option<usize>) {
+        self.send_limit.set(bytes_per_second);
+    }
+
+    // Bytes which can still be written during the current tick, if limited
+    pub fn send_budget(&self) -> Option<usize> {
+        self.send_limit.budget()
+    }
+
+    pub fn fragment_size(&self) -> usize {
+        self.fragment_size
+    }
+
+    // Messages larger than this are sent in fragments, interleaved with the
+    // other outgoing frames
+    pub fn set_fragment_size(&mut self, fragment_size: usize) {
+        self.fragment_size = fragment_size;
+        self.outgoing.set_fragment_size(fragment_size);
+        self.held.set_fragment_size(fragment_size);
+    }
+
+    // Messages queued on the same channel within a tick share a single frame,
+    // disabling this sends one frame per message which is easier to debug
+    pub fn set_batching(&mut self, batching: bool) {
+        self.batching = batching;
+        self.outgoing.set_batching(batching);
+        self.held.set_batching(batching);
+    }
+
+    // Puts the tick in front of every message so the server can tell their
+    // age, this is announced during the handshake and takes effect once the
+    // client connects the next time
+    pub fn set_stamping(&mut self, stamping: bool) {
+        self.stamping = stamping;
+    }
+
+    // Frames on high priority channels are sent ahead of all other frames
+    // which are still waiting in the outgoing buffer
+    pub fn set_channel_priority(&mut self, channel: u8, priority: bool) {
+        self.priority_channels.set(channel, priority);
+    }
+
+    // Lets newer messages on the channel replace those which are still
+    // waiting for a slow connection, see dropped_frames()
+    pub fn set_queue_policy(&mut self, channel: u8, policy: QueuePolicy) {
+        self.queue_policies.set(channel, policy);
+    }
+
+    pub fn connection_timeout(&self) -> Duration {
+        self.connection_timeout
+    }
+
+    pub fn set_connection_timeout(&mut self, timeout: Duration) {
+        self.connection_timeout = timeout;
+    }
+
+    pub fn set_strict(&mut self, strict: bool) {
+        self.incoming.set_strict(strict);
+    }
+
+    pub fn set_late_policy(&mut self, policy: LatePolicy) {
+        self.incoming.set_late_policy(policy);
+    }
+
+    // Sees every byte read from and written to the server, the connection id is
+    // always ConnectionId(0) and ticks start over with every connection
+    pub fn set_tap<T: Tap + 'static>(&mut self, tap: T) {
+        self.tap = Some(Box::new(tap));
+    }
+
+    pub fn clear_tap(&mut self) {
+        self.tap = None;
+    }
+
+    // Handles the control messages the server sends with Remote::send_control(),
+    // prefixes from 16 upwards are available to applications
+    pub fn register_control<M: Serialize + DeserializeOwned + 'static, F: FnMut(M) + Send + 'static>(&mut self, prefix: u8, mut handler: F) -> Result<(), Error> where E: 'static {
+        check_control_prefix(prefix)?;
+        self.controls.insert(prefix, Box::new(move |bytes| {
+            handler(from_bytes::<E, M>(prefix, bytes)?);
+            Ok(())
+        }));
+        Ok(())
+    }
+
+    pub fn unregister_control(&mut self, prefix: u8) {
+        self.controls.remove(&prefix);
+    }
+
+    // Stops reading from the connection once this many bytes are waiting to
+    // be received by the application
+    pub fn set_max_incoming_size(&mut self, max_incoming_size: usize) {
+        self.incoming.set_max_incoming_size(max_incoming_size);
+    }
+
+    // Limits the bytes read from the connection per tick
+    pub fn set_read_limit(&mut self, bytes_per_tick: Option<usize>) {
+        self.incoming.set_read_limit(bytes_per_tick);
+    }
+
+    // Number of reads which stopped early because of the incoming limits
+    pub fn reads_throttled(&self) -> u64 {
+        self.incoming.reads_throttled()
+    }
+
+    // Frames above the threshold are compressed if the server supports it,
+    // None disables compression of outgoing frames
+    #[cfg(feature = "compression")]
+    pub fn set_compression_threshold(&mut self, threshold: Option<usize>) {
+        self.compression_threshold = threshold;
+        self.update_compression();
+    }
+
+    // Encrypts the frames of all connections made afterwards, the server has
+    // to use the same key
+    #[cfg(feature = "encryption")]
+    pub fn set_psk(&mut self, psk: [u8; KEY_SIZE]) {
+        self.psk = Some(psk);
+    }
+
+    // Bytes which compression kept off the wire so far
+    pub fn compressed_bytes_saved(&self) -> usize {
+        self.outgoing.compressed_bytes_saved()
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        self.request_timeout
+    }
+
+    pub fn set_request_timeout(&mut self, timeout: Duration) {
+        self.request_timeout = timeout;
+    }
+
+    pub fn set_auto_reconnect(&mut self, policy: Option<ReconnectPolicy>) {
+        self.reconnect = policy;
+        self.reconnect_attempts = 0;
+        self.next_reconnect = None;
+        self.queued.clear();
+    }
+
+    // Lets send() and send_on() keep up to max_messages while disconnected or
+    // connecting instead of failing, they are encoded and sent in order once
+    // the handshake completed; 0 turns the queue off again
+    pub fn set_preconnect_queue(&mut self, max_messages: usize) {
+        self.preconnect_size = max_messages;
+    }
+
+    // Messages still waiting for a connection, e.g. after a failed connect or
+    // a disconnect(); left alone they are sent with the next connection
+    pub fn take_unsent(&mut self) -> Vec<Out> {
+        self.preconnect.drain(0..).map(|(_, message)| message).collect()
+    }
+
+    pub fn peer_addr(&self) -> Result<Addr<P>, Error> {
         if let Some(connection) = self.connection.as_ref() {
-            connection.peer_addr()
+            Ok(connection.peer_addr()?)
 
         } else {
-            Err(IOError::new(ErrorKind::NotConnected, ""))
+            Err(Error::NotConnected)
         }
     }
 
-    pub fn connect<A: ToSocketAddrs>(&mut self, addr: A, timeout: Duration) -> Result<(), IOError> {
+    pub fn local_addr(&self) -> Result<Addr<P>, Error> {
+        if let Some(connection) = self.connection.as_ref() {
+            Ok(connection.local_addr()?)
+
+        } else {
+            Err(Error::NotConnected)
+        }
+    }
+
+    // All resolved addresses are tried in order, each getting an equal share
+    // of what is left of the timeout
+    pub fn connect<A: ToAddrs<Addr<P>>>(&mut self, addr: A, timeout: Duration) -> Result<(), Error> {
+        let addrs = self.resolve(addr)?;
+        self.connect_addrs(addrs, timeout)
+    }
+
+    // Like connect() but with an explicit list of addresses, e.g. a primary
+    // server and its fallbacks
+    pub fn connect_any(&mut self, addrs: &[Addr<P>], timeout: Duration) -> Result<(), Error> {
+        if self.connection.is_some() || self.connecting.is_some() {
+            Err(Error::AlreadyConnected)
+
+        } else if addrs.is_empty() {
+            Err(Error::AddressResolution)
+
+        } else {
+            self.connect_addrs(addrs.to_vec(), timeout)
+        }
+    }
+
+    // The address which the current connection was established with
+    pub fn connected_addr(&self) -> Option<Addr<P>> {
+        self.connection.as_ref().and(self.connected_addr.clone())
+    }
+
+    // Returns immediately, with the connection being established during the
+    // following calls to receive() and sleep()
+    pub fn connect_nonblocking<A: ToAddrs<Addr<P>>>(&mut self, addr: A, timeout: Duration) -> Result<(), Error> {
+        self.addrs = self.resolve(addr)?;
+        self.start_connect(timeout)
+    }
+
+    pub fn send(&mut self, message: Out) -> Result<(), Error> {
+        self.send_on(0, message)
+    }
+
+    // Messages keep their order within a channel, channel 0 is used by send()
+    pub fn send_on(&mut self, channel: u8, message: Out) -> Result<(), Error> {
+        if self.preconnecting() {
+            if self.preconnect.len() >= self.preconnect_size {
+                Err(Error::BufferFull)
+
+            } else {
+                self.preconnect.push((channel, message));
+                Ok(())
+            }
+
+        } else {
+            self.send_with(channel, |outgoing, max_message_size| outgoing.encode::<E, Out>(&message, max_message_size))
+        }
+    }
+
+    // Sends bytes which were encoded beforehand as a message on channel 0, they
+    // must be a valid encoding of a message unless the server uses receive_raw().
+    // The preconnect queue only keeps messages, so without a connection these
+    // fail with NotConnected even while send() would queue them
+    pub fn send_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.send_with(0, |outgoing, max_message_size| outgoing.encode_raw(bytes, max_message_size))
+    }
+
+    // Control messages are neither counted as messages nor queued while
+    // reconnecting, see Server::register_control()
+    pub fn send_control<M: Serialize>(&mut self, prefix: u8, message: M) -> Result<(), Error> {
+        check_control_prefix(prefix)?;
         if self.connection.is_none() {
-            let connection = P::Connection::connect(addr, timeout)?;
-            self.connection = Some(connection);
-            self.timer.reset();
-            Ok(())
+            Err(self.not_connected())
+
+        } else if self.outgoing_len() >= self.max_outgoing_size {
+            Err(Error::BufferFull)
 
         } else {
-            Err(IOError::new(ErrorKind::AlreadyExists, ""))
+            let bytes = self.outgoing.encode::<E, _>(&message, self.max_message_size)?;
+            self.app_outgoing().push_frame(prefix, bytes);
+            self.send_outgoing()?;
+            Ok(())
         }
     }
 
-    pub fn send(&mut self, message: M) -> Result<(), IOError> {
-        self.send_raw(1, message)
+    pub fn decode_errors(&mut self) -> impl Iterator<Item=DecodeError> {
+        self.incoming.drain_decode_errors().into_iter()
     }
 
-    pub fn receive(&mut self) -> Result<MessageIterator<M, InternalMessage>, IOError> {
-        if let Some(connection) = self.connection.as_mut() {
-            connection.read(&mut self.incoming)?;
-            Ok(create_message_iterator(&mut self.incoming, &mut self.internal_messages))
+    pub fn request(&mut self, message: Out) -> Result<RequestId, Error> {
+        let id = RequestId(self.next_request);
+        self.send_correlated(2, id, message)?;
+        self.next_request = self.next_request.wrapping_add(1);
+        self.pending_requests.push((id, self.timer.now()));
+        Ok(id)
+    }
+
+    // Yields the responses received so far, as well as errors for requests
+    // which were not answered within the request timeout
+    pub fn responses(&mut self) -> impl Iterator<Item=(RequestId, Result<In, Error>)> {
+
+        self.incoming.extract_frames::<E>();
+
+        let mut responses = Vec::new();
+        for (id, message) in self.incoming.drain_correlated::<E, In>(3) {
+            // Responses to requests which already timed out are dropped
+            if let Some(index) = self.pending_requests.iter().position(|&(p, _)| p == id) {
+                self.pending_requests.remove(index);
+                responses.push((id, Ok(message)));
+            }
+        }
+
+        let now = self.timer.now();
+        let timeout = self.request_timeout;
+        self.pending_requests.retain(|&(id, sent)| {
+            if now.duration_since(sent) > timeout {
+                responses.push((id, Err(Error::RequestTimeout)));
+                false
+
+            } else {
+                true
+            }
+        });
+
+        responses.into_iter()
+
+    }
+
+    // The server acknowledges these messages once its application received them,
+    // ids start over with every connection
+    pub fn send_acked(&mut self, message: Out) -> Result<AckId, Error> {
+        if self.connection.is_none() {
+            Err(self.not_connected())
+
+        } else if self.outgoing_len() >= self.max_outgoing_size {
+            Err(Error::BufferFull)
 
         } else {
-            Err(IOError::new(ErrorKind::NotConnected, ""))
+            let bytes = self.outgoing.encode::<E, Out>(&message, self.max_message_size)?;
+            let id = AckId(self.next_ack);
+            self.next_ack = self.next_ack.wrapping_add(1);
+            self.app_outgoing().push_acked_frame(id, bytes);
+            self.messages_sent += 1;
+            self.send_outgoing()?;
+            Ok(id)
         }
     }
 
-    pub fn sleep(&mut self) {
-        let messages = self.internal_messages.drain(0..).collect::<Vec<_>>();
-        for m in self.timer.receive(messages) {
-            self.send_raw(0, m).ok();
+    // The message is held back by the server's receive_for_tick() until it
+    // reaches the given tick, see estimated_server_tick()
+    pub fn send_at_tick(&mut self, tick: u64, message: Out) -> Result<(), Error> {
+        if self.connection.is_none() {
+            Err(self.not_connected())
+
+        } else if self.outgoing_len() >= self.max_outgoing_size {
+            Err(Error::BufferFull)
+
+        } else {
+            let bytes = self.outgoing.encode::<E, Out>(&message, self.max_message_size)?;
+            self.app_outgoing().push_scheduled_frame(tick, bytes);
+            self.messages_sent += 1;
+            self.send_outgoing()?;
+            Ok(())
+        }
+    }
+
+    // Yields the acked messages which were processed by the server since the last call
+    pub fn acked(&mut self) -> impl Iterator<Item=AckId> {
+        let end = self.last_acked.map_or(0, |id| id.0.wrapping_add(1));
+        let start = mem::replace(&mut self.acks_reported, end);
+        (0..end.wrapping_sub(start)).map(move |offset| AckId(start.wrapping_add(offset)))
+    }
+
+    pub fn last_acked(&self) -> Option<AckId> {
+        self.last_acked
+    }
+
+    pub fn receive(&mut self) -> Result<MessageIterator<'_, In, InternalMessage, E>, Error> {
+        self.read_incoming()?;
+        Ok(create_message_iterator(&mut self.incoming, None))
+    }
+
+    // Like receive() but also yields the channel of each message
+    pub fn receive_channels(&mut self) -> Result<ChannelIterator<'_, In, InternalMessage, E>, Error> {
+        self.read_incoming()?;
+        Ok(create_channel_iterator(&mut self.incoming, None))
+    }
+
+    // Yields the messages sent with send_at_tick() for this and all earlier
+    // server ticks along with their tick, messages for later ticks are held back
+    pub fn receive_for_tick(&mut self, tick: u64) -> Result<impl Iterator<Item=(u64, In)>, Error> {
+        self.read_incoming()?;
+        self.incoming.extract_frames::<E>();
+        Ok(self.incoming.drain_scheduled::<E, In>(tick).into_iter())
+    }
+
+    // Like receive() but leaves decoding the messages to the application
+    pub fn receive_raw(&mut self) -> Result<RawIterator<'_, InternalMessage, E>, Error> {
+        self.read_incoming()?;
+        Ok(create_raw_iterator(&mut self.incoming, None))
+    }
+
+    // Like receive() but decodes into types which borrow from the incoming
+    // buffer, e.g. with &[u8] fields, instead of copying out of it
+    pub fn receive_borrowed<'a, M: Deserialize<'a>>(&'a mut self) -> Result<BorrowedIterator<'a, M, E>, Error> where E: BorrowCodec {
+        self.read_incoming()?;
+        Ok(create_borrowed_iterator::<M, InternalMessage, E>(&mut self.incoming, None))
+    }
+
+    // Like receive() but also yields how long ago the server sent each
+    // message, which requires the server to have stamping enabled
+    pub fn receive_stamped(&mut self) -> Result<StampedIterator<'_, In, InternalMessage, E>, Error> {
+        self.read_incoming()?;
+        Ok(create_stamped_iterator(&mut self.incoming, None, self.timer.peer_clock()))
+    }
+
+    // Whether the server stamps its messages with its tick
+    pub fn stamped(&self) -> bool {
+        self.incoming.stamped()
+    }
+
+    // Equivalent to receive() plus the connection state changes since the last call
+    pub fn events(&mut self) -> impl Iterator<Item=ClientEvent<In>> {
+
+        let mut events = Vec::new();
+        if self.connected_event {
+            self.connected_event = false;
+            events.push(ClientEvent::Connected);
+        }
+
+        if let Ok(messages) = self.receive() {
+            events.extend(messages.map(ClientEvent::Message));
+        }
+
+        if self.lost_event {
+            self.lost_event = false;
+            events.push(ClientEvent::Lost);
+        }
+
+        if self.failed_event {
+            self.failed_event = false;
+            events.push(ClientEvent::Failed);
         }
+
+        events.into_iter()
+
+    }
+
+    pub fn sleep(&mut self) {
+        self.tick();
         self.timer.sleep();
+
+        #[cfg(feature = "log")]
+        {
+            let report = self.timer.last_tick_report();
+            if report.overrun > Duration::new(0, 0) {
+                debug!("Client tick {} overran its budget by {:?}, {} ticks behind", self.ticks, report.overrun, report.ticks_behind);
+            }
+        }
     }
 
-    pub fn disconnect(&mut self) -> Result<(), IOError> {
+    // The frame driven counterpart of events() and sleep(), which must not be
+    // mixed within a tick; the tick itself is only processed once its time has
+    // passed and otherwise buffered messages are just flushed
+    pub fn update(&mut self) -> UpdateResult<ClientEvent<In>> {
+
+        let ticked = self.timer.try_tick();
+        let events = self.events().collect();
+        if ticked {
+            self.tick();
+
+        } else if self.connection.is_some() {
+            self.send_outgoing().ok();
+        }
+
+        UpdateResult {
+            events: events,
+            ticked: ticked
+        }
+
+    }
+
+    // Sends buffered bytes right away instead of during the next sleep()
+    pub fn flush(&mut self) -> Result<usize, Error> {
+        self.send_outgoing()
+    }
+
+    pub fn disconnect(&mut self) -> Result<(), Error> {
+        self.next_reconnect = None;
+        self.queued.clear();
+        if let Some(mut connecting) = self.connecting.take() {
+            self.state = ClientState::Disconnected;
+            return Ok(connecting.connection.shutdown()?);
+        }
+        if self.connection.is_some() {
+            self.send_internal(InternalMessage::Close(0)).ok();
+            self.flush().ok();
+        }
         if let Some(mut connection) = self.connection.take() {
-            connection.shutdown()
+            #[cfg(feature = "log")]
+            info!("Disconnected from {}", self.peer());
+            self.state = ClientState::Disconnected;
+            self.disconnect_reason = Some(CloseReason::LocalClose);
+            Ok(connection.shutdown()?)
 
         } else {
-            Err(IOError::new(ErrorKind::NotConnected, ""))
+            Err(Error::NotConnected)
         }
     }
 
 
     // Internal ---------------------------------------------------------------
-    fn send_raw<T: Serialize + DeserializeOwned>(&mut self, prefix: u8, message: T) -> Result<(), IOError> {
-        if let Some(connection) = self.connection.as_mut() {
-            if let Ok(message_bytes) = serialize(&message, Infinite) {
-                let mut bytes = vec![prefix];
-                bytes.extend(message_bytes);
-                connection.write(&bytes[..])?;
+    fn tick(&mut self) {
+
+        if let Some(reason) = self.closing.take() {
+            self.lose_connection(reason);
+        }
+
+        self.poll_connect();
+        self.try_reconnect();
+        self.outgoing.tick();
+        self.incoming.extract_frames::<E>();
+
+        if self.incoming.violation() {
+            self.begin_close(CLOSE_PROTOCOL_ERROR, CloseReason::ProtocolError);
+        }
+
+        let mut messages = Vec::new();
+        for m in self.incoming.drain_internal() {
+            match m {
+                InternalMessage::Close(code) => self.receive_close(code),
+                InternalMessage::Ack(id) => self.last_acked = Some(AckId(id)),
+                InternalMessage::Pause => self.timer.pause(),
+                InternalMessage::Resume => self.timer.resume(true),
+                InternalMessage::Hello { protocol_version, version, ticks_per_second, compression, .. } => {
+                    if protocol_version != PROTOCOL_VERSION || version != self.version {
+                        self.reject_version(version);
+
+                    } else {
+                        self.timer.set_ticks_per_second(ticks_per_second);
+                        self.peer_compression = compression;
+                        self.update_compression();
+                        self.complete_handshake();
+                    }
+                },
+                m => messages.push(m)
+            }
+        }
+
+        // Control messages without a handler are skipped
+        let controls = &mut self.controls;
+        self.incoming.dispatch_control(|prefix, bytes| match controls.get_mut(&prefix) {
+            Some(handler) => handler(bytes),
+            None => Ok(())
+        });
+
+        if self.connection.is_some() {
+            self.send_limit.tick(self.timer.ticks_per_second());
+            for m in self.timer.receive(messages, self.ticks) {
+                self.send_internal(m).ok();
+            }
+
+            // Report the acked messages the application went through this tick
+            if let Some(id) = self.incoming.take_ack() {
+                self.send_internal(InternalMessage::Ack(id)).ok();
+            }
+            self.send_outgoing().ok();
+
+            // Stop waiting for servers which no longer answer our pings
+            if self.timer.timed_out(self.connection_timeout) || self.timer.dead() {
+                self.lose_connection(CloseReason::Timeout);
+            }
+        }
+
+        self.sent.tick();
+        self.received.tick();
+        self.incoming.tick();
+        self.ticks += 1;
+        if self.outgoing.stamping() {
+            self.outgoing.set_stamp(Some(self.ticks as u32));
+            self.held.set_stamp(Some(self.ticks as u32));
+        }
+
+    }
+
+    fn resolve<A: ToAddrs<Addr<P>>>(&self, addr: A) -> Result<Vec<Addr<P>>, Error> {
+        if self.connection.is_some() || self.connecting.is_some() {
+            Err(Error::AlreadyConnected)
+
+        } else {
+            let addrs = addr.to_addrs().map_err(|_| Error::AddressResolution)?;
+            if addrs.is_empty() {
+                Err(Error::AddressResolution)
+
+            } else {
+                Ok(addrs)
+            }
+        }
+    }
+
+    fn connect_addrs(&mut self, addrs: Vec<Addr<P>>, timeout: Duration) -> Result<(), Error> {
+        self.addrs = addrs;
+        self.state = ClientState::Connecting;
+        self.version_mismatch = None;
+
+        let deadline = Instant::now() + timeout;
+        let mut failures = Vec::new();
+        for (index, addr) in self.addrs.iter().enumerate() {
+            let timeout = attempt_timeout(deadline, Instant::now(), self.addrs.len() - index);
+            match P::Connection::connect(slice::from_ref(addr), timeout, &self.protocol_config) {
+                Ok(connection) => {
+                    let addr = addr.clone();
+                    self.connected(connection, addr);
+                    return Ok(());
+                },
+                Err(err) => failures.push((addr.clone(), err))
+            }
+        }
+
+        self.state = ClientState::Disconnected;
+        Err(connect_error(failures))
+    }
+
+    fn start_connect(&mut self, timeout: Duration) -> Result<(), Error> {
+        let deadline = self.timer.now() + timeout;
+        self.connecting = Some(self.connect_next(self.addrs.clone(), deadline, Vec::new())?);
+        self.state = ClientState::Connecting;
+        self.failed_event = false;
+        self.version_mismatch = None;
+        self.incoming.clear();
+        Ok(())
+    }
+
+    fn read_incoming(&mut self) -> Result<(), Error> {
+
+        self.poll_connect();
+
+        let limit = self.incoming.read_limit();
+        let bytes = if limit == 0 {
+            self.incoming.received(0, limit);
+            return Ok(());
+
+        } else if let Some(connection) = self.connection.as_mut() {
+            let buffer = self.incoming.buffer_mut();
+            let bytes = connection.read(buffer, limit);
+            if let (Some(tap), Ok(bytes)) = (self.tap.as_mut(), bytes.as_ref()) {
+                tap.on_read(TAP_CONNECTION_ID, self.ticks, &buffer[buffer.len() - bytes..]);
+            }
+            bytes
+
+        // Messages which arrived right before the connection was lost are still delivered
+        } else if !self.incoming.is_empty() || self.connecting.is_some() {
+            return Ok(());
+
+        } else {
+            return Err(self.not_connected());
+        };
+
+        match bytes {
+            Ok(bytes) => {
+                self.incoming.received(bytes, limit);
+                self.received.add(bytes);
+                if self.handshaking {
+                    self.peek_hello();
+                }
+                Ok(())
+            },
+            Err(err) => {
+                self.lose_connection(io_close_reason(err.kind()));
+                Err(Error::Io(err))
+            }
+        }
+    }
+
+    fn preconnecting(&self) -> bool {
+        self.preconnect_size > 0 && !self.reconnecting() && match self.state {
+            ClientState::Disconnected | ClientState::Connecting => true,
+            ClientState::Connected | ClientState::Lost | ClientState::Failed => false
+        }
+    }
+
+    fn reconnecting(&self) -> bool {
+        self.reconnect.is_some() && !self.addrs.is_empty() && match self.state {
+            ClientState::Lost | ClientState::Failed => true,
+            ClientState::Connecting => self.reconnect_attempts > 0,
+            ClientState::Disconnected | ClientState::Connected => false
+        }
+    }
+
+    fn try_reconnect(&mut self) {
+        let policy = match self.reconnect {
+            Some(policy) if self.connecting.is_none() && self.reconnecting() => policy,
+            _ => return
+        };
+
+        // Retrying will not make the versions match
+        if self.version_mismatch.is_some() || policy.max_attempts.is_some_and(|max| self.reconnect_attempts >= max) {
+            return;
+        }
+
+        let now = self.timer.now();
+        if let Some(due) = self.next_reconnect {
+            if now >= due {
+                self.next_reconnect = None;
+                self.reconnect_attempts += 1;
+                #[cfg(feature = "log")]
+                debug!("Reconnecting, attempt {}", self.reconnect_attempts);
+                if self.start_connect(policy.timeout).is_err() {
+                    self.state = ClientState::Failed;
+                }
+            }
+
+        } else {
+            self.next_reconnect = Some(now + policy.delay(self.reconnect_attempts));
+        }
+    }
+
+    fn connected(&mut self, connection: P::Connection, addr: Addr<P>) {
+        self.connection = Some(connection);
+        self.connected_addr = Some(addr);
+        self.close_reason = None;
+        self.disconnect_reason = None;
+        self.closing = None;
+        self.lost_event = false;
+        self.handshaking = true;
+        self.version_mismatch = None;
+        self.incoming.clear();
+        self.incoming.set_encryption(self.psk);
+        self.outgoing.clear();
+        self.outgoing.set_encryption(self.psk);
+        self.held.clear();
+        self.peer_compression = false;
+        self.update_compression();
+        self.pending_requests.clear();
+        self.next_ack = 0;
+        self.last_acked = None;
+        self.acks_reported = 0;
+        self.timer.reset();
+        self.ticks = 0;
+        self.messages_sent = 0;
+        let stamp = if self.stamping { Some(0) } else { None };
+        self.outgoing.set_stamp(stamp);
+        self.held.set_stamp(stamp);
+        if let Some(tap) = self.tap.as_mut() {
+            tap.on_connect(TAP_CONNECTION_ID, 0);
+        }
+        self.sent = Throughput::new(self.timer.ticks_per_second());
+        self.received = Throughput::new(self.timer.ticks_per_second());
+
+        // Let the server know which versions and features are supported first,
+        // the connection is only established once the server answered
+        if let Ok(bytes) = self.outgoing.encode::<E, _>(&InternalMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            version: self.version,
+            ticks_per_second: self.timer.ticks_per_second(),
+            compression: compression::supported(),
+            stamping: self.stamping
+
+        }, self.max_message_size) {
+            self.outgoing.push_internal_frame(bytes);
+        }
+
+        // Replay whatever was sent while reconnecting
+        self.reconnect_attempts = 0;
+        self.next_reconnect = None;
+        for (channel, bytes) in self.queued.drain(0..) {
+            self.held.push_message(channel, self.priority_channels.contains(channel), self.queue_policies.max_frames(channel), bytes);
+            self.messages_sent += 1;
+        }
+    }
+
+    // The layout of the messages following the hello of the server has to be
+    // known before they are handed out, so this cannot wait for the next tick
+    fn peek_hello(&mut self) {
+        self.incoming.extract_frames::<E>();
+        let mut stamped = None;
+        self.incoming.remove_internal(|m| {
+            if let InternalMessage::Hello { stamping, .. } = *m {
+                stamped = Some(stamping);
+            }
+            false
+        });
+        if let Some(stamped) = stamped {
+            self.incoming.set_stamped(stamped);
+        }
+    }
+
+    fn complete_handshake(&mut self) {
+        if self.handshaking {
+            #[cfg(feature = "log")]
+            info!("Connected to {}", self.peer());
+            self.handshaking = false;
+            self.state = ClientState::Connected;
+            self.connected_event = true;
+            self.send_preconnect();
+            let held = mem::replace(&mut self.held, Outgoing::new());
+            self.held.set_fragment_size(self.fragment_size);
+            self.held.set_batching(self.batching);
+            self.outgoing.append(held);
+        }
+    }
+
+    // Queued messages go out ahead of everything sent during the handshake
+    fn send_preconnect(&mut self) {
+        for (channel, message) in mem::take(&mut self.preconnect) {
+            match self.outgoing.encode::<E, Out>(&message, self.max_message_size) {
+                Ok(bytes) => {
+                    let (priority, max_frames) = (self.priority_channels.contains(channel), self.queue_policies.max_frames(channel));
+                    self.outgoing.push_message(channel, priority, max_frames, bytes);
+                    self.messages_sent += 1;
+                },
+                Err(_err) => {
+                    #[cfg(feature = "log")]
+                    warn!("Dropped message queued for channel {} before connecting: {}", channel, _err);
+                }
+            }
+        }
+    }
+
+    fn reject_version(&mut self, server: u32) {
+        if let Some(mut connection) = self.connection.take() {
+            #[cfg(feature = "log")]
+            warn!("Connection to {} rejected, server version {} does not match version {}", self.peer(), server, self.version);
+            connection.shutdown().ok();
+            self.state = ClientState::Failed;
+            self.failed_event = true;
+            self.close_reason = Some(CLOSE_VERSION_MISMATCH);
+            self.disconnect_reason = Some(CloseReason::LocalClose);
+            self.version_mismatch = Some(server);
+        }
+    }
+
+    #[cfg(feature = "log")]
+    fn peer(&self) -> String {
+        self.connected_addr.as_ref().map_or_else(|| "nowhere".to_string(), |addr| format!("{:?}", addr))
+    }
+
+    fn not_connected(&self) -> Error {
+        if let Some(server) = self.version_mismatch {
+            Error::VersionMismatch {
+                server: server
+            }
+
+        } else {
+            Error::NotConnected
+        }
+    }
+
+    // Application frames are held back until the handshake completed
+    fn app_outgoing(&mut self) -> &mut Outgoing {
+        if self.handshaking {
+            &mut self.held
+
+        } else {
+            &mut self.outgoing
+        }
+    }
+
+    fn update_compression(&mut self) {
+        let threshold = self.compression_threshold.filter(|_| self.peer_compression);
+        self.outgoing.set_compression(threshold);
+    }
+
+    // Starts the first attempt which does not fail right away
+    fn connect_next(&self, mut remaining: Vec<Addr<P>>, deadline: Instant, mut failures: Vec<(Addr<P>, IOError)>) -> Result<Connecting<P>, Error> {
+        while !remaining.is_empty() {
+            let now = self.timer.now();
+            let timeout = attempt_timeout(deadline, now, remaining.len());
+            let addr = remaining.remove(0);
+            match P::Connection::connect_start(slice::from_ref(&addr), timeout, &self.protocol_config) {
+                Ok(connection) => return Ok(Connecting {
+                    connection: connection,
+                    addr: addr,
+                    attempt_deadline: now + timeout,
+                    remaining: remaining,
+                    deadline: deadline
+                }),
+                Err(err) => failures.push((addr, err))
+            }
+        }
+        Err(connect_error(failures))
+    }
+
+    fn poll_connect(&mut self) {
+        if let Some(mut connecting) = self.connecting.take() {
+            let now = self.timer.now();
+            let failure = match connecting.connection.connect_poll() {
+                Ok(true) => return self.connected(connecting.connection, connecting.addr),
+                Ok(false) if now < connecting.attempt_deadline => {
+                    self.connecting = Some(connecting);
+                    return;
+                },
+                Ok(false) => IOError::new(ErrorKind::TimedOut, ""),
+                Err(err) => err
+            };
+
+            connecting.connection.shutdown().ok();
+            let next = if now < connecting.deadline {
+                self.connect_next(connecting.remaining, connecting.deadline, vec![(connecting.addr, failure)]).ok()
+
+            } else {
+                None
+            };
+
+            if next.is_some() {
+                self.connecting = next;
+
+            } else {
+                self.state = ClientState::Failed;
+                self.failed_event = true;
+            }
+        }
+    }
+
+    // The connection is only reported as lost on the next tick, so messages
+    // which arrived right before the close request are received first
+    fn receive_close(&mut self, code: u8) {
+        self.begin_close(code, CloseReason::RemoteClose(code));
+    }
+
+    fn begin_close(&mut self, code: u8, reason: CloseReason) {
+        // Acknowledge the close request before shutting down our side
+        self.send_internal(InternalMessage::Close(code)).ok();
+        if self.connection.is_some() && self.closing.is_none() {
+            #[cfg(feature = "log")]
+            debug!("Connection to {} closing: {}", self.peer(), reason);
+            self.closing = Some(reason);
+        }
+    }
+
+    fn lose_connection(&mut self, reason: CloseReason) {
+        // The server shuts down its side as soon as the close was acknowledged
+        let reason = self.closing.take().unwrap_or(reason);
+        if let Some(mut connection) = self.connection.take() {
+            #[cfg(feature = "log")]
+            info!("Connection to {} lost: {}", self.peer(), reason);
+            connection.shutdown().ok();
+            self.state = ClientState::Lost;
+            self.lost_event = true;
+            self.close_reason = match reason {
+                CloseReason::RemoteClose(code) => Some(code),
+                CloseReason::ProtocolError => Some(CLOSE_PROTOCOL_ERROR),
+                _ => None
+            };
+            self.disconnect_reason = Some(reason);
+        }
+    }
+
+    // Internal messages must never be prevented by a full buffer, and all but
+    // closes skip ahead of the application frames queued so far
+    fn send_internal(&mut self, message: InternalMessage) -> Result<(), Error> {
+        if self.connection.is_none() {
+            Err(Error::NotConnected)
+
+        } else {
+            let bytes = self.outgoing.encode::<E, _>(&message, self.max_message_size)?;
+            match message {
+                InternalMessage::Ping(seq, _) => self.outgoing.push_ping_frame(seq, bytes),
+                InternalMessage::Close(_) => self.outgoing.push_frame(0, bytes),
+                _ => self.outgoing.push_internal_frame(bytes)
+            }
+            self.send_outgoing()?;
+            Ok(())
+        }
+    }
+
+    // Messages are only encoded once they can actually be sent or queued, into
+    // one of the spare buffers of the outgoing queue
+    fn send_with<F: FnOnce(&mut Outgoing, usize) -> Result<Vec<u8>, Error>>(&mut self, channel: u8, encode: F) -> Result<(), Error> {
+        if self.connection.is_none() && self.reconnecting() {
+            // Keep messages around until the connection has been re-established
+            let queue_size = self.reconnect.map_or(0, |policy| policy.queue_size);
+            if queue_size == 0 {
+                Err(Error::NotConnected)
+
+            } else if self.queued.len() >= queue_size {
+                Err(Error::BufferFull)
+
+            } else {
+                let bytes = encode(&mut self.outgoing, self.max_message_size)?;
+                self.queued.push((channel, bytes));
                 Ok(())
+            }
+
+        } else {
+            self.send_message(channel, encode)
+        }
+    }
+
+    fn send_message<F: FnOnce(&mut Outgoing, usize) -> Result<Vec<u8>, Error>>(&mut self, channel: u8, encode: F) -> Result<(), Error> {
+        if self.connection.is_none() {
+            Err(self.not_connected())
+
+        } else if self.outgoing_len() >= self.max_outgoing_size {
+            Err(Error::BufferFull)
+
+        } else {
+            let bytes = encode(&mut self.outgoing, self.max_message_size)?;
+            #[cfg(feature = "log")]
+            trace!("Queued {} bytes on channel {}", bytes.len(), channel);
+            let (priority, max_frames) = (self.priority_channels.contains(channel), self.queue_policies.max_frames(channel));
+            self.app_outgoing().push_message(channel, priority, max_frames, bytes);
+            self.messages_sent += 1;
+            self.send_outgoing()?;
+            Ok(())
+        }
+    }
+
+    fn send_correlated(&mut self, prefix: u8, id: RequestId, message: Out) -> Result<(), Error> {
+        if self.connection.is_none() {
+            Err(self.not_connected())
+
+        } else if self.outgoing_len() >= self.max_outgoing_size {
+            Err(Error::BufferFull)
+
+        } else {
+            let bytes = self.outgoing.encode::<E, Out>(&message, self.max_message_size)?;
+            self.app_outgoing().push_correlated_frame(prefix, id, bytes);
+            self.messages_sent += 1;
+            self.send_outgoing()?;
+            Ok(())
+        }
+    }
+
+    fn send_outgoing(&mut self) -> Result<usize, Error> {
+        if let Some(connection) = self.connection.as_mut() {
+            if self.outgoing.is_empty() || self.send_limit.available() == 0 {
+                Ok(0)
 
             } else {
-                Err(IOError::new(ErrorKind::InvalidData, ""))
+                // Keep whatever could not be written for the next attempt
+                let bytes = if let Some(tap) = self.tap.as_mut() {
+                    let ticks = self.ticks;
+                    self.outgoing.write_tapped(connection, self.send_limit.available(), |bytes| tap.on_write(TAP_CONNECTION_ID, ticks, bytes))?
+
+                } else {
+                    self.outgoing.write_to(connection, self.send_limit.available())?
+                };
+                for seq in self.outgoing.written_pings() {
+                    self.timer.ping_written(seq);
+                }
+                self.send_limit.consume(bytes);
+                self.sent.add(bytes);
+                Ok(bytes)
             }
 
         } else {
-            Err(IOError::new(ErrorKind::NotConnected, ""))
+            Err(Error::NotConnected)
         }
     }
 
 }
 
+impl<P: Protocol, Out: Serialize + DeserializeOwned, In: Serialize + DeserializeOwned, E: Codec> Drop for Client<P, Out, In, E> {
+    // Lets the server know right away instead of leaving it to a reset or timeout
+    fn drop(&mut self) {
+        self.disconnect().ok();
+    }
+}
+
+// Client(state=Connected peer=Some(127.0.0.1:7156) rtt=12.4ms clock=1.2ms outgoing=0 incoming=18 ticks=240)
+impl<P: Protocol, Out: Serialize + DeserializeOwned, In: Serialize + DeserializeOwned, E: Codec> fmt::Debug for Client<P, Out, In, E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f, "Client(state={:?} peer={:?} rtt={:.1}ms clock={:.1}ms outgoing={} incoming={} ticks={})",
+            self.state, self.connected_addr, self.timer.rtt(), self.timer.clock(), self.outgoing.len(), self.incoming.pending_bytes(), self.ticks
+        )
+    }
+}
+
+impl<P: Protocol, Out: Serialize + DeserializeOwned + Send, In: Serialize + DeserializeOwned + Send, E: Codec> Client<P, Out, In, E> where Self: Send + 'static {
+
+    // Runs the tick loop on a thread of its own, for applications which need to
+    // keep control over their main loop
+    pub fn spawn<A: ToAddrs<Addr<P>>>(addr: A, ticks_per_second: u8) -> Result<ClientHandle<Out, In>, Error> {
+
+        let mut client = Client::<P, Out, In, E>::new(ticks_per_second);
+        client.connect_nonblocking(addr, Duration::from_secs(DEFAULT_CONNECTION_TIMEOUT))?;
+
+        let (command_sender, commands) = mpsc::channel();
+        let (event_sender, events) = mpsc::channel();
+        let stats = Arc::new(Mutex::new((0.0, 0.0)));
+        let thread_stats = stats.clone();
+
+        let thread = thread::spawn(move || {
+            loop {
+
+                // A dropped handle ends the thread just like an explicit shutdown
+                loop {
+                    match commands.try_recv() {
+                        Ok(Command::Send(message)) => {
+                            client.send(message).ok();
+                        },
+                        Ok(Command::Shutdown) | Err(TryRecvError::Disconnected) => {
+                            client.flush().ok();
+                            client.disconnect().ok();
+                            return;
+                        },
+                        Err(TryRecvError::Empty) => break
+                    }
+                }
+
+                for event in client.events() {
+                    event_sender.send(event).ok();
+                }
+
+                if let Ok(mut stats) = thread_stats.lock() {
+                    *stats = (client.rtt(), client.clock());
+                }
+
+                client.sleep();
+
+            }
+        });
+
+        Ok(ClientHandle {
+            commands: command_sender,
+            events: Mutex::new(events),
+            stats: stats,
+            thread: Some(thread)
+        })
+
+    }
+
+}
+
+
+// Helpers --------------------------------------------------------------------
+// Later addresses get whatever an attempt which failed early did not use up
+fn attempt_timeout(deadline: Instant, now: Instant, attempts: usize) -> Duration {
+    cmp::max(deadline.saturating_duration_since(now) / attempts as u32, Duration::from_millis(1))
+}
+
+// A single address fails with the error of its attempt, as before
+fn connect_error<A: Address>(mut failures: Vec<(A, IOError)>) -> Error {
+    if failures.len() == 1 {
+        Error::Io(failures.remove(0).1)
+
+    } else {
+        Error::ConnectFailed(failures.into_iter().map(|(addr, err)| (format!("{:?}", addr), err)).collect())
+    }
+}
+
+
+// Client Handle --------------------------------------------------------------
+enum Command<M> {
+    Send(M),
+    Shutdown
+}
+
+pub struct ClientHandle<Out, In = Out> {
+    commands: Sender<Command<Out>>,
+    events: Mutex<Receiver<ClientEvent<In>>>,
+    stats: Arc<Mutex<(f64, f64)>>,
+    thread: Option<JoinHandle<()>>
+}
+
+impl<Out, In> ClientHandle<Out, In> {
+
+    pub fn send(&self, message: Out) -> Result<(), Error> {
+        self.commands.send(Command::Send(message)).map_err(|_| Error::NotConnected)
+    }
+
+    pub fn try_recv(&self) -> Option<ClientEvent<In>> {
+        self.events.lock().ok().and_then(|events| events.try_recv().ok())
+    }
+
+    pub fn rtt(&self) -> f64 {
+        self.stats.lock().map(|stats| stats.0).unwrap_or(0.0)
+    }
+
+    pub fn clock(&self) -> f64 {
+        self.stats.lock().map(|stats| stats.1).unwrap_or(0.0)
+    }
+
+    // Waits for the client thread to send out everything that is still pending
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.commands.send(Command::Shutdown).ok();
+            thread.join().ok();
+        }
+    }
+
+}
+
+impl<Out, In> Drop for ClientHandle<Out, In> {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
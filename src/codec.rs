@@ -0,0 +1,209 @@
+// Copyright (c) 2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::fmt;
+use std::error::Error;
+
+
+// External Dependencies ------------------------------------------------------
+use serde::Serialize;
+use serde::de::{Deserialize, DeserializeOwned};
+use bincode::{serialize, serialize_into, deserialize, deserialize_from, Bounded, Infinite};
+#[cfg(feature = "serde_json")]
+use serde_json;
+#[cfg(feature = "cbor")]
+use ciborium;
+
+
+// Codec Abstraction ----------------------------------------------------------
+pub trait Codec {
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError>;
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<(T, usize), CodecError>;
+
+    // Appends to the buffer, codecs which can write directly into it avoid
+    // allocating for every message
+    fn encode_into<T: Serialize>(value: &T, buffer: &mut Vec<u8>) -> Result<(), CodecError> {
+        buffer.extend(Self::encode(value)?);
+        Ok(())
+    }
+}
+
+// Codecs which can decode values borrowing from the bytes they were decoded
+// from, so fields like &[u8] and &str do not need to be copied
+pub trait BorrowCodec: Codec {
+    fn decode_borrowed<'de, T: Deserialize<'de>>(bytes: &'de [u8]) -> Result<T, CodecError>;
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    Encode(String),
+    Decode(String)
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CodecError::Encode(ref err) => write!(f, "failed to encode message: {}", err),
+            CodecError::Decode(ref err) => write!(f, "failed to decode message: {}", err)
+        }
+    }
+}
+
+impl Error for CodecError {}
+
+
+// Bincode --------------------------------------------------------------------
+pub struct BincodeCodec;
+impl Codec for BincodeCodec {
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError> {
+        serialize(value, Infinite).map_err(|err| CodecError::Encode(err.to_string()))
+    }
+
+    fn encode_into<T: Serialize>(value: &T, buffer: &mut Vec<u8>) -> Result<(), CodecError> {
+        serialize_into(buffer, value, Infinite).map_err(|err| CodecError::Encode(err.to_string()))
+    }
+
+    // Bounded by the input so lengths taken from the wire can never make the
+    // reader allocate more than the peer actually sent
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<(T, usize), CodecError> {
+        let mut reader = bytes;
+        let value = deserialize_from(&mut reader, Bounded(bytes.len() as u64)).map_err(|err| CodecError::Decode(err.to_string()))?;
+        Ok((value, bytes.len() - reader.len()))
+    }
+
+}
+
+impl BorrowCodec for BincodeCodec {
+    fn decode_borrowed<'de, T: Deserialize<'de>>(bytes: &'de [u8]) -> Result<T, CodecError> {
+        deserialize(bytes).map_err(|err| CodecError::Decode(err.to_string()))
+    }
+}
+
+
+// JSON -----------------------------------------------------------------------
+#[cfg(feature = "serde_json")]
+pub struct JsonCodec;
+
+#[cfg(feature = "serde_json")]
+impl Codec for JsonCodec {
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError> {
+        serde_json::to_vec(value).map_err(|err| CodecError::Encode(err.to_string()))
+    }
+
+    fn encode_into<T: Serialize>(value: &T, buffer: &mut Vec<u8>) -> Result<(), CodecError> {
+        serde_json::to_writer(buffer, value).map_err(|err| CodecError::Encode(err.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<(T, usize), CodecError> {
+        let mut stream = serde_json::Deserializer::from_slice(bytes).into_iter::<T>();
+        match stream.next() {
+            Some(Ok(value)) => Ok((value, stream.byte_offset())),
+            Some(Err(err)) => Err(CodecError::Decode(err.to_string())),
+            None => Err(CodecError::Decode("unexpected end of input".to_string()))
+        }
+    }
+
+}
+
+// Strings only borrow when they contain no escapes, byte slices never do
+#[cfg(feature = "serde_json")]
+impl BorrowCodec for JsonCodec {
+    fn decode_borrowed<'de, T: Deserialize<'de>>(bytes: &'de [u8]) -> Result<T, CodecError> {
+        serde_json::from_slice(bytes).map_err(|err| CodecError::Decode(err.to_string()))
+    }
+}
+
+
+// CBOR -----------------------------------------------------------------------
+// Items are self-delimiting, but are still sent inside of the usual frames so
+// peers in other languages only need a CBOR library and the frame layout;
+// ciborium only decodes owned values, so there is no BorrowCodec for it
+#[cfg(feature = "cbor")]
+pub struct CborCodec;
+
+#[cfg(feature = "cbor")]
+impl Codec for CborCodec {
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError> {
+        let mut buffer = Vec::new();
+        CborCodec::encode_into(value, &mut buffer)?;
+        Ok(buffer)
+    }
+
+    fn encode_into<T: Serialize>(value: &T, buffer: &mut Vec<u8>) -> Result<(), CodecError> {
+        ciborium::ser::into_writer(value, buffer).map_err(|err| CodecError::Encode(err.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<(T, usize), CodecError> {
+        let mut reader = bytes;
+        let value = ciborium::de::from_reader(&mut reader).map_err(|err| CodecError::Decode(err.to_string()))?;
+        Ok((value, bytes.len() - reader.len()))
+    }
+
+}
+
+
+// Tests ----------------------------------------------------------------------
+#[cfg(test)]
+mod test {
+
+    use super::{BincodeCodec, Codec, CodecError};
+    #[cfg(feature = "cbor")]
+    use super::CborCodec;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Message {
+        Chat(String),
+        Data(Vec<u8>)
+    }
+
+    // A 12 byte frame claiming a string of a terabyte
+    fn huge_string() -> Vec<u8> {
+        let mut bytes = vec![0, 0, 0, 0];
+        bytes.extend_from_slice(&(1u64 << 40).to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn bincode_rejects_lengths_beyond_the_input() {
+        match BincodeCodec::decode::<Message>(&huge_string()) {
+            Err(CodecError::Decode(_)) => {},
+            result => panic!("expected a decode error, got {:?}", result)
+        }
+
+        let mut bytes = vec![1, 0, 0, 0];
+        bytes.extend_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(BincodeCodec::decode::<Message>(&bytes), Err(CodecError::Decode(_))));
+    }
+
+    #[test]
+    fn bincode_reports_the_decoded_size() {
+        let mut bytes = BincodeCodec::encode(&Message::Data(vec![1, 2, 3])).unwrap();
+        let size = bytes.len();
+        bytes.extend_from_slice(&[0xff; 8]);
+        let (message, decoded) = BincodeCodec::decode::<Message>(&bytes).unwrap();
+        assert_eq!(message, Message::Data(vec![1, 2, 3]));
+        assert_eq!(decoded, size);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_rejects_lengths_beyond_the_input() {
+        // {"Chat": <text of a terabyte>}
+        let mut bytes = vec![0xa1, 0x64];
+        bytes.extend_from_slice(b"Chat");
+        bytes.push(0x7b);
+        bytes.extend_from_slice(&(1u64 << 40).to_be_bytes());
+        assert!(matches!(CborCodec::decode::<Message>(&bytes), Err(CodecError::Decode(_))));
+    }
+
+}
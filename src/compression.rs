@@ -0,0 +1,57 @@
+// Copyright (c) 2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+
+// External Dependencies ------------------------------------------------------
+#[cfg(feature = "compression")]
+use lz4_flex::block;
+
+
+// Statics --------------------------------------------------------------------
+pub static DEFAULT_COMPRESSION_THRESHOLD: usize = 512;
+
+
+// Frame Compression ----------------------------------------------------------
+// Whether frames received from the peer can be decompressed
+pub fn supported() -> bool {
+    cfg!(feature = "compression")
+}
+
+// Compressed bytes are prefixed with their original size
+#[cfg(feature = "compression")]
+pub fn compress(bytes: &[u8]) -> Option<Vec<u8>> {
+    Some(block::compress_prepend_size(bytes))
+}
+
+#[cfg(not(feature = "compression"))]
+pub fn compress(_: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+// Bytes which would decompress to more than max_size are rejected up front
+#[cfg(feature = "compression")]
+pub fn decompress(bytes: &[u8], max_size: usize) -> Option<Vec<u8>> {
+    if bytes.len() < 4 {
+        None
+
+    } else {
+        let size = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+        if size > max_size {
+            None
+
+        } else {
+            block::decompress(&bytes[4..], size).ok().filter(|bytes| bytes.len() == size)
+        }
+    }
+}
+
+#[cfg(not(feature = "compression"))]
+pub fn decompress(_: &[u8], _: usize) -> Option<Vec<u8>> {
+    None
+}
+
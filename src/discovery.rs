@@ -0,0 +1,143 @@
+// Copyright (c) 2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::cmp;
+use std::time::{Duration, Instant};
+use std::io::{Error as IOError, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+
+
+// Internal Dependencies ------------------------------------------------------
+use ::error::Error;
+
+
+// Statics --------------------------------------------------------------------
+const PROBE: &[u8] = b"COBALT?";
+const REPLY: &[u8] = b"COBALT!";
+
+// Replies fit into a single unfragmented datagram on common networks
+const MAX_DATAGRAM_SIZE: usize = 1472;
+const MAX_INFO_SIZE: usize = MAX_DATAGRAM_SIZE - REPLY.len() - 2;
+
+
+// Discovered Servers ---------------------------------------------------------
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DiscoveredServer {
+    pub addr: SocketAddr,
+    pub info: Vec<u8>
+}
+
+
+// Beacon ---------------------------------------------------------------------
+pub struct Beacon {
+    socket: UdpSocket,
+    reply: Vec<u8>
+}
+
+impl Beacon {
+
+    pub fn new(port: u16, listen_port: u16, info: Vec<u8>) -> Result<Self, Error> {
+
+        if info.len() > MAX_INFO_SIZE {
+            return Err(Error::MessageTooLarge {
+                size: info.len(),
+                max: MAX_INFO_SIZE
+            });
+        }
+
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+        socket.set_nonblocking(true)?;
+
+        let mut reply = REPLY.to_vec();
+        reply.extend_from_slice(&listen_port.to_le_bytes());
+        reply.extend(info);
+
+        Ok(Self {
+            socket: socket,
+            reply: reply
+        })
+
+    }
+
+    // Answers all probes which arrived since the last call
+    pub fn poll(&mut self) {
+        let mut buffer = [0; 64];
+        loop {
+            match self.socket.recv_from(&mut buffer) {
+                Ok((bytes, addr)) => if &buffer[..bytes] == PROBE {
+                    self.socket.send_to(&self.reply[..], addr).ok();
+                },
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+                // Errors caused by previous replies are reported on the next receive
+                Err(ref err) if err.kind() == ErrorKind::ConnectionReset => {},
+                Err(_) => break
+            }
+        }
+    }
+
+}
+
+
+// Discovery ------------------------------------------------------------------
+// Broadcasts a probe and collects the replies which arrive within the timeout
+pub fn discover(port: u16, timeout: Duration) -> Result<Vec<DiscoveredServer>, Error> {
+
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+
+    // Broadcasts reach the local machine as well, unless there is no network
+    if socket.send_to(PROBE, (Ipv4Addr::BROADCAST, port)).is_err() {
+        socket.send_to(PROBE, (Ipv4Addr::LOCALHOST, port))?;
+    }
+
+    let mut servers: Vec<DiscoveredServer> = Vec::new();
+    let mut buffer = [0; MAX_DATAGRAM_SIZE];
+    let deadline = Instant::now() + timeout;
+    loop {
+
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+
+        socket.set_read_timeout(Some(cmp::max(deadline - now, Duration::from_millis(1))))?;
+        let (bytes, addr) = match socket.recv_from(&mut buffer) {
+            Ok(reply) => reply,
+            Err(ref err) if is_timeout(err) => break,
+            Err(ref err) if err.kind() == ErrorKind::ConnectionReset => continue,
+            Err(err) => return Err(Error::Io(err))
+        };
+
+        let reply = &buffer[..bytes];
+        if reply.len() < REPLY.len() + 2 || &reply[..REPLY.len()] != REPLY {
+            continue;
+        }
+
+        let listen_port = u16::from_le_bytes([reply[REPLY.len()], reply[REPLY.len() + 1]]);
+        let server = DiscoveredServer {
+            addr: SocketAddr::new(addr.ip(), listen_port),
+            info: reply[REPLY.len() + 2..].to_vec()
+        };
+
+        // Servers answer once for every probe they received
+        if !servers.contains(&server) {
+            servers.push(server);
+        }
+
+    }
+
+    Ok(servers)
+
+}
+
+fn is_timeout(err: &IOError) -> bool {
+    err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut
+}
+
@@ -0,0 +1,121 @@
+// Copyright (c) 2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+
+// External Dependencies ------------------------------------------------------
+#[cfg(feature = "encryption")]
+use chacha20poly1305::{Key, KeyInit, Tag, XChaCha20Poly1305, XNonce};
+#[cfg(feature = "encryption")]
+use chacha20poly1305::aead::{AeadInPlace, OsRng};
+#[cfg(feature = "encryption")]
+use chacha20poly1305::aead::rand_core::RngCore;
+
+
+// Statics --------------------------------------------------------------------
+pub const KEY_SIZE: usize = 32;
+pub const SALT_SIZE: usize = 16;
+pub const TAG_SIZE: usize = 16;
+
+
+// Frame Encryption -----------------------------------------------------------
+// Seals or opens the frames of one direction of a connection, the nonce of a
+// frame is the salt of the sender followed by the number of frames it sealed
+// before, so no nonce is ever used twice with the same key
+#[cfg(feature = "encryption")]
+pub struct Cipher {
+    aead: XChaCha20Poly1305,
+    salt: [u8; SALT_SIZE],
+    counter: u64
+}
+
+#[cfg(feature = "encryption")]
+impl Cipher {
+
+    // Every connection picks a fresh random salt for the frames it sends
+    pub fn outgoing(psk: &[u8; KEY_SIZE]) -> Option<Self> {
+        let mut salt = [0; SALT_SIZE];
+        OsRng.try_fill_bytes(&mut salt).ok()?;
+        Some(Cipher::new(psk, salt))
+    }
+
+    // Uses the salt the peer sent in front of its first sealed frame
+    pub fn incoming(psk: &[u8; KEY_SIZE], salt: &[u8]) -> Option<Self> {
+        let mut bytes = [0; SALT_SIZE];
+        if salt.len() == SALT_SIZE {
+            bytes.copy_from_slice(salt);
+            Some(Cipher::new(psk, bytes))
+
+        } else {
+            None
+        }
+    }
+
+    pub fn salt(&self) -> &[u8] {
+        &self.salt
+    }
+
+    // Encrypts the bytes in place and appends the tag, which also covers the
+    // plain header of the frame
+    pub fn seal(&mut self, header: &[u8], bytes: &mut Vec<u8>) {
+        let nonce = self.next_nonce();
+        if let Ok(tag) = self.aead.encrypt_in_place_detached(&nonce, header, &mut bytes[..]) {
+            bytes.extend_from_slice(&tag);
+        }
+    }
+
+    pub fn open(&mut self, header: &[u8], bytes: &mut [u8], tag: &[u8]) -> bool {
+        let nonce = self.next_nonce();
+        tag.len() == TAG_SIZE && self.aead.decrypt_in_place_detached(&nonce, header, bytes, Tag::from_slice(tag)).is_ok()
+    }
+
+    fn new(psk: &[u8; KEY_SIZE], salt: [u8; SALT_SIZE]) -> Self {
+        Self {
+            aead: XChaCha20Poly1305::new(Key::from_slice(psk)),
+            salt: salt,
+            counter: 0
+        }
+    }
+
+    fn next_nonce(&mut self) -> XNonce {
+        let mut nonce = XNonce::default();
+        nonce[..SALT_SIZE].copy_from_slice(&self.salt);
+        nonce[SALT_SIZE..].copy_from_slice(&self.counter.to_le_bytes());
+        self.counter += 1;
+        nonce
+    }
+
+}
+
+// Never constructed, keys can only be set with the encryption feature
+#[cfg(not(feature = "encryption"))]
+pub enum Cipher {}
+
+#[cfg(not(feature = "encryption"))]
+impl Cipher {
+
+    pub fn outgoing(_: &[u8; KEY_SIZE]) -> Option<Self> {
+        None
+    }
+
+    pub fn incoming(_: &[u8; KEY_SIZE], _: &[u8]) -> Option<Self> {
+        None
+    }
+
+    pub fn salt(&self) -> &[u8] {
+        match *self {}
+    }
+
+    pub fn seal(&mut self, _: &[u8], _: &mut Vec<u8>) {
+        match *self {}
+    }
+
+    pub fn open(&mut self, _: &[u8], _: &mut [u8], _: &[u8]) -> bool {
+        match *self {}
+    }
+
+}
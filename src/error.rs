@@ -0,0 +1,99 @@
+// Copyright (c) 2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::fmt;
+use std::error;
+use std::io::Error as IOError;
+
+
+// Internal Dependencies ------------------------------------------------------
+use ::codec::CodecError;
+
+
+// Error Abstraction ----------------------------------------------------------
+#[derive(Debug)]
+pub enum Error {
+    AlreadyConnected,
+    NotConnected,
+    AlreadyBound,
+    NotBound,
+    AddressResolution,
+    BufferFull,
+    Vetoed,
+    RequestTimeout,
+    UnknownGroup,
+    VersionMismatch {
+        server: u32
+    },
+    MessageTooLarge {
+        size: usize,
+        max: usize
+    },
+    ReservedPrefix {
+        prefix: u8
+    },
+    // Rejected by the build() of a server or client builder
+    InvalidConfig(String),
+    // Every address which was tried along with the reason it failed
+    ConnectFailed(Vec<(String, IOError)>),
+    Serialization(CodecError),
+    Io(IOError)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::AlreadyConnected => write!(f, "already connected"),
+            Error::NotConnected => write!(f, "not connected"),
+            Error::AlreadyBound => write!(f, "already bound"),
+            Error::NotBound => write!(f, "not bound"),
+            Error::AddressResolution => write!(f, "address did not resolve to any socket address"),
+            Error::BufferFull => write!(f, "outgoing buffer is full"),
+            Error::Vetoed => write!(f, "message was vetoed by the outbound hook"),
+            Error::RequestTimeout => write!(f, "request was not answered in time"),
+            Error::UnknownGroup => write!(f, "unknown group"),
+            Error::VersionMismatch { server } => write!(f, "server runs incompatible version {}", server),
+            Error::MessageTooLarge { size, max } => write!(f, "message of {} bytes exceeds the maximum size of {} bytes", size, max),
+            Error::ReservedPrefix { prefix } => write!(f, "frame prefix {} is reserved", prefix),
+            Error::InvalidConfig(ref reason) => write!(f, "invalid configuration: {}", reason),
+            Error::ConnectFailed(ref failures) => {
+                write!(f, "failed to connect to any address")?;
+                for (index, (addr, err)) in failures.iter().enumerate() {
+                    write!(f, "{} {}: {}", if index == 0 { ":" } else { "," }, addr, err)?;
+                }
+                Ok(())
+            },
+            Error::Serialization(ref err) => write!(f, "serialization failed: {}", err),
+            Error::Io(ref err) => write!(f, "io error: {}", err)
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Serialization(ref err) => Some(err),
+            Error::Io(ref err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<IOError> for Error {
+    fn from(err: IOError) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<CodecError> for Error {
+    fn from(err: CodecError) -> Self {
+        Error::Serialization(err)
+    }
+}
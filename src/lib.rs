@@ -5,6 +5,7 @@
 // <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
+#![allow(clippy::redundant_field_names, clippy::type_complexity)]
 
 
 // Crates ---------------------------------------------------------------------
@@ -12,19 +13,87 @@
 extern crate serde_derive;
 extern crate serde;
 extern crate bincode;
+extern crate socket2;
+#[cfg(feature = "serde_json")]
+extern crate serde_json;
+#[cfg(feature = "tls")]
+extern crate rustls;
+#[cfg(feature = "websocket")]
+extern crate tungstenite;
+#[cfg(feature = "poll")]
+extern crate mio;
+#[cfg(feature = "compression")]
+extern crate lz4_flex;
+#[cfg(feature = "encryption")]
+extern crate chacha20poly1305;
+#[cfg(feature = "cbor")]
+extern crate ciborium;
+#[cfg(feature = "log")]
+#[macro_use]
+extern crate log;
 
 
 // Modules --------------------------------------------------------------------
+mod builder;
 mod client;
+mod codec;
+mod compression;
+mod discovery;
+mod encryption;
+mod error;
 mod message;
 mod protocol;
+mod query;
+mod replay;
 mod server;
+mod simulation;
+#[cfg(feature = "testing")]
+mod testing;
 mod time;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "websocket")]
+mod websocket;
 
 
 // Exports --------------------------------------------------------------------
-pub use self::client::Client;
-pub use self::protocol::TCP;
-pub use self::server::{Remote, Server};
-pub use self::message::{Message, MessageIterator};
+pub use self::builder::{ClientBuilder, ServerBuilder};
+pub use self::client::{Client, ClientEvent, ClientHandle, ClientState, ReconnectPolicy, SymmetricClient};
+pub use self::codec::{BorrowCodec, Codec, CodecError, BincodeCodec};
+pub use self::discovery::DiscoveredServer;
+pub use self::error::Error;
+#[cfg(feature = "serde_json")]
+pub use self::codec::JsonCodec;
+#[cfg(feature = "cbor")]
+pub use self::codec::CborCodec;
+pub use self::protocol::{TCP, TcpConfig, TcpConnection, UDP, UdpConfig, UdpConnection};
+pub use self::query::query;
+pub use self::replay::{Capture, FileTap, Replay, ReplayConfig, ReplayConnection, Tap};
+#[cfg(unix)]
+pub use self::protocol::{Unix, UnixConnection};
+pub use self::server::{Accepted, Closed, Connected, ConnectionId, GroupId, HookAction, LimitPolicy, NetStats, Remote, Remotes, Server, ServerEvent, SymmetricServer, UpdateResult};
+#[cfg(unix)]
+pub use self::server::ExportedRemote;
+pub use self::simulation::{Simulated, SimulationConfig};
+#[cfg(feature = "testing")]
+pub use self::testing::{FakePeer, Memory, MemoryConnection, MemoryHost, ScriptError};
+pub use self::message::{AckId, BorrowedIterator, ChannelIterator, CloseReason, DecodeError, LatePolicy, Message, MessageIterator, QueuePolicy, RawIterator, RequestId, Stamped, StampedIterator};
+pub use self::time::{AdaptiveSend, Average, CatchUpPolicy, Clock, ConnectionQuality, MockClock, QualityThresholds, SystemClock, TickReport, TimingConfig};
+#[cfg(feature = "tls")]
+pub use self::tls::{Tls, TlsAcceptor, TlsConnector};
+#[cfg(feature = "websocket")]
+pub use self::websocket::WebSocket;
+
+
+// Thread Safety --------------------------------------------------------------
+// Fails to compile as soon as any of these types stops being Send
+#[allow(dead_code)]
+fn assert_send<T: Send>() {}
 
+#[allow(dead_code)]
+fn assert_send_types<P: protocol::Protocol, M: serde::Serialize + serde::de::DeserializeOwned + Send, D: Send, E: Codec>() {
+    assert_send::<Server<P, M, M, D, E>>();
+    assert_send::<Client<P, M, M, E>>();
+    assert_send::<Remote<P::Connection, M, M, E>>();
+    assert_send::<ClientHandle<M, M>>();
+}
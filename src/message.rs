@@ -8,97 +8,2383 @@
 
 
 // STD Dependencies -----------------------------------------------------------
+use std::cmp;
+use std::fmt;
+use std::mem;
+use std::ops;
+use std::error;
+use std::slice;
+use std::vec;
 use std::marker::PhantomData;
+use std::collections::VecDeque;
+use std::io::{Error as IOError, ErrorKind, IoSlice};
 
 
 // External Dependencies ------------------------------------------------------
 use serde::Serialize;
-use serde::de::DeserializeOwned;
-use bincode::{serialized_size, deserialize};
+use serde::de::{Deserialize, DeserializeOwned};
+
+
+// Internal Dependencies ------------------------------------------------------
+use ::error::Error;
+use ::codec::{BorrowCodec, Codec, CodecError, BincodeCodec};
+use ::protocol::Connection;
+use ::compression;
+use ::time::PeerClock;
+use ::encryption::{Cipher, KEY_SIZE, SALT_SIZE, TAG_SIZE};
 
 
 // Traits ---------------------------------------------------------------------
 pub trait Message: Serialize + DeserializeOwned {}
 
 
+// Request Identifiers --------------------------------------------------------
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct RequestId(pub u32);
+
+
+// Acknowledgement Identifiers ------------------------------------------------
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct AckId(pub u32);
+
+
 // Internal Messages ----------------------------------------------------------
 #[derive(Debug, Serialize, Deserialize)]
 pub enum InternalMessage {
-    Ping(u8, u64),
-    Pong(u8, u64, u64),
-    //Configure(u8)
+    Ping(u32, u64),
+    Pong(u32, u64, u64, u64),
+    Close(u8),
+    Ack(u32),
+    Query,
+    Status(Vec<u8>),
+    Hello {
+        protocol_version: u32,
+        version: u32,
+        ticks_per_second: f64,
+        compression: bool,
+        stamping: bool
+    },
+    Pause,
+    Resume
 }
 
 
-// Message Iterator Abstraction -----------------------------------------------
-pub struct MessageIterator<'a, M: Serialize + DeserializeOwned, I: Serialize + DeserializeOwned + 'a> {
-    buffer: &'a mut Vec<u8>,
-    internal_queue: &'a mut Vec<I>,
-    message: PhantomData<M>
+// Statics --------------------------------------------------------------------
+pub static DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024;
+pub static DEFAULT_MAX_OUTGOING_SIZE: usize = 1024 * 1024;
+pub static DEFAULT_FRAGMENT_SIZE: usize = 16 * 1024;
+pub static DEFAULT_MAX_INCOMING_SIZE: usize = 1024 * 1024;
+pub static DEFAULT_CONNECTION_TIMEOUT: u64 = 10;
+pub static DEFAULT_REQUEST_TIMEOUT: u64 = 10;
+pub static CLOSE_PROTOCOL_ERROR: u8 = 1;
+pub static CLOSE_TIMEOUT: u8 = 2;
+pub static CLOSE_SLOW_CONSUMER: u8 = 3;
+pub static CLOSE_REJECTED: u8 = 4;
+pub static CLOSE_BANNED: u8 = 5;
+pub static CLOSE_FLOOD: u8 = 6;
+pub static CLOSE_VERSION_MISMATCH: u8 = 7;
+
+// Bumped whenever the wire format changes in an incompatible way
+pub static PROTOCOL_VERSION: u32 = 4;
+
+// Sent in front of all other frames when encryption is enabled
+pub static SALT_PREFIX: u8 = 8;
+
+// Consecutive messages of a single channel which share one frame
+pub static BATCH_PREFIX: u8 = 9;
+
+// Prefixes below are reserved for the frames of the crate itself
+pub static MIN_CONTROL_PREFIX: u8 = 16;
+
+
+// Close Reasons --------------------------------------------------------------
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CloseReason {
+    // Closed by this side, e.g. a kick, a ban or a rejected connection
+    LocalClose,
+    // The peer asked for the close, with one of the close codes
+    RemoteClose(u8),
+    // The peer stopped answering or never completed its handshake
+    Timeout,
+    // The peer sent something which violates the protocol
+    ProtocolError,
+    // The peer closed the connection without the close handshake
+    Disconnected,
+    // The connection failed before the close handshake
+    Io(ErrorKind)
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CloseReason::LocalClose => write!(f, "closed locally"),
+            CloseReason::RemoteClose(code) => write!(f, "closed by the peer with code {}", code),
+            CloseReason::Timeout => write!(f, "timed out"),
+            CloseReason::ProtocolError => write!(f, "protocol error"),
+            CloseReason::Disconnected => write!(f, "disconnected by the peer"),
+            CloseReason::Io(kind) => write!(f, "connection failed: {}", kind)
+        }
+    }
 }
 
-fn from_bytes<M: Serialize + DeserializeOwned>(bytes: &[u8]) -> Option<(M, usize)> {
-    if let Ok(msg) = deserialize::<M>(bytes) {
-        let len = serialized_size(&msg) as usize;
-        Some((msg, len))
+// Reason for closes initiated by this side with the given code
+pub fn local_close_reason(code: u8) -> CloseReason {
+    if code == CLOSE_TIMEOUT {
+        CloseReason::Timeout
+
+    } else if code == CLOSE_PROTOCOL_ERROR {
+        CloseReason::ProtocolError
 
     } else {
-        None
+        CloseReason::LocalClose
     }
 }
 
-impl<'a, M: Serialize + DeserializeOwned, I: Serialize + DeserializeOwned> Iterator for MessageIterator<'a, M, I> {
+// Connections report their end as UnexpectedEof, which is not a failure
+pub fn io_close_reason(kind: ErrorKind) -> CloseReason {
+    if kind == ErrorKind::UnexpectedEof {
+        CloseReason::Disconnected
 
-    type Item = M;
+    } else {
+        CloseReason::Io(kind)
+    }
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.buffer.is_empty() {
-            None
 
-        } else {
-            let mut index = 0;
-            let mut message = None;
+// Decode Errors --------------------------------------------------------------
+#[derive(Debug)]
+pub struct DecodeError {
+    pub prefix: u8,
+    pub length: usize,
+    pub error: CodecError
+}
 
-            while index < self.buffer.len() {
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid frame with prefix {} and {} bytes of payload: {}", self.prefix, self.length, self.error)
+    }
+}
 
-                // Internal Messages
-                if self.buffer[index] == 0 {
-                    if let Some((msg, bytes)) = from_bytes::<I>(&self.buffer[index + 1..]) {
-                        self.internal_queue.push(msg);
-                        index += bytes;
+impl error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+// Scheduled Messages ---------------------------------------------------------
+// What happens to messages whose target tick already passed when they get
+// received with receive_for_tick()
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum LatePolicy {
+    // Yield them right away, their tick tells that they are late
+    #[default]
+    Deliver,
+    Drop
+}
+
+static MAX_DECODE_ERRORS: usize = 64;
+const MIN_INCOMING_CAPACITY: usize = 16 * 1024;
+const INCOMING_IDLE_TICKS: u32 = 64;
+
+
+// Incoming Message Buffer ----------------------------------------------------
+pub struct Incoming<I: Serialize + DeserializeOwned> {
+    buffer: Vec<u8>,
+    consumed: usize,
+    internal_queue: Vec<I>,
+    correlated_queue: Vec<(u8, u32, Vec<u8>)>,
+    scheduled_queue: Vec<(u64, Vec<u8>)>,
+    control_queue: Vec<(u8, Vec<u8>)>,
+    late_policy: LatePolicy,
+    fragments: Vec<u8>,
+    borrowed: Vec<Borrowed>,
+    unyielded: Vec<Borrowed>,
+    messages: u64,
+    max_message_size: usize,
+    max_incoming_size: usize,
+    read_limit: Option<usize>,
+    read_this_tick: usize,
+    reads_throttled: u64,
+    idle_ticks: u32,
+    unchecked: usize,
+    frames_received: u64,
+    acked: Option<u32>,
+    ack_sent: Option<u32>,
+    decode_errors: Vec<DecodeError>,
+    strict: bool,
+    violation: bool,
+    encryption: Option<[u8; KEY_SIZE]>,
+    cipher: Option<Cipher>,
+    stamped: bool,
+    stamp: Option<u32>
+}
+
+impl<I: Serialize + DeserializeOwned> Incoming<I> {
+
+    pub fn new(max_message_size: usize) -> Self {
+        Self {
+            buffer: Vec::new(),
+            consumed: 0,
+            internal_queue: Vec::new(),
+            correlated_queue: Vec::new(),
+            scheduled_queue: Vec::new(),
+            control_queue: Vec::new(),
+            late_policy: LatePolicy::default(),
+            fragments: Vec::new(),
+            borrowed: Vec::new(),
+            unyielded: Vec::new(),
+            messages: 0,
+            max_message_size: max_message_size,
+            max_incoming_size: DEFAULT_MAX_INCOMING_SIZE,
+            read_limit: None,
+            read_this_tick: 0,
+            reads_throttled: 0,
+            idle_ticks: 0,
+            unchecked: 0,
+            frames_received: 0,
+            acked: None,
+            ack_sent: None,
+            decode_errors: Vec::new(),
+            strict: false,
+            violation: false,
+            encryption: None,
+            cipher: None,
+            stamped: false,
+            stamp: None
+        }
+    }
+
+    // Expects the salt of the peer as its first frame, must be set before
+    // anything was received
+    pub fn set_encryption(&mut self, psk: Option<[u8; KEY_SIZE]>) {
+        self.encryption = psk;
+        self.cipher = None;
+    }
+
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.max_message_size = max_message_size;
+    }
+
+    // Whether the peer announced that its messages start with their tick,
+    // must be set before any of them are handed out
+    pub fn set_stamped(&mut self, stamped: bool) {
+        self.stamped = stamped;
+    }
+
+    pub fn stamped(&self) -> bool {
+        self.stamped
+    }
+
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    pub fn set_late_policy(&mut self, policy: LatePolicy) {
+        self.late_policy = policy;
+    }
+
+    pub fn set_max_incoming_size(&mut self, max_incoming_size: usize) {
+        self.max_incoming_size = max_incoming_size;
+    }
+
+    pub fn set_read_limit(&mut self, read_limit: Option<usize>) {
+        self.read_limit = read_limit;
+    }
+
+    // Number of bytes the next read may append, always leaving room for one
+    // complete frame of the maximum message size
+    pub fn read_limit(&self) -> usize {
+        let pending = self.buffer.len() - self.consumed;
+        let max_size = cmp::max(self.max_incoming_size, self.max_frame_length() + FRAME_HEADER_SIZE);
+        let total = max_size.saturating_sub(pending);
+        let tick = self.read_limit.map_or(usize::MAX, |limit| limit.saturating_sub(self.read_this_tick));
+        cmp::min(total, tick)
+    }
+
+    // Records a read, reads which exhausted their limit count as throttled
+    pub fn received(&mut self, bytes: usize, limit: usize) {
+        self.read_this_tick += bytes;
+        if bytes >= limit {
+            self.reads_throttled += 1;
+        }
+        self.unchecked += bytes;
+        self.inflate();
+    }
+
+    // Decompresses the frames completed by the latest reads in place, so the
+    // rest of the buffer never contains compressed frames
+    fn inflate(&mut self) {
+        let mut index = self.buffer.len().saturating_sub(self.unchecked);
+        while let Some(length) = frame_length(&self.buffer[index..]) {
+
+            let end = index + FRAME_HEADER_SIZE + length;
+            if self.buffer.len() < end {
+                break;
+            }
+
+            // The first frame of the peer carries the salt for all frames after it
+            if let Some(psk) = self.encryption.filter(|_| self.cipher.is_none()) {
+                self.cipher = salt(&self.buffer[index..end]).and_then(|salt| Cipher::incoming(&psk, salt));
+                if self.cipher.is_none() {
+                    self.reject_frames();
+                    return;
+                }
+                self.buffer.drain(index..end);
+                continue;
+
+            // Peers which encrypt frames we cannot open are just as incompatible
+            } else if self.encryption.is_none() && salt(&self.buffer[index..end]).is_some() {
+                self.reject_frames();
+                return;
+            }
+
+            // Frames which fail to authenticate are never handed out
+            let end = match self.cipher.as_mut() {
+                Some(cipher) => match open_frame(cipher, &mut self.buffer, index, end) {
+                    Some(end) => end,
+                    None => {
+                        self.reject_frames();
+                        return;
                     }
+                },
+                None => end
+            };
 
-                // Application Messages
-                } else if self.buffer[index] == 1 {
-                    if let Some((msg, bytes)) = from_bytes::<M>(&self.buffer[index + 1..]) {
-                        message = Some(msg);
-                        index += bytes + 1;
-                        break;
+            self.frames_received += 1;
+            let end = if is_compressed(&self.buffer[index..]) {
+                let max_size = self.max_message_size + MAX_FRAME_OVERHEAD;
+                if let Some(body) = compression::decompress(&self.buffer[index + FRAME_HEADER_SIZE..end], max_size) {
+                    let mut frame = (body.len() as u32).to_le_bytes().to_vec();
+                    frame.extend(body);
+                    let length = frame.len();
+                    self.buffer.splice(index..end, frame);
+                    index + length
+
+                // Frames which fail to decompress may not be skipped in strict mode either
+                } else {
+                    self.reject_frames();
+                    return;
+                }
+
+            } else {
+                end
+            };
+
+            // Batches are split back up into one frame per message, so their
+            // messages are handed out like any other
+            if self.buffer.get(index + FRAME_HEADER_SIZE) == Some(&BATCH_PREFIX) {
+                if let Some(frames) = unbatch(&self.buffer[index + FRAME_HEADER_SIZE + 1..end]) {
+                    let length = frames.len();
+                    self.buffer.splice(index..end, frames);
+                    index += length;
+
+                } else {
+                    self.reject_frames();
+                    return;
+                }
+
+            } else {
+                index = end;
+            }
+
+        }
+        self.unchecked = self.buffer.len() - index;
+    }
+
+    // Everything received so far is dropped, the peer is in violation of the protocol
+    fn reject_frames(&mut self) {
+        self.violation = true;
+        self.buffer.clear();
+        self.consumed = 0;
+        self.unchecked = 0;
+    }
+
+    // Sealed frames which have not been opened yet still carry their tag
+    fn max_frame_length(&self) -> usize {
+        let tag = if self.encryption.is_some() { TAG_SIZE } else { 0 };
+        self.max_stamped_size() + MAX_FRAME_OVERHEAD + tag
+    }
+
+    fn max_stamped_size(&self) -> usize {
+        if self.stamped { self.max_message_size + STAMP_SIZE } else { self.max_message_size }
+    }
+
+    // Frames leave room for the largest header of all prefixes, so their
+    // payload is checked against the limit once the prefix is known
+    fn oversized(&self, frame: &[u8]) -> bool {
+        let header = match frame.first() {
+            Some(&prefix) if prefix <= 1 || prefix >= MIN_CONTROL_PREFIX => 1,
+            Some(&4) => 2,
+            Some(&5) => 3,
+            Some(&2) | Some(&3) | Some(&6) => 5,
+            _ => MAX_FRAME_OVERHEAD
+        };
+        frame.len() > header + self.max_stamped_size()
+    }
+
+    pub fn reads_throttled(&self) -> u64 {
+        self.reads_throttled
+    }
+
+    // Number of frames which arrived completely since the last call
+    pub fn take_frames_received(&mut self) -> u64 {
+        mem::replace(&mut self.frames_received, 0)
+    }
+
+    // Takes everything which was read but not yet handed out, including the
+    // fragments received so far, see restore_pending()
+    pub fn take_pending(&mut self) -> (Vec<u8>, Vec<u8>) {
+        self.buffer.drain(0..self.consumed);
+        self.consumed = 0;
+        self.unchecked = 0;
+        (mem::take(&mut self.buffer), mem::take(&mut self.fragments))
+    }
+
+    // Frames which were inflated before are left as they are, so only the ones
+    // which were still incomplete count as received
+    pub fn restore_pending(&mut self, buffer: Vec<u8>, fragments: Vec<u8>) {
+        let frames_received = self.frames_received;
+        self.unchecked = buffer.len();
+        self.buffer = buffer;
+        self.fragments = fragments;
+        self.inflate();
+        self.frames_received = frames_received;
+    }
+
+    // Bytes which were read but not yet handed out as messages
+    pub fn pending_bytes(&self) -> usize {
+        self.buffer.len() - self.consumed + self.fragments.len() + self.unyielded.iter().map(Borrowed::len).sum::<usize>()
+    }
+
+    pub fn pending_internal(&self) -> usize {
+        self.internal_queue.len()
+    }
+
+    // Resets the per tick limit and releases the capacity left behind by bursts
+    // once the buffer has been mostly idle for a while
+    pub fn tick(&mut self) {
+        self.read_this_tick = 0;
+        let pending = self.buffer.len() - self.consumed;
+        if self.buffer.capacity() > MIN_INCOMING_CAPACITY && pending < self.buffer.capacity() / 4 {
+            self.idle_ticks += 1;
+            if self.idle_ticks >= INCOMING_IDLE_TICKS {
+                self.buffer.drain(0..self.consumed);
+                self.consumed = 0;
+                self.buffer.shrink_to(cmp::max(pending * 2, MIN_INCOMING_CAPACITY));
+                self.idle_ticks = 0;
+            }
+
+        } else {
+            self.idle_ticks = 0;
+        }
+    }
+
+    pub fn buffer_mut(&mut self) -> &mut Vec<u8> {
+        // Compact the buffer only once per read instead of after every single message
+        self.buffer.drain(0..self.consumed);
+        self.consumed = 0;
+        &mut self.buffer
+    }
+
+    // Decode all complete internal, request and response frames ahead of time,
+    // so they get handled even if the application never iterates its messages
+    pub fn extract_frames<E: Codec>(&mut self) {
+
+        let mut index = self.consumed;
+        let mut found = false;
+        let mut remaining = Vec::new();
+
+        while let Some(length) = frame_length(&self.buffer[index..]) {
+
+            if length > self.max_frame_length() {
+                self.violation = true;
+                self.buffer.clear();
+                self.consumed = 0;
+                return;
+            }
+
+            let end = index + FRAME_HEADER_SIZE + length;
+            if self.buffer.len() < end {
+                break;
+            }
+
+            if self.oversized(&self.buffer[index + FRAME_HEADER_SIZE..end]) {
+                self.violation = true;
+                self.buffer.clear();
+                self.consumed = 0;
+                return;
+            }
+
+            if length > 0 && self.buffer[index + FRAME_HEADER_SIZE] == 0 {
+                match from_bytes::<E, I>(0, &self.buffer[index + FRAME_HEADER_SIZE + 1..end]) {
+                    Ok(msg) => self.internal_queue.push(msg),
+                    Err(err) => if self.decode_failed(err) {
+                        return;
                     }
                 }
+                found = true;
+
+            } else if length > 0 && is_correlated(self.buffer[index + FRAME_HEADER_SIZE]) {
+                push_correlated(&mut self.correlated_queue, &self.buffer[index + FRAME_HEADER_SIZE..end]);
+                found = true;
+
+            } else if length > 0 && self.buffer[index + FRAME_HEADER_SIZE] == 7 {
+                push_scheduled(&mut self.scheduled_queue, &self.buffer[index + FRAME_HEADER_SIZE..end]);
+                found = true;
+
+            } else if length > 0 && self.buffer[index + FRAME_HEADER_SIZE] >= MIN_CONTROL_PREFIX {
+                push_control(&mut self.control_queue, &self.buffer[index + FRAME_HEADER_SIZE..end]);
+                found = true;
+
+            } else {
+                remaining.extend_from_slice(&self.buffer[index..end]);
+            }
+
+            index = end;
+
+        }
+
+        // Leave the buffer untouched unless there was something to remove
+        if found {
+            remaining.extend_from_slice(&self.buffer[index..]);
+            self.buffer = remaining;
+            self.consumed = 0;
+        }
+
+    }
+
+    // Removes the internal messages matching the filter, returns whether there were any
+    pub fn remove_internal<F: FnMut(&I) -> bool>(&mut self, mut filter: F) -> bool {
+        let count = self.internal_queue.len();
+        self.internal_queue.retain(|m| !filter(m));
+        self.internal_queue.len() != count
+    }
+
+    pub fn drain_internal(&mut self) -> Vec<I> {
+        self.internal_queue.drain(0..).collect()
+    }
+
+    // Hands out the control messages of the application, the handler decodes
+    // them and failures count as decode errors
+    pub fn dispatch_control<F: FnMut(u8, &[u8]) -> Result<(), DecodeError>>(&mut self, mut handler: F) {
+        for (prefix, bytes) in mem::take(&mut self.control_queue) {
+            if let Err(err) = handler(prefix, &bytes) {
+                if self.decode_failed(err) {
+                    break;
+                }
+            }
+        }
+    }
 
-                index += 1;
+    pub fn drain_correlated<E: Codec, M: Serialize + DeserializeOwned>(&mut self, prefix: u8) -> Vec<(RequestId, M)> {
+        let mut messages = Vec::new();
+        let mut remaining = Vec::new();
+        let mut errors = Vec::new();
+        for (p, id, bytes) in self.correlated_queue.drain(0..) {
+            if p != prefix {
+                remaining.push((p, id, bytes));
 
+            } else {
+                match from_bytes::<E, M>(p, &bytes) {
+                    Ok(msg) => messages.push((RequestId(id), msg)),
+                    Err(err) => errors.push(err)
+                }
             }
+        }
+        self.correlated_queue = remaining;
+        self.messages += messages.len() as u64;
+        for err in errors {
+            self.decode_failed(err);
+        }
+        messages
+    }
 
-            *self.buffer = (&self.buffer[index..]).to_vec();
-            message
+    // Messages scheduled for the given tick or earlier ones, ordered by their
+    // tick; messages for later ticks are kept until those are due
+    pub fn drain_scheduled<E: Codec, M: Serialize + DeserializeOwned>(&mut self, tick: u64) -> Vec<(u64, M)> {
+        let mut messages = Vec::new();
+        let mut errors = Vec::new();
+        let late_policy = self.late_policy;
+        self.scheduled_queue.sort_by_key(|&(t, _)| t);
+        let due = self.scheduled_queue.iter().take_while(|&&(t, _)| t <= tick).count();
+        for (t, bytes) in self.scheduled_queue.drain(0..due) {
+            if t < tick && late_policy == LatePolicy::Drop {
+                continue;
+            }
+            match from_bytes::<E, M>(7, &bytes) {
+                Ok(msg) => messages.push((t, msg)),
+                Err(err) => errors.push(err)
+            }
+        }
+        self.messages += messages.len() as u64;
+        for err in errors {
+            self.decode_failed(err);
         }
+        messages
     }
 
-}
+    // Returns the latest acked message handed to the application, once
+    pub fn take_ack(&mut self) -> Option<u32> {
+        if self.acked != self.ack_sent {
+            self.ack_sent = self.acked;
+            self.acked
+
+        } else {
+            None
+        }
+    }
 
+    pub fn drain_decode_errors(&mut self) -> Vec<DecodeError> {
+        self.decode_errors.drain(0..).collect()
+    }
 
-// Internal Factory -----------------------------------------------------------
-pub fn create_message_iterator<'a, M: Serialize + DeserializeOwned, I: Serialize + DeserializeOwned>(
-    buffer: &'a mut Vec<u8>,
-    internal_queue: &'a mut Vec<I>
+    pub fn messages(&self) -> u64 {
+        self.messages
+    }
 
-) -> MessageIterator<'a, M, I> {
-    MessageIterator {
-        buffer: buffer,
-        internal_queue: internal_queue,
-        message: PhantomData
+    pub fn is_empty(&self) -> bool {
+        self.buffer.len() <= self.consumed && self.unyielded.is_empty()
     }
+
+    pub fn violation(&self) -> bool {
+        self.violation
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.consumed = 0;
+        self.internal_queue.clear();
+        self.correlated_queue.clear();
+        self.scheduled_queue.clear();
+        self.control_queue.clear();
+        self.fragments.clear();
+        self.borrowed.clear();
+        self.unyielded.clear();
+        self.messages = 0;
+        self.read_this_tick = 0;
+        self.reads_throttled = 0;
+        self.idle_ticks = 0;
+        self.unchecked = 0;
+        self.acked = None;
+        self.ack_sent = None;
+        self.decode_errors.clear();
+        self.violation = false;
+        self.cipher = None;
+        self.stamped = false;
+        self.stamp = None;
+    }
+
+    // Returns true in strict mode, where the first invalid frame is treated as
+    // a protocol violation since the peers are incompatible anyway
+    fn decode_failed(&mut self, error: DecodeError) -> bool {
+        if record_decode_error(&mut self.decode_errors, error, self.strict) {
+            self.violation = true;
+            self.buffer.clear();
+            self.consumed = 0;
+            true
+
+        } else {
+            false
+        }
+    }
+
+}
+
+
+fn record_decode_error(errors: &mut Vec<DecodeError>, error: DecodeError, strict: bool) -> bool {
+    #[cfg(feature = "log")]
+    debug!("Skipped {}", error);
+    if errors.len() < MAX_DECODE_ERRORS {
+        errors.push(error);
+    }
+    strict
+}
+
+
+// Outgoing Message Buffer ----------------------------------------------------
+pub struct Outgoing {
+    frames: VecDeque<Frame>,
+    internal: VecDeque<Frame>,
+    internal_end: usize,
+    written_pings: Vec<u32>,
+    priority: VecDeque<Frame>,
+    deferred: VecDeque<(u8, Frame)>,
+    replaceable: VecDeque<(u8, Frame)>,
+    dropped_frames: u64,
+    size: usize,
+    offset: usize,
+    frames_sent: u64,
+    fragment_size: usize,
+    compression: Option<usize>,
+    compressed_bytes_saved: usize,
+    spare: Vec<Vec<u8>>,
+    spare_taken: bool,
+    spare_idle_ticks: u64,
+    cipher: Option<Cipher>,
+    sealed: usize,
+    batching: bool,
+    batch: Vec<Vec<u8>>,
+    batch_channel: u8,
+    batch_size: usize,
+    total_frames_sent: u64,
+    stamp: Option<u32>
 }
 
+impl Outgoing {
+
+    pub fn new() -> Self {
+        Self {
+            frames: VecDeque::new(),
+            internal: VecDeque::new(),
+            internal_end: 0,
+            written_pings: Vec::new(),
+            priority: VecDeque::new(),
+            deferred: VecDeque::new(),
+            replaceable: VecDeque::new(),
+            dropped_frames: 0,
+            size: 0,
+            offset: 0,
+            frames_sent: 0,
+            fragment_size: DEFAULT_FRAGMENT_SIZE,
+            compression: None,
+            compressed_bytes_saved: 0,
+            spare: Vec::new(),
+            spare_taken: false,
+            spare_idle_ticks: 0,
+            cipher: None,
+            sealed: 0,
+            batching: true,
+            batch: Vec::new(),
+            batch_channel: 0,
+            batch_size: 0,
+            total_frames_sent: 0,
+            stamp: None
+        }
+    }
+
+    // Consecutive messages on the same channel share a single frame until
+    // anything else gets queued or the buffer is written
+    pub fn set_batching(&mut self, batching: bool) {
+        if batching != self.batching {
+            self.close_batch();
+            self.batching = batching;
+        }
+    }
+
+    // Application messages queued from now on start with the given tick
+    pub fn set_stamp(&mut self, tick: Option<u32>) {
+        self.stamp = tick;
+    }
+
+    pub fn stamping(&self) -> bool {
+        self.stamp.is_some()
+    }
+
+    // Puts the salt in front of everything else, must be set before anything
+    // was written
+    pub fn set_encryption(&mut self, psk: Option<[u8; KEY_SIZE]>) {
+        self.cipher = psk.and_then(|psk| Cipher::outgoing(&psk));
+        self.sealed = 0;
+        if let Some(cipher) = self.cipher.as_ref() {
+            let frame = Frame::new(SALT_PREFIX, &[], cipher.salt().to_vec());
+            self.size += frame.len();
+            self.frames.push_front(frame);
+            self.sealed = 1;
+        }
+    }
+
+    // Encodes into a buffer of a frame which has already been written, so
+    // sending does not allocate once there are enough of them
+    pub fn encode<E: Codec, T: Serialize>(&mut self, message: &T, max_message_size: usize) -> Result<Vec<u8>, Error> {
+        let mut buffer = self.take_spare();
+        match encode_into::<E, T>(message, max_message_size, &mut buffer) {
+            Ok(()) => Ok(buffer),
+            Err(err) => {
+                self.recycle(buffer);
+                Err(err)
+            }
+        }
+    }
+
+    pub fn encode_raw(&mut self, bytes: &[u8], max_message_size: usize) -> Result<Vec<u8>, Error> {
+        check_message_size(bytes.len(), max_message_size)?;
+        let mut buffer = self.take_spare();
+        buffer.extend_from_slice(bytes);
+        Ok(buffer)
+    }
+
+    // Spare buffers are released after a while without any sends
+    pub fn tick(&mut self) {
+        if mem::take(&mut self.spare_taken) {
+            self.spare_idle_ticks = 0;
+
+        } else if !self.spare.is_empty() {
+            self.spare_idle_ticks += 1;
+            if self.spare_idle_ticks >= MAX_SPARE_IDLE_TICKS {
+                self.spare = Vec::new();
+            }
+        }
+    }
+
+    // Frames with a payload above the threshold are compressed, which must only
+    // be enabled once the peer announced that it supports compression
+    pub fn set_compression(&mut self, threshold: Option<usize>) {
+        self.compression = threshold;
+    }
+
+    pub fn compressed_bytes_saved(&self) -> usize {
+        self.compressed_bytes_saved
+    }
+
+    pub fn set_fragment_size(&mut self, fragment_size: usize) {
+        self.fragment_size = cmp::max(fragment_size, 1);
+    }
+
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    // Number of frames which were written completely since the last call
+    pub fn take_frames_sent(&mut self) -> u64 {
+        mem::replace(&mut self.frames_sent, 0)
+    }
+
+    pub fn total_frames_sent(&self) -> u64 {
+        self.total_frames_sent
+    }
+
+    // Frames which were replaced by newer ones before they were written
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped_frames
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    // Moves all frames of the other buffer behind the ones queued so far, the
+    // other buffer must not have been written to a connection yet
+    pub fn append(&mut self, mut other: Outgoing) {
+        self.close_batch();
+        other.close_batch();
+        self.frames.extend(other.frames);
+        self.internal.extend(other.internal);
+        self.priority.extend(other.priority);
+        self.deferred.extend(other.deferred);
+        self.replaceable.extend(other.replaceable);
+        self.dropped_frames += other.dropped_frames;
+        self.size += other.size;
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.internal.clear();
+        self.internal_end = 0;
+        self.written_pings.clear();
+        self.priority.clear();
+        self.deferred.clear();
+        self.replaceable.clear();
+        self.size = 0;
+        self.offset = 0;
+        self.sealed = 0;
+        self.batch.clear();
+        self.batch_size = 0;
+    }
+
+    pub fn push_frame(&mut self, prefix: u8, payload: Vec<u8>) {
+        self.close_batch();
+        self.push(Frame::new(prefix, &[], payload));
+    }
+
+    // Internal messages skip ahead of all application frames on the next
+    // write, so pings and acks never wait behind a large snapshot
+    pub fn push_internal_frame(&mut self, payload: Vec<u8>) {
+        self.push_internal(None, payload);
+    }
+
+    // Pings are tagged so the time they actually got written can be reported,
+    // see written_pings()
+    pub fn push_ping_frame(&mut self, seq: u32, payload: Vec<u8>) {
+        self.push_internal(Some(seq), payload);
+    }
+
+    // The sequence numbers of the pings written completely since the last call
+    pub fn written_pings(&mut self) -> vec::Drain<'_, u32> {
+        self.written_pings.drain(0..)
+    }
+
+    // Goes out after everything queued so far, including deferred fragments
+    pub fn push_last_frame(&mut self, prefix: u8, payload: Vec<u8>) {
+        self.close_batch();
+        let frame = Frame::new(prefix, &[], payload);
+        if self.deferred.is_empty() {
+            self.push(frame);
+
+        } else {
+            self.defer(0, frame);
+        }
+    }
+
+    pub fn push_correlated_frame(&mut self, prefix: u8, id: RequestId, payload: Vec<u8>) {
+        self.close_batch();
+        self.push(Frame::new(prefix, &id.0.to_le_bytes(), payload));
+    }
+
+    // Acked messages travel on channel 0 and keep their order with it
+    pub fn push_acked_frame(&mut self, id: AckId, payload: Vec<u8>) {
+        let payload = self.stamped(payload);
+        self.close_batch();
+        self.push_ordered(Frame::new(6, &id.0.to_le_bytes(), payload));
+    }
+
+    // Messages for a specific tick of the peer, ordered like acked messages
+    pub fn push_scheduled_frame(&mut self, tick: u64, payload: Vec<u8>) {
+        self.close_batch();
+        self.push_ordered(Frame::new(7, &tick.to_le_bytes(), payload));
+    }
+
+    // Channels with a limit on their unsent frames drop stale ones, see
+    // push_replaceable_frame()
+    pub fn push_message(&mut self, channel: u8, priority: bool, max_frames: Option<usize>, payload: Vec<u8>) {
+        if let Some(max_frames) = max_frames {
+            self.push_replaceable_frame(channel, max_frames, payload);
+
+        } else {
+            self.push_channel_frame(channel, priority, payload);
+        }
+    }
+
+    // Frames of high priority channels are kept apart so they can skip ahead
+    // of everything else on the next write
+    pub fn push_channel_frame(&mut self, channel: u8, priority: bool, payload: Vec<u8>) {
+        let payload = self.stamped(payload);
+        self.queue_channel_frame(channel, priority, payload);
+    }
+
+    // Held back until everything else has been written, so newer frames can
+    // still take the place of the oldest ones while the connection is slow;
+    // they are neither batched nor sent with priority. Messages which need
+    // to be fragmented are never dropped, but replace all older frames.
+    pub fn push_replaceable_frame(&mut self, channel: u8, max_frames: usize, payload: Vec<u8>) {
+
+        let payload = self.stamped(payload);
+        let fragmented = payload.len() > self.fragment_size;
+        let keep = if fragmented { 0 } else { cmp::max(max_frames, 1) - 1 };
+        let mut queued = self.replaceable.iter().filter(|&&(c, _)| c == channel).count();
+        while queued > keep {
+            let index = self.replaceable.iter().position(|&(c, _)| c == channel);
+            if let Some((_, frame)) = index.and_then(|index| self.replaceable.remove(index)) {
+                self.size -= frame.len();
+                self.recycle(frame.payload);
+                self.dropped_frames += 1;
+            }
+            queued -= 1;
+        }
+
+        if fragmented {
+            self.queue_channel_frame(channel, false, payload);
+
+        } else {
+            let frame = self.compress(Frame::channel(channel, payload));
+            self.size += frame.len();
+            self.replaceable.push_back((channel, frame));
+        }
+
+    }
+
+    fn queue_channel_frame(&mut self, channel: u8, priority: bool, payload: Vec<u8>) {
+
+        // Priority frames skip ahead anyway, so they never end a batch
+        let deferred = self.deferred.iter().any(|&(c, _)| c == channel);
+        if self.batching && !priority && !deferred && BATCH_OVERHEAD + BATCH_ITEM_OVERHEAD + payload.len() <= self.fragment_size {
+            self.push_batched(channel, payload);
+            return;
+
+        } else if !priority {
+            self.close_batch();
+        }
+
+        // Large messages are split up and only trickle into the buffer once it
+        // has drained, later messages on the same channel queue up behind them
+        if payload.len() > self.fragment_size {
+            let count = payload.len().div_ceil(self.fragment_size);
+            for (index, chunk) in payload.chunks(self.fragment_size).enumerate() {
+                let more = if index + 1 < count { 1 } else { 0 };
+                let mut buffer = self.take_spare();
+                buffer.extend_from_slice(chunk);
+                self.defer(channel, Frame::new(5, &[channel, more], buffer));
+            }
+            self.recycle(payload);
+
+        } else if deferred {
+            self.defer(channel, Frame::channel(channel, payload));
+
+        } else if priority {
+            let frame = self.compress(Frame::channel(channel, payload));
+            self.size += frame.len();
+            self.priority.push_back(frame);
+
+        } else {
+            self.push(Frame::channel(channel, payload));
+        }
+    }
+
+    // Writes as much as the connection accepts up to the given number of bytes,
+    // deferred fragments are fed in one by one so that frames queued in the
+    // meantime can go in between them
+    pub fn write_to<C: Connection>(&mut self, connection: &mut C, max_bytes: usize) -> Result<usize, IOError> {
+        self.write_tapped(connection, max_bytes, |_| {})
+    }
+
+    // Like write_to() but hands all bytes the connection accepted to the tap
+    pub fn write_tapped<C: Connection, T: FnMut(&[u8])>(&mut self, connection: &mut C, max_bytes: usize, mut tap: T) -> Result<usize, IOError> {
+        let mut written = 0;
+        self.close_batch();
+        loop {
+            self.prepare();
+            self.seal();
+            let (bytes, offered) = {
+                let slices = self.slices(max_bytes - written);
+                let offered: usize = slices.iter().map(|slice| slice.len()).sum();
+                if offered == 0 {
+                    return Ok(written);
+                }
+                let bytes = connection.write_vectored(&slices)?;
+                let mut remaining = bytes;
+                for slice in slices.iter() {
+                    if remaining == 0 {
+                        break;
+                    }
+                    let length = cmp::min(remaining, slice.len());
+                    tap(&slice[..length]);
+                    remaining -= length;
+                }
+                (bytes, offered)
+            };
+            self.consume(bytes);
+            written += bytes;
+            if bytes < offered || written == max_bytes {
+                return Ok(written);
+            }
+        }
+    }
+
+    fn stamped(&self, mut payload: Vec<u8>) -> Vec<u8> {
+        if let Some(tick) = self.stamp {
+            payload.splice(0..0, tick.to_le_bytes());
+        }
+        payload
+    }
+
+    // Counted as a batch frame right away, so len() already covers it
+    fn push_batched(&mut self, channel: u8, payload: Vec<u8>) {
+        if !self.batch.is_empty() && (channel != self.batch_channel || self.batch_size + BATCH_ITEM_OVERHEAD + payload.len() > self.fragment_size) {
+            self.close_batch();
+        }
+        if self.batch.is_empty() {
+            self.batch_channel = channel;
+            self.batch_size = BATCH_OVERHEAD;
+            self.size += FRAME_HEADER_SIZE + 2 + BATCH_OVERHEAD;
+        }
+        self.batch_size += BATCH_ITEM_OVERHEAD + payload.len();
+        self.size += BATCH_ITEM_OVERHEAD + payload.len();
+        self.batch.push(payload);
+    }
+
+    // Batches of a single message go out as a plain frame
+    fn close_batch(&mut self) {
+        if self.batch.is_empty() {
+            return;
+        }
+
+        self.size -= FRAME_HEADER_SIZE + 2 + self.batch_size;
+        self.batch_size = 0;
+
+        let frame = if self.batch.len() == 1 {
+            let payload = self.batch.remove(0);
+            Frame::channel(self.batch_channel, payload)
+
+        } else {
+            let mut payload = self.take_spare();
+            let mut batch = mem::take(&mut self.batch);
+            payload.extend_from_slice(&(batch.len() as u32).to_le_bytes());
+            for message in batch.drain(0..) {
+                payload.extend_from_slice(&(message.len() as u32).to_le_bytes());
+                payload.extend_from_slice(&message);
+                self.recycle(message);
+            }
+            self.batch = batch;
+            Frame::new(BATCH_PREFIX, &[self.batch_channel], payload)
+        };
+        self.push(frame);
+    }
+
+    fn push_internal(&mut self, ping: Option<u32>, payload: Vec<u8>) {
+        let mut frame = self.compress(Frame::new(0, &[], payload));
+        frame.ping = ping;
+        self.size += frame.len();
+        self.internal.push_back(frame);
+    }
+
+    fn push(&mut self, frame: Frame) {
+        let frame = self.compress(frame);
+        self.size += frame.len();
+        self.frames.push_back(frame);
+    }
+
+    // Queues up behind deferred fragments of channel 0
+    fn push_ordered(&mut self, frame: Frame) {
+        if self.deferred.iter().any(|&(c, _)| c == 0) {
+            self.defer(0, frame);
+
+        } else {
+            self.push(frame);
+        }
+    }
+
+    fn defer(&mut self, channel: u8, frame: Frame) {
+        let frame = self.compress(frame);
+        self.size += frame.len();
+        self.deferred.push_back((channel, frame));
+    }
+
+    // Frames are only sent compressed if that actually saves some bytes
+    fn compress(&mut self, frame: Frame) -> Frame {
+        match self.compression {
+            Some(threshold) if frame.payload.len() > threshold => {
+                let mut body = frame.header[FRAME_HEADER_SIZE..frame.header_len].to_vec();
+                body.extend_from_slice(&frame.payload);
+                match compression::compress(&body) {
+                    Some(bytes) if bytes.len() < body.len() => {
+                        self.compressed_bytes_saved += body.len() - bytes.len();
+                        self.recycle(frame.payload);
+                        Frame::compressed(bytes)
+                    },
+                    _ => frame
+                }
+            },
+            _ => frame
+        }
+    }
+
+    // Places the internal frames right after a partially written frame and
+    // the ones which skipped ahead before, followed by the priority frames, and
+    // feeds in the next deferred frame once all other frames have been written
+    fn prepare(&mut self) {
+        let written = if self.offset > 0 { 1 } else { 0 };
+        let mut at = cmp::max(cmp::max(written, self.sealed), self.internal_end);
+        for frame in self.internal.drain(0..) {
+            self.frames.insert(at, frame);
+            at += 1;
+        }
+        self.internal_end = at;
+        for (index, frame) in self.priority.drain(0..).enumerate() {
+            self.frames.insert(at + index, frame);
+        }
+        if self.frames.len() == written {
+            if let Some((_, frame)) = self.deferred.pop_front() {
+                self.frames.push_back(frame);
+
+            } else {
+                self.frames.extend(self.replaceable.drain(0..).map(|(_, frame)| frame));
+            }
+        }
+    }
+
+    // Frames are sealed once their place on the wire is final, since the
+    // peer expects the nonces in the order the frames arrive
+    fn seal(&mut self) {
+        if let Some(cipher) = self.cipher.as_mut() {
+            let end = cmp::min(self.frames.len(), MAX_WRITE_FRAMES);
+            for frame in self.frames.range_mut(cmp::min(self.sealed, end)..end) {
+                frame.seal(cipher);
+                self.size += TAG_SIZE;
+            }
+            self.sealed = cmp::max(self.sealed, end);
+        }
+    }
+
+    fn take_spare(&mut self) -> Vec<u8> {
+        self.spare_taken = true;
+        self.spare.pop().unwrap_or_default()
+    }
+
+    // Buffers of huge messages are not kept around
+    fn recycle(&mut self, mut buffer: Vec<u8>) {
+        if buffer.capacity() <= MAX_SPARE_CAPACITY && self.spare.len() < MAX_SPARE_BUFFERS {
+            buffer.clear();
+            self.spare.push(buffer);
+        }
+    }
+
+    fn slices(&self, max_bytes: usize) -> WriteSlices<'_> {
+        let mut slices = WriteSlices::new();
+        let mut remaining = max_bytes;
+        let mut offset = self.offset;
+        for frame in self.frames.iter().take(MAX_WRITE_FRAMES) {
+            for part in &[frame.header(), &frame.payload[..]] {
+                if offset >= part.len() {
+                    offset -= part.len();
+                    continue;
+                }
+                let end = cmp::min(part.len(), offset.saturating_add(remaining));
+                slices.push(IoSlice::new(&part[offset..end]));
+                remaining -= end - offset;
+                offset = 0;
+                if remaining == 0 {
+                    return slices;
+                }
+            }
+        }
+        slices
+    }
+
+    // Drops frames which have been written completely and remembers how much
+    // of the frame at the front has already been sent
+    fn consume(&mut self, bytes: usize) {
+        self.size -= bytes;
+        let mut offset = self.offset + bytes;
+        while let Some(length) = self.frames.front().map(Frame::len) {
+            if offset < length {
+                break;
+            }
+            offset -= length;
+            if let Some(frame) = self.frames.pop_front() {
+                self.written_pings.extend(frame.ping);
+                self.recycle(frame.payload);
+            }
+            self.sealed = self.sealed.saturating_sub(1);
+            self.internal_end = self.internal_end.saturating_sub(1);
+            self.frames_sent += 1;
+            self.total_frames_sent += 1;
+        }
+        self.offset = offset;
+    }
+
+}
+
+
+// Priority Channels ----------------------------------------------------------
+#[derive(Debug, Default, Copy, Clone)]
+pub struct PriorityChannels([u64; 4]);
+
+impl PriorityChannels {
+
+    pub fn set(&mut self, channel: u8, priority: bool) {
+        let (index, bit) = (usize::from(channel / 64), 1u64 << (channel % 64));
+        if priority {
+            self.0[index] |= bit;
+
+        } else {
+            self.0[index] &= !bit;
+        }
+    }
+
+    pub fn contains(&self, channel: u8) -> bool {
+        self.0[usize::from(channel / 64)] & (1u64 << (channel % 64)) != 0
+    }
+
+}
+
+
+// Queue Policies -------------------------------------------------------------
+// What happens to messages of a channel which have not been written yet when
+// newer ones are sent, for data which is worthless once it got stale
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum QueuePolicy {
+    Reliable,
+    DropOldest(usize),
+    LatestOnly
+}
+
+// The number of unsent frames kept for each channel, zero for reliable ones
+#[derive(Copy, Clone)]
+pub struct QueuePolicies([u32; 256]);
+
+impl QueuePolicies {
+
+    pub fn set(&mut self, channel: u8, policy: QueuePolicy) {
+        self.0[usize::from(channel)] = match policy {
+            QueuePolicy::Reliable => 0,
+            QueuePolicy::DropOldest(max_frames) => cmp::min(cmp::max(max_frames, 1), u32::MAX as usize) as u32,
+            QueuePolicy::LatestOnly => 1
+        };
+    }
+
+    pub fn max_frames(&self, channel: u8) -> Option<usize> {
+        match self.0[usize::from(channel)] {
+            0 => None,
+            max_frames => Some(max_frames as usize)
+        }
+    }
+
+}
+
+impl Default for QueuePolicies {
+    fn default() -> Self {
+        QueuePolicies([0; 256])
+    }
+}
+
+
+// Message Iterator Abstraction -----------------------------------------------
+// Messages for which the filter returns false are skipped
+pub type MessageFilter<'a, M> = Box<dyn FnMut(&M) -> bool + 'a>;
+
+pub struct MessageIterator<'a, M: Serialize + DeserializeOwned, I: Serialize + DeserializeOwned + 'a, E: Codec = BincodeCodec> {
+    incoming: &'a mut Incoming<I>,
+    filter: Option<MessageFilter<'a, M>>,
+    message: PhantomData<M>,
+    codec: PhantomData<E>
+}
+
+pub fn from_bytes<E: Codec, M: Serialize + DeserializeOwned>(prefix: u8, bytes: &[u8]) -> Result<M, DecodeError> {
+    E::decode::<M>(bytes).map(|(msg, _)| msg).map_err(|err| DecodeError {
+        prefix: prefix,
+        length: bytes.len(),
+        error: err
+    })
+}
+
+fn is_correlated(prefix: u8) -> bool {
+    prefix == 2 || prefix == 3
+}
+
+fn push_scheduled(queue: &mut Vec<(u64, Vec<u8>)>, frame: &[u8]) {
+    if frame.len() >= 9 {
+        let mut tick = [0; 8];
+        tick.copy_from_slice(&frame[1..9]);
+        queue.push((u64::from_le_bytes(tick), frame[9..].to_vec()));
+    }
+}
+
+fn push_control(queue: &mut Vec<(u8, Vec<u8>)>, frame: &[u8]) {
+    queue.push((frame[0], frame[1..].to_vec()));
+}
+
+// Applications can only use the prefixes which are not reserved
+pub fn check_control_prefix(prefix: u8) -> Result<(), Error> {
+    if prefix < MIN_CONTROL_PREFIX {
+        Err(Error::ReservedPrefix {
+            prefix: prefix
+        })
+
+    } else {
+        Ok(())
+    }
+}
+
+fn push_correlated(queue: &mut Vec<(u8, u32, Vec<u8>)>, frame: &[u8]) {
+    // Frames too short to even carry their identifier are dropped
+    if frame.len() >= 5 {
+        let id = u32::from_le_bytes([frame[1], frame[2], frame[3], frame[4]]);
+        queue.push((frame[0], id, frame[5..].to_vec()));
+    }
+}
+
+impl<'a, M: Serialize + DeserializeOwned, I: Serialize + DeserializeOwned, E: Codec> Iterator for MessageIterator<'a, M, I, E> {
+
+    type Item = M;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        next_filtered::<M, I, E, _>(self.incoming, &mut self.filter, from_bytes::<E, M>).map(|(_, msg)| msg)
+    }
+
+}
+
+// Yields the application messages of all channels along with their channel
+pub struct ChannelIterator<'a, M: Serialize + DeserializeOwned, I: Serialize + DeserializeOwned + 'a, E: Codec = BincodeCodec> {
+    incoming: &'a mut Incoming<I>,
+    filter: Option<MessageFilter<'a, M>>,
+    message: PhantomData<M>,
+    codec: PhantomData<E>
+}
+
+impl<'a, M: Serialize + DeserializeOwned, I: Serialize + DeserializeOwned, E: Codec> Iterator for ChannelIterator<'a, M, I, E> {
+
+    type Item = (u8, M);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        next_filtered::<M, I, E, _>(self.incoming, &mut self.filter, from_bytes::<E, M>)
+    }
+
+}
+
+// Yields the payloads of application messages without decoding them, these
+// are the bytes the peer passed to send_bytes() or the encoding of its messages
+pub struct RawIterator<'a, I: Serialize + DeserializeOwned + 'a, E: Codec = BincodeCodec> {
+    incoming: &'a mut Incoming<I>,
+    filter: Option<MessageFilter<'a, Vec<u8>>>,
+    codec: PhantomData<E>
+}
+
+impl<'a, I: Serialize + DeserializeOwned, E: Codec> Iterator for RawIterator<'a, I, E> {
+
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        next_filtered::<Vec<u8>, I, E, _>(self.incoming, &mut self.filter, |_, bytes: &[u8]| Ok(bytes.to_vec())).map(|(_, bytes)| bytes)
+    }
+
+}
+
+// Messages along with how long ago the peer sent them, the age is NaN for
+// peers which do not stamp their messages and until the first pong arrived
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stamped<M> {
+    pub message: M,
+    pub age_ms: f64,
+    pub sender_tick: u32
+}
+
+pub struct StampedIterator<'a, M: Serialize + DeserializeOwned, I: Serialize + DeserializeOwned + 'a, E: Codec = BincodeCodec> {
+    incoming: &'a mut Incoming<I>,
+    filter: Option<MessageFilter<'a, M>>,
+    clock: Option<PeerClock>,
+    message: PhantomData<M>,
+    codec: PhantomData<E>
+}
+
+impl<'a, M: Serialize + DeserializeOwned, I: Serialize + DeserializeOwned, E: Codec> Iterator for StampedIterator<'a, M, I, E> {
+
+    type Item = Stamped<M>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        next_filtered::<M, I, E, _>(self.incoming, &mut self.filter, from_bytes::<E, M>).map(|(_, msg)| {
+            let tick = self.incoming.stamp;
+            Stamped {
+                message: msg,
+                age_ms: match (tick, self.clock) {
+                    (Some(tick), Some(clock)) => clock.age_ms(tick),
+                    _ => f64::NAN
+                },
+                sender_tick: tick.unwrap_or(0)
+            }
+        })
+    }
+
+}
+
+// Yields messages which borrow from the incoming buffer, see BorrowCodec; the
+// frames are all walked up front so nothing moves the buffer while they are
+// borrowed and it is only compacted again by the read after the iteration.
+// Messages left over when the iterator is dropped are copied out and yielded
+// first by the next one, acks only cover the messages which were handed out
+pub struct BorrowedIterator<'a, M, E: BorrowCodec = BincodeCodec> {
+    buffer: &'a [u8],
+    payloads: slice::Iter<'a, Borrowed>,
+    unyielded: &'a mut Vec<Borrowed>,
+    acked: &'a mut Option<u32>,
+    walked_ack: Option<u32>,
+    errors: &'a mut Vec<DecodeError>,
+    violation: &'a mut bool,
+    strict: bool,
+    message: PhantomData<M>,
+    codec: PhantomData<E>
+}
+
+// The ack is the one of the last frame up to and including the payload
+struct Borrowed {
+    prefix: u8,
+    end: usize,
+    acked: Option<u32>,
+    payload: Payload
+}
+
+impl Borrowed {
+
+    fn bytes<'a>(&'a self, buffer: &'a [u8]) -> &'a [u8] {
+        match self.payload {
+            Payload::Frame(length) => &buffer[self.end - length..self.end],
+            Payload::Reassembled(ref bytes) => bytes
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self.payload {
+            Payload::Frame(length) => length,
+            Payload::Reassembled(ref bytes) => bytes.len()
+        }
+    }
+
+}
+
+// Unfragmented payloads are always the tail of their frame, so only where the
+// frame ended and their length have to be known
+enum Payload {
+    Frame(usize),
+    Reassembled(Vec<u8>)
+}
+
+impl<'a, M: Deserialize<'a>, E: BorrowCodec> Iterator for BorrowedIterator<'a, M, E> {
+
+    type Item = M;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !*self.violation {
+            let borrowed = match self.payloads.next() {
+                Some(borrowed) => borrowed,
+                None => {
+                    *self.acked = self.walked_ack;
+                    return None;
+                }
+            };
+            let (prefix, bytes) = (borrowed.prefix, borrowed.bytes(self.buffer));
+            *self.acked = borrowed.acked;
+            match E::decode_borrowed::<M>(bytes) {
+                Ok(msg) => return Some(msg),
+                Err(err) => {
+                    let err = DecodeError {
+                        prefix: prefix,
+                        length: bytes.len(),
+                        error: err
+                    };
+
+                    // The buffer itself is cleared by the next read
+                    if record_decode_error(self.errors, err, self.strict) {
+                        *self.violation = true;
+                    }
+                }
+            }
+        }
+        None
+    }
+
+}
+
+impl<'a, M, E: BorrowCodec> Drop for BorrowedIterator<'a, M, E> {
+    fn drop(&mut self) {
+        if *self.violation {
+            return;
+        }
+        for borrowed in self.payloads.by_ref() {
+            self.unyielded.push(Borrowed {
+                prefix: borrowed.prefix,
+                end: 0,
+                acked: borrowed.acked,
+                payload: Payload::Reassembled(borrowed.bytes(self.buffer).to_vec())
+            });
+        }
+
+        // Frames after the last message are handled along with it
+        if let Some(last) = self.unyielded.last_mut() {
+            last.acked = self.walked_ack;
+
+        } else {
+            *self.acked = self.walked_ack;
+        }
+    }
+}
+
+fn next_filtered<M, I: Serialize + DeserializeOwned, E: Codec, D: FnMut(u8, &[u8]) -> Result<M, DecodeError>>(
+    incoming: &mut Incoming<I>,
+    filter: &mut Option<MessageFilter<'_, M>>,
+    mut decode: D
+
+) -> Option<(u8, M)> {
+    while let Some((channel, msg)) = next_message::<M, I, E, D>(incoming, &mut decode) {
+        if filter.as_mut().is_none_or(|filter| filter(&msg)) {
+            return Some((channel, msg));
+        }
+    }
+    None
+}
+
+fn next_message<M, I: Serialize + DeserializeOwned, E: Codec, D: FnMut(u8, &[u8]) -> Result<M, DecodeError>>(incoming: &mut Incoming<I>, decode: &mut D) -> Option<(u8, M)> {
+    if incoming.buffer.len() <= incoming.consumed || incoming.violation {
+        None
+
+    } else {
+        let mut index = incoming.consumed;
+        let mut message = None;
+
+        while let Some(length) = frame_length(&incoming.buffer[index..]) {
+
+            // Peers exceeding the size limit are in violation of the protocol
+            if length > incoming.max_frame_length() {
+                incoming.violation = true;
+                incoming.buffer.clear();
+                incoming.consumed = 0;
+                return None;
+            }
+
+            // Only consider frames which have been fully received
+            if incoming.buffer.len() < index + FRAME_HEADER_SIZE + length {
+                break;
+            }
+
+            let frame = &incoming.buffer[index + FRAME_HEADER_SIZE..index + FRAME_HEADER_SIZE + length];
+            index += FRAME_HEADER_SIZE + length;
+
+            if frame.is_empty() {
+                continue;
+
+            } else if incoming.oversized(frame) {
+                incoming.violation = true;
+                incoming.buffer.clear();
+                incoming.consumed = 0;
+                return None;
+            }
+
+            let result = match frame[0] {
+
+                // Internal Messages
+                0 => from_bytes::<E, I>(0, &frame[1..]).map(|msg| {
+                    incoming.internal_queue.push(msg);
+                }),
+
+                // Application Messages
+                1 => unstamp(incoming.stamped, &mut incoming.stamp, 1, &frame[1..]).and_then(|bytes| decode(1, bytes)).map(|msg| {
+                    incoming.messages += 1;
+                    message = Some((0, msg));
+                }),
+
+                // Requests and Responses
+                2 | 3 => {
+                    push_correlated(&mut incoming.correlated_queue, frame);
+                    Ok(())
+                },
+
+                // Messages which are only yielded once their tick is due
+                7 => {
+                    push_scheduled(&mut incoming.scheduled_queue, frame);
+                    Ok(())
+                },
+
+                // Control messages of the application, see dispatch_control()
+                prefix if prefix >= MIN_CONTROL_PREFIX => {
+                    push_control(&mut incoming.control_queue, frame);
+                    Ok(())
+                },
+
+                // Application Messages on other channels
+                4 if frame.len() >= 2 => {
+                    let channel = frame[1];
+                    unstamp(incoming.stamped, &mut incoming.stamp, 4, &frame[2..]).and_then(|bytes| decode(4, bytes)).map(|msg| {
+                        incoming.messages += 1;
+                        message = Some((channel, msg));
+                    })
+                },
+
+                // Fragments of messages which exceeded the fragment size
+                5 if frame.len() >= 3 => {
+
+                    // Reassembled messages are subject to the same size limit
+                    if incoming.fragments.len() + frame.len() - 3 > incoming.max_stamped_size() {
+                        incoming.violation = true;
+                        incoming.buffer.clear();
+                        incoming.consumed = 0;
+                        incoming.fragments.clear();
+                        return None;
+                    }
+
+                    incoming.fragments.extend_from_slice(&frame[3..]);
+                    if frame[2] == 0 {
+                        let channel = frame[1];
+                        let bytes = mem::take(&mut incoming.fragments);
+                        unstamp(incoming.stamped, &mut incoming.stamp, 5, &bytes).and_then(|bytes| decode(5, bytes)).map(|msg| {
+                            incoming.messages += 1;
+                            message = Some((channel, msg));
+                        })
+
+                    } else {
+                        Ok(())
+                    }
+
+                },
+
+                // Application Messages which are acknowledged once handed out,
+                // ones which fail to decode count as handled as well
+                6 if frame.len() >= 5 => {
+                    incoming.acked = Some(u32::from_le_bytes([frame[1], frame[2], frame[3], frame[4]]));
+                    unstamp(incoming.stamped, &mut incoming.stamp, 6, &frame[5..]).and_then(|bytes| decode(6, bytes)).map(|msg| {
+                        incoming.messages += 1;
+                        message = Some((0, msg));
+                    })
+                },
+
+                prefix => Err(DecodeError {
+                    prefix: prefix,
+                    length: frame.len() - 1,
+                    error: CodecError::Decode("unknown frame prefix".to_string())
+                })
+
+            };
+
+            if let Err(err) = result {
+                if incoming.decode_failed(err) {
+                    return None;
+                }
+
+            } else if message.is_some() {
+                break;
+            }
+
+        }
+
+        incoming.consumed = index;
+        message
+    }
+}
+
+
+// Stamped messages start with the lower bits of the tick they were sent at
+fn unstamp<'b>(stamped: bool, stamp: &mut Option<u32>, prefix: u8, bytes: &'b [u8]) -> Result<&'b [u8], DecodeError> {
+    if !stamped {
+        *stamp = None;
+        Ok(bytes)
+
+    } else if bytes.len() < STAMP_SIZE {
+        Err(DecodeError {
+            prefix: prefix,
+            length: bytes.len(),
+            error: CodecError::Decode("missing stamp".to_string())
+        })
+
+    } else {
+        *stamp = Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+        Ok(&bytes[STAMP_SIZE..])
+    }
+}
+
+
+// Framing --------------------------------------------------------------------
+pub const FRAME_HEADER_SIZE: usize = 4;
+
+// Prefix byte plus the optional request or acknowledgement identifier, or
+// the target tick of scheduled messages
+const MAX_FRAME_OVERHEAD: usize = 9;
+
+// The highest bit of the frame length marks compressed frames
+const COMPRESSED_FLAG: u32 = 1 << 31;
+
+// Batches start with their message count, followed by each message prefixed
+// with its length
+const BATCH_OVERHEAD: usize = 4;
+const BATCH_ITEM_OVERHEAD: usize = 4;
+
+// Sender tick in front of the payload of stamped messages
+pub const STAMP_SIZE: usize = 4;
+
+// Keeps the slices of a single write below the usual IOV_MAX
+const MAX_WRITE_FRAMES: usize = 512;
+
+// Every frame is written as its header and its payload
+const MAX_WRITE_SLICES: usize = MAX_WRITE_FRAMES * 2;
+
+// Limits the memory kept around by the spare buffers of a single connection
+const MAX_SPARE_BUFFERS: usize = 1024;
+const MAX_SPARE_CAPACITY: usize = 64 * 1024;
+const MAX_SPARE_IDLE_TICKS: u64 = 600;
+
+pub fn encode<E: Codec, T: Serialize>(message: &T, max_message_size: usize) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::new();
+    encode_into::<E, T>(message, max_message_size, &mut bytes)?;
+    Ok(bytes)
+}
+
+// Replaces the contents of the buffer but keeps its capacity
+pub fn encode_into<E: Codec, T: Serialize>(message: &T, max_message_size: usize, buffer: &mut Vec<u8>) -> Result<(), Error> {
+    buffer.clear();
+    E::encode_into(message, buffer)?;
+    check_message_size(buffer.len(), max_message_size)
+}
+
+// Pre-encoded payloads are subject to the same limit as encoded messages
+pub fn check_message_size(size: usize, max_message_size: usize) -> Result<(), Error> {
+    if size > max_message_size {
+        Err(Error::MessageTooLarge {
+            size: size,
+            max: max_message_size
+        })
+
+    } else {
+        Ok(())
+    }
+}
+
+// The slices of a single write live on the stack
+struct WriteSlices<'a> {
+    slices: [IoSlice<'a>; MAX_WRITE_SLICES],
+    len: usize
+}
+
+impl<'a> WriteSlices<'a> {
+
+    fn new() -> Self {
+        Self {
+            slices: [IoSlice::new(&[]); MAX_WRITE_SLICES],
+            len: 0
+        }
+    }
+
+    fn push(&mut self, slice: IoSlice<'a>) {
+        self.slices[self.len] = slice;
+        self.len += 1;
+    }
+
+}
+
+impl<'a> ops::Deref for WriteSlices<'a> {
+    type Target = [IoSlice<'a>];
+    fn deref(&self) -> &Self::Target {
+        &self.slices[..self.len]
+    }
+}
+
+// Header and payload are kept apart so the encoded payload never has to be
+// copied before it is written
+struct Frame {
+    header: [u8; FRAME_HEADER_SIZE + MAX_FRAME_OVERHEAD],
+    header_len: usize,
+    payload: Vec<u8>,
+    ping: Option<u32>
+}
+
+impl Frame {
+
+    fn new(prefix: u8, extra: &[u8], payload: Vec<u8>) -> Self {
+        let mut header = [0; FRAME_HEADER_SIZE + MAX_FRAME_OVERHEAD];
+        let length = (1 + extra.len() + payload.len()) as u32;
+        header[..FRAME_HEADER_SIZE].copy_from_slice(&length.to_le_bytes());
+        header[FRAME_HEADER_SIZE] = prefix;
+        header[FRAME_HEADER_SIZE + 1..FRAME_HEADER_SIZE + 1 + extra.len()].copy_from_slice(extra);
+        Self {
+            header: header,
+            header_len: FRAME_HEADER_SIZE + 1 + extra.len(),
+            payload: payload,
+            ping: None
+        }
+    }
+
+    // The compressed body replaces the prefix and payload of the original frame
+    fn compressed(body: Vec<u8>) -> Self {
+        let mut header = [0; FRAME_HEADER_SIZE + MAX_FRAME_OVERHEAD];
+        let length = body.len() as u32 | COMPRESSED_FLAG;
+        header[..FRAME_HEADER_SIZE].copy_from_slice(&length.to_le_bytes());
+        Self {
+            header: header,
+            header_len: FRAME_HEADER_SIZE,
+            payload: body,
+            ping: None
+        }
+    }
+
+    // Channel 0 uses the plain message prefix
+    fn channel(channel: u8, payload: Vec<u8>) -> Self {
+        if channel == 0 {
+            Frame::new(1, &[], payload)
+
+        } else {
+            Frame::new(4, &[channel], payload)
+        }
+    }
+
+    // Only the length and prefix stay readable, any identifiers of the header
+    // move in front of the payload so they get encrypted along with it
+    fn seal(&mut self, cipher: &mut Cipher) {
+        let plain = if is_compressed(&self.header) { FRAME_HEADER_SIZE } else { FRAME_HEADER_SIZE + 1 };
+        if self.header_len > plain {
+            self.payload.splice(0..0, self.header[plain..self.header_len].iter().cloned());
+            self.header_len = plain;
+        }
+        let length = u32::from_le_bytes([self.header[0], self.header[1], self.header[2], self.header[3]]) + TAG_SIZE as u32;
+        self.header[..FRAME_HEADER_SIZE].copy_from_slice(&length.to_le_bytes());
+        cipher.seal(&self.header[..self.header_len], &mut self.payload);
+    }
+
+    fn header(&self) -> &[u8] {
+        &self.header[..self.header_len]
+    }
+
+    fn len(&self) -> usize {
+        self.header_len + self.payload.len()
+    }
+
+}
+
+pub fn frame_length(bytes: &[u8]) -> Option<usize> {
+    if bytes.len() < FRAME_HEADER_SIZE {
+        None
+
+    } else {
+        Some((u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) & !COMPRESSED_FLAG) as usize)
+    }
+}
+
+fn is_compressed(bytes: &[u8]) -> bool {
+    bytes.len() >= FRAME_HEADER_SIZE && bytes[FRAME_HEADER_SIZE - 1] & 0x80 != 0
+}
+
+// Turns the channel and messages of a batch into the frames they would have
+// been sent as on their own, None for batches which do not add up
+fn unbatch(bytes: &[u8]) -> Option<Vec<u8>> {
+
+    if bytes.len() < 1 + BATCH_OVERHEAD {
+        return None;
+    }
+
+    let channel = bytes[0];
+    let count = u32::from_le_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]) as usize;
+    if count > bytes.len() / BATCH_ITEM_OVERHEAD {
+        return None;
+    }
+
+    let mut frames = Vec::with_capacity(bytes.len() + count * FRAME_HEADER_SIZE);
+    let mut index = 1 + BATCH_OVERHEAD;
+    for _ in 0..count {
+
+        if bytes.len() - index < BATCH_ITEM_OVERHEAD {
+            return None;
+        }
+
+        let length = u32::from_le_bytes([bytes[index], bytes[index + 1], bytes[index + 2], bytes[index + 3]]) as usize;
+        index += BATCH_ITEM_OVERHEAD;
+        if bytes.len() - index < length {
+            return None;
+        }
+
+        let frame = Frame::channel(channel, Vec::new());
+        let header = frame.header();
+        frames.extend_from_slice(&((header.len() - FRAME_HEADER_SIZE + length) as u32).to_le_bytes());
+        frames.extend_from_slice(&header[FRAME_HEADER_SIZE..]);
+        frames.extend_from_slice(&bytes[index..index + length]);
+        index += length;
+
+    }
+
+    if index == bytes.len() {
+        Some(frames)
+
+    } else {
+        None
+    }
+
+}
+
+fn salt(frame: &[u8]) -> Option<&[u8]> {
+    if frame.len() == FRAME_HEADER_SIZE + 1 + SALT_SIZE && !is_compressed(frame) && frame[FRAME_HEADER_SIZE] == SALT_PREFIX {
+        Some(&frame[FRAME_HEADER_SIZE + 1..])
+
+    } else {
+        None
+    }
+}
+
+// Opens the complete frame at the index in place and removes its tag, returns
+// where the frame ends afterwards
+fn open_frame(cipher: &mut Cipher, buffer: &mut Vec<u8>, index: usize, end: usize) -> Option<usize> {
+    let plain = if is_compressed(&buffer[index..]) { FRAME_HEADER_SIZE } else { FRAME_HEADER_SIZE + 1 };
+    if end - index < plain + TAG_SIZE {
+        return None;
+    }
+
+    let (header, rest) = buffer[index..end].split_at_mut(plain);
+    let (bytes, tag) = rest.split_at_mut(rest.len() - TAG_SIZE);
+    if !cipher.open(header, bytes, tag) {
+        return None;
+    }
+
+    let length = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) - TAG_SIZE as u32;
+    header[..FRAME_HEADER_SIZE].copy_from_slice(&length.to_le_bytes());
+    buffer.drain(end - TAG_SIZE..end);
+    Some(end - TAG_SIZE)
+}
+
+
+// Internal Factory -----------------------------------------------------------
+pub fn create_message_iterator<'a, M: Serialize + DeserializeOwned, I: Serialize + DeserializeOwned, E: Codec>(
+    incoming: &'a mut Incoming<I>,
+    filter: Option<MessageFilter<'a, M>>
+
+) -> MessageIterator<'a, M, I, E> {
+    MessageIterator {
+        incoming: incoming,
+        filter: filter,
+        message: PhantomData,
+        codec: PhantomData
+    }
+}
+
+pub fn create_channel_iterator<'a, M: Serialize + DeserializeOwned, I: Serialize + DeserializeOwned, E: Codec>(
+    incoming: &'a mut Incoming<I>,
+    filter: Option<MessageFilter<'a, M>>
+
+) -> ChannelIterator<'a, M, I, E> {
+    ChannelIterator {
+        incoming: incoming,
+        filter: filter,
+        message: PhantomData,
+        codec: PhantomData
+    }
+}
+
+pub fn create_stamped_iterator<'a, M: Serialize + DeserializeOwned, I: Serialize + DeserializeOwned, E: Codec>(
+    incoming: &'a mut Incoming<I>,
+    filter: Option<MessageFilter<'a, M>>,
+    clock: Option<PeerClock>
+
+) -> StampedIterator<'a, M, I, E> {
+    StampedIterator {
+        incoming: incoming,
+        filter: filter,
+        clock: clock,
+        message: PhantomData,
+        codec: PhantomData
+    }
+}
+
+pub fn create_borrowed_iterator<'a, M: Deserialize<'a>, I: Serialize + DeserializeOwned, E: BorrowCodec>(
+    incoming: &'a mut Incoming<I>,
+    mut filter: Option<MessageFilter<'_, [u8]>>
+
+) -> BorrowedIterator<'a, M, E> {
+
+    let mut borrowed = mem::take(&mut incoming.borrowed);
+    borrowed.clear();
+    borrowed.append(&mut incoming.unyielded);
+
+    // Acks are advanced by the walk and only restored as messages are handed out
+    let acked = incoming.acked;
+    if let Some(last) = borrowed.last() {
+        incoming.acked = last.acked;
+    }
+
+    let mut payload = |prefix: u8, bytes: &[u8]| -> Result<Option<(u8, Payload)>, DecodeError> {
+        if filter.as_mut().is_some_and(|filter| !filter(bytes)) {
+            Ok(None)
+
+        } else if prefix == 5 {
+            Ok(Some((prefix, Payload::Reassembled(bytes.to_vec()))))
+
+        } else {
+            Ok(Some((prefix, Payload::Frame(bytes.len()))))
+        }
+    };
+
+    while let Some((_, payload)) = next_message::<_, I, E, _>(incoming, &mut payload) {
+        if let Some((prefix, payload)) = payload {
+            borrowed.push(Borrowed {
+                prefix: prefix,
+                end: incoming.consumed,
+                acked: incoming.acked,
+                payload: payload
+            });
+        }
+    }
+
+    // Violations clear the buffer along with everything that was found in it
+    if incoming.violation {
+        borrowed.clear();
+    }
+
+    let walked_ack = incoming.acked;
+    incoming.acked = acked;
+    incoming.borrowed = borrowed;
+    BorrowedIterator {
+        buffer: &incoming.buffer,
+        payloads: incoming.borrowed.iter(),
+        unyielded: &mut incoming.unyielded,
+        acked: &mut incoming.acked,
+        walked_ack: walked_ack,
+        errors: &mut incoming.decode_errors,
+        violation: &mut incoming.violation,
+        strict: incoming.strict,
+        message: PhantomData,
+        codec: PhantomData
+    }
+
+}
+
+pub fn create_raw_iterator<'a, I: Serialize + DeserializeOwned, E: Codec>(
+    incoming: &'a mut Incoming<I>,
+    filter: Option<MessageFilter<'a, Vec<u8>>>
+
+) -> RawIterator<'a, I, E> {
+    RawIterator {
+        incoming: incoming,
+        filter: filter,
+        codec: PhantomData
+    }
+}
+
+
+
+// Tests ----------------------------------------------------------------------
+#[cfg(test)]
+mod test {
+
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use std::io::{Error as IOError, ErrorKind};
+
+    use serde::Serialize;
+    use serde::de::DeserializeOwned;
+
+    use ::codec::{BincodeCodec, CodecError};
+    use ::error::Error;
+    use ::protocol::Connection;
+    use super::{Incoming, InternalMessage, Outgoing, DEFAULT_MAX_MESSAGE_SIZE, create_borrowed_iterator, create_message_iterator, encode};
+
+    // Bincode prefixes byte vectors with their length as a u64
+    const LIMIT: usize = 64;
+    const LENGTH_SIZE: usize = 8;
+
+    fn frame(prefix: u8, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = ((payload.len() + 1) as u32).to_le_bytes().to_vec();
+        bytes.push(prefix);
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Command {
+        Move(u8, u8),
+        Stop
+    }
+
+    fn push(incoming: &mut Incoming<InternalMessage>, bytes: &[u8]) {
+        incoming.buffer_mut().extend_from_slice(bytes);
+        incoming.received(bytes.len(), usize::MAX);
+    }
+
+    fn receive<M: Serialize + DeserializeOwned>(incoming: &mut Incoming<InternalMessage>, bytes: &[u8]) -> Vec<M> {
+        push(incoming, bytes);
+        create_message_iterator::<M, InternalMessage, BincodeCodec>(incoming, None).collect()
+    }
+
+    #[test]
+    fn messages_at_the_limit_are_encoded() {
+        let message = vec![1u8; LIMIT - LENGTH_SIZE];
+        assert_eq!(encode::<BincodeCodec, _>(&message, LIMIT).unwrap().len(), LIMIT);
+    }
+
+    #[test]
+    fn messages_over_the_limit_are_not_encoded() {
+        let message = vec![1u8; LIMIT - LENGTH_SIZE + 1];
+        match encode::<BincodeCodec, _>(&message, LIMIT) {
+            Err(Error::MessageTooLarge { size, max }) => assert_eq!((size, max), (LIMIT + 1, LIMIT)),
+            other => panic!("expected MessageTooLarge, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn messages_at_the_limit_are_received() {
+        let message = vec![1u8; LIMIT - LENGTH_SIZE];
+        let payload = encode::<BincodeCodec, _>(&message, LIMIT).unwrap();
+
+        let mut incoming = Incoming::new(LIMIT);
+        assert_eq!(receive::<Vec<u8>>(&mut incoming, &frame(1, &payload)), vec![message]);
+        assert!(!incoming.violation());
+    }
+
+    #[test]
+    fn messages_over_the_limit_are_a_violation() {
+        let message = vec![1u8; LIMIT - LENGTH_SIZE + 1];
+        let payload = encode::<BincodeCodec, _>(&message, LIMIT + 1).unwrap();
+
+        let mut incoming = Incoming::new(LIMIT);
+        assert!(receive::<Vec<u8>>(&mut incoming, &frame(1, &payload)).is_empty());
+        assert!(incoming.violation());
+        assert_eq!(incoming.pending_bytes(), 0);
+    }
+
+    #[test]
+    fn truncated_frames_are_reported_and_skipped() {
+        let payload = encode::<BincodeCodec, _>(&Command::Move(1, 2), LIMIT).unwrap();
+        let mut bytes = frame(1, &payload[..payload.len() - 1]);
+        bytes.extend(frame(1, &encode::<BincodeCodec, _>(&Command::Stop, LIMIT).unwrap()));
+
+        let mut incoming = Incoming::new(LIMIT);
+        assert_eq!(receive::<Command>(&mut incoming, &bytes), vec![Command::Stop]);
+
+        let errors = incoming.drain_decode_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!((errors[0].prefix, errors[0].length), (1, payload.len() - 1));
+        match errors[0].error {
+            CodecError::Decode(_) => {},
+            ref other => panic!("expected a decode error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn unknown_variants_are_reported_with_their_channel_prefix() {
+        let mut bytes = frame(4, &[3, 9, 0, 0, 0]);
+        bytes.extend(frame(4, &[3, 1, 0, 0, 0]));
+
+        let mut incoming = Incoming::new(LIMIT);
+        assert_eq!(receive::<Command>(&mut incoming, &bytes), vec![Command::Stop]);
+
+        let errors = incoming.drain_decode_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!((errors[0].prefix, errors[0].length), (4, 4));
+        assert!(!incoming.violation());
+    }
+
+    #[test]
+    fn strict_mode_stops_at_the_first_decode_error() {
+        let mut bytes = frame(1, &[9, 0, 0, 0]);
+        bytes.extend(frame(1, &encode::<BincodeCodec, _>(&Command::Stop, LIMIT).unwrap()));
+
+        let mut incoming = Incoming::new(LIMIT);
+        incoming.set_strict(true);
+        assert!(receive::<Command>(&mut incoming, &bytes).is_empty());
+        assert_eq!(incoming.drain_decode_errors().len(), 1);
+        assert!(incoming.violation());
+    }
+
+    #[test]
+    fn borrowed_messages_point_into_the_incoming_buffer() {
+        let chunks: Vec<Vec<u8>> = (0..3).map(|i| vec![i; 32]).collect();
+        let mut bytes = Vec::new();
+        for chunk in &chunks {
+            bytes.extend(frame(1, &encode::<BincodeCodec, _>(chunk, LIMIT).unwrap()));
+        }
+
+        let mut incoming = Incoming::new(LIMIT);
+        push(&mut incoming, &bytes);
+        let buffer = incoming.buffer_mut().as_ptr_range();
+
+        // All of them stay valid until the iterator and its items are gone
+        let borrowed: Vec<&[u8]> = create_borrowed_iterator::<&[u8], InternalMessage, BincodeCodec>(&mut incoming, None).collect();
+        assert_eq!(borrowed, chunks);
+        assert!(borrowed.iter().all(|chunk| buffer.contains(&chunk.as_ptr())));
+    }
+
+    #[test]
+    fn buffer_is_compacted_once_borrowed_messages_are_released() {
+        let first = frame(1, &encode::<BincodeCodec, _>(&vec![1u8; 32], LIMIT).unwrap());
+        let second = frame(1, &encode::<BincodeCodec, _>(&vec![2u8; 32], LIMIT).unwrap());
+
+        // The second frame only arrives in part
+        let mut incoming = Incoming::new(LIMIT);
+        let mut bytes = first.clone();
+        bytes.extend_from_slice(&second[..10]);
+        push(&mut incoming, &bytes);
+        {
+            let borrowed: Vec<&[u8]> = create_borrowed_iterator::<&[u8], InternalMessage, BincodeCodec>(&mut incoming, None).collect();
+            assert_eq!(borrowed, vec![&[1u8; 32][..]]);
+        }
+        assert_eq!(incoming.pending_bytes(), 10);
+
+        // Only the unread tail is kept once more bytes are read
+        assert_eq!(incoming.buffer_mut().as_slice(), &second[..10]);
+        push(&mut incoming, &second[10..]);
+        let borrowed: Vec<&[u8]> = create_borrowed_iterator::<&[u8], InternalMessage, BincodeCodec>(&mut incoming, None).collect();
+        assert_eq!(borrowed, vec![&[2u8; 32][..]]);
+    }
+
+    fn acked_frame(id: u32, message: &[u8]) -> Vec<u8> {
+        let mut payload = id.to_le_bytes().to_vec();
+        payload.extend(encode::<BincodeCodec, _>(&message, LIMIT).unwrap());
+        frame(6, &payload)
+    }
+
+    #[test]
+    fn borrowed_messages_left_over_are_yielded_by_the_next_iterator() {
+        let mut bytes = acked_frame(0, &[1u8; 32]);
+        bytes.extend(acked_frame(1, &[2u8; 32]));
+        bytes.extend(acked_frame(2, &[3u8; 32]));
+
+        let mut incoming = Incoming::new(LIMIT);
+        push(&mut incoming, &bytes);
+        {
+            let mut borrowed = create_borrowed_iterator::<&[u8], InternalMessage, BincodeCodec>(&mut incoming, None);
+            assert_eq!(borrowed.next(), Some(&[1u8; 32][..]));
+        }
+
+        // Only the message which was handed out is acked
+        assert_eq!(incoming.take_ack(), Some(0));
+        assert!(!incoming.is_empty());
+
+        // The others outlive the compaction by the next read
+        push(&mut incoming, &acked_frame(3, &[4u8; 32]));
+        assert_eq!(incoming.buffer_mut().len(), acked_frame(3, &[4u8; 32]).len());
+        let borrowed: Vec<&[u8]> = create_borrowed_iterator::<&[u8], InternalMessage, BincodeCodec>(&mut incoming, None).collect();
+        assert_eq!(borrowed, vec![&[2u8; 32][..], &[3u8; 32][..], &[4u8; 32][..]]);
+        assert_eq!(incoming.take_ack(), Some(3));
+        assert!(incoming.is_empty());
+    }
+
+    // Accepts only a few bytes per write and blocks on every other one
+    struct Window {
+        size: usize,
+        blocked: bool,
+        written: Vec<u8>
+    }
+
+    impl Connection for Window {
+
+        type Addr = SocketAddr;
+        type Config = ();
+
+        fn connect(_: &[SocketAddr], _: Duration, _: &()) -> Result<Self, IOError> {
+            Err(IOError::new(ErrorKind::Unsupported, ""))
+        }
+
+        fn peer_addr(&self) -> Result<SocketAddr, IOError> {
+            Err(IOError::new(ErrorKind::NotConnected, ""))
+        }
+
+        fn local_addr(&self) -> Result<SocketAddr, IOError> {
+            Err(IOError::new(ErrorKind::NotConnected, ""))
+        }
+
+        fn read(&mut self, _: &mut Vec<u8>, _: usize) -> Result<usize, IOError> {
+            Err(IOError::new(ErrorKind::WouldBlock, ""))
+        }
+
+        fn write(&mut self, bytes: &[u8]) -> Result<usize, IOError> {
+            self.blocked = !self.blocked;
+            if self.blocked {
+                Err(IOError::new(ErrorKind::WouldBlock, ""))
+
+            } else {
+                let length = bytes.len().min(self.size);
+                self.written.extend_from_slice(&bytes[..length]);
+                Ok(length)
+            }
+        }
+
+        fn shutdown(&mut self) -> Result<(), IOError> {
+            Ok(())
+        }
+
+    }
+
+    #[test]
+    fn partial_writes_resume_without_loss_or_reordering() {
+
+        // Some are large enough to be split into fragments as well
+        let messages: Vec<Vec<u8>> = (0..64).map(|i| vec![i as u8; if i % 16 == 0 { 300 } else { i }]).collect();
+
+        let mut outgoing = Outgoing::new();
+        outgoing.set_fragment_size(128);
+        let mut connection = Window {
+            size: 7,
+            blocked: false,
+            written: Vec::new()
+        };
+
+        // Messages keep being queued in between the partial writes
+        let mut ticks = 0;
+        for message in &messages {
+            outgoing.push_channel_frame(0, false, encode::<BincodeCodec, _>(message, DEFAULT_MAX_MESSAGE_SIZE).unwrap());
+            match outgoing.write_to(&mut connection, usize::MAX) {
+                Ok(bytes) => assert!(bytes <= 7),
+                Err(err) => assert_eq!(err.kind(), ErrorKind::WouldBlock)
+            }
+        }
+        while !outgoing.is_empty() {
+            outgoing.write_to(&mut connection, usize::MAX).ok();
+            ticks += 1;
+        }
+        assert!(ticks > 100);
+
+        let mut incoming = Incoming::new(DEFAULT_MAX_MESSAGE_SIZE);
+        assert_eq!(receive::<Vec<u8>>(&mut incoming, &connection.written), messages);
+        assert_eq!(incoming.pending_bytes(), 0);
+
+    }
+
+}
@@ -8,36 +8,156 @@
 
 
 // STD Dependencies -----------------------------------------------------------
-use std::time::Duration;
+use std::cmp;
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
 use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::io::{Error as IOError, ErrorKind};
-use std::net::{SocketAddr, Shutdown, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::collections::HashMap;
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::io::{Error as IOError, ErrorKind, IoSlice};
+use std::net::{IpAddr, SocketAddr, Shutdown, ToSocketAddrs};
+#[cfg(unix)]
+use std::fs;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(all(feature = "poll", unix))]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+
+
+// External Dependencies ------------------------------------------------------
+use socket2::{Domain, Protocol as SocketProtocol, SockRef, Socket, TcpKeepalive, Type};
+#[cfg(feature = "poll")]
+use mio::{Registry, Token};
+#[cfg(all(feature = "poll", unix))]
+use mio::Interest;
+#[cfg(all(feature = "poll", unix))]
+use mio::unix::SourceFd;
+
+
+// Internal Dependencies ------------------------------------------------------
+use ::message::{frame_length, FRAME_HEADER_SIZE};
+
+
+// Address Abstraction --------------------------------------------------------
+pub trait Address: Clone + fmt::Debug + Send {
+    // Addresses without an IP cannot be banned
+    fn ip(&self) -> Option<IpAddr>;
+    // Addresses without a port cannot be discovered
+    fn port(&self) -> Option<u16>;
+}
+
+impl Address for SocketAddr {
+    fn ip(&self) -> Option<IpAddr> {
+        Some(SocketAddr::ip(self))
+    }
+    fn port(&self) -> Option<u16> {
+        Some(SocketAddr::port(self))
+    }
+}
+
+#[cfg(unix)]
+impl Address for PathBuf {
+    fn ip(&self) -> Option<IpAddr> {
+        None
+    }
+    fn port(&self) -> Option<u16> {
+        None
+    }
+}
+
+// Resolves the user supplied target of bind() and connect() into addresses
+pub trait ToAddrs<T> {
+    fn to_addrs(&self) -> Result<Vec<T>, IOError>;
+}
+
+impl<A: ToSocketAddrs + ?Sized> ToAddrs<SocketAddr> for A {
+    fn to_addrs(&self) -> Result<Vec<SocketAddr>, IOError> {
+        Ok(self.to_socket_addrs()?.collect())
+    }
+}
+
+#[cfg(unix)]
+impl<P: AsRef<Path> + ?Sized> ToAddrs<PathBuf> for P {
+    fn to_addrs(&self) -> Result<Vec<PathBuf>, IOError> {
+        Ok(vec![self.as_ref().to_path_buf()])
+    }
+}
+
+pub type Addr<P> = <<P as Protocol>::Connection as Connection>::Addr;
+pub type Config<P> = <<P as Protocol>::Connection as Connection>::Config;
 
 
 // Connection Abstraction -----------------------------------------------------
+// Hosts and connections must be Send, so servers and clients can be moved to
+// the thread which runs their tick loop
 pub trait Protocol {
     type Host: Host<Connection = Self::Connection>;
     type Connection: Connection;
 }
 
-pub trait Host {
+pub trait Host: Send {
     type Connection: Connection;
-    fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self, IOError> where Self: Sized;
+    fn bind(addrs: &[<Self::Connection as Connection>::Addr], config: &<Self::Connection as Connection>::Config) -> Result<Self, IOError> where Self: Sized;
     fn accept(&mut self) -> Result<Self::Connection, IOError> where Self: Sized;
+    fn local_addr(&self) -> Result<<Self::Connection as Connection>::Addr, IOError> where Self: Sized;
+
+    // Lets the server wake up as soon as there is something to accept
+    #[cfg(feature = "poll")]
+    fn register(&mut self, _: &Registry, _: Token) -> Result<(), IOError> where Self: Sized {
+        Ok(())
+    }
+
     fn shutdown(self) -> Result<(), IOError> where Self: Sized;
 }
 
-pub trait Connection {
-    fn connect<A: ToSocketAddrs>(addr: A, timeout: Duration) -> Result<Self, IOError> where Self: Sized;
-    fn peer_addr(&self) -> Result<SocketAddr, IOError> where Self: Sized;
-    fn read(&mut self, &mut Vec<u8>) -> Result<usize, IOError> where Self: Sized;
-    fn write(&mut self, &[u8]) -> Result<usize, IOError> where Self: Sized;
+pub trait Connection: Send {
+    type Addr: Address;
+
+    // Protocol specific socket options, the defaults must not change any
+    type Config: Default + Clone + Send;
+
+    fn connect(addrs: &[Self::Addr], timeout: Duration, config: &Self::Config) -> Result<Self, IOError> where Self: Sized;
+
+    // Protocols which cannot connect in the background simply block in connect_start()
+    fn connect_start(addrs: &[Self::Addr], timeout: Duration, config: &Self::Config) -> Result<Self, IOError> where Self: Sized {
+        Self::connect(addrs, timeout, config)
+    }
+
+    fn connect_poll(&mut self) -> Result<bool, IOError> where Self: Sized {
+        Ok(true)
+    }
+
+    fn peer_addr(&self) -> Result<Self::Addr, IOError> where Self: Sized;
+    fn local_addr(&self) -> Result<Self::Addr, IOError> where Self: Sized;
+    // Appends at most about max_bytes to the buffer, leaving the rest unread
+    fn read(&mut self, buffer: &mut Vec<u8>, max_bytes: usize) -> Result<usize, IOError> where Self: Sized;
+    fn write(&mut self, bytes: &[u8]) -> Result<usize, IOError> where Self: Sized;
+
+    // Falls back to a single write() of all slices copied into one buffer
+    fn write_vectored(&mut self, slices: &[IoSlice]) -> Result<usize, IOError> where Self: Sized {
+        let bytes: Vec<u8> = slices.iter().flat_map(|slice| slice.iter().cloned()).collect();
+        self.write(&bytes)
+    }
+
+    #[cfg(feature = "poll")]
+    fn register(&mut self, _: &Registry, _: Token) -> Result<(), IOError> where Self: Sized {
+        Ok(())
+    }
+
     fn shutdown(&mut self) -> Result<(), IOError> where Self: Sized;
 }
 
 
 // TPC Protocol ---------------------------------------------------------------
+const DEFAULT_LISTEN_BACKLOG: i32 = 128;
+
 pub struct TCP;
 impl Protocol for TCP {
     type Host = TcpHost;
@@ -45,30 +165,118 @@ impl Protocol for TCP {
 }
 
 
+#[derive(Debug, Clone)]
+pub struct TcpConfig {
+    pub nodelay: bool,
+    // Idle time before the first probe as well as the time between probes
+    pub keepalive: Option<Duration>,
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+    pub ttl: Option<u32>,
+    // Only apply to listeners, the backlog being the number of connections
+    // the OS keeps waiting until they are accepted; addresses are always
+    // reused on unix, just like with TcpListener::bind()
+    pub reuse_address: bool,
+    pub backlog: Option<i32>
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: None,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            ttl: None,
+            reuse_address: false,
+            backlog: None
+        }
+    }
+}
+
+pub fn configure_stream(stream: &TcpStream, config: &TcpConfig) -> Result<(), IOError> {
+    stream.set_nodelay(config.nodelay)?;
+    if let Some(ttl) = config.ttl {
+        stream.set_ttl(ttl)?;
+    }
+
+    let socket = SockRef::from(stream);
+    if let Some(interval) = config.keepalive {
+        socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(interval).with_interval(interval))?;
+    }
+    if let Some(size) = config.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = config.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    Ok(())
+}
+
+fn bind_listener(addr: &SocketAddr, config: &TcpConfig) -> Result<TcpListener, IOError> {
+    if config.reuse_address || config.backlog.is_some() {
+        let socket = Socket::new(Domain::for_address(*addr), Type::STREAM, Some(SocketProtocol::TCP))?;
+        socket.set_reuse_address(config.reuse_address || cfg!(unix))?;
+        socket.bind(&(*addr).into())?;
+        socket.listen(config.backlog.unwrap_or(DEFAULT_LISTEN_BACKLOG))?;
+        Ok(socket.into())
+
+    } else {
+        TcpListener::bind(addr)
+    }
+}
+
 pub struct TcpHost {
-    listener: TcpListener
+    listener: TcpListener,
+    config: TcpConfig
+}
+
+impl TcpHost {
+    // Used by protocols which wrap the raw stream themselves
+    pub fn accept_stream(&mut self) -> Result<(TcpStream, SocketAddr), IOError> {
+        let (stream, addr) = self.listener.accept()?;
+        configure_stream(&stream, &self.config)?;
+        stream.set_nonblocking(true)?;
+        Ok((stream, addr))
+    }
 }
 
 impl Host for TcpHost {
 
     type Connection = TcpConnection;
 
-    fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self, IOError> where Self: Sized {
-        let listener = TcpListener::bind(addr)?;
+    fn bind(addrs: &[SocketAddr], config: &TcpConfig) -> Result<Self, IOError> where Self: Sized {
+
+        // Try every address in turn just like TcpListener::bind() does
+        let mut result = Err(IOError::new(ErrorKind::AddrNotAvailable, ""));
+        for addr in addrs {
+            result = bind_listener(addr, config);
+            if result.is_ok() {
+                break;
+            }
+        }
+
+        let listener = result?;
         listener.set_nonblocking(true)?;
         Ok(Self {
-            listener: listener
+            listener: listener,
+            config: config.clone()
         })
+
     }
 
     fn accept(&mut self) -> Result<TcpConnection, IOError> where Self: Sized {
-        let (stream, addr) = self.listener.accept()?;
-        stream.set_nodelay(true)?;
-        stream.set_nonblocking(true)?;
-        Ok(TcpConnection {
-            stream: stream,
-            peer_addr: Some(addr)
-        })
+        let (stream, addr) = self.accept_stream()?;
+        Ok(TcpConnection::from_stream(stream, addr))
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, IOError> where Self: Sized {
+        self.listener.local_addr()
+    }
+
+    #[cfg(all(feature = "poll", unix))]
+    fn register(&mut self, registry: &Registry, token: Token) -> Result<(), IOError> where Self: Sized {
+        registry.register(&mut SourceFd(&self.listener.as_raw_fd()), token, Interest::READABLE)
     }
 
     fn shutdown(self) -> Result<(), IOError> where Self: Sized {
@@ -78,20 +286,84 @@ impl Host for TcpHost {
 }
 
 pub struct TcpConnection {
-    stream: TcpStream,
-    peer_addr: Option<SocketAddr>
+    stream: Option<TcpStream>,
+    connecting: Option<Receiver<Result<TcpStream, IOError>>>,
+    peer_addr: Option<SocketAddr>,
+    error: Option<IOError>
+}
+
+impl TcpConnection {
+
+    // Expects an already configured, non-blocking stream
+    fn from_stream(stream: TcpStream, addr: SocketAddr) -> Self {
+        Self {
+            stream: Some(stream),
+            connecting: None,
+            peer_addr: Some(addr),
+            error: None
+        }
+    }
+
+    fn stream(&mut self) -> Result<&mut TcpStream, IOError> {
+        self.stream.as_mut().ok_or_else(|| IOError::new(ErrorKind::NotConnected, ""))
+    }
+
+    // Gives up the descriptor without closing it, None while still connecting
+    #[cfg(unix)]
+    pub fn into_raw_fd(mut self) -> Option<RawFd> {
+        self.stream.take().map(IntoRawFd::into_raw_fd)
+    }
+
+    // Safety: the descriptor must be a connected TCP socket which is owned by
+    // nothing else, it is closed once the connection is dropped
+    #[cfg(unix)]
+    #[allow(clippy::missing_safety_doc)]
+    pub unsafe fn from_raw_fd(fd: RawFd) -> Result<Self, IOError> {
+        let stream = TcpStream::from_raw_fd(fd);
+        stream.set_nonblocking(true)?;
+        let addr = stream.peer_addr()?;
+        Ok(TcpConnection::from_stream(stream, addr))
+    }
+
 }
 
 impl Connection for TcpConnection {
 
-    fn connect<A: ToSocketAddrs>(addr: A, timeout: Duration) -> Result<Self, IOError> where Self: Sized {
-        if let Some(addr) = addr.to_socket_addrs()?.next() {
+    type Addr = SocketAddr;
+    type Config = TcpConfig;
+
+    fn connect(addrs: &[SocketAddr], timeout: Duration, config: &TcpConfig) -> Result<Self, IOError> where Self: Sized {
+        if let Some(&addr) = addrs.first() {
             let stream = TcpStream::connect_timeout(&addr, timeout)?;
-            stream.set_nodelay(true)?;
+            configure_stream(&stream, config)?;
             stream.set_nonblocking(true)?;
+            Ok(TcpConnection::from_stream(stream, addr))
+
+        } else {
+            Err(IOError::new(ErrorKind::AddrNotAvailable, ""))
+        }
+    }
+
+    fn connect_start(addrs: &[SocketAddr], timeout: Duration, config: &TcpConfig) -> Result<Self, IOError> where Self: Sized {
+        if let Some(&addr) = addrs.first() {
+
+            // The standard library offers no non-blocking connect, so wait for it on a
+            // separate thread instead
+            let (sender, receiver) = mpsc::channel();
+            let config = config.clone();
+            thread::spawn(move || {
+                let result = TcpStream::connect_timeout(&addr, timeout).and_then(|stream| {
+                    configure_stream(&stream, &config)?;
+                    Ok(stream)
+                });
+                sender.send(result).ok();
+            });
+
             Ok(Self {
-                stream: stream,
-                peer_addr: Some(addr)
+                stream: None,
+                connecting: Some(receiver),
+                peer_addr: Some(addr),
+                error: None
             })
 
         } else {
@@ -99,6 +371,25 @@ impl Connection for TcpConnection {
         }
     }
 
+    fn connect_poll(&mut self) -> Result<bool, IOError> where Self: Sized {
+        let result = if let Some(receiver) = self.connecting.as_ref() {
+            match receiver.try_recv() {
+                Ok(result) => result,
+                Err(TryRecvError::Empty) => return Ok(false),
+                Err(TryRecvError::Disconnected) => Err(IOError::other(""))
+            }
+
+        } else {
+            return Ok(true);
+        };
+
+        self.connecting = None;
+        let stream = result?;
+        stream.set_nonblocking(true)?;
+        self.stream = Some(stream);
+        Ok(true)
+    }
+
     fn peer_addr(&self) -> Result<SocketAddr, IOError> where Self: Sized{
         if let Some(addr) = self.peer_addr {
             Ok(addr)
@@ -108,30 +399,691 @@ impl Connection for TcpConnection {
         }
     }
 
-    fn read(&mut self, buffer: &mut Vec<u8>) -> Result<usize, IOError> where Self: Sized {
-        if let Ok(bytes) = self.stream.read_to_end(buffer) {
-            if bytes == 0 {
-                self.stream.shutdown(Shutdown::Both).ok();
-                Err(IOError::new(ErrorKind::ConnectionReset, ""))
+    fn local_addr(&self) -> Result<SocketAddr, IOError> where Self: Sized {
+        if let Some(stream) = self.stream.as_ref() {
+            stream.local_addr()
 
-            } else {
-                Ok(bytes)
+        } else {
+            Err(IOError::new(ErrorKind::NotConnected, ""))
+        }
+    }
+
+    fn read(&mut self, buffer: &mut Vec<u8>, max_bytes: usize) -> Result<usize, IOError> where Self: Sized {
+        let stream = self.stream.as_mut().ok_or_else(|| IOError::new(ErrorKind::NotConnected, ""))?;
+        let result = read_stream(stream, buffer, max_bytes, &mut self.error);
+        if result.is_err() {
+            stream.shutdown(Shutdown::Both).ok();
+        }
+        result
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<usize, IOError> where Self: Sized {
+        write_stream(self.stream()?, &[IoSlice::new(bytes)])
+    }
+
+    fn write_vectored(&mut self, slices: &[IoSlice]) -> Result<usize, IOError> where Self: Sized {
+        write_stream(self.stream()?, slices)
+    }
+
+    #[cfg(all(feature = "poll", unix))]
+    fn register(&mut self, registry: &Registry, token: Token) -> Result<(), IOError> where Self: Sized {
+        if let Some(stream) = self.stream.as_ref() {
+            registry.register(&mut SourceFd(&stream.as_raw_fd()), token, Interest::READABLE)
+
+        } else {
+            Ok(())
+        }
+    }
+
+    fn shutdown(&mut self) -> Result<(), IOError> where Self: Sized {
+        // Connections still in progress are simply abandoned
+        self.connecting = None;
+        if let Some(stream) = self.stream.as_ref() {
+            stream.shutdown(Shutdown::Both)
+
+        } else {
+            Ok(())
+        }
+    }
+
+}
+
+
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+
+// Reads until the stream would block, the end of the stream is reported as
+// UnexpectedEof and errors keep their kind; both are held back until
+// everything received before them was handed out
+fn read_stream<S: Read>(stream: &mut S, buffer: &mut Vec<u8>, max_bytes: usize, error: &mut Option<IOError>) -> Result<usize, IOError> {
+    if let Some(err) = error.take() {
+        return Err(err);
+    }
+    let mut chunk = [0; READ_CHUNK_SIZE];
+    let mut received = 0;
+    while received < max_bytes {
+        let size = cmp::min(READ_CHUNK_SIZE, max_bytes - received);
+        let err = match stream.read(&mut chunk[..size]) {
+            Ok(0) => IOError::new(ErrorKind::UnexpectedEof, "closed by the peer"),
+            Ok(n) => {
+                buffer.extend_from_slice(&chunk[..n]);
+                received += n;
+                continue;
+            },
+            Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+            Err(err) => err
+        };
+        if received == 0 {
+            return Err(err);
+        }
+        *error = Some(err);
+        break;
+    }
+    Ok(received)
+}
+
+// The slices are not copied, so the rest of a partially written slice is
+// written on its own before going on with the others
+fn write_stream<S: Write>(stream: &mut S, slices: &[IoSlice]) -> Result<usize, IOError> {
+    let (mut index, mut offset) = (0, 0);
+    let mut written = 0;
+    while index < slices.len() {
+        let result = if offset > 0 {
+            stream.write(&slices[index][offset..])
+
+        } else {
+            stream.write_vectored(&slices[index..])
+        };
+        match result {
+            Ok(0) => return Err(IOError::new(ErrorKind::WriteZero, "")),
+            Ok(mut n) => {
+                written += n;
+                while index < slices.len() && n >= slices[index].len() - offset {
+                    n -= slices[index].len() - offset;
+                    index += 1;
+                    offset = 0;
+                }
+                offset += n;
+            },
+            Err(ref err) if err.kind() == ErrorKind::Interrupted => {},
+            // The send buffer is full, try again later
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+            Err(err) => return Err(err)
+        }
+    }
+    Ok(written)
+}
+
+
+// Unix Domain Socket Protocol ------------------------------------------------
+#[cfg(unix)]
+pub struct Unix;
+
+#[cfg(unix)]
+impl Protocol for Unix {
+    type Host = UnixHost;
+    type Connection = UnixConnection;
+}
+
+#[cfg(unix)]
+pub struct UnixHost {
+    listener: UnixListener,
+    path: PathBuf
+}
+
+#[cfg(unix)]
+impl Host for UnixHost {
+
+    type Connection = UnixConnection;
+
+    fn bind(addrs: &[PathBuf], _: &()) -> Result<Self, IOError> where Self: Sized {
+        if let Some(path) = addrs.first() {
+            let listener = UnixListener::bind(path)?;
+            listener.set_nonblocking(true)?;
+            Ok(Self {
+                listener: listener,
+                path: path.clone()
+            })
+
+        } else {
+            Err(IOError::new(ErrorKind::AddrNotAvailable, ""))
+        }
+    }
+
+    fn accept(&mut self) -> Result<UnixConnection, IOError> where Self: Sized {
+        // Clients are usually unnamed, so they are identified by the path they connected to
+        let (stream, _) = self.listener.accept()?;
+        UnixConnection::from_stream(stream, self.path.clone())
+    }
+
+    fn local_addr(&self) -> Result<PathBuf, IOError> where Self: Sized {
+        Ok(self.path.clone())
+    }
+
+    #[cfg(all(feature = "poll", unix))]
+    fn register(&mut self, registry: &Registry, token: Token) -> Result<(), IOError> where Self: Sized {
+        registry.register(&mut SourceFd(&self.listener.as_raw_fd()), token, Interest::READABLE)
+    }
+
+    fn shutdown(self) -> Result<(), IOError> where Self: Sized {
+        // Otherwise the next bind() to the same path fails
+        fs::remove_file(&self.path)
+    }
+
+}
+
+#[cfg(unix)]
+pub struct UnixConnection {
+    stream: UnixStream,
+    peer_addr: PathBuf,
+    error: Option<IOError>
+}
+
+#[cfg(unix)]
+impl UnixConnection {
+    fn from_stream(stream: UnixStream, peer_addr: PathBuf) -> Result<Self, IOError> {
+        stream.set_nonblocking(true)?;
+        Ok(Self {
+            stream: stream,
+            peer_addr: peer_addr,
+            error: None
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixConnection {
+
+    type Addr = PathBuf;
+    type Config = ();
+
+    fn connect(addrs: &[PathBuf], _: Duration, _: &()) -> Result<Self, IOError> where Self: Sized {
+        if let Some(path) = addrs.first() {
+            UnixConnection::from_stream(UnixStream::connect(path)?, path.clone())
+
+        } else {
+            Err(IOError::new(ErrorKind::AddrNotAvailable, ""))
+        }
+    }
+
+    fn peer_addr(&self) -> Result<PathBuf, IOError> where Self: Sized {
+        Ok(self.peer_addr.clone())
+    }
+
+    fn local_addr(&self) -> Result<PathBuf, IOError> where Self: Sized {
+        if let Some(path) = self.stream.local_addr()?.as_pathname() {
+            Ok(path.to_path_buf())
+
+        } else {
+            Err(IOError::new(ErrorKind::AddrNotAvailable, ""))
+        }
+    }
+
+    fn read(&mut self, buffer: &mut Vec<u8>, max_bytes: usize) -> Result<usize, IOError> where Self: Sized {
+        let result = read_stream(&mut self.stream, buffer, max_bytes, &mut self.error);
+        if result.is_err() {
+            self.stream.shutdown(Shutdown::Both).ok();
+        }
+        result
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<usize, IOError> where Self: Sized {
+        write_stream(&mut self.stream, &[IoSlice::new(bytes)])
+    }
+
+    fn write_vectored(&mut self, slices: &[IoSlice]) -> Result<usize, IOError> where Self: Sized {
+        write_stream(&mut self.stream, slices)
+    }
+
+    #[cfg(all(feature = "poll", unix))]
+    fn register(&mut self, registry: &Registry, token: Token) -> Result<(), IOError> where Self: Sized {
+        registry.register(&mut SourceFd(&self.stream.as_raw_fd()), token, Interest::READABLE)
+    }
+
+    fn shutdown(&mut self) -> Result<(), IOError> where Self: Sized {
+        self.stream.shutdown(Shutdown::Both)
+    }
+
+}
+
+
+// UDP Protocol ---------------------------------------------------------------
+const UDP_MAX_DATAGRAM_SIZE: usize = 65_507;
+const DEFAULT_UDP_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct UDP;
+impl Protocol for UDP {
+    type Host = UdpHost;
+    type Connection = UdpConnection;
+}
+
+#[derive(Debug, Clone)]
+pub struct UdpConfig {
+    // Silence after which a connection is considered closed, since there is
+    // no FIN to signal it
+    pub timeout: Duration
+}
+
+impl Default for UdpConfig {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_UDP_TIMEOUT
+        }
+    }
+}
+
+struct UdpSocketState {
+    socket: UdpSocket,
+    accepting: bool,
+    incoming: HashMap<SocketAddr, Vec<u8>>,
+    pending: Vec<SocketAddr>
+}
+
+impl UdpSocketState {
+
+    fn new(socket: UdpSocket, accepting: bool) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Self {
+            socket: socket,
+            accepting: accepting,
+            incoming: HashMap::new(),
+            pending: Vec::new()
+        }))
+    }
+
+    fn receive(&mut self) {
+        let mut buffer = [0u8; UDP_MAX_DATAGRAM_SIZE];
+        while let Ok((bytes, addr)) = self.socket.recv_from(&mut buffer) {
+
+            // Only complete frames are kept, so a lost or reordered datagram
+            // never leaves the stream of a connection in the middle of a frame
+            let frames = &buffer[..complete_frames(&buffer[..bytes])];
+
+            // Route datagrams to their connection
+            if let Some(incoming) = self.incoming.get_mut(&addr) {
+                incoming.extend_from_slice(frames);
+
+            // Synthesize a new connection for unknown senders
+            } else if self.accepting {
+                self.incoming.insert(addr, frames.to_vec());
+                self.pending.push(addr);
             }
 
+        }
+    }
+
+}
+
+// Length of the complete frames at the start of the bytes
+fn complete_frames(bytes: &[u8]) -> usize {
+    let mut end = 0;
+    while let Some(length) = frame_length(&bytes[end..]) {
+        if bytes.len() - end - FRAME_HEADER_SIZE < length {
+            break;
+        }
+        end += FRAME_HEADER_SIZE + length;
+    }
+    end
+}
+
+fn lock(state: &Arc<Mutex<UdpSocketState>>) -> Result<MutexGuard<'_, UdpSocketState>, IOError> {
+    state.lock().map_err(|_| IOError::other(""))
+}
+
+pub struct UdpHost {
+    state: Arc<Mutex<UdpSocketState>>,
+    config: UdpConfig
+}
+
+impl Host for UdpHost {
+
+    type Connection = UdpConnection;
+
+    fn bind(addrs: &[SocketAddr], config: &UdpConfig) -> Result<Self, IOError> where Self: Sized {
+        let socket = UdpSocket::bind(addrs)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            state: UdpSocketState::new(socket, true),
+            config: config.clone()
+        })
+    }
+
+    fn accept(&mut self) -> Result<UdpConnection, IOError> where Self: Sized {
+        let mut state = lock(&self.state)?;
+        state.receive();
+        if state.pending.is_empty() {
+            Err(IOError::new(ErrorKind::WouldBlock, ""))
+
+        } else {
+            let addr = state.pending.remove(0);
+            Ok(UdpConnection::new(self.state.clone(), addr, self.config.timeout))
+        }
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, IOError> where Self: Sized {
+        lock(&self.state)?.socket.local_addr()
+    }
+
+    // Datagrams for all connections arrive on this socket, so they need no registration of their own
+    #[cfg(all(feature = "poll", unix))]
+    fn register(&mut self, registry: &Registry, token: Token) -> Result<(), IOError> where Self: Sized {
+        registry.register(&mut SourceFd(&lock(&self.state)?.socket.as_raw_fd()), token, Interest::READABLE)
+    }
+
+    fn shutdown(self) -> Result<(), IOError> where Self: Sized {
+        Ok(())
+    }
+
+}
+
+pub struct UdpConnection {
+    state: Arc<Mutex<UdpSocketState>>,
+    peer_addr: SocketAddr,
+    timeout: Duration,
+    last_receive: Instant,
+    closed: bool
+}
+
+impl UdpConnection {
+    fn new(state: Arc<Mutex<UdpSocketState>>, peer_addr: SocketAddr, timeout: Duration) -> Self {
+        Self {
+            state: state,
+            peer_addr: peer_addr,
+            timeout: timeout,
+            last_receive: Instant::now(),
+            closed: false
+        }
+    }
+}
+
+impl Connection for UdpConnection {
+
+    type Addr = SocketAddr;
+    type Config = UdpConfig;
+
+    fn connect(addrs: &[SocketAddr], _: Duration, config: &UdpConfig) -> Result<Self, IOError> where Self: Sized {
+        if let Some(&addr) = addrs.first() {
+            let socket = if addr.is_ipv4() {
+                UdpSocket::bind("0.0.0.0:0")?
+
+            } else {
+                UdpSocket::bind("[::]:0")?
+            };
+            socket.set_nonblocking(true)?;
+
+            let state = UdpSocketState::new(socket, false);
+            lock(&state)?.incoming.insert(addr, Vec::new());
+            Ok(UdpConnection::new(state, addr, config.timeout))
+
+        } else {
+            Err(IOError::new(ErrorKind::AddrNotAvailable, ""))
+        }
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr, IOError> where Self: Sized {
+        Ok(self.peer_addr)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, IOError> where Self: Sized {
+        lock(&self.state)?.socket.local_addr()
+    }
+
+    fn read(&mut self, buffer: &mut Vec<u8>, max_bytes: usize) -> Result<usize, IOError> where Self: Sized {
+
+        if self.closed {
+            return Err(IOError::new(ErrorKind::NotConnected, ""));
+        }
+
+        let mut state = lock(&self.state)?;
+        state.receive();
+
+        let (bytes, waiting) = if let Some(incoming) = state.incoming.get_mut(&self.peer_addr) {
+            let bytes = cmp::min(incoming.len(), max_bytes);
+            buffer.extend(incoming.drain(0..bytes));
+            (bytes, !incoming.is_empty())
+
+        } else {
+            (0, false)
+        };
+
+        // Datagrams which are held back still count as activity
+        if bytes > 0 || waiting {
+            self.last_receive = Instant::now();
+            Ok(bytes)
+
+        } else if self.last_receive.elapsed() > self.timeout {
+            state.incoming.remove(&self.peer_addr);
+            self.closed = true;
+            Err(IOError::new(ErrorKind::ConnectionReset, ""))
+
         } else {
             Ok(0)
         }
+
     }
 
     fn write(&mut self, bytes: &[u8]) -> Result<usize, IOError> where Self: Sized {
-        self.stream.write_all(&bytes[..])?;
-        self.stream.flush()?;
-        Ok(bytes.len())
+        self.write_vectored(&[IoSlice::new(bytes)])
+    }
+
+    // Packs as many complete frames into each datagram as fit, frames cut off
+    // by the end of the slices are left for the next write
+    fn write_vectored(&mut self, slices: &[IoSlice]) -> Result<usize, IOError> where Self: Sized {
+
+        if self.closed {
+            return Err(IOError::new(ErrorKind::NotConnected, ""));
+        }
+
+        let bytes: Vec<u8> = slices.iter().flat_map(|slice| slice.iter().cloned()).collect();
+        let state = lock(&self.state)?;
+        let mut sent = 0;
+        while sent < bytes.len() {
+
+            let mut end = sent;
+            while let Some(length) = frame_length(&bytes[end..]) {
+                let next = end + FRAME_HEADER_SIZE + length;
+                if next > bytes.len() || next - sent > UDP_MAX_DATAGRAM_SIZE {
+                    break;
+                }
+                end = next;
+            }
+
+            // Frames which exceed a datagram on their own can never be sent
+            if end == sent {
+                let too_large = frame_length(&bytes[sent..]).is_some_and(|length| FRAME_HEADER_SIZE + length > UDP_MAX_DATAGRAM_SIZE);
+                if too_large && sent == 0 {
+                    return Err(IOError::new(ErrorKind::InvalidInput, "frame exceeds the maximum datagram size"));
+                }
+                break;
+            }
+
+            match state.socket.send_to(&bytes[sent..end], self.peer_addr) {
+                Ok(_) => sent = end,
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err)
+            }
+
+        }
+        Ok(sent)
+
     }
 
     fn shutdown(&mut self) -> Result<(), IOError> where Self: Sized {
-        self.stream.shutdown(Shutdown::Both)
+        self.closed = true;
+        let mut state = lock(&self.state)?;
+        state.incoming.remove(&self.peer_addr);
+        Ok(())
     }
 
 }
 
+
+// Tests ----------------------------------------------------------------------
+#[cfg(test)]
+mod test {
+
+    use std::thread;
+    use std::net::UdpSocket;
+    use std::time::Duration;
+    use std::io::{Error as IOError, ErrorKind, IoSlice, Read};
+    use ::codec::{BincodeCodec, Codec};
+    use ::message::{InternalMessage, Incoming, DEFAULT_MAX_MESSAGE_SIZE, create_message_iterator};
+    use super::{Connection, Host, TcpConfig, TcpHost, UdpConfig, UdpConnection, UdpHost, read_stream};
+    #[cfg(unix)]
+    use socket2::SockRef;
+
+    fn frame(prefix: u8, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = ((payload.len() + 1) as u32).to_le_bytes().to_vec();
+        bytes.push(prefix);
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    // A stream which hands out a single byte per tick and would block otherwise
+    struct Trickle {
+        bytes: Vec<u8>,
+        index: usize,
+        ready: bool
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buffer: &mut [u8]) -> Result<usize, IOError> {
+            if !self.ready || self.index == self.bytes.len() {
+                Err(IOError::new(ErrorKind::WouldBlock, ""))
+
+            } else {
+                self.ready = false;
+                buffer[0] = self.bytes[self.index];
+                self.index += 1;
+                Ok(1)
+            }
+        }
+    }
+
+    #[test]
+    fn stream_frames_received_byte_by_byte_are_delivered_once() {
+        let message = "split across many reads".to_string();
+        let mut stream = Trickle {
+            bytes: frame(1, &BincodeCodec::encode(&message).unwrap()),
+            index: 0,
+            ready: false
+        };
+
+        let mut incoming = Incoming::<InternalMessage>::new(DEFAULT_MAX_MESSAGE_SIZE);
+        let mut error = None;
+        let mut received = Vec::new();
+        for _ in 0..stream.bytes.len() * 2 {
+            stream.ready = true;
+            let bytes = read_stream(&mut stream, incoming.buffer_mut(), usize::MAX, &mut error).unwrap();
+            incoming.received(bytes, usize::MAX);
+            received.extend(create_message_iterator::<String, InternalMessage, BincodeCodec>(&mut incoming, None));
+            if stream.index < stream.bytes.len() {
+                assert!(received.is_empty());
+            }
+        }
+
+        assert_eq!(received, vec![message]);
+        assert_eq!(incoming.pending_bytes(), 0);
+    }
+
+    fn udp_pair(config: &UdpConfig) -> (UdpHost, UdpConnection) {
+        let host = UdpHost::bind(&["127.0.0.1:0".parse().unwrap()], config).unwrap();
+        let client = UdpConnection::connect(&[host.local_addr().unwrap()], Duration::from_millis(100), config).unwrap();
+        (host, client)
+    }
+
+    fn accept(host: &mut UdpHost) -> UdpConnection {
+        for _ in 0..1000 {
+            if let Ok(connection) = host.accept() {
+                return connection;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        panic!("no connection was accepted");
+    }
+
+    fn read_until(connection: &mut UdpConnection, buffer: &mut Vec<u8>, length: usize) {
+        for _ in 0..1000 {
+            connection.read(buffer, usize::MAX).unwrap();
+            if buffer.len() >= length {
+                return;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn udp_writes_more_than_a_datagram_per_call() {
+        let (mut host, mut client) = udp_pair(&UdpConfig::default());
+        let frames: Vec<Vec<u8>> = (0..100).map(|i| frame(1, &[i as u8; 1000])).collect();
+        let slices: Vec<IoSlice> = frames.iter().map(|frame| IoSlice::new(frame)).collect();
+        let expected = frames.concat();
+        assert_eq!(client.write_vectored(&slices).unwrap(), expected.len());
+
+        let mut remote = accept(&mut host);
+        let mut buffer = Vec::new();
+        read_until(&mut remote, &mut buffer, expected.len());
+        assert_eq!(buffer, expected);
+    }
+
+    #[test]
+    fn udp_holds_back_frames_cut_off_by_the_slices() {
+        let (_host, mut client) = udp_pair(&UdpConfig::default());
+        let (a, b) = (frame(1, b"complete"), frame(1, b"cut off"));
+        let slices = [IoSlice::new(&a), IoSlice::new(&b[..5])];
+        assert_eq!(client.write_vectored(&slices).unwrap(), a.len());
+    }
+
+    #[test]
+    fn udp_drops_partial_frames_at_datagram_boundaries() {
+        let (mut host, _client) = udp_pair(&UdpConfig::default());
+        let (a, b, c) = (frame(1, b"first"), frame(1, &[0xff; 32]), frame(1, b"third"));
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = host.local_addr().unwrap();
+
+        // The second frame is split up, with its tail arriving on its own
+        socket.send_to(&[&a[..], &b[..20]].concat(), addr).unwrap();
+        let mut remote = accept(&mut host);
+        socket.send_to(&b[20..], addr).unwrap();
+        socket.send_to(&c, addr).unwrap();
+
+        let mut buffer = Vec::new();
+        read_until(&mut remote, &mut buffer, a.len() + c.len());
+        thread::sleep(Duration::from_millis(10));
+        remote.read(&mut buffer, usize::MAX).unwrap();
+        assert_eq!(buffer, [&a[..], &c[..]].concat());
+    }
+
+    #[test]
+    fn udp_timeout_is_configured_per_host() {
+        let short = UdpConfig {
+            timeout: Duration::from_millis(20)
+        };
+        let (mut short_host, mut short_client) = udp_pair(&short);
+        let (mut default_host, mut default_client) = udp_pair(&UdpConfig::default());
+        short_client.write(&frame(1, b"hello")).unwrap();
+        default_client.write(&frame(1, b"hello")).unwrap();
+
+        let mut short_remote = accept(&mut short_host);
+        let mut default_remote = accept(&mut default_host);
+        let mut buffer = Vec::new();
+        short_remote.read(&mut buffer, usize::MAX).unwrap();
+        default_remote.read(&mut buffer, usize::MAX).unwrap();
+
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(short_remote.read(&mut buffer, usize::MAX).unwrap_err().kind(), ErrorKind::ConnectionReset);
+        assert_eq!(default_remote.read(&mut buffer, usize::MAX).unwrap(), 0);
+    }
+
+    // Otherwise a rebind fails while old connections are still in TIME_WAIT
+    #[cfg(unix)]
+    #[test]
+    fn listeners_with_a_backlog_reuse_their_address() {
+        let config = TcpConfig {
+            backlog: Some(16),
+            .. TcpConfig::default()
+        };
+        let host = TcpHost::bind(&["127.0.0.1:0".parse().unwrap()], &config).unwrap();
+        assert!(SockRef::from(&host.listener).reuse_address().unwrap());
+    }
+
+}
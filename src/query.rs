@@ -0,0 +1,84 @@
+// Copyright (c) 2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::thread;
+use std::time::{Duration, Instant};
+use std::io::{Error as IOError, ErrorKind};
+
+
+// External Dependencies ------------------------------------------------------
+use serde::de::DeserializeOwned;
+
+
+// Internal Dependencies ------------------------------------------------------
+use ::error::Error;
+use ::codec::{Codec, BincodeCodec};
+use ::protocol::{Addr, Config, Protocol, Connection, ToAddrs};
+use ::message::{InternalMessage, Incoming, Outgoing, DEFAULT_MAX_MESSAGE_SIZE, encode};
+
+
+// Statics --------------------------------------------------------------------
+static QUERY_POLL_MS: u64 = 1;
+
+
+// Server Queries -------------------------------------------------------------
+// Asks a server for its current status without establishing a session, the
+// status is provided by the closure passed to Server::set_status_provider()
+pub fn query<P: Protocol, S: DeserializeOwned, A: ToAddrs<Addr<P>>>(addr: A, timeout: Duration) -> Result<S, Error> {
+
+    let addrs = addr.to_addrs().map_err(|_| Error::AddressResolution)?;
+    if addrs.is_empty() {
+        return Err(Error::AddressResolution);
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut connection = P::Connection::connect(&addrs[..], timeout, &Config::<P>::default())?;
+
+    let mut outgoing = Outgoing::new();
+    outgoing.push_frame(0, encode::<BincodeCodec, _>(&InternalMessage::Query, DEFAULT_MAX_MESSAGE_SIZE)?);
+
+    let mut incoming: Incoming<InternalMessage> = Incoming::new(DEFAULT_MAX_MESSAGE_SIZE);
+    let result = loop {
+
+        if !outgoing.is_empty() {
+            if let Err(err) = outgoing.write_to(&mut connection, usize::MAX) {
+                break Err(Error::Io(err));
+            }
+        }
+
+        let limit = incoming.read_limit();
+        let read = connection.read(incoming.buffer_mut(), limit);
+        incoming.extract_frames::<BincodeCodec>();
+
+        let status = incoming.drain_internal().into_iter().find_map(|m| match m {
+            InternalMessage::Status(bytes) => Some(bytes),
+            _ => None
+        });
+
+        if let Some(bytes) = status {
+            break BincodeCodec::decode::<S>(&bytes).map(|(status, _)| status).map_err(Error::Serialization);
+
+        // Servers without a status provider simply close the connection
+        } else if let Err(err) = read {
+            break Err(Error::Io(err));
+
+        } else if Instant::now() >= deadline {
+            break Err(Error::Io(IOError::new(ErrorKind::TimedOut, "")));
+        }
+
+        thread::sleep(Duration::from_millis(QUERY_POLL_MS));
+
+    };
+
+    connection.shutdown().ok();
+    result
+
+}
+
@@ -0,0 +1,353 @@
+// Copyright (c) 2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::cmp;
+use std::fs::{self, File};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::io::{BufWriter, Error as IOError, ErrorKind, Write};
+use std::net::{Ipv4Addr, SocketAddr};
+
+
+// Internal Dependencies ------------------------------------------------------
+use ::error::Error;
+use ::server::ConnectionId;
+use ::time::{Clock, SystemClock};
+use ::protocol::{Protocol, Host, Connection};
+
+
+// Statics --------------------------------------------------------------------
+const CAPTURE_MAGIC: &[u8] = b"CBLTCAP1";
+const RECORD_HEADER_SIZE: usize = 21;
+const RECORD_CONNECT: u8 = 0;
+const RECORD_READ: u8 = 1;
+const RECORD_WRITE: u8 = 2;
+const DEFAULT_REPLAY_TICKS_PER_SECOND: f64 = 60.0;
+
+
+// Taps -----------------------------------------------------------------------
+// Sees all bytes exactly as they were read from and written to a connection,
+// stamped with the ticks of the server or client the tap is installed on
+pub trait Tap: Send {
+    fn on_connect(&mut self, _: ConnectionId, _: u64) {}
+    fn on_read(&mut self, id: ConnectionId, tick: u64, bytes: &[u8]);
+    fn on_write(&mut self, id: ConnectionId, tick: u64, bytes: &[u8]);
+}
+
+// Records are a kind byte, the connection id, the tick and the length of the
+// bytes which follow them; the file is complete once the tap is dropped
+pub struct FileTap {
+    writer: BufWriter<File>
+}
+
+impl FileTap {
+
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(CAPTURE_MAGIC)?;
+        Ok(Self {
+            writer: writer
+        })
+    }
+
+    // Failed writes only cost the capture, never the connection
+    fn record(&mut self, kind: u8, id: ConnectionId, tick: u64, bytes: &[u8]) {
+        let mut header = Vec::with_capacity(RECORD_HEADER_SIZE);
+        header.push(kind);
+        header.extend_from_slice(&id.0.to_le_bytes());
+        header.extend_from_slice(&tick.to_le_bytes());
+        header.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        self.writer.write_all(&header).ok();
+        self.writer.write_all(bytes).ok();
+    }
+
+}
+
+impl Tap for FileTap {
+
+    fn on_connect(&mut self, id: ConnectionId, tick: u64) {
+        self.record(RECORD_CONNECT, id, tick, &[]);
+    }
+
+    fn on_read(&mut self, id: ConnectionId, tick: u64, bytes: &[u8]) {
+        self.record(RECORD_READ, id, tick, bytes);
+    }
+
+    fn on_write(&mut self, id: ConnectionId, tick: u64, bytes: &[u8]) {
+        self.record(RECORD_WRITE, id, tick, bytes);
+    }
+
+}
+
+
+// Captures -------------------------------------------------------------------
+#[derive(Debug, Clone)]
+struct Session {
+    id: ConnectionId,
+    connected: u64,
+    reads: Vec<(u64, Vec<u8>)>
+}
+
+// Only the reads are replayed, what gets written is up to the code under test
+#[derive(Debug, Clone, Default)]
+pub struct Capture {
+    sessions: Vec<Session>
+}
+
+impl Capture {
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Capture::from_bytes(&fs::read(path)?[..])
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+
+        if bytes.len() < CAPTURE_MAGIC.len() || &bytes[..CAPTURE_MAGIC.len()] != CAPTURE_MAGIC {
+            return Err(invalid_capture());
+        }
+
+        let mut sessions: Vec<Session> = Vec::new();
+        let mut current: HashMap<ConnectionId, usize> = HashMap::new();
+        let mut index = CAPTURE_MAGIC.len();
+        while index < bytes.len() {
+
+            if bytes.len() - index < RECORD_HEADER_SIZE {
+                return Err(invalid_capture());
+            }
+
+            let header = &bytes[index..index + RECORD_HEADER_SIZE];
+            let id = ConnectionId(u64::from_le_bytes(to_array(&header[1..9])));
+            let tick = u64::from_le_bytes(to_array(&header[9..17]));
+            let length = u32::from_le_bytes(to_array(&header[17..21])) as usize;
+            index += RECORD_HEADER_SIZE;
+            if bytes.len() - index < length {
+                return Err(invalid_capture());
+            }
+
+            let data = &bytes[index..index + length];
+            index += length;
+
+            // Ids may be used again, e.g. by a client which reconnected
+            match header[0] {
+                RECORD_CONNECT => {
+                    current.insert(id, sessions.len());
+                    sessions.push(Session {
+                        id: id,
+                        connected: tick,
+                        reads: Vec::new()
+                    });
+                },
+                RECORD_READ => match current.get(&id) {
+                    Some(&session) => sessions[session].reads.push((tick, data.to_vec())),
+                    None => return Err(invalid_capture())
+                },
+                RECORD_WRITE => {},
+                _ => return Err(invalid_capture())
+            }
+
+        }
+
+        Ok(Self {
+            sessions: sessions
+        })
+
+    }
+
+    pub fn connections(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn connection_ids(&self) -> impl Iterator<Item=ConnectionId> + '_ {
+        self.sessions.iter().map(|session| session.id)
+    }
+
+}
+
+fn invalid_capture() -> Error {
+    Error::Io(IOError::new(ErrorKind::InvalidData, "invalid capture"))
+}
+
+fn to_array<const N: usize>(bytes: &[u8]) -> [u8; N] {
+    let mut array = [0; N];
+    array.copy_from_slice(bytes);
+    array
+}
+
+
+// Replay Configuration -------------------------------------------------------
+// The tick rate must match the one of the recording, with a MockClock the
+// replay runs as fast as possible while keeping the original pacing
+#[derive(Clone)]
+pub struct ReplayConfig {
+    sessions: Arc<Vec<Session>>,
+    next_session: Arc<AtomicUsize>,
+    ticks_per_second: f64,
+    clock: Arc<dyn Clock>
+}
+
+impl ReplayConfig {
+
+    pub fn new(capture: Capture, ticks_per_second: f64, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            sessions: Arc::new(capture.sessions),
+            next_session: Arc::new(AtomicUsize::new(0)),
+            ticks_per_second: ticks_per_second,
+            clock: clock
+        }
+    }
+
+    fn ticks_since(&self, started: Instant) -> u64 {
+        let elapsed = self.clock.now_instant().saturating_duration_since(started);
+        // Keeps ticks which end exactly on the clock from being rounded down
+        (elapsed.as_secs_f64() * self.ticks_per_second + 0.001) as u64
+    }
+
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        ReplayConfig::new(Capture::default(), DEFAULT_REPLAY_TICKS_PER_SECOND, Arc::new(SystemClock))
+    }
+}
+
+
+// Replay Protocol ------------------------------------------------------------
+// Servers bound to a replay host accept every recorded connection at its
+// original tick, clients connecting take the recorded connections in order
+pub struct Replay;
+impl Protocol for Replay {
+    type Host = ReplayHost;
+    type Connection = ReplayConnection;
+}
+
+pub struct ReplayHost {
+    config: ReplayConfig,
+    local_addr: SocketAddr,
+    started: Instant,
+    next_session: usize
+}
+
+impl Host for ReplayHost {
+
+    type Connection = ReplayConnection;
+
+    fn bind(addrs: &[SocketAddr], config: &ReplayConfig) -> Result<Self, IOError> where Self: Sized {
+        Ok(Self {
+            config: config.clone(),
+            local_addr: first_addr(addrs),
+            started: config.clock.now_instant(),
+            next_session: 0
+        })
+    }
+
+    fn accept(&mut self) -> Result<ReplayConnection, IOError> where Self: Sized {
+        let ticks = self.config.ticks_since(self.started);
+        match self.config.sessions.get(self.next_session) {
+            Some(session) if session.connected <= ticks => {
+                self.next_session += 1;
+                Ok(ReplayConnection::new(session, &self.config, self.started, 0, self.local_addr, first_addr(&[])))
+            },
+            _ => Err(IOError::new(ErrorKind::WouldBlock, ""))
+        }
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, IOError> where Self: Sized {
+        Ok(self.local_addr)
+    }
+
+    fn shutdown(self) -> Result<(), IOError> where Self: Sized {
+        Ok(())
+    }
+
+}
+
+pub struct ReplayConnection {
+    reads: VecDeque<(u64, Vec<u8>)>,
+    config: ReplayConfig,
+    started: Instant,
+    start_tick: u64,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr
+}
+
+impl ReplayConnection {
+
+    fn new(session: &Session, config: &ReplayConfig, started: Instant, start_tick: u64, local_addr: SocketAddr, peer_addr: SocketAddr) -> Self {
+        Self {
+            reads: session.reads.iter().cloned().collect(),
+            config: config.clone(),
+            started: started,
+            start_tick: start_tick,
+            local_addr: local_addr,
+            peer_addr: peer_addr
+        }
+    }
+
+}
+
+impl Connection for ReplayConnection {
+
+    type Addr = SocketAddr;
+    type Config = ReplayConfig;
+
+    fn connect(addrs: &[SocketAddr], _: Duration, config: &ReplayConfig) -> Result<Self, IOError> where Self: Sized {
+        let index = config.next_session.fetch_add(1, Ordering::SeqCst);
+        if let Some(session) = config.sessions.get(index) {
+            Ok(ReplayConnection::new(session, config, config.clock.now_instant(), session.connected, first_addr(&[]), first_addr(addrs)))
+
+        } else {
+            Err(IOError::new(ErrorKind::ConnectionRefused, "no more recorded connections"))
+        }
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr, IOError> where Self: Sized {
+        Ok(self.peer_addr)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, IOError> where Self: Sized {
+        Ok(self.local_addr)
+    }
+
+    // Hands out everything which was read by the tick the replay is at, the
+    // connection stays open once the recording ran out
+    fn read(&mut self, buffer: &mut Vec<u8>, max_bytes: usize) -> Result<usize, IOError> where Self: Sized {
+        let ticks = self.start_tick + self.config.ticks_since(self.started);
+        let mut bytes = 0;
+        while bytes < max_bytes && self.reads.front().is_some_and(|&(tick, _)| tick <= ticks) {
+            if let Some((_, data)) = self.reads.front_mut() {
+                let length = cmp::min(max_bytes - bytes, data.len());
+                buffer.extend(data.drain(0..length));
+                bytes += length;
+                if data.is_empty() {
+                    self.reads.pop_front();
+                }
+            }
+        }
+        Ok(bytes)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<usize, IOError> where Self: Sized {
+        Ok(bytes.len())
+    }
+
+    fn shutdown(&mut self) -> Result<(), IOError> where Self: Sized {
+        self.reads.clear();
+        Ok(())
+    }
+
+}
+
+fn first_addr(addrs: &[SocketAddr]) -> SocketAddr {
+    addrs.first().cloned().unwrap_or_else(|| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))
+}
+
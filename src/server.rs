@@ -8,248 +8,2817 @@
 
 
 // STD Dependencies -----------------------------------------------------------
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::mem;
+use std::vec;
+use std::slice;
+use std::iter;
 use std::marker::PhantomData;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+#[cfg(unix)]
+use std::fs::File;
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
 use std::io::{Error as IOError, ErrorKind};
-use std::net::{SocketAddr, ToSocketAddrs};
 
 
 // External Dependencies ------------------------------------------------------
 use serde::Serialize;
-use serde::de::DeserializeOwned;
-use bincode::{serialize, Infinite};
+use serde::de::{Deserialize, DeserializeOwned};
+#[cfg(feature = "poll")]
+use mio::{Events, Poll, Token};
 
 
 // Internal Dependencies ------------------------------------------------------
-use ::time::Timer;
-use ::protocol::{Protocol, Connection, Host};
-use ::message::{MessageIterator, InternalMessage, create_message_iterator};
+use ::error::Error;
+use ::builder::ServerBuilder;
+use ::time::{CatchUpPolicy, Clock, ConnectionQuality, TickReport, Timer, TimingConfig, Throughput, RateLimit, MessageLimit, AdaptiveSend, SendAllowance};
+use ::codec::{BorrowCodec, Codec, BincodeCodec};
+use ::discovery::Beacon;
+use ::replay::Tap;
+use ::compression::{self, DEFAULT_COMPRESSION_THRESHOLD};
+use ::encryption::KEY_SIZE;
+use ::protocol::{Addr, Address, Config, Protocol, Connection, Host, ToAddrs};
+#[cfg(unix)]
+use ::protocol::{TCP, TcpConnection};
+#[cfg(unix)]
+use ::time::TimerState;
+use ::message::{
+    AckId, BorrowedIterator, ChannelIterator, MessageIterator, RawIterator, StampedIterator, InternalMessage, Incoming, Outgoing, PriorityChannels, QueuePolicies, QueuePolicy, RequestId, DecodeError, LatePolicy,
+    DEFAULT_MAX_MESSAGE_SIZE, DEFAULT_MAX_OUTGOING_SIZE, DEFAULT_CONNECTION_TIMEOUT, DEFAULT_FRAGMENT_SIZE,
+    DEFAULT_MAX_INCOMING_SIZE, CLOSE_PROTOCOL_ERROR, CLOSE_TIMEOUT, CLOSE_SLOW_CONSUMER, CLOSE_REJECTED,
+    CLOSE_BANNED, CLOSE_FLOOD, CLOSE_VERSION_MISMATCH, PROTOCOL_VERSION, CloseReason, io_close_reason, local_close_reason,
+    MessageFilter, create_borrowed_iterator, create_channel_iterator, create_message_iterator, create_raw_iterator, create_stamped_iterator, check_control_prefix, check_message_size, encode_into, from_bytes
+};
+
+
+// Connection Identifiers -----------------------------------------------------
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+pub struct ConnectionId(pub u64);
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct GroupId(pub u64);
+
+
+// Server Events --------------------------------------------------------------
+#[derive(Debug)]
+pub enum ServerEvent<M, D> {
+    Accepted(ConnectionId),
+    Message(ConnectionId, M),
+    Closed(ConnectionId, D)
+}
+
+// Returned by Server::update() and Client::update(), ticked is false when the
+// call happened before the current tick was over and only moved data around
+#[derive(Debug)]
+pub struct UpdateResult<T> {
+    pub events: Vec<T>,
+    pub ticked: bool
+}
+
+impl<T> IntoIterator for UpdateResult<T> {
+    type Item = T;
+    type IntoIter = vec::IntoIter<T>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.events.into_iter()
+    }
+}
+
+
+// Message Hooks --------------------------------------------------------------
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HookAction {
+    Pass,
+    Drop,
+    // Drops the message and every further one, then closes the connection
+    Close
+}
+
+type InboundHook<In> = Arc<Mutex<dyn FnMut(ConnectionId, &In) -> HookAction + Send>>;
+type OutboundHook<Out> = Arc<Mutex<dyn FnMut(ConnectionId, &Out) -> bool + Send>>;
+type SharedTap = Arc<Mutex<dyn Tap>>;
+
+// Decodes the payload of a control message and hands it to the application
+type ControlHandler = Box<dyn FnMut(ConnectionId, &[u8]) -> Result<(), DecodeError> + Send>;
+
+// Shared between the server and its remotes, the hooks only ever get to see a
+// connection id and a message so they cannot call back into either of them
+struct Hooks<In, Out> {
+    inbound: Option<InboundHook<In>>,
+    outbound: Option<OutboundHook<Out>>,
+    tap: Option<SharedTap>
+}
+
+impl<In, Out> Hooks<In, Out> {
+
+    fn new() -> Self {
+        Self {
+            inbound: None,
+            outbound: None,
+            tap: None
+        }
+    }
+
+    fn allows(&self, id: ConnectionId, message: &Out) -> bool {
+        self.outbound.as_ref().is_none_or(|hook| match hook.lock() {
+            Ok(mut hook) => (*hook)(id, message),
+            Err(_) => true
+        })
+    }
+
+    // Pre-encoded messages are only decoded if there is a hook to consult,
+    // bytes which fail to decode are let through
+    fn decode_outbound<E: Codec>(&self, bytes: &[u8]) -> Option<Out> where Out: DeserializeOwned {
+        self.outbound.as_ref().and_then(|_| E::decode::<Out>(bytes).ok()).map(|(message, _)| message)
+    }
+
+    fn on_connect(&self, id: ConnectionId, tick: u64) {
+        if let Some(Ok(mut tap)) = self.tap.as_ref().map(|tap| tap.lock()) {
+            tap.on_connect(id, tick);
+        }
+    }
+
+    fn on_read(&self, id: ConnectionId, tick: u64, bytes: &[u8]) {
+        if let Some(Ok(mut tap)) = self.tap.as_ref().map(|tap| tap.lock()) {
+            tap.on_read(id, tick, bytes);
+        }
+    }
+
+    fn on_write(&self, id: ConnectionId, tick: u64, bytes: &[u8]) {
+        if let Some(Ok(mut tap)) = self.tap.as_ref().map(|tap| tap.lock()) {
+            tap.on_write(id, tick, bytes);
+        }
+    }
+
+}
+
+impl<In, Out> Clone for Hooks<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inbound: self.inbound.clone(),
+            outbound: self.outbound.clone(),
+            tap: self.tap.clone()
+        }
+    }
+}
+
+// Messages over the rate limit are dropped before they reach the hook
+fn inbound_filter<'a, In: 'a>(
+    id: ConnectionId,
+    hook: &Option<InboundHook<In>>,
+    rejected: &'a mut bool,
+    limit: &'a mut MessageLimit
+
+) -> Option<MessageFilter<'a, In>> {
+
+    if hook.is_none() && !limit.is_enabled() {
+        return None;
+    }
+
+    let hook = hook.clone();
+    Some(Box::new(move |message: &In| {
+        if *rejected || !limit.allow() {
+            return false;
+        }
+        let action = hook.as_ref().map_or(HookAction::Pass, |hook| hook_action(id, hook, message));
+        *rejected = action == HookAction::Close;
+        action == HookAction::Pass
+    }))
+
+}
+
+// Raw messages only need decoding when the hook wants to see them
+fn raw_filter<'a, In: DeserializeOwned + 'a, E: Codec, B: AsRef<[u8]> + ?Sized>(
+    id: ConnectionId,
+    hook: &Option<InboundHook<In>>,
+    rejected: &'a mut bool,
+    limit: &'a mut MessageLimit
+
+) -> Option<MessageFilter<'a, B>> {
+
+    if hook.is_none() && !limit.is_enabled() {
+        return None;
+    }
+
+    let hook = hook.clone();
+    Some(Box::new(move |bytes: &B| {
+        if *rejected || !limit.allow() {
+            return false;
+        }
+        let action = match (hook.as_ref(), E::decode::<In>(bytes.as_ref())) {
+            (Some(hook), Ok((message, _))) => hook_action(id, hook, &message),
+            _ => HookAction::Pass
+        };
+        *rejected = action == HookAction::Close;
+        action == HookAction::Pass
+    }))
+
+}
+
+fn hook_action<In>(id: ConnectionId, hook: &InboundHook<In>, message: &In) -> HookAction {
+    match hook.lock() {
+        Ok(mut hook) => (*hook)(id, message),
+        Err(_) => HookAction::Pass
+    }
+}
+
+
+// Statics --------------------------------------------------------------------
+static CLOSE_TIMEOUT_TICKS: u8 = 10;
+static MIN_COMPACTED_SLOTS: usize = 64;
+#[cfg(feature = "poll")]
+const LISTENER_TOKEN: Token = Token(usize::MAX);
+
+
+// Readiness Polling ----------------------------------------------------------
+#[cfg(feature = "poll")]
+struct Poller {
+    poll: Poll,
+    events: Events
+}
+
+#[cfg(feature = "poll")]
+impl Poller {
+
+    fn new() -> Result<Self, IOError> {
+        Ok(Self {
+            poll: Poll::new()?,
+            events: Events::with_capacity(256)
+        })
+    }
+
+    fn wait(&mut self, timeout: Duration) {
+        self.poll.poll(&mut self.events, Some(timeout)).ok();
+    }
+
+}
+
+
+// Listener Errors ------------------------------------------------------------
+// Errors of single connections which went away while waiting in the backlog
+fn is_connection_error(err: &IOError) -> bool {
+    matches!(err.kind(), ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset | ErrorKind::Interrupted)
+}
+
+// EMFILE and ENFILE, the process or the whole system ran out of descriptors
+#[cfg(unix)]
+fn is_descriptor_exhaustion(err: &IOError) -> bool {
+    matches!(err.raw_os_error(), Some(24) | Some(23))
+}
+
+// Held on to so there is always a descriptor which can be given up in order
+// to accept and close a connection once all others are in use
+#[cfg(unix)]
+fn reserve_descriptor() -> Option<File> {
+    File::open("/dev/null").ok()
+}
+
+
+// Server Abstraction ---------------------------------------------------------
+// D is the data the application keeps for each remote, the server can be moved
+// to another thread as long as D and the message types are Send
+pub struct Server<P: Protocol, In: Serialize + DeserializeOwned, Out: Serialize + DeserializeOwned, D, E: Codec = BincodeCodec> {
+    listener: Option<P::Host>,
+    #[cfg(feature = "poll")]
+    poller: Option<Poller>,
+    remotes: Slots<(Remote<<<P as Protocol>::Host as Host>::Connection, In, Out, E>, D)>,
+    pending: Vec<(Remote<<<P as Protocol>::Host as Host>::Connection, In, Out, E>, u64)>,
+    handshaking: Vec<Remote<<<P as Protocol>::Host as Host>::Connection, In, Out, E>>,
+    rejecting: Vec<Remote<<<P as Protocol>::Host as Host>::Connection, In, Out, E>>,
+    closed_ids: Vec<ConnectionId>,
+    next_id: u64,
+    accepting: bool,
+    draining: Option<Instant>,
+    listener_addrs: Vec<Addr<P>>,
+    listener_error: Option<Error>,
+    listener_errors: usize,
+    #[cfg(unix)]
+    reserve: Option<File>,
+    discovery: Option<Beacon>,
+    status_provider: Option<Box<dyn FnMut() -> Option<Vec<u8>> + Send>>,
+    controls: HashMap<u8, ControlHandler>,
+    hooks: Hooks<In, Out>,
+    status: Option<(u64, Vec<u8>)>,
+    bans: HashMap<IpAddr, Option<Instant>>,
+    groups: HashMap<GroupId, HashSet<ConnectionId>>,
+    next_group: u64,
+    max_connections: usize,
+    limit_policy: LimitPolicy,
+    accept_limit: usize,
+    accepted_this_tick: usize,
+    protocol_config: Config<P>,
+    config: RemoteConfig,
+    version: u32,
+    bytes_sent: usize,
+    bytes_received: usize,
+    stats: NetStats,
+    scratch: Vec<u8>,
+    timer: Timer,
+    ticks: u64,
+    accepted_done: bool,
+    connected_done: bool,
+    closed_done: bool,
+    paused: bool
+}
+
+// Server which receives and sends the same message type
+pub type SymmetricServer<P, M, D, E = BincodeCodec> = Server<P, M, M, D, E>;
+
+impl<P: Protocol, In: Serialize + DeserializeOwned, Out: Serialize + DeserializeOwned, D, E: Codec> Server<P, In, Out, D, E> {
+
+    pub fn new(ticks_per_second: u8) -> Self {
+        Server::with_tick_rate(f64::from(ticks_per_second))
+    }
+
+    // Supports fractional rates and rates above 255 ticks per second
+    pub fn with_tick_rate(ticks_per_second: f64) -> Self {
+        ServerBuilder::new().tick_rate(ticks_per_second).assemble()
+    }
+
+    pub fn with_timing(ticks_per_second: f64, timing: TimingConfig) -> Self {
+        let mut server = Server::with_tick_rate(ticks_per_second);
+        server.set_timing(timing);
+        server
+    }
+
+    pub fn with_clock(ticks_per_second: f64, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            listener: None,
+            #[cfg(feature = "poll")]
+            poller: None,
+            timer: Timer::new(ticks_per_second, clock),
+            ticks: 0,
+            remotes: Slots::new(),
+            pending: Vec::new(),
+            handshaking: Vec::new(),
+            rejecting: Vec::new(),
+            closed_ids: Vec::new(),
+            next_id: 0,
+            accepting: true,
+            draining: None,
+            listener_addrs: Vec::new(),
+            listener_error: None,
+            listener_errors: 0,
+            #[cfg(unix)]
+            reserve: None,
+            discovery: None,
+            status_provider: None,
+            controls: HashMap::new(),
+            hooks: Hooks::new(),
+            status: None,
+            bans: HashMap::new(),
+            groups: HashMap::new(),
+            next_group: 0,
+            max_connections: usize::MAX,
+            limit_policy: LimitPolicy::Backlog,
+            accept_limit: usize::MAX,
+            accepted_this_tick: 0,
+            protocol_config: Config::<P>::default(),
+            config: RemoteConfig {
+                max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+                max_outgoing_size: DEFAULT_MAX_OUTGOING_SIZE,
+                connection_timeout: Duration::from_secs(DEFAULT_CONNECTION_TIMEOUT),
+                slow_consumer_ticks: None,
+                strict: false,
+                late_policy: LatePolicy::default(),
+                priority_channels: PriorityChannels::default(),
+                queue_policies: QueuePolicies::default(),
+                fragment_size: DEFAULT_FRAGMENT_SIZE,
+                batching: true,
+                stamping: false,
+                max_incoming_size: DEFAULT_MAX_INCOMING_SIZE,
+                read_limit: None,
+                message_rate_limit: None,
+                flood_threshold: None,
+                compression_threshold: Some(DEFAULT_COMPRESSION_THRESHOLD),
+                psk: None,
+                timing: TimingConfig::default()
+            },
+            version: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            stats: NetStats::default(),
+            scratch: Vec::new(),
+            accepted_done: false,
+            connected_done: false,
+            closed_done: false,
+            paused: false
+        }
+    }
+
+    pub fn bind<A: ToAddrs<Addr<P>>>(&mut self, addr: A) -> Result<(), Error> {
+        if self.listener.is_none() {
+            let addrs = addr.to_addrs()?;
+            let listener = P::Host::bind(&addrs[..], &self.protocol_config)?;
+            self.listener_addrs = listener.local_addr().map(|addr| vec![addr]).unwrap_or(addrs);
+            self.listener = Some(listener);
+            self.listener_error = None;
+            self.start_polling();
+
+            #[cfg(unix)]
+            {
+                self.reserve = reserve_descriptor();
+            }
+
+            // Rebinding after unbind() keeps the clock of the remotes still served
+            if !self.has_connections() {
+                self.draining = None;
+                self.timer.reset();
+                self.ticks = 0;
+                self.bytes_sent = 0;
+                self.bytes_received = 0;
+            }
+            Ok(())
+
+        } else {
+            Err(Error::AlreadyBound)
+        }
+    }
+
+    // Stops accepting and releases the address, connected remotes are still
+    // served until they disconnect and bind() may be called again later
+    pub fn unbind(&mut self) -> Result<(), Error> {
+        if let Some(listener) = self.listener.take() {
+            self.discovery = None;
+            Ok(listener.shutdown()?)
+
+        } else {
+            Err(Error::NotBound)
+        }
+    }
+
+    // Replaces the listener with a new one on the address it was bound to,
+    // e.g. after it failed with listener_error(); remotes are not affected
+    pub fn rebind(&mut self) -> Result<(), Error> {
+        if let Some(listener) = self.listener.take() {
+            listener.shutdown().ok();
+            let listener = P::Host::bind(&self.listener_addrs[..], &self.protocol_config)?;
+            self.listener = Some(listener);
+            self.listener_error = None;
+            self.start_polling();
+            Ok(())
+
+        } else {
+            Err(Error::NotBound)
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<Addr<P>, Error> {
+        if let Some(listener) = self.listener.as_ref() {
+            Ok(listener.local_addr()?)
+
+        } else {
+            Err(Error::NotBound)
+        }
+    }
+
+    // The last error the listener ran into since it was bound, accepting is
+    // retried every tick until the listener is rebound
+    pub fn listener_error(&self) -> Option<&Error> {
+        self.listener_error.as_ref()
+    }
+
+    pub fn listener_errors(&self) -> usize {
+        self.listener_errors
+    }
+
+    pub fn ticks_per_second(&self) -> f64 {
+        self.timer.ticks_per_second()
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    // Timing of the last completed tick, see TickReport
+    pub fn last_tick_report(&self) -> TickReport {
+        self.timer.last_tick_report()
+    }
+
+    // Number of ticks which took longer than their budget
+    pub fn overrun_ticks(&self) -> u64 {
+        self.timer.overrun_ticks()
+    }
+
+    // How to make up for ticks which took longer than their budget
+    pub fn set_catch_up(&mut self, policy: CatchUpPolicy) {
+        self.timer.set_catch_up(policy);
+    }
+
+    pub fn time(&self) -> f64 {
+        self.ticks as f64 / self.timer.ticks_per_second()
+    }
+
+    // Time passed since the server was bound, unlike time() this includes
+    // overruns and pauses
+    pub fn wall_time(&self) -> Duration {
+        self.timer.elapsed()
+    }
+
+    // Freezes the ticks, e.g. for the pause menu of a listen server; sleep()
+    // keeps its cadence but nothing is written until resume(). Remotes are
+    // told so they do not time out in the meantime, messages sent to them
+    // and received from them stay buffered. Connections accepted while paused
+    // get their hello right away and are paused along with the others
+    pub fn pause(&mut self) {
+        if !self.paused {
+            self.paused = true;
+            for &mut (ref mut remote, _) in &mut self.remotes {
+                remote.pause();
+            }
+            for &mut (ref mut remote, _) in &mut self.pending {
+                remote.pause();
+            }
+        }
+    }
+
+    pub fn resume(&mut self) {
+        if self.paused {
+            self.paused = false;
+            for &mut (ref mut remote, _) in &mut self.remotes {
+                remote.resume();
+            }
+            for &mut (ref mut remote, _) in &mut self.pending {
+                remote.resume();
+            }
+        }
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    // Clients with a different version are closed before they get accepted,
+    // only affects connections which did not finish their handshake yet
+    pub fn set_version(&mut self, version: u32) {
+        self.version = version;
+    }
+
+    pub fn max_message_size(&self) -> usize {
+        self.config.max_message_size
+    }
+
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.config.max_message_size = max_message_size;
+        self.update_config();
+    }
+
+    pub fn max_outgoing_size(&self) -> usize {
+        self.config.max_outgoing_size
+    }
+
+    // Must be set before bind() in order to apply to the listener
+    pub fn set_protocol_config(&mut self, config: Config<P>) {
+        self.protocol_config = config;
+    }
+
+    pub fn set_max_outgoing_size(&mut self, max_outgoing_size: usize) {
+        self.config.max_outgoing_size = max_outgoing_size;
+        self.update_config();
+    }
+
+    pub fn set_slow_consumer_ticks(&mut self, ticks: Option<u64>) {
+        self.config.slow_consumer_ticks = ticks;
+        self.update_config();
+    }
+
+    pub fn set_strict(&mut self, strict: bool) {
+        self.config.strict = strict;
+        self.update_config();
+    }
+
+    pub fn set_late_policy(&mut self, policy: LatePolicy) {
+        self.config.late_policy = policy;
+        self.update_config();
+    }
+
+    pub fn fragment_size(&self) -> usize {
+        self.config.fragment_size
+    }
+
+    // Messages larger than this are sent in fragments, interleaved with the
+    // other outgoing frames
+    pub fn set_fragment_size(&mut self, fragment_size: usize) {
+        self.config.fragment_size = fragment_size;
+        self.update_config();
+    }
+
+    // Messages queued on the same channel within a tick share a single frame,
+    // disabling this sends one frame per message which is easier to debug
+    pub fn set_batching(&mut self, batching: bool) {
+        self.config.batching = batching;
+        self.update_config();
+    }
+
+    // Remotes accepted from now on put their tick in front of every message,
+    // so their peers can tell the age of them, see Remote::receive_stamped()
+    pub fn set_stamping(&mut self, stamping: bool) {
+        self.config.stamping = stamping;
+    }
+
+    pub fn max_incoming_size(&self) -> usize {
+        self.config.max_incoming_size
+    }
+
+    // Remotes stop reading from their connection once this many bytes are
+    // waiting to be received by the application
+    pub fn set_max_incoming_size(&mut self, max_incoming_size: usize) {
+        self.config.max_incoming_size = max_incoming_size;
+        self.update_config();
+    }
+
+    // Limits the bytes each remote reads from its connection per tick
+    pub fn set_read_limit(&mut self, bytes_per_tick: Option<usize>) {
+        self.config.read_limit = bytes_per_tick;
+        self.update_config();
+    }
+
+    // Messages above the rate are dropped by receive() and counted in
+    // Remote::dropped_messages(), bursts of up to the given size are allowed
+    pub fn set_message_rate_limit(&mut self, messages_per_second: u32, burst: u32) {
+        self.config.message_rate_limit = Some((messages_per_second, burst));
+        self.update_config();
+    }
+
+    pub fn disable_message_rate_limit(&mut self) {
+        self.config.message_rate_limit = None;
+        self.update_config();
+    }
+
+    // Remotes which get more than this many messages dropped within a single
+    // tick are closed for flooding
+    pub fn set_flood_threshold(&mut self, dropped_per_tick: Option<u32>) {
+        self.config.flood_threshold = dropped_per_tick;
+        self.update_config();
+    }
+
+    // Frames above the threshold are compressed for peers which support it,
+    // None disables compression of outgoing frames
+    #[cfg(feature = "compression")]
+    pub fn set_compression_threshold(&mut self, threshold: Option<usize>) {
+        self.config.compression_threshold = threshold;
+        self.update_config();
+    }
+
+    // Encrypts the frames of all connections accepted afterwards, peers which
+    // use a different key or none at all are closed with a protocol error
+    #[cfg(feature = "encryption")]
+    pub fn set_psk(&mut self, psk: [u8; KEY_SIZE]) {
+        self.config.psk = Some(psk);
+        self.update_config();
+    }
+
+    // Frames on high priority channels are sent ahead of all other frames
+    // which are still waiting in the outgoing buffers
+    pub fn set_channel_priority(&mut self, channel: u8, priority: bool) {
+        self.config.priority_channels.set(channel, priority);
+        self.update_config();
+    }
+
+    // Lets newer messages on the channel replace those which are still
+    // waiting for a slow connection, see Remote::dropped_frames()
+    pub fn set_queue_policy(&mut self, channel: u8, policy: QueuePolicy) {
+        self.config.queue_policies.set(channel, policy);
+        self.update_config();
+    }
+
+    pub fn connection_timeout(&self) -> Duration {
+        self.config.connection_timeout
+    }
+
+    pub fn set_connection_timeout(&mut self, timeout: Duration) {
+        self.config.connection_timeout = timeout;
+        self.update_config();
+    }
+
+    pub fn timing(&self) -> TimingConfig {
+        self.config.timing
+    }
+
+    // Applies to all remotes, changing a window size discards their samples
+    pub fn set_timing(&mut self, timing: TimingConfig) {
+        self.config.timing = timing;
+        self.update_config();
+    }
+
+    // Remotes ping their peer every tick for this many ticks after connecting
+    pub fn set_ping_warmup(&mut self, ticks: u64) {
+        self.config.timing.ping_warmup_ticks = ticks;
+        self.update_config();
+    }
+
+    // Ticks between two pings once the warm up is over
+    pub fn set_ping_interval(&mut self, ticks: u64) {
+        self.config.timing.ping_interval_ticks = ticks;
+        self.update_config();
+    }
+
+    pub fn max_connections(&self) -> usize {
+        self.max_connections
+    }
+
+    pub fn set_max_connections(&mut self, max_connections: usize) {
+        self.max_connections = max_connections;
+    }
+
+    pub fn set_limit_policy(&mut self, policy: LimitPolicy) {
+        self.limit_policy = policy;
+    }
+
+    // Spreads bursts of new connections over several ticks, the ones over the
+    // limit stay in the backlog of the listener until the following ticks
+    pub fn set_accept_limit_per_tick(&mut self, limit: usize) {
+        self.accept_limit = limit;
+    }
+
+    // Connections taken from the listener during the current tick, including
+    // the ones which were rejected or dropped right away
+    pub fn accepted_this_tick(&self) -> usize {
+        self.accepted_this_tick
+    }
+
+    pub fn pause_accepting(&mut self) {
+        self.accepting = false;
+    }
+
+    pub fn resume_accepting(&mut self) {
+        self.accepting = true;
+    }
+
+    pub fn ban(&mut self, addr: IpAddr, duration: Option<Duration>) {
+        let expires = duration.map(|duration| self.timer.now() + duration);
+        self.bans.insert(addr, expires);
+    }
+
+    pub fn unban(&mut self, addr: IpAddr) -> bool {
+        self.bans.remove(&addr).is_some()
+    }
+
+    pub fn bans(&self) -> impl Iterator<Item=(IpAddr, Option<Instant>)> + '_ {
+        let now = self.timer.now();
+        self.bans.iter().filter(move |&(_, expires)| {
+            expires.is_none_or(|expires| expires > now)
+
+        }).map(|(addr, expires)| (*addr, *expires))
+    }
+
+    // Remotes whose address has no IP are only kicked
+    pub fn kick_and_ban(&mut self, id: ConnectionId, duration: Option<Duration>) -> Result<(), Error> {
+        let addr = if let Some(&mut (ref mut remote, _)) = self.remote_mut(id) {
+            remote.close_with_code(CLOSE_BANNED).ok();
+            remote.peer_addr().ip()
+
+        } else {
+            return Err(Error::NotConnected);
+        };
+        if let Some(addr) = addr {
+            self.ban(addr, duration);
+        }
+        Ok(())
+    }
+
+    pub fn create_group(&mut self) -> GroupId {
+        let id = GroupId(self.next_group);
+        self.next_group += 1;
+        self.groups.insert(id, HashSet::new());
+        id
+    }
+
+    pub fn remove_group(&mut self, group: GroupId) -> bool {
+        self.groups.remove(&group).is_some()
+    }
+
+    pub fn add_to_group(&mut self, id: ConnectionId, group: GroupId) -> Result<(), Error> {
+        if !self.remotes.get(id).is_some_and(|entry| entry.0.open()) {
+            Err(Error::NotConnected)
+
+        } else if let Some(members) = self.groups.get_mut(&group) {
+            members.insert(id);
+            Ok(())
+
+        } else {
+            Err(Error::UnknownGroup)
+        }
+    }
+
+    pub fn remove_from_group(&mut self, id: ConnectionId, group: GroupId) -> bool {
+        self.groups.get_mut(&group).is_some_and(|members| members.remove(&id))
+    }
+
+    // Members which are already closing are skipped until closed() removes them
+    pub fn group_members(&self, group: GroupId) -> impl Iterator<Item=ConnectionId> + '_ {
+        let members = self.groups.get(&group);
+        self.remotes.iter().filter(move |entry| {
+            entry.0.open() && members.is_some_and(|members| members.contains(&entry.0.id()))
+
+        }).map(|entry| entry.0.id())
+    }
+
+    pub fn broadcast_group(&mut self, group: GroupId, message: Out) -> Result<usize, Error> {
+        let members = self.groups.get(&group).ok_or(Error::UnknownGroup)?;
+        encode_into::<E, _>(&message, self.config.max_message_size, &mut self.scratch)?;
+        let mut count = 0;
+        for &mut (ref mut remote, _) in &mut self.remotes {
+            if remote.open() && !remote.outgoing_full() && members.contains(&remote.id()) && remote.hooks.allows(remote.id, &message) {
+                remote.send_scratch(&self.scratch);
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    pub fn bytes_sent(&self) -> usize {
+        self.remotes.iter().fold(self.bytes_sent, |total, entry| total + entry.0.bytes_sent())
+    }
+
+    pub fn bytes_received(&self) -> usize {
+        self.remotes.iter().fold(self.bytes_received, |total, entry| total + entry.0.bytes_received())
+    }
+
+    // Totals of the last tick, see NetStats
+    pub fn stats(&self) -> NetStats {
+        self.stats
+    }
+
+    pub fn bytes_sent_per_second(&self) -> f64 {
+        self.remotes.iter().map(|entry| entry.0.bytes_sent_per_second()).sum()
+    }
+
+    pub fn bytes_received_per_second(&self) -> f64 {
+        self.remotes.iter().map(|entry| entry.0.bytes_received_per_second()).sum()
+    }
+
+    // Connections for which the closure returns an error are sent that message
+    // as a farewell and closed again
+    pub fn accepted_with<'a, C: FnMut(Addr<P>) -> Result<D, Out>>(&'a mut self, mut data: C) -> Accepted<'a, <<P as Protocol>::Host as Host>::Connection, In, Out, D, E> {
+
+        if !self.accepted_done {
+            self.accepted_done = true;
+            for mut remote in self.accept_connections() {
+                match data(remote.peer_addr()) {
+                    Ok(data) => self.promote(remote, data),
+                    Err(farewell) => {
+                        remote.send(farewell).ok();
+                        remote.close_with_code(CLOSE_REJECTED).ok();
+                        self.rejecting.push(remote);
+                    }
+                }
+            }
+        }
+
+        Accepted {
+            remotes: self.remotes.slots_mut()
+        }
+
+    }
+
+    // Like accepted_with() but new connections are held back until their first
+    // message arrives, which is then handed to the closure instead of receive();
+    // connections which send nothing within the timeout are closed again
+    pub fn accepted_with_handshake<'a, C: FnMut(Addr<P>, &In) -> Option<D>>(&'a mut self, timeout_ticks: u64, mut data: C) -> Accepted<'a, <<P as Protocol>::Host as Host>::Connection, In, Out, D, E> {
+
+        if !self.accepted_done {
+
+            self.accepted_done = true;
+
+            let deadline = self.ticks.saturating_add(timeout_ticks);
+            for mut remote in self.accept_connections() {
+                if self.paused {
+                    remote.pause();
+                }
+                self.pending.push((remote, deadline));
+            }
+
+            for (mut remote, deadline) in mem::take(&mut self.pending) {
+
+                remote.read_incoming(self.ticks);
+                let message = remote.receive().next();
+                if remote.queried() {
+                    let status = self.status();
+                    remote.answer_query(status);
+                    self.rejecting.push(remote);
+
+                } else if let Some(message) = message {
+                    if let Some(data) = data(remote.peer_addr(), &message) {
+                        self.promote(remote, data);
+
+                    } else {
+                        remote.close_with_code(CLOSE_REJECTED).ok();
+                        self.rejecting.push(remote);
+                    }
+
+                } else if !remote.open() {
+                    self.rejecting.push(remote);
+
+                } else if self.ticks >= deadline {
+                    remote.close_with_code(CLOSE_TIMEOUT).ok();
+                    self.rejecting.push(remote);
+
+                } else {
+                    self.pending.push((remote, deadline));
+                }
+
+            }
+
+        }
+
+        Accepted {
+            remotes: self.remotes.slots_mut()
+        }
+
+    }
+
+    pub fn connected<'a>(&'a mut self) -> Connected<'a, <<P as Protocol>::Host as Host>::Connection, In, Out, D, E> {
+
+        self.read_remotes();
+        Connected {
+            remotes: self.remotes.slots_mut()
+        }
+
+    }
+
+    pub fn remote_ids(&self) -> impl Iterator<Item=ConnectionId> + '_ {
+        self.remotes.iter().map(|entry| entry.0.id())
+    }
+
+    pub fn remote_mut(&mut self, id: ConnectionId) -> Option<&mut (Remote<<<P as Protocol>::Host as Host>::Connection, In, Out, E>, D)> {
+        self.remotes.get_mut(id)
+    }
+
+    // Gives access to all remotes with their data borrowed apart from them,
+    // without performing any reads or writes
+    pub fn remotes(&mut self) -> Remotes<'_, <<P as Protocol>::Host as Host>::Connection, In, Out, D, E> {
+        Remotes {
+            remotes: &mut self.remotes
+        }
+    }
+
+    pub fn closed(&mut self) -> Closed<<<P as Protocol>::Host as Host>::Connection, In, Out, D, E> {
+
+        self.write_remotes();
+
+        // Yielded in the order in which the remotes were accepted
+        let remotes = &self.remotes;
+        self.closed_ids.sort_unstable_by_key(|&id| remotes.slot(id));
+        let mut closed = Vec::new();
+        for id in mem::take(&mut self.closed_ids) {
+            let (remote, data) = match self.remotes.remove(id) {
+                Some(entry) => entry,
+                None => continue
+            };
+            for members in self.groups.values_mut() {
+                members.remove(&remote.id());
+            }
+            self.bytes_sent += remote.bytes_sent();
+            self.bytes_received += remote.bytes_received();
+            #[cfg(feature = "log")]
+            info!(
+                "Connection {} from {:?} closed: {}",
+                remote.id.0, remote.peer_addr, remote.close_reason.map_or_else(|| "unknown".to_string(), |reason| reason.to_string())
+            );
+            closed.push((remote.id(), remote, data));
+        }
+        self.remotes.compact();
+
+        Closed {
+            remotes: closed.into_iter()
+        }
+
+    }
+
+    // Equivalent to calling accepted_with(), connected() with receive() on every
+    // remote and closed() in sequence, collecting everything they yield
+    pub fn events<C: FnMut(Addr<P>) -> Result<D, Out>>(&mut self, accept: C) -> impl Iterator<Item=ServerEvent<In, D>> {
+
+        let mut events: Vec<ServerEvent<In, D>> = self.accepted_with(accept).map(|entry| {
+            ServerEvent::Accepted(entry.0.id())
+
+        }).collect();
+
+        for &mut (ref mut remote, _) in self.connected() {
+            let id = remote.id();
+            events.extend(remote.receive().map(|message| ServerEvent::Message(id, message)));
+        }
+
+        events.extend(self.closed().map(|(id, _, data)| ServerEvent::Closed(id, data)));
+        events.into_iter()
+
+    }
+
+    // Receives from all connected remotes in connection order, the messages
+    // are owned so the remotes can be borrowed again while handling them
+    pub fn messages(&mut self) -> impl Iterator<Item=(ConnectionId, In)> {
+        let mut messages = Vec::new();
+        for &mut (ref mut remote, _) in self.connected() {
+            let id = remote.id();
+            messages.extend(remote.receive().map(|message| (id, message)));
+        }
+        messages.into_iter()
+    }
+
+    pub fn broadcast(&mut self, message: Out) -> Result<usize, Error> {
+        self.broadcast_filter(message, |_, _| true)
+    }
+
+    // Sends the same pre-encoded message to all remotes, see Remote::send_bytes()
+    pub fn broadcast_bytes(&mut self, bytes: &[u8]) -> Result<usize, Error> {
+        let message = self.hooks.decode_outbound::<E>(bytes);
+        check_message_size(bytes.len(), self.config.max_message_size)?;
+        let mut count = 0;
+        for &mut (ref mut remote, _) in &mut self.remotes {
+            if remote.open() && !remote.outgoing_full() && message.as_ref().is_none_or(|message| remote.hooks.allows(remote.id, message)) {
+                remote.send_scratch(bytes);
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    pub fn broadcast_filter<F: FnMut(&Remote<<<P as Protocol>::Host as Host>::Connection, In, Out, E>, &D) -> bool>(&mut self, message: Out, mut filter: F) -> Result<usize, Error> {
+        encode_into::<E, _>(&message, self.config.max_message_size, &mut self.scratch)?;
+        let mut count = 0;
+        for &mut (ref mut remote, ref data) in &mut self.remotes {
+            if remote.open() && !remote.outgoing_full() && filter(remote, data) && remote.hooks.allows(remote.id, &message) {
+                remote.send_scratch(&self.scratch);
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    // Performs the read and write pass for all remotes which have not yet been
+    // handled this tick by connected() and closed()
+    pub fn flush(&mut self) {
+        self.read_remotes();
+        self.write_remotes();
+    }
+
+    // With the poll feature this returns early once data arrives, so it can be
+    // handled right away; writes still only happen once per tick
+    pub fn sleep(&mut self) {
+        if self.paused {
+            self.accepted_done = false;
+            self.connected_done = false;
+            self.timer.sleep();
+            return;
+        }
+        self.flush();
+        if let Some(beacon) = self.discovery.as_mut() {
+            beacon.poll();
+        }
+        self.accepted_done = false;
+        self.connected_done = false;
+        if self.wait() {
+            self.closed_done = false;
+            self.ticks += 1;
+
+            #[cfg(feature = "log")]
+            {
+                let report = self.timer.last_tick_report();
+                if report.overrun > Duration::new(0, 0) {
+                    debug!("Server tick {} overran its budget by {:?}, {} ticks behind", self.ticks, report.overrun, report.ticks_behind);
+                }
+            }
+        }
+    }
+
+    // For loops driven by an engine's frame rate, replaces sleep() and the
+    // accepted_with(), connected() and closed() steps; the two must not be
+    // mixed within a tick. Accepts and reads on every call, the tick itself is
+    // only processed once its time has passed and otherwise outgoing data is
+    // just flushed. Messages sent afterwards go out during the next update()
+    pub fn update<C: FnMut(Addr<P>) -> Result<D, Out>>(&mut self, accept: C) -> UpdateResult<ServerEvent<In, D>> {
+
+        // Paused servers keep the cadence without ever processing a tick
+        let ticked = self.timer.try_tick() && !self.paused;
+        self.accepted_done = false;
+        self.connected_done = false;
+
+        let mut events: Vec<ServerEvent<In, D>> = self.accepted_with(accept).map(|entry| {
+            ServerEvent::Accepted(entry.0.id())
+
+        }).collect();
+
+        for &mut (ref mut remote, _) in self.connected() {
+            let id = remote.id();
+            events.extend(remote.receive().map(|message| ServerEvent::Message(id, message)));
+        }
+
+        if ticked {
+            self.closed_done = false;
+
+        } else if !self.paused {
+            for &mut (ref mut remote, _) in &mut self.remotes {
+                remote.flush().ok();
+            }
+        }
+
+        events.extend(self.closed().map(|(id, _, data)| ServerEvent::Closed(id, data)));
+
+        if ticked {
+            if let Some(beacon) = self.discovery.as_mut() {
+                beacon.poll();
+            }
+            self.ticks += 1;
+        }
+
+        UpdateResult {
+            events: events,
+            ticked: ticked
+        }
+
+    }
+
+    // Answers discovery probes on the given UDP port with the listening port
+    // and the info, until the server is shut down
+    pub fn enable_discovery(&mut self, port: u16, info: Vec<u8>) -> Result<(), Error> {
+        let listen_port = self.local_addr()?.port().ok_or_else(|| {
+            IOError::new(ErrorKind::Unsupported, "address has no port to be discovered")
+        })?;
+        self.discovery = Some(Beacon::new(port, listen_port, info)?);
+        Ok(())
+    }
+
+    pub fn disable_discovery(&mut self) {
+        self.discovery = None;
+    }
+
+    // Runs for every message right before receive() would yield it
+    pub fn set_inbound_hook<F: FnMut(ConnectionId, &In) -> HookAction + Send + 'static>(&mut self, hook: F) where In: 'static {
+        self.hooks.inbound = Some(Arc::new(Mutex::new(hook)));
+        self.update_hooks();
+    }
+
+    // Messages for which the hook returns false are not sent, send() then
+    // fails with Error::Vetoed and broadcasts skip the remote
+    pub fn set_outbound_hook<F: FnMut(ConnectionId, &Out) -> bool + Send + 'static>(&mut self, hook: F) where Out: 'static {
+        self.hooks.outbound = Some(Arc::new(Mutex::new(hook)));
+        self.update_hooks();
+    }
+
+    pub fn clear_hooks(&mut self) {
+        self.hooks.inbound = None;
+        self.hooks.outbound = None;
+        self.update_hooks();
+    }
+
+    // Sees every byte read from and written to the remotes, stamped with the
+    // server's ticks, e.g. a FileTap recording sessions for a later Replay
+    pub fn set_tap<T: Tap + 'static>(&mut self, tap: T) {
+        self.hooks.tap = Some(Arc::new(Mutex::new(tap)));
+        self.update_hooks();
+    }
+
+    // Drops the tap once the remotes let go of it, which completes a FileTap
+    pub fn clear_tap(&mut self) {
+        self.hooks.tap = None;
+        self.update_hooks();
+    }
+
+    // Connections opened by query() receive the status and are closed right
+    // away, without ever being handed to the accept closure
+    pub fn set_status_provider<S: Serialize, F: FnMut() -> S + Send + 'static>(&mut self, mut provider: F) where E: 'static {
+        self.status_provider = Some(Box::new(move || E::encode(&provider()).ok()));
+        self.status = None;
+    }
+
+    // Control messages bypass the message types and hooks, like the pings of the
+    // crate itself; prefixes from 16 upwards are available to applications and
+    // control messages without a handler are skipped
+    pub fn register_control<M: Serialize + DeserializeOwned + 'static, F: FnMut(ConnectionId, M) + Send + 'static>(&mut self, prefix: u8, mut handler: F) -> Result<(), Error> where E: 'static {
+        check_control_prefix(prefix)?;
+        self.controls.insert(prefix, Box::new(move |id, bytes| {
+            handler(id, from_bytes::<E, M>(prefix, bytes)?);
+            Ok(())
+        }));
+        Ok(())
+    }
+
+    pub fn unregister_control(&mut self, prefix: u8) {
+        self.controls.remove(&prefix);
+    }
+
+    // Stops accepting and closes all remotes, which are then flushed and yielded
+    // by closed() as usual; remotes still open after the timeout are dropped
+    pub fn begin_shutdown(&mut self, timeout: Duration) -> Result<(), Error> {
+        if self.listener.is_some() || self.has_connections() {
+            if self.draining.is_none() {
+                self.draining = Some(self.timer.now() + timeout);
+                for &mut (ref mut remote, _) in &mut self.remotes {
+                    remote.close().ok();
+                }
+                for (mut remote, _) in self.pending.drain(0..) {
+                    remote.close_with_code(CLOSE_REJECTED).ok();
+                    self.rejecting.push(remote);
+                }
+                for mut remote in self.handshaking.drain(0..) {
+                    remote.close_with_code(CLOSE_REJECTED).ok();
+                    self.rejecting.push(remote);
+                }
+            }
+            Ok(())
+
+        } else {
+            Err(Error::NotBound)
+        }
+    }
+
+    // Returns true once all remotes have been closed and handed out by closed(),
+    // at which point the listener is shut down as well
+    pub fn shutdown_complete(&mut self) -> bool {
+        if self.draining.is_some() && !self.has_connections() {
+            self.shutdown_now().ok();
+            true
+
+        } else {
+            self.draining.is_none() && self.listener.is_none() && !self.has_connections()
+        }
+    }
+
+    // Closes all connections right away, dropping their remotes and any data
+    // which has not been sent yet
+    pub fn shutdown_now(&mut self) -> Result<(), Error> {
+        if self.listener.is_some() || self.draining.is_some() || self.has_connections() {
+            self.stop_polling();
+            self.draining = None;
+            self.discovery = None;
+            for &mut (ref mut remote, _) in &mut self.remotes {
+                remote.force_close();
+            }
+            for &mut (ref mut remote, _) in &mut self.pending {
+                remote.force_close();
+            }
+            for remote in &mut self.handshaking {
+                remote.force_close();
+            }
+            for remote in &mut self.rejecting {
+                remote.force_close();
+            }
+            self.closed_ids.clear();
+            self.remotes.clear();
+            self.pending.clear();
+            self.handshaking.clear();
+            self.rejecting.clear();
+            for members in self.groups.values_mut() {
+                members.clear();
+            }
+            if let Some(listener) = self.listener.take() {
+                listener.shutdown()?;
+            }
+            Ok(())
+
+        } else {
+            Err(Error::NotBound)
+        }
+    }
+
+
+    // Internal ---------------------------------------------------------------
+    fn has_connections(&self) -> bool {
+        !self.remotes.is_empty() || !self.pending.is_empty() || !self.handshaking.is_empty() || !self.rejecting.is_empty()
+    }
+
+    #[cfg(feature = "poll")]
+    fn start_polling(&mut self) {
+        // Without a working poll the server falls back to sleeping for whole ticks,
+        // remotes served across an unbind() stay registered with the existing one
+        if self.poller.is_none() {
+            self.poller = Poller::new().ok();
+        }
+        if let (Some(poller), Some(listener)) = (self.poller.as_ref(), self.listener.as_mut()) {
+            listener.register(poller.poll.registry(), LISTENER_TOKEN).ok();
+        }
+    }
+
+    #[cfg(feature = "poll")]
+    fn stop_polling(&mut self) {
+        self.poller = None;
+    }
+
+    #[cfg(not(feature = "poll"))]
+    fn start_polling(&mut self) {}
+
+    #[cfg(not(feature = "poll"))]
+    fn stop_polling(&mut self) {}
+
+    #[cfg(feature = "poll")]
+    fn wait(&mut self) -> bool {
+        if let Some(poller) = self.poller.as_mut() {
+            self.timer.sleep_with(|timeout| poller.wait(timeout))
+
+        } else {
+            self.timer.sleep();
+            true
+        }
+    }
+
+    #[cfg(not(feature = "poll"))]
+    fn wait(&mut self) -> bool {
+        self.timer.sleep();
+        true
+    }
+
+    // Accepts all waiting connections, those over the connection limit are
+    // rejected right away
+    fn accept_connections(&mut self) -> Vec<Remote<<<P as Protocol>::Host as Host>::Connection, In, Out, E>> {
+
+        let mut accepted = Vec::new();
+        let mut queries = Vec::new();
+        self.accepted_this_tick = 0;
+        if let (true, Some(listener)) = (self.accepting && self.draining.is_none(), self.listener.as_mut()) {
+
+            // Expired bans are only removed when they would affect a new connection
+            let now = self.timer.now();
+            self.bans.retain(|_, expires| expires.is_none_or(|expires| expires > now));
+
+            loop {
+
+                // Leave connections over either limit in the backlog of the host
+                let full = self.remotes.len() + self.pending.len() + self.handshaking.len() >= self.max_connections;
+                if (full && self.limit_policy == LimitPolicy::Backlog) || self.accepted_this_tick >= self.accept_limit {
+                    break;
+                }
+
+                let mut connection = match listener.accept() {
+                    Ok(connection) => {
+                        self.accepted_this_tick += 1;
+                        connection
+                    },
+                    Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+                    Err(ref err) if is_connection_error(err) => continue,
+                    Err(err) => {
+
+                        // Without a free descriptor the connection would stay
+                        // in the backlog and be returned again every tick
+                        #[cfg(unix)]
+                        {
+                            if is_descriptor_exhaustion(&err) {
+                                self.reserve = None;
+                                if let Ok(mut connection) = listener.accept() {
+                                    connection.shutdown().ok();
+                                }
+                                self.reserve = reserve_descriptor();
+                            }
+                        }
+
+                        #[cfg(feature = "log")]
+                        warn!("Server listener error: {}", err);
+
+                        self.listener_errors += 1;
+                        self.listener_error = Some(Error::Io(err));
+                        break;
+
+                    }
+                };
+
+                // Connections which were reset before we got their address are dropped
+                let peer_addr = match connection.peer_addr() {
+                    Ok(addr) => addr,
+                    Err(_) => {
+                        connection.shutdown().ok();
+                        continue;
+                    }
+                };
+
+                let bans = &self.bans;
+                if peer_addr.ip().is_some_and(|ip| bans.contains_key(&ip)) {
+                    #[cfg(feature = "log")]
+                    debug!("Connection from banned address {:?} dropped", peer_addr);
+                    connection.shutdown().ok();
+                    continue;
+                }
+
+                #[cfg(feature = "poll")]
+                {
+                    if let Some(poller) = self.poller.as_ref() {
+                        connection.register(poller.poll.registry(), Token(self.next_id as usize)).ok();
+                    }
+                }
+
+                let mut remote = Remote::from_connection(
+                    ConnectionId(self.next_id),
+                    connection,
+                    peer_addr,
+                    self.timer.clone(),
+                    self.config
+                );
+                remote.hooks = self.hooks.clone();
+                remote.hooks.on_connect(remote.id, self.ticks);
+                self.next_id += 1;
+
+                #[cfg(feature = "log")]
+                debug!("Connection {} from {:?} opened", remote.id.0, remote.peer_addr);
+
+                // Queries usually arrive together with the connection itself
+                remote.read_incoming(self.ticks);
+                if remote.queried() {
+                    queries.push(remote);
+
+                } else if full {
+                    #[cfg(feature = "log")]
+                    info!("Connection {} from {:?} rejected, the server is full", remote.id.0, remote.peer_addr);
+                    remote.close_with_code(CLOSE_REJECTED).ok();
+                    self.rejecting.push(remote);
+
+                } else {
+                    self.handshaking.push(remote);
+                }
+
+            }
+
+        }
+
+        // Connections are only accepted once their peer turned out to be compatible
+        for mut remote in mem::take(&mut self.handshaking) {
+            remote.read_incoming(self.ticks);
+            if remote.queried() {
+                queries.push(remote);
+
+            } else if let Some((protocol_version, version)) = remote.hello() {
+                remote.send_internal(self.hello(remote.stamping));
+                if protocol_version != PROTOCOL_VERSION || version != self.version {
+                    #[cfg(feature = "log")]
+                    info!(
+                        "Connection {} from {:?} rejected, protocol {} version {} does not match protocol {} version {}",
+                        remote.id.0, remote.peer_addr, protocol_version, version, PROTOCOL_VERSION, self.version
+                    );
+                    remote.close_with_code(CLOSE_VERSION_MISMATCH).ok();
+                    self.rejecting.push(remote);
+
+                } else {
+                    accepted.push(remote);
+                }
+
+            } else if !remote.open() {
+                self.rejecting.push(remote);
+
+            } else if remote.age() >= self.config.connection_timeout {
+                remote.close_with_code(CLOSE_TIMEOUT).ok();
+                self.rejecting.push(remote);
+
+            } else {
+                self.handshaking.push(remote);
+            }
+        }
+
+        for mut remote in queries {
+            let status = self.status();
+            remote.answer_query(status);
+            self.rejecting.push(remote);
+        }
+        accepted
+
+    }
+
+    // The provider is called at most once per tick
+    fn status(&mut self) -> Option<Vec<u8>> {
+        let ticks = self.ticks;
+        if self.status.as_ref().is_none_or(|&(t, _)| t != ticks) {
+            self.status = self.status_provider.as_mut().and_then(|provider| provider()).map(|bytes| (ticks, bytes));
+        }
+        self.status.as_ref().map(|(_, bytes)| bytes.clone())
+    }
+
+    fn promote(&mut self, mut remote: Remote<<<P as Protocol>::Host as Host>::Connection, In, Out, E>, data: D) {
+        if self.paused && !remote.timer.paused() {
+            remote.pause();
+        }
+        #[cfg(feature = "log")]
+        info!("Connection {} from {:?} accepted", remote.id.0, remote.peer_addr);
+        self.remotes.insert(remote.id, (remote, data));
+    }
+
+    // Lets clients know about our versions and supported features
+    fn hello(&self, stamping: bool) -> InternalMessage {
+        InternalMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            version: self.version,
+            ticks_per_second: self.timer.ticks_per_second(),
+            compression: compression::supported(),
+            stamping: stamping
+        }
+    }
+
+    fn read_remotes(&mut self) {
+        if !self.connected_done {
+            self.connected_done = true;
+            let ticks = self.ticks;
+            for &mut (ref mut remote, _) in &mut self.remotes {
+                remote.read(ticks);
+            }
+        }
+    }
+
+    fn write_remotes(&mut self) {
+        if !self.closed_done && !self.paused {
+            self.closed_done = true;
+            let ticks = self.ticks;
+
+            // Remotes which failed to close in time are dropped at the deadline
+            if self.draining.is_some_and(|deadline| self.timer.now() >= deadline) {
+                for &mut (ref mut remote, _) in &mut self.remotes {
+                    if !remote.closed() {
+                        remote.force_close();
+                    }
+                }
+            }
+
+            for &mut (ref mut remote, _) in &mut self.remotes {
+                remote.write(ticks);
+
+                let (controls, id) = (&mut self.controls, remote.id);
+                remote.incoming.dispatch_control(|prefix, bytes| match controls.get_mut(&prefix) {
+                    Some(handler) => handler(id, bytes),
+                    None => Ok(())
+                });
+
+                // Remotes closed during sleep() stay around until closed() is called
+                if remote.closed() && !self.closed_ids.contains(&remote.id) {
+                    self.closed_ids.push(remote.id);
+                }
+            }
+
+            // Queries which only arrived after their connection was accepted
+            if self.remotes.iter().any(|entry| entry.0.queried) {
+                let status = self.status();
+                for &mut (ref mut remote, _) in &mut self.remotes {
+                    if mem::take(&mut remote.queried) {
+                        remote.answer_query(status.clone());
+                    }
+                }
+            }
+
+            // Pending connections are kept alive until they get promoted
+            for &mut (ref mut remote, _) in &mut self.pending {
+                remote.write(ticks);
+            }
+            for remote in &mut self.handshaking {
+                remote.write(ticks);
+            }
+
+            // Rejected connections only linger until their farewell was flushed
+            for remote in &mut self.rejecting {
+                remote.read(ticks);
+                remote.write(ticks);
+                if remote.closed() {
+                    self.bytes_sent += remote.bytes_sent();
+                    self.bytes_received += remote.bytes_received();
+                }
+            }
+            self.rejecting.retain(|remote| !remote.closed());
+
+            let mut stats = NetStats::default();
+            for (remote, _) in self.remotes.iter() {
+                stats.add(&remote.stats);
+            }
+            for remote in self.pending.iter().map(|entry| &entry.0).chain(self.handshaking.iter()) {
+                let pending = NetStats {
+                    accepted: 0,
+                    connected: 0,
+                    pending: 1,
+                    .. remote.stats
+                };
+                stats.add(&pending);
+            }
+            for remote in &self.rejecting {
+                stats.add(&remote.stats);
+            }
+            self.stats = stats;
+
+            #[cfg(feature = "log")]
+            debug!("Server tick {}: {:?}", self.ticks, self.stats);
+
+        }
+    }
+
+    fn update_hooks(&mut self) {
+        for &mut (ref mut remote, _) in &mut self.remotes {
+            remote.hooks = self.hooks.clone();
+        }
+        for &mut (ref mut remote, _) in &mut self.pending {
+            remote.hooks = self.hooks.clone();
+        }
+        for remote in &mut self.handshaking {
+            remote.hooks = self.hooks.clone();
+        }
+        for remote in &mut self.rejecting {
+            remote.hooks = self.hooks.clone();
+        }
+    }
+
+    fn update_config(&mut self) {
+        for &mut (ref mut remote, _) in &mut self.remotes {
+            remote.set_config(self.config);
+        }
+        for &mut (ref mut remote, _) in &mut self.pending {
+            remote.set_config(self.config);
+        }
+        for remote in &mut self.handshaking {
+            remote.set_config(self.config);
+        }
+    }
+
+}
+
+impl<P: Protocol, In: Serialize + DeserializeOwned, Out: Serialize + DeserializeOwned, D, E: Codec> Drop for Server<P, In, Out, D, E> {
+    // Makes one last attempt at flushing every remote before closing it
+    fn drop(&mut self) {
+        self.shutdown_now().ok();
+    }
+}
+
+// Single line summaries which can be grepped for in logs, e.g.
+// Server(state=Listening addr=Some(127.0.0.1:7156) remotes=2 pending=0 handshaking=1 ticks=300)
+impl<P: Protocol, In: Serialize + DeserializeOwned, Out: Serialize + DeserializeOwned, D, E: Codec> fmt::Debug for Server<P, In, Out, D, E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = if self.listener.is_none() {
+            "Unbound"
+
+        } else if self.draining.is_some() {
+            "Draining"
+
+        } else if !self.accepting {
+            "Paused"
+
+        } else {
+            "Listening"
+        };
+        write!(
+            f, "Server(state={} addr={:?} remotes={} pending={} handshaking={} ticks={})",
+            state, self.local_addr().ok(), self.remotes.len(), self.pending.len(), self.handshaking.len(), self.ticks
+        )
+    }
+}
+
+// Connection Handoff ---------------------------------------------------------
+// Everything another process needs to take over a remote; the descriptor is
+// owned by whoever holds this until it is imported, and usually has a
+// different number once it was passed on with SCM_RIGHTS
+#[cfg(unix)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedRemote {
+    fd: RawFd,
+    id: ConnectionId,
+    data: Vec<u8>,
+    timer: TimerState,
+    age: Duration,
+    ticks: u64,
+    messages_sent: u64,
+    next_ack: u32,
+    last_acked: Option<u32>,
+    peer_compression: bool,
+    peer_stamping: bool,
+    incoming: Vec<u8>,
+    fragments: Vec<u8>
+}
+
+#[cfg(unix)]
+impl ExportedRemote {
+
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    pub fn set_fd(&mut self, fd: RawFd) {
+        self.fd = fd;
+    }
+
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+}
+
+#[cfg(unix)]
+impl<In: Serialize + DeserializeOwned, Out: Serialize + DeserializeOwned, D: Serialize + DeserializeOwned, E: Codec> Server<TCP, In, Out, D, E> {
+
+    // Hands the open remotes over to another process, their peers are paused
+    // until the remotes were imported there. Remotes are only exported once
+    // everything queued for them was written, the others stay and can be
+    // exported with a later call; encrypted remotes always stay. Groups are
+    // left behind as well.
+    pub fn export_remotes(&mut self) -> Result<Vec<ExportedRemote>, Error> {
+
+        // Nothing is removed unless the data of all remotes could be encoded
+        let mut exportable = Vec::new();
+        for &mut (ref remote, ref data) in &mut self.remotes {
+            if remote.open() && remote.config.psk.is_none() {
+                exportable.push((remote.id, E::encode(data)?));
+            }
+        }
+
+        let mut exported = Vec::new();
+        for (id, data) in exportable {
+
+            // Remotes of a paused server are paused already and stay that way
+            if let Some(&mut (ref mut remote, _)) = self.remotes.get_mut(id) {
+                if !self.paused {
+                    remote.pause();
+                }
+                if !remote.outgoing.is_empty() {
+                    if !self.paused {
+                        remote.resume();
+                    }
+                    continue;
+                }
+            }
+
+            let (mut remote, _) = match self.remotes.remove(id) {
+                Some(entry) => entry,
+                None => continue
+            };
+            for members in self.groups.values_mut() {
+                members.remove(&id);
+            }
+            self.bytes_sent += remote.bytes_sent();
+            self.bytes_received += remote.bytes_received();
+
+            #[cfg(feature = "log")]
+            info!("Connection {} from {:?} exported", remote.id.0, remote.peer_addr);
+
+            let (incoming, fragments) = remote.incoming.take_pending();
+            let state = ExportedRemote {
+                fd: -1,
+                id: id,
+                data: data,
+                timer: remote.timer.state(),
+                age: remote.age(),
+                ticks: remote.ticks,
+                messages_sent: remote.messages_sent,
+                next_ack: remote.next_ack,
+                last_acked: remote.last_acked.map(|id| id.0),
+                peer_compression: remote.peer_compression,
+                peer_stamping: remote.incoming.stamped(),
+                incoming: incoming,
+                fragments: fragments
+            };
+            if let Some(fd) = remote.connection.into_raw_fd() {
+                exported.push(ExportedRemote {
+                    fd: fd,
+                    .. state
+                });
+            }
+
+        }
+
+        self.remotes.compact();
+        Ok(exported)
+
+    }
+
+    /// Takes over remotes exported by another server, which resume right where
+    /// they were paused. Fails without taking any of the descriptors when one of
+    /// the ids is already in use, by a remote or a connection which was not yet
+    /// accepted, or appears twice. Remotes which cannot be restored are closed
+    /// and the first error is returned once all the others were imported.
+    ///
+    /// # Safety
+    /// Each descriptor must be a connected TCP socket which is owned by nothing else.
+    pub unsafe fn import_remotes(&mut self, remotes: Vec<ExportedRemote>) -> Result<(), Error> {
+
+        let mut ids: HashSet<ConnectionId> = self.remotes.iter().map(|entry| entry.0.id)
+            .chain(self.pending.iter().map(|entry| entry.0.id))
+            .chain(self.handshaking.iter().chain(self.rejecting.iter()).map(|remote| remote.id))
+            .collect();
+
+        if !remotes.iter().all(|exported| ids.insert(exported.id)) {
+            return Err(Error::Io(IOError::new(ErrorKind::AlreadyExists, "connection id is already in use")));
+        }
+
+        let mut result = Ok(());
+        for exported in remotes {
+
+            let connection = TcpConnection::from_raw_fd(exported.fd);
+            let (connection, data) = match (connection, E::decode::<D>(&exported.data)) {
+                (Ok(connection), Ok((data, _))) => (connection, data),
+                (Err(err), _) => {
+                    result = result.and(Err(Error::Io(err)));
+                    continue;
+                },
+                (_, Err(err)) => {
+                    result = result.and(Err(Error::Serialization(err)));
+                    continue;
+                }
+            };
+
+            let peer_addr = match connection.peer_addr() {
+                Ok(addr) => addr,
+                Err(err) => {
+                    result = result.and(Err(Error::Io(err)));
+                    continue;
+                }
+            };
+
+            #[cfg(feature = "poll")]
+            let mut connection = connection;
+            #[cfg(feature = "poll")]
+            {
+                if let Some(poller) = self.poller.as_ref() {
+                    connection.register(poller.poll.registry(), Token(exported.id.0 as usize)).ok();
+                }
+            }
+
+            let mut remote = Remote::from_connection(exported.id, connection, peer_addr, self.timer.clone(), self.config);
+            remote.hooks = self.hooks.clone();
+            remote.timer.restore(&exported.timer);
+            remote.connected_at = remote.timer.now().checked_sub(exported.age).unwrap_or(remote.connected_at);
+            remote.ticks = exported.ticks;
+            remote.seen_at_tick = exported.ticks;
+            remote.messages_sent = exported.messages_sent;
+            remote.next_ack = exported.next_ack;
+            remote.last_acked = exported.last_acked.map(AckId);
+            remote.peer_compression = exported.peer_compression;
+            remote.update_compression();
+            remote.incoming.set_stamped(exported.peer_stamping);
+            remote.incoming.restore_pending(exported.incoming, exported.fragments);
+            remote.state = RemoteState::Connected;
+            remote.resume();
+            self.next_id = self.next_id.max(exported.id.0 + 1);
+
+            #[cfg(feature = "log")]
+            info!("Connection {} from {:?} imported", remote.id.0, remote.peer_addr);
+
+            self.remotes.insert(remote.id, (remote, data));
+
+        }
+
+        result
+
+    }
+
+}
+
+
+// Statistics -----------------------------------------------------------------
+// Totals across all remotes for the last tick, pending remotes are those
+// still waiting on their hello or on accepted_with_handshake()
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct NetStats {
+    pub bytes_sent: usize,
+    pub bytes_received: usize,
+    pub frames_sent: u64,
+    pub frames_received: u64,
+    pub pending_outgoing_bytes: usize,
+    pub pending_incoming_bytes: usize,
+    pub pending_internal_messages: usize,
+    pub dropped_messages: u32,
+    pub accepted: usize,
+    pub connected: usize,
+    pub closing: usize,
+    pub pending: usize
+}
+
+impl NetStats {
+
+    fn add(&mut self, other: &NetStats) {
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+        self.frames_sent += other.frames_sent;
+        self.frames_received += other.frames_received;
+        self.pending_outgoing_bytes += other.pending_outgoing_bytes;
+        self.pending_incoming_bytes += other.pending_incoming_bytes;
+        self.pending_internal_messages += other.pending_internal_messages;
+        self.dropped_messages += other.dropped_messages;
+        self.accepted += other.accepted;
+        self.connected += other.connected;
+        self.closing += other.closing;
+        self.pending += other.pending;
+    }
+
+}
+
+
+// Remote Storage -------------------------------------------------------------
+// Entries keep their slot until they are removed, so looking them up by id and
+// removing them never moves any of the others; since ids are never reused they
+// also serve as the generation of their slot. Iteration follows the order of
+// insertion, the slots left behind are compacted once they make up most of
+// the storage
+struct Slots<T> {
+    slots: Vec<Option<T>>,
+    ids: Vec<ConnectionId>,
+    index: HashMap<ConnectionId, usize>
+}
+
+impl<T> Slots<T> {
+
+    fn new() -> Self {
+        Self {
+            slots: Vec::new(),
+            ids: Vec::new(),
+            index: HashMap::new()
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    fn insert(&mut self, id: ConnectionId, value: T) {
+        self.index.insert(id, self.slots.len());
+        self.slots.push(Some(value));
+        self.ids.push(id);
+    }
+
+    fn slot(&self, id: ConnectionId) -> Option<usize> {
+        self.index.get(&id).cloned()
+    }
+
+    fn get(&self, id: ConnectionId) -> Option<&T> {
+        self.slot(id).and_then(|slot| self.slots[slot].as_ref())
+    }
+
+    fn get_mut(&mut self, id: ConnectionId) -> Option<&mut T> {
+        self.slot(id).and_then(move |slot| self.slots[slot].as_mut())
+    }
+
+    // Returns None if any of the ids is unknown or given more than once
+    fn get_disjoint_mut<const N: usize>(&mut self, ids: [ConnectionId; N]) -> Option<[&mut T; N]> {
+        let mut slots = [0; N];
+        for (slot, id) in slots.iter_mut().zip(ids.iter()) {
+            *slot = self.slot(*id)?;
+        }
+        let entries = self.slots.get_disjoint_mut(slots).ok()?;
+        Some(entries.map(|entry| entry.as_mut().expect("indexed slots are occupied")))
+    }
+
+    fn remove(&mut self, id: ConnectionId) -> Option<T> {
+        self.index.remove(&id).and_then(|slot| self.slots[slot].take())
+    }
+
+    fn clear(&mut self) {
+        self.slots.clear();
+        self.ids.clear();
+        self.index.clear();
+    }
+
+    fn compact(&mut self) {
+        if self.slots.len() > MIN_COMPACTED_SLOTS && self.slots.len() > self.index.len() * 2 {
+            let slots = mem::take(&mut self.slots);
+            let ids = mem::take(&mut self.ids);
+            self.index.clear();
+            for (value, id) in slots.into_iter().zip(ids) {
+                if let Some(value) = value {
+                    self.insert(id, value);
+                }
+            }
+        }
+    }
+
+    fn iter(&self) -> iter::Flatten<slice::Iter<'_, Option<T>>> {
+        self.slots.iter().flatten()
+    }
+
+    fn iter_mut(&mut self) -> iter::Flatten<slice::IterMut<'_, Option<T>>> {
+        self.slots.iter_mut().flatten()
+    }
+
+    fn slots_mut(&mut self) -> slice::IterMut<'_, Option<T>> {
+        self.slots.iter_mut()
+    }
+
+}
+
+impl<'a, T> IntoIterator for &'a mut Slots<T> {
+    type Item = &'a mut T;
+    type IntoIter = iter::Flatten<slice::IterMut<'a, Option<T>>>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+
+// Remote Views ---------------------------------------------------------------
+pub struct Remotes<'a, C: Connection + 'a, In: Serialize + DeserializeOwned + 'a, Out: Serialize + DeserializeOwned + 'a, D: 'a, E: Codec + 'a> {
+    remotes: &'a mut Slots<(Remote<C, In, Out, E>, D)>
+}
+
+impl<'a, C: Connection, In: Serialize + DeserializeOwned, Out: Serialize + DeserializeOwned, D, E: Codec> Remotes<'a, C, In, Out, D, E> {
+
+    pub fn len(&self) -> usize {
+        self.remotes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remotes.is_empty()
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item=(&mut Remote<C, In, Out, E>, &mut D)> {
+        self.remotes.iter_mut().map(|&mut (ref mut remote, ref mut data)| (remote, data))
+    }
+
+    pub fn get_mut(&mut self, id: ConnectionId) -> Option<(&mut Remote<C, In, Out, E>, &mut D)> {
+        self.remotes.get_mut(id).map(|&mut (ref mut remote, ref mut data)| (remote, data))
+    }
+
+    // Returns None if any of the ids is unknown or given more than once
+    pub fn get_many_mut<const N: usize>(&mut self, ids: [ConnectionId; N]) -> Option<[(&mut Remote<C, In, Out, E>, &mut D); N]> {
+        let entries = self.remotes.get_disjoint_mut(ids)?;
+        Some(entries.map(|&mut (ref mut remote, ref mut data)| (remote, data)))
+    }
+
+    // Remotes and their data at the same indexes, so either side can be
+    // borrowed independently of the other
+    pub fn split(&mut self) -> (Vec<&mut Remote<C, In, Out, E>>, Vec<&mut D>) {
+        self.iter_mut().unzip()
+    }
+
+}
+
+
+// Iterators ------------------------------------------------------------------
+// Remotes are yielded in the order in which they were accepted, both within a
+// tick and across them
+pub struct Accepted<'a, C: Connection + 'a, In: Serialize + DeserializeOwned + 'a, Out: Serialize + DeserializeOwned + 'a, D: 'a, E: Codec + 'a> {
+    remotes: slice::IterMut<'a, Option<(Remote<C, In, Out, E>, D)>>
+}
+
+impl<'a, C: Connection, In: Serialize + DeserializeOwned, Out: Serialize + DeserializeOwned, D, E: Codec> Iterator for Accepted<'a, C, In, Out, D, E> {
+    type Item = &'a mut (Remote<C, In, Out, E>, D);
+    fn next(&mut self) -> Option<Self::Item> {
+        self.remotes.find_map(|slot| slot.as_mut().filter(|entry| entry.0.accepted()))
+    }
+}
+
+pub struct Connected<'a, C: Connection + 'a, In: Serialize + DeserializeOwned + 'a, Out: Serialize + DeserializeOwned + 'a, D: 'a, E: Codec + 'a> {
+    remotes: slice::IterMut<'a, Option<(Remote<C, In, Out, E>, D)>>
+}
+
+impl<'a, C: Connection, In: Serialize + DeserializeOwned, Out: Serialize + DeserializeOwned, D, E: Codec> Iterator for Connected<'a, C, In, Out, D, E> {
+    type Item = &'a mut (Remote<C, In, Out, E>, D);
+    fn next(&mut self) -> Option<Self::Item> {
+        self.remotes.find_map(|slot| slot.as_mut().filter(|entry| entry.0.connected()))
+    }
+}
+
+pub struct Closed<C: Connection, In: Serialize + DeserializeOwned, Out: Serialize + DeserializeOwned, D, E: Codec> {
+    remotes: vec::IntoIter<(ConnectionId, Remote<C, In, Out, E>, D)>
+}
+
+impl<C: Connection, In: Serialize + DeserializeOwned, Out: Serialize + DeserializeOwned, D, E: Codec> Iterator for Closed<C, In, Out, D, E> {
+    type Item = (ConnectionId, Remote<C, In, Out, E>, D);
+    fn next(&mut self) -> Option<Self::Item> {
+        self.remotes.next()
+    }
+}
+
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LimitPolicy {
+    Backlog,
+    Reject
+}
+
+#[derive(Copy, Clone)]
+struct RemoteConfig {
+    max_message_size: usize,
+    max_outgoing_size: usize,
+    connection_timeout: Duration,
+    slow_consumer_ticks: Option<u64>,
+    strict: bool,
+    late_policy: LatePolicy,
+    priority_channels: PriorityChannels,
+    queue_policies: QueuePolicies,
+    fragment_size: usize,
+    batching: bool,
+    stamping: bool,
+    max_incoming_size: usize,
+    read_limit: Option<usize>,
+    message_rate_limit: Option<(u32, u32)>,
+    flood_threshold: Option<u32>,
+    compression_threshold: Option<usize>,
+    psk: Option<[u8; KEY_SIZE]>,
+    timing: TimingConfig
+}
+
+#[derive(Debug, Eq, PartialEq)]
+enum RemoteState {
+    Accepted,
+    Connected,
+    Closing,
+    Closed
+}
+
+pub struct Remote<C: Connection, In: Serialize + DeserializeOwned, Out: Serialize + DeserializeOwned, E: Codec = BincodeCodec> {
+    id: ConnectionId,
+    connection: C,
+    peer_addr: C::Addr,
+    incoming: Incoming<InternalMessage>,
+    outgoing: Outgoing,
+    outgoing_full_ticks: u64,
+    send_limit: RateLimit,
+    send_allowance: SendAllowance,
+    queued_at_tick: usize,
+    message_limit: MessageLimit,
+    next_ack: u32,
+    last_acked: Option<AckId>,
+    acks_reported: u32,
+    config: RemoteConfig,
+    connected_at: Instant,
+    messages_sent: u64,
+    timer: Timer,
+    ticks: u64,
+    server_ticks: u64,
+    seen_at_tick: u64,
+    sent: Throughput,
+    received: Throughput,
+    state: RemoteState,
+    close_ticks: u8,
+    close_acknowledged: bool,
+    close_reason: Option<CloseReason>,
+    queried: bool,
+    peer_compression: bool,
+    stamping: bool,
+    stats: NetStats,
+    hooks: Hooks<In, Out>,
+    rejected: bool,
+    incoming_message: PhantomData<In>,
+    outgoing_message: PhantomData<Out>,
+    // Codecs are never instantiated, so they have no say in whether this is Send
+    codec: PhantomData<fn() -> E>
+}
+
+impl<C: Connection, In: Serialize + DeserializeOwned, Out: Serialize + DeserializeOwned, E: Codec> Remote<C, In, Out, E> {
+
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    pub fn rtt(&self) -> f64 {
+        self.timer.rtt()
+    }
+
+    // Whether enough pings were answered for rtt() to be meaningful
+    pub fn rtt_is_stable(&self) -> bool {
+        self.timer.rtt_is_stable()
+    }
+
+    pub fn jitter(&self) -> f64 {
+        self.timer.jitter()
+    }
+
+    // Set once the peer missed enough pongs, see TimingConfig::suspect_intervals
+    pub fn suspect(&self) -> bool {
+        self.timer.suspect()
+    }
+
+    // Ticks since the last byte was received
+    pub fn last_seen(&self) -> u64 {
+        self.ticks - self.seen_at_tick
+    }
+
+    pub fn rtt_bounds(&self) -> (f64, f64) {
+        self.timer.rtt_bounds()
+    }
+
+    pub fn quality(&self) -> Option<ConnectionQuality> {
+        self.timer.quality()
+    }
+
+    pub fn clock(&self) -> f64 {
+        self.timer.clock()
+    }
+
+    pub fn clock_deviation(&self) -> f64 {
+        self.timer.clock_deviation()
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    pub fn outgoing_len(&self) -> usize {
+        self.outgoing.len()
+    }
+
+    // Same as outgoing_len(), for symmetry with pending_incoming_bytes()
+    pub fn pending_outgoing_bytes(&self) -> usize {
+        self.outgoing.len()
+    }
+
+    // Bytes which were received but not yet handed out by receive()
+    pub fn pending_incoming_bytes(&self) -> usize {
+        self.incoming.pending_bytes()
+    }
+
+    // Pings, acks and other internal messages waiting to be handled on the next tick
+    pub fn pending_internal_messages(&self) -> usize {
+        self.incoming.pending_internal()
+    }
+
+    pub fn messages_sent(&self) -> u64 {
+        self.messages_sent
+    }
+
+    // Frames written so far, below messages_sent() when messages were batched
+    pub fn frames_sent(&self) -> u64 {
+        self.outgoing.total_frames_sent()
+    }
+
+    // Messages dropped for exceeding the message rate limit
+    pub fn dropped_messages(&self) -> u64 {
+        self.message_limit.dropped()
+    }
+
+    // Outgoing messages replaced by newer ones according to their queue policy
+    pub fn dropped_frames(&self) -> u64 {
+        self.outgoing.dropped_frames()
+    }
+
+    // Caps the bytes written to the connection per second, everything above
+    // the limit stays buffered until send() reports a full buffer
+    pub fn set_send_limit(&mut self, bytes_per_second: Option<usize>) {
+        self.send_limit.set(bytes_per_second);
+    }
+
+    // Bytes which can still be written during the current tick, if limited
+    pub fn send_budget(&self) -> Option<usize> {
+        self.send_limit.budget()
+    }
+
+    // Recommends how many bytes to send per tick based on the RTT trend,
+    // nothing is ever dropped when the application sends more than that
+    pub fn set_adaptive_send(&mut self, config: Option<AdaptiveSend>) {
+        self.send_allowance.set(config);
+    }
+
+    // Bytes recommended for the current tick, unlimited without adaptive sending
+    pub fn send_allowance(&self) -> usize {
+        self.send_allowance.allowance()
+    }
+
+    // The RTT the allowance is judged against, None until the RTT is stable
+    pub fn send_baseline_rtt(&self) -> Option<f64> {
+        self.send_allowance.baseline()
+    }
+
+    // Number of ticks during which more bytes were queued than allowed
+    pub fn send_allowance_exceeded(&self) -> u64 {
+        self.send_allowance.exceeded()
+    }
+
+    // Number of reads which stopped early because of the incoming limits
+    pub fn reads_throttled(&self) -> u64 {
+        self.incoming.reads_throttled()
+    }
+
+    // Bytes which compression kept off the wire so far
+    pub fn compressed_bytes_saved(&self) -> usize {
+        self.outgoing.compressed_bytes_saved()
+    }
+
+    pub fn messages_received(&self) -> u64 {
+        self.incoming.messages()
+    }
+
+    pub fn connected_at(&self) -> Instant {
+        self.connected_at
+    }
+
+    pub fn age(&self) -> Duration {
+        self.timer.now().duration_since(self.connected_at)
+    }
+
+    pub fn bytes_sent(&self) -> usize {
+        self.sent.total()
+    }
+
+    pub fn bytes_received(&self) -> usize {
+        self.received.total()
+    }
+
+    pub fn bytes_sent_per_second(&self) -> f64 {
+        self.sent.per_second(self.timer.ticks_per_second())
+    }
+
+    pub fn bytes_received_per_second(&self) -> f64 {
+        self.received.per_second(self.timer.ticks_per_second())
+    }
 
+    pub fn peer_addr(&self) -> C::Addr {
+        self.peer_addr.clone()
+    }
 
-// Server Abstraction ---------------------------------------------------------
-pub struct Server<P: Protocol, M: Serialize + DeserializeOwned, D> {
-    listener: Option<P::Host>,
-    remotes: Vec<(Remote<<<P as Protocol>::Host as Host>::Connection, M>, D)>,
-    closed_indexes: Vec<usize>,
-    timer: Timer,
-    accepted_done: bool,
-    connected_done: bool,
-    closed_done: bool
-}
+    pub fn send(&mut self, message: Out) -> Result<(), Error> {
+        self.send_on(0, message)
+    }
 
-impl<P: Protocol, M: Serialize + DeserializeOwned, D> Server<P, M, D> {
+    // Messages keep their order within a channel, channel 0 is used by send()
+    pub fn send_on(&mut self, channel: u8, message: Out) -> Result<(), Error> {
+        if self.outgoing_full() {
+            Err(Error::BufferFull)
 
-    pub fn new(ticks_per_second: u8) -> Self {
-        Self {
-            listener: None,
-            timer: Timer::new(ticks_per_second),
-            remotes: Vec::new(),
-            closed_indexes: Vec::new(),
-            accepted_done: false,
-            connected_done: false,
-            closed_done: false
+        } else if !self.hooks.allows(self.id, &message) {
+            Err(Error::Vetoed)
+
+        } else {
+            let bytes = self.outgoing.encode::<E, _>(&message, self.config.max_message_size)?;
+            self.send_message(channel, bytes);
+            Ok(())
         }
     }
 
-    pub fn bind<A: ToSocketAddrs>(&mut self, addr: A) -> Result<(), IOError> {
-        if self.listener.is_none() {
-            let listener = P::Host::bind(addr)?;
-            self.listener = Some(listener);
-            self.timer.reset();
-            Ok(())
+    // Control messages are neither counted as messages nor seen by the hooks,
+    // see Server::register_control()
+    pub fn send_control<M: Serialize>(&mut self, prefix: u8, message: M) -> Result<(), Error> {
+        check_control_prefix(prefix)?;
+        if self.outgoing_full() {
+            Err(Error::BufferFull)
+
+        } else if !self.open() {
+            Err(Error::NotConnected)
 
         } else {
-            Err(IOError::new(ErrorKind::AlreadyExists, ""))
+            let bytes = self.outgoing.encode::<E, _>(&message, self.config.max_message_size)?;
+            self.outgoing.push_frame(prefix, bytes);
+            Ok(())
         }
     }
 
-    pub fn accepted_with<'a, C: FnMut(SocketAddr) -> Option<D>>(&'a mut self, mut data: C) -> Box<Iterator<Item=&mut (Remote<<<P as Protocol>::Host as Host>::Connection, M>, D)> + 'a> {
+    // Sends bytes which were encoded beforehand as a message on channel 0, they
+    // must be a valid encoding of a message unless the peer uses receive_raw()
+    pub fn send_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        if self.outgoing_full() {
+            Err(Error::BufferFull)
 
-        if !self.accepted_done {
+        } else if self.hooks.decode_outbound::<E>(bytes).is_some_and(|message| !self.hooks.allows(self.id, &message)) {
+            Err(Error::Vetoed)
 
-            self.accepted_done = true;
+        } else {
+            let bytes = self.outgoing.encode_raw(bytes, self.config.max_message_size)?;
+            self.send_message(0, bytes);
+            Ok(())
+        }
+    }
 
-            // Accept new connections
-            if let Some(listener) = self.listener.as_mut() {
-                while let Ok(mut connection) = listener.accept() {
-                    if let Some(data) = data(connection.peer_addr().unwrap()) {
-                        let remote = Remote::from_connection(
-                            connection,
-                            self.timer.clone()
-                        );
-                        self.remotes.push((remote, data));
+    // The peer acknowledges these messages once its application received them,
+    // they are not resent but reported by acked()
+    pub fn send_acked(&mut self, message: Out) -> Result<AckId, Error> {
+        if self.outgoing_full() {
+            Err(Error::BufferFull)
 
-                    } else {
-                        connection.shutdown().ok();
-                    }
-                }
-            }
+        } else if !self.hooks.allows(self.id, &message) {
+            Err(Error::Vetoed)
 
+        } else {
+            let bytes = self.outgoing.encode::<E, _>(&message, self.config.max_message_size)?;
+            let id = AckId(self.next_ack);
+            self.next_ack = self.next_ack.wrapping_add(1);
+            self.messages_sent += 1;
+            self.outgoing.push_acked_frame(id, bytes);
+            Ok(id)
         }
-
-        Box::new(self.remotes.iter_mut().filter(|entry| entry.0.accepted() ))
-
     }
 
-    pub fn connected<'a>(&'a mut self) -> Box<Iterator<Item=&mut (Remote<<<P as Protocol>::Host as Host>::Connection, M>, D)> + 'a> {
+    // The message is held back by the peer's receive_for_tick() until it
+    // reaches the given tick
+    pub fn send_at_tick(&mut self, tick: u64, message: Out) -> Result<(), Error> {
+        if self.outgoing_full() {
+            Err(Error::BufferFull)
 
-        if !self.connected_done {
+        } else if !self.hooks.allows(self.id, &message) {
+            Err(Error::Vetoed)
 
-            self.connected_done = true;
+        } else {
+            let bytes = self.outgoing.encode::<E, _>(&message, self.config.max_message_size)?;
+            self.messages_sent += 1;
+            self.outgoing.push_scheduled_frame(tick, bytes);
+            Ok(())
+        }
+    }
 
-            for &mut (ref mut remote, _) in &mut self.remotes {
-                if self.listener.is_some() {
-                    remote.read();
-                }
-            }
+    // Yields the acked messages which were processed by the peer since the last call
+    pub fn acked(&mut self) -> impl Iterator<Item=AckId> {
+        let end = self.last_acked.map_or(0, |id| id.0.wrapping_add(1));
+        let start = mem::replace(&mut self.acks_reported, end);
+        (0..end.wrapping_sub(start)).map(move |offset| AckId(start.wrapping_add(offset)))
+    }
 
-        }
+    pub fn last_acked(&self) -> Option<AckId> {
+        self.last_acked
+    }
 
-        Box::new(self.remotes.iter_mut().filter(|remote| remote.0.connected() ))
+    pub fn receive(&mut self) -> MessageIterator<'_, In, InternalMessage, E> {
+        let filter = inbound_filter(self.id, &self.hooks.inbound, &mut self.rejected, &mut self.message_limit);
+        create_message_iterator(&mut self.incoming, filter)
+    }
 
+    // Like receive() but also yields the channel of each message
+    pub fn receive_channels(&mut self) -> ChannelIterator<'_, In, InternalMessage, E> {
+        let filter = inbound_filter(self.id, &self.hooks.inbound, &mut self.rejected, &mut self.message_limit);
+        create_channel_iterator(&mut self.incoming, filter)
     }
 
-    pub fn closed<'a>(&'a mut self) -> Box<Iterator<Item=(Remote<<<P as Protocol>::Host as Host>::Connection, M>, D)> + 'a> {
+    // Like receive() but leaves decoding the messages to the application
+    pub fn receive_raw(&mut self) -> RawIterator<'_, InternalMessage, E> {
+        let filter = raw_filter::<In, E, _>(self.id, &self.hooks.inbound, &mut self.rejected, &mut self.message_limit);
+        create_raw_iterator(&mut self.incoming, filter)
+    }
 
-        if !self.closed_done {
-            self.closed_done = true;
-            for (index, &mut (ref mut remote, _)) in self.remotes.iter_mut().enumerate() {
-                remote.write();
-                if remote.closed() {
-                    self.closed_indexes.push(index);
-                }
-            }
-        }
+    // Like receive() but decodes into types which borrow from the incoming
+    // buffer, e.g. with &[u8] fields, instead of copying out of it; inbound
+    // hooks still see the owned messages
+    pub fn receive_borrowed<'a, M: Deserialize<'a>>(&'a mut self) -> BorrowedIterator<'a, M, E> where E: BorrowCodec {
+        let filter = raw_filter::<In, E, _>(self.id, &self.hooks.inbound, &mut self.rejected, &mut self.message_limit);
+        create_borrowed_iterator::<M, InternalMessage, E>(&mut self.incoming, filter)
+    }
 
-        let mut closed = Vec::new();
-        for (offset, index) in self.closed_indexes.iter().enumerate() {
-            closed.push(self.remotes.swap_remove(index - offset));
-        }
-        self.closed_indexes.clear();
+    // Like receive() but also yields how long ago the peer sent each message,
+    // which requires the peer to have stamping enabled
+    pub fn receive_stamped(&mut self) -> StampedIterator<'_, In, InternalMessage, E> {
+        let clock = self.timer.peer_clock();
+        let filter = inbound_filter(self.id, &self.hooks.inbound, &mut self.rejected, &mut self.message_limit);
+        create_stamped_iterator(&mut self.incoming, filter, clock)
+    }
 
-        Box::new(closed.into_iter())
+    // Whether the peer stamps its messages with its tick
+    pub fn stamped(&self) -> bool {
+        self.incoming.stamped()
+    }
 
+    // Yields the messages sent with send_at_tick() for this and all earlier
+    // ticks along with their tick, messages for later ticks are held back
+    pub fn receive_for_tick(&mut self, tick: u64) -> impl Iterator<Item=(u64, In)> {
+        self.incoming.extract_frames::<E>();
+        let messages = self.incoming.drain_scheduled::<E, In>(tick);
+        let mut filter = inbound_filter(self.id, &self.hooks.inbound, &mut self.rejected, &mut self.message_limit);
+        let messages: Vec<(u64, In)> = messages.into_iter().filter(|(_, message)| {
+            filter.as_mut().is_none_or(|filter| filter(message))
+
+        }).collect();
+        messages.into_iter()
     }
 
-    pub fn sleep(&mut self) {
-        self.accepted_done = false;
-        self.connected_done = false;
-        self.closed_done = false;
-        self.timer.sleep();
+    pub fn decode_errors(&mut self) -> impl Iterator<Item=DecodeError> {
+        self.incoming.drain_decode_errors().into_iter()
     }
 
-    pub fn shutdown(&mut self) -> Result<(), IOError> {
-        if self.listener.take().is_some() {
-            for &mut (ref mut remote, _) in &mut self.remotes {
-                remote.close().ok();
-                remote.try_close();
-            }
-            self.closed_indexes.clear();
-            self.remotes.clear();
-            Ok(())
+    pub fn requests(&mut self) -> impl Iterator<Item=(RequestId, In)> {
+        self.incoming.extract_frames::<E>();
+        self.incoming.drain_correlated::<E, In>(2).into_iter()
+    }
+
+    pub fn respond(&mut self, id: RequestId, message: Out) -> Result<(), Error> {
+        if self.outgoing_full() {
+            Err(Error::BufferFull)
+
+        } else if !self.hooks.allows(self.id, &message) {
+            Err(Error::Vetoed)
 
         } else {
-            Err(IOError::new(ErrorKind::NotConnected, ""))
+            let bytes = self.outgoing.encode::<E, _>(&message, self.config.max_message_size)?;
+            self.outgoing.push_correlated_frame(3, id, bytes);
+            self.messages_sent += 1;
+            Ok(())
         }
     }
 
-}
+    pub fn close(&mut self) -> Result<(), Error> {
+        self.close_with_code(0)
+    }
 
-#[derive(Eq, PartialEq)]
-enum RemoteState {
-    Accepted,
-    Connected,
-    Closing,
-    Closed
-}
+    // Known as soon as the remote started closing, so it is always set for
+    // the remotes yielded by closed()
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.close_reason
+    }
 
-pub struct Remote<C: Connection, M: Serialize + DeserializeOwned> {
-    connection: C,
-    incoming: Vec<u8>,
-    outgoing: Vec<u8>,
-    internal_messages: Vec<InternalMessage>,
-    timer: Timer,
-    state: RemoteState,
-    message: PhantomData<M>
-}
+    // Writes as much of the outgoing buffer as the connection accepts right now,
+    // instead of waiting for the next tick
+    pub fn flush(&mut self) -> Result<usize, Error> {
+        if self.closed() {
+            Err(Error::NotConnected)
 
-impl<C: Connection, M: Serialize + DeserializeOwned> Remote<C, M> {
+        } else if self.outgoing.is_empty() || self.send_limit.available() == 0 {
+            Ok(0)
 
-    pub fn rtt(&self) -> f64 {
-        self.timer.rtt()
+        } else {
+            let bytes = if self.hooks.tap.is_some() {
+                let (hooks, id, ticks) = (&self.hooks, self.id, self.server_ticks);
+                self.outgoing.write_tapped(&mut self.connection, self.send_limit.available(), |bytes| hooks.on_write(id, ticks, bytes))?
+
+            } else {
+                self.outgoing.write_to(&mut self.connection, self.send_limit.available())?
+            };
+            for seq in self.outgoing.written_pings() {
+                self.timer.ping_written(seq);
+            }
+            self.send_limit.consume(bytes);
+            self.sent.add(bytes);
+            Ok(bytes)
+        }
     }
 
-    pub fn clock(&self) -> f64 {
-        self.timer.clock()
-    }
+    // Queues a final message, e.g. the reason for a kick, which is sent before
+    // the connection gets closed
+    pub fn close_with(&mut self, message: Out) -> Result<(), Error> {
+        if self.open() {
+            if self.hooks.allows(self.id, &message) {
+                let bytes = self.outgoing.encode::<E, _>(&message, self.config.max_message_size)?;
+                self.send_message(0, bytes);
+            }
+            self.close()
 
-    pub fn peer_addr(&self) -> SocketAddr {
-        self.connection.peer_addr().unwrap()
+        } else {
+            Err(Error::NotConnected)
+        }
     }
 
-    pub fn send(&mut self, message: M) {
-        self.send_raw(1, message)
+    // The connection is shut down once the peer acknowledged the close and
+    // everything queued before it has been sent, or after a few ticks
+    pub fn close_with_code(&mut self, code: u8) -> Result<(), Error> {
+        self.close_with_reason(code, local_close_reason(code))
     }
 
-    pub fn receive(&mut self) -> MessageIterator<M, InternalMessage> {
-        create_message_iterator(&mut self.incoming, &mut self.internal_messages)
-    }
 
-    pub fn close(&mut self) -> Result<(), IOError> {
+    // Internal ---------------------------------------------------------------
+    fn close_with_reason(&mut self, code: u8, reason: CloseReason) -> Result<(), Error> {
         match self.state {
             RemoteState::Accepted | RemoteState::Connected => {
+                #[cfg(feature = "log")]
+                debug!("Connection {} from {:?} closing: {}", self.id.0, self.peer_addr, reason);
                 self.state = RemoteState::Closing;
+                self.close_reason = Some(reason);
+                if let Ok(bytes) = self.outgoing.encode::<E, _>(&InternalMessage::Close(code), self.config.max_message_size) {
+                    self.outgoing.push_last_frame(0, bytes);
+                }
                 Ok(())
             },
-            RemoteState::Closing | RemoteState::Closed => Err(IOError::new(ErrorKind::NotConnected, ""))
+            RemoteState::Closing | RemoteState::Closed => Err(Error::NotConnected)
         }
     }
 
+    fn read(&mut self, server_ticks: u64) {
+        self.try_connect();
+        self.read_incoming(server_ticks);
+    }
 
-    // Internal ---------------------------------------------------------------
-    fn read(&mut self) {
+    // Reads without completing the connection, so the remote is still yielded
+    // by accepted_with_handshake() once it gets promoted
+    fn read_incoming(&mut self, server_ticks: u64) {
 
-        self.try_connect();
+        self.server_ticks = server_ticks;
+        if self.stamping {
+            self.outgoing.set_stamp(Some(server_ticks as u32));
+        }
+        let limit = self.incoming.read_limit();
+        if limit == 0 {
+            self.incoming.received(0, limit);
+            return;
+        }
 
-        if self.connection.read(&mut self.incoming).is_err() {
-            self.close().ok();
+        let buffer = self.incoming.buffer_mut();
+        match self.connection.read(buffer, limit) {
+            Ok(bytes) => {
+                if self.hooks.tap.is_some() {
+                    self.hooks.on_read(self.id, server_ticks, &buffer[buffer.len() - bytes..]);
+                }
+                self.incoming.received(bytes, limit);
+                self.received.add(bytes);
+                if bytes > 0 {
+                    self.seen_at_tick = self.ticks;
+                }
+            },
+            Err(err) => {
+                // The connection is gone, so there's no point in waiting for the close handshake
+                self.close_with_reason(0, io_close_reason(err.kind())).ok();
+                self.outgoing.clear();
+                self.close_acknowledged = true;
+            }
         }
 
     }
 
-    fn write(&mut self) {
+    fn write(&mut self, server_ticks: u64) {
+
+        self.server_ticks = server_ticks;
+        self.outgoing.tick();
+        self.incoming.extract_frames::<E>();
+
+        if self.incoming.violation() {
+            #[cfg(feature = "log")]
+            warn!("Connection {} from {:?} violated the protocol", self.id.0, self.peer_addr);
+            self.close_with_code(CLOSE_PROTOCOL_ERROR).ok();
+        }
+
+        // Closes requested by the inbound hook
+        if mem::take(&mut self.rejected) {
+            self.close().ok();
+        }
+
+        if self.message_limit.flooded() {
+            self.close_with_code(CLOSE_FLOOD).ok();
+        }
+
+        let mut messages = Vec::new();
+        for m in self.incoming.drain_internal() {
+            match m {
+                InternalMessage::Close(code) => self.receive_close(code),
+                InternalMessage::Ack(id) => self.last_acked = Some(AckId(id)),
+                InternalMessage::Query => self.queried = true,
+                InternalMessage::Hello { compression, .. } => {
+                    self.peer_compression = compression;
+                    self.update_compression();
+                },
+                m => messages.push(m)
+            }
+        }
+
+        if self.open() {
+            for m in self.timer.receive(messages, server_ticks) {
+                self.send_internal(m);
+            }
+
+            // Report the acked messages the application went through this tick
+            if let Some(id) = self.incoming.take_ack() {
+                self.send_internal(InternalMessage::Ack(id));
+            }
 
-        let messages = self.internal_messages.drain(0..).collect::<Vec<_>>();
-        for m in self.timer.receive(messages) {
-            self.send_raw(0, m);
+            // Peers which stopped answering our pings are considered gone
+            if self.timer.timed_out(self.config.connection_timeout) || self.timer.dead() {
+                self.close_with_code(CLOSE_TIMEOUT).ok();
+                self.close_acknowledged = true;
+            }
         }
 
-        if !self.outgoing.is_empty() && self.connection.write(&self.outgoing[..]).is_ok() {
-            self.outgoing.clear();
+        self.send_limit.tick(self.timer.ticks_per_second());
+        self.flush().ok();
+
+        // Everything queued since the last tick, whether it was written yet or not
+        let queued = (self.outgoing.len() + self.sent.current()).saturating_sub(self.queued_at_tick);
+        let rtt = if self.timer.rtt_is_stable() { Some(self.timer.rtt()) } else { None };
+        self.send_allowance.tick(queued, rtt, self.timer.ticks_per_second());
+        self.queued_at_tick = self.outgoing.len();
+
+        // Kick peers which fail to drain their buffer for too long
+        if self.outgoing_full() {
+            #[cfg(feature = "log")]
+            {
+                if self.outgoing_full_ticks == 0 {
+                    warn!("Connection {} from {:?} filled its outgoing buffer of {} bytes", self.id.0, self.peer_addr, self.config.max_outgoing_size);
+                }
+            }
+            self.outgoing_full_ticks += 1;
+            if self.config.slow_consumer_ticks.is_some_and(|ticks| self.outgoing_full_ticks >= ticks) {
+                self.close_with_code(CLOSE_SLOW_CONSUMER).ok();
+                self.close_acknowledged = true;
+            }
+
+        } else {
+            self.outgoing_full_ticks = 0;
         }
 
+        self.stats = NetStats {
+            bytes_sent: self.sent.current(),
+            bytes_received: self.received.current(),
+            frames_sent: self.outgoing.take_frames_sent(),
+            frames_received: self.incoming.take_frames_received(),
+            pending_outgoing_bytes: self.outgoing.len(),
+            pending_incoming_bytes: self.incoming.pending_bytes(),
+            pending_internal_messages: self.incoming.pending_internal(),
+            dropped_messages: self.message_limit.dropped_this_tick(),
+            accepted: if self.state == RemoteState::Accepted { 1 } else { 0 },
+            connected: if self.state == RemoteState::Connected { 1 } else { 0 },
+            closing: if self.state == RemoteState::Closing { 1 } else { 0 },
+            pending: 0
+        };
+
+        self.sent.tick();
+        self.message_limit.tick(self.timer.ticks_per_second());
+        self.received.tick();
+        self.incoming.tick();
+        self.ticks += 1;
+
         self.try_close();
 
     }
 
-    fn from_connection(connection: C, timer: Timer) -> Self {
+    fn from_connection(
+        id: ConnectionId,
+        connection: C,
+        peer_addr: C::Addr,
+        mut timer: Timer,
+        config: RemoteConfig
+
+    ) -> Self {
+        timer.set_timing(config.timing);
+        let mut incoming = Incoming::new(config.max_message_size);
+        incoming.set_strict(config.strict);
+        incoming.set_late_policy(config.late_policy);
+        incoming.set_max_incoming_size(config.max_incoming_size);
+        incoming.set_read_limit(config.read_limit);
+        incoming.set_encryption(config.psk);
+        let mut outgoing = Outgoing::new();
+        outgoing.set_fragment_size(config.fragment_size);
+        outgoing.set_batching(config.batching);
+        outgoing.set_encryption(config.psk);
+        if config.stamping {
+            outgoing.set_stamp(Some(0));
+        }
+        let mut message_limit = MessageLimit::new();
+        message_limit.set(config.message_rate_limit, config.flood_threshold);
         Self {
+            id: id,
             connection: connection,
-            incoming: Vec::new(),
-            outgoing: Vec::new(),
-            internal_messages: Vec::new(),
+            peer_addr: peer_addr,
+            incoming: incoming,
+            outgoing: outgoing,
+            outgoing_full_ticks: 0,
+            send_limit: RateLimit::new(),
+            send_allowance: SendAllowance::new(),
+            queued_at_tick: 0,
+            seen_at_tick: 0,
+            message_limit: message_limit,
+            next_ack: 0,
+            last_acked: None,
+            acks_reported: 0,
+            config: config,
+            connected_at: timer.now(),
+            messages_sent: 0,
+            sent: Throughput::new(timer.ticks_per_second()),
+            received: Throughput::new(timer.ticks_per_second()),
             timer: timer,
+            ticks: 0,
+            server_ticks: 0,
             state: RemoteState::Accepted,
-            message: PhantomData
+            close_ticks: 0,
+            close_acknowledged: false,
+            close_reason: None,
+            queried: false,
+            peer_compression: false,
+            stamping: config.stamping,
+            stats: NetStats::default(),
+            hooks: Hooks::new(),
+            rejected: false,
+            incoming_message: PhantomData,
+            outgoing_message: PhantomData,
+            codec: PhantomData
+        }
+    }
+
+    // Copies a message which was encoded once for all remotes
+    fn send_scratch(&mut self, bytes: &[u8]) {
+        if let Ok(bytes) = self.outgoing.encode_raw(bytes, usize::MAX) {
+            self.send_message(0, bytes);
         }
     }
 
-    fn send_raw<T: Serialize + DeserializeOwned>(&mut self, prefix: u8, message: T) {
-        if let Ok(bytes) = serialize(&message, Infinite) {
-            self.outgoing.push(prefix);
-            self.outgoing.extend(bytes);
+    // Internal messages must never be prevented by a full buffer, nor wait
+    // behind the application frames queued so far
+    fn send_internal(&mut self, message: InternalMessage) {
+        if let Ok(bytes) = self.outgoing.encode::<E, _>(&message, self.config.max_message_size) {
+            match message {
+                InternalMessage::Ping(seq, _) => self.outgoing.push_ping_frame(seq, bytes),
+                _ => self.outgoing.push_internal_frame(bytes)
+            }
         }
     }
 
+    fn set_config(&mut self, config: RemoteConfig) {
+        self.config = config;
+        self.incoming.set_max_message_size(config.max_message_size);
+        self.incoming.set_strict(config.strict);
+        self.incoming.set_late_policy(config.late_policy);
+        self.incoming.set_max_incoming_size(config.max_incoming_size);
+        self.incoming.set_read_limit(config.read_limit);
+        self.outgoing.set_fragment_size(config.fragment_size);
+        self.outgoing.set_batching(config.batching);
+        self.message_limit.set(config.message_rate_limit, config.flood_threshold);
+        self.timer.set_timing(config.timing);
+        self.update_compression();
+    }
+
+    fn update_compression(&mut self) {
+        let threshold = self.config.compression_threshold.filter(|_| self.peer_compression);
+        self.outgoing.set_compression(threshold);
+    }
+
+    fn outgoing_full(&self) -> bool {
+        self.outgoing.len() >= self.config.max_outgoing_size
+    }
+
+    fn send_message(&mut self, channel: u8, bytes: Vec<u8>) {
+        #[cfg(feature = "log")]
+        trace!("Connection {} queued {} bytes on channel {}", self.id.0, bytes.len(), channel);
+        self.messages_sent += 1;
+        self.outgoing.push_message(channel, self.config.priority_channels.contains(channel), self.config.queue_policies.max_frames(channel), bytes);
+    }
+
+    fn open(&self) -> bool {
+        self.state == RemoteState::Accepted || self.state == RemoteState::Connected
+    }
+
     fn accepted(&self) -> bool {
         self.state == RemoteState::Accepted
     }
@@ -263,17 +2832,441 @@ impl<C: Connection, M: Serialize + DeserializeOwned> Remote<C, M> {
     }
 
     fn try_connect(&mut self)  {
-        if self.accepted() {
+        // Protocols with a handshake of their own connect once it has completed
+        if self.accepted() && self.connection.connect_poll().unwrap_or(false) {
             self.state = RemoteState::Connected;
         }
     }
 
+    fn receive_close(&mut self, code: u8) {
+        match self.state {
+            // Acknowledge close requests from the peer
+            RemoteState::Accepted | RemoteState::Connected => {
+                self.close_with_reason(code, CloseReason::RemoteClose(code)).ok();
+                self.close_acknowledged = true;
+            },
+            RemoteState::Closing => {
+                self.close_acknowledged = true;
+            },
+            RemoteState::Closed => {}
+        }
+    }
+
     fn try_close(&mut self) {
         if self.state == RemoteState::Closing {
-            self.connection.shutdown().ok();
-            self.state = RemoteState::Closed;
+            self.close_ticks = self.close_ticks.saturating_add(1);
+            if (self.close_acknowledged && self.outgoing.is_empty()) || self.close_ticks > CLOSE_TIMEOUT_TICKS {
+                self.connection.shutdown().ok();
+                self.state = RemoteState::Closed;
+            }
+        }
+    }
+
+    // Versions announced by the peer, once its hello arrived
+    fn hello(&mut self) -> Option<(u32, u32)> {
+        self.incoming.extract_frames::<E>();
+        let mut hello = None;
+        self.incoming.remove_internal(|m| match *m {
+            InternalMessage::Hello { protocol_version, version, compression, stamping, .. } => {
+                hello = Some((protocol_version, version, compression, stamping));
+                true
+            },
+            _ => false
+        });
+        hello.map(|(protocol_version, version, compression, stamping)| {
+            self.peer_compression = compression;
+            self.update_compression();
+            self.incoming.set_stamped(stamping);
+            (protocol_version, version)
+        })
+    }
+
+    // Whether the peer only connected in order to query the server status
+    fn queried(&mut self) -> bool {
+        self.incoming.extract_frames::<E>();
+        self.incoming.remove_internal(|m| matches!(*m, InternalMessage::Query))
+    }
+
+    fn answer_query(&mut self, status: Option<Vec<u8>>) {
+        if let Some(bytes) = status {
+            self.send_internal(InternalMessage::Status(bytes));
+        }
+        self.close().ok();
+    }
+
+    // The frame has to go out right away, as nothing else is written until
+    // the server resumes
+    fn pause(&mut self) {
+        if self.open() {
+            self.send_internal(InternalMessage::Pause);
+            self.flush().ok();
+        }
+        self.timer.pause();
+    }
+
+    fn resume(&mut self) {
+        self.timer.resume(false);
+        if self.open() {
+            self.send_internal(InternalMessage::Resume);
         }
     }
 
+    fn force_close(&mut self) {
+        self.close().ok();
+        self.flush().ok();
+        self.outgoing.clear();
+        self.connection.shutdown().ok();
+        self.state = RemoteState::Closed;
+    }
+
+}
+
+// Remote(id=3 state=Connected peer=127.0.0.1:50312 rtt=12.4ms clock=1.2ms outgoing=0 incoming=18 ticks=240)
+impl<C: Connection, In: Serialize + DeserializeOwned, Out: Serialize + DeserializeOwned, E: Codec> fmt::Debug for Remote<C, In, Out, E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f, "Remote(id={} state={:?} peer={:?} rtt={:.1}ms clock={:.1}ms outgoing={} incoming={} ticks={})",
+            self.id.0, self.state, self.peer_addr, self.timer.rtt(), self.timer.clock(), self.outgoing.len(), self.incoming.pending_bytes(), self.ticks
+        )
+    }
 }
 
+
+
+// Tests ----------------------------------------------------------------------
+#[cfg(test)]
+mod test {
+
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use std::net::SocketAddr;
+    use std::io::{Error as IOError, ErrorKind, IoSlice};
+
+    use ::client::{Client, ClientState};
+    use ::error::Error;
+    use ::time::MockClock;
+    use ::protocol::{Connection, Host, Protocol, TCP, TcpConfig, TcpConnection, TcpHost};
+    use ::message::AckId;
+    use super::{ConnectionId, Server};
+
+    // TCP, but the address of the first accepted connection cannot be
+    // determined, as if it was reset right away
+    struct Unaddressed;
+    impl Protocol for Unaddressed {
+        type Host = UnaddressedHost;
+        type Connection = UnaddressedConnection;
+    }
+
+    struct UnaddressedHost {
+        host: TcpHost,
+        accepted: usize
+    }
+
+    impl Host for UnaddressedHost {
+
+        type Connection = UnaddressedConnection;
+
+        fn bind(addrs: &[SocketAddr], config: &TcpConfig) -> Result<Self, IOError> {
+            Ok(Self {
+                host: TcpHost::bind(addrs, config)?,
+                accepted: 0
+            })
+        }
+
+        fn accept(&mut self) -> Result<UnaddressedConnection, IOError> {
+            let connection = self.host.accept()?;
+            self.accepted += 1;
+            Ok(UnaddressedConnection {
+                connection: connection,
+                reset: self.accepted == 1
+            })
+        }
+
+        fn local_addr(&self) -> Result<SocketAddr, IOError> {
+            self.host.local_addr()
+        }
+
+        fn shutdown(self) -> Result<(), IOError> {
+            self.host.shutdown()
+        }
+
+    }
+
+    struct UnaddressedConnection {
+        connection: TcpConnection,
+        reset: bool
+    }
+
+    impl Connection for UnaddressedConnection {
+
+        type Addr = SocketAddr;
+        type Config = TcpConfig;
+
+        fn connect(addrs: &[SocketAddr], timeout: Duration, config: &TcpConfig) -> Result<Self, IOError> {
+            Ok(Self {
+                connection: TcpConnection::connect(addrs, timeout, config)?,
+                reset: false
+            })
+        }
+
+        fn peer_addr(&self) -> Result<SocketAddr, IOError> {
+            if self.reset {
+                Err(IOError::new(ErrorKind::NotConnected, "reset"))
+
+            } else {
+                self.connection.peer_addr()
+            }
+        }
+
+        fn local_addr(&self) -> Result<SocketAddr, IOError> {
+            self.connection.local_addr()
+        }
+
+        fn read(&mut self, buffer: &mut Vec<u8>, max_bytes: usize) -> Result<usize, IOError> {
+            self.connection.read(buffer, max_bytes)
+        }
+
+        fn write(&mut self, bytes: &[u8]) -> Result<usize, IOError> {
+            self.connection.write(bytes)
+        }
+
+        fn write_vectored(&mut self, slices: &[IoSlice]) -> Result<usize, IOError> {
+            self.connection.write_vectored(slices)
+        }
+
+        fn shutdown(&mut self) -> Result<(), IOError> {
+            self.connection.shutdown()
+        }
+
+    }
+
+    type TestServer = Server<Unaddressed, (), (), SocketAddr>;
+
+    // Ticks the client and the server until the condition holds
+    fn tick<F: FnMut(&mut TestServer, &mut Client<TCP, ()>) -> bool>(server: &mut TestServer, client: &mut Client<TCP, ()>, mut done: F) {
+        for _ in 0..1000 {
+            if done(server, client) {
+                return;
+            }
+            for _ in server.connected() {}
+            for _ in server.closed() {}
+            server.sleep();
+            client.receive().map(|messages| messages.count()).ok();
+            client.sleep();
+            thread::sleep(Duration::from_millis(1));
+        }
+        panic!("condition not met within 1000 ticks");
+    }
+
+    #[test]
+    fn remotes_without_an_address_are_skipped() {
+
+        let mut server = TestServer::with_clock(30.0, Arc::new(MockClock::new()));
+        server.bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+
+        // The connection is shut down without ever becoming a remote
+        let mut reset = Client::with_clock(30.0, Arc::new(MockClock::new()));
+        reset.connect(addr, Duration::from_millis(500)).unwrap();
+        tick(&mut server, &mut reset, |server, client| {
+            assert_eq!(server.accepted_with(Ok).count(), 0);
+            client.state() == ClientState::Lost
+        });
+
+        // Later connections are still accepted
+        let mut client = Client::with_clock(30.0, Arc::new(MockClock::new()));
+        client.connect(addr, Duration::from_millis(500)).unwrap();
+        let mut accepted = Vec::new();
+        tick(&mut server, &mut client, |server, _| {
+            accepted.extend(server.accepted_with(Ok).map(|entry| entry.1));
+            !accepted.is_empty()
+        });
+        assert_eq!(accepted, vec![client.local_addr().unwrap()]);
+        assert_eq!(server.remotes().len(), 1);
+
+    }
+
+    type TcpServer = Server<TCP, u8, u8, char>;
+
+    // Runs the step until it returns true
+    fn run<F: FnMut() -> bool>(mut step: F) {
+        for _ in 0..1000 {
+            if step() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        panic!("condition not met within 1000 steps");
+    }
+
+    // Connects a client whose remote is labeled 'a'
+    fn accept(server: &mut TcpServer) -> Client<TCP, u8> {
+        let mut client = Client::with_clock(30.0, Arc::new(MockClock::new()));
+        client.connect(server.local_addr().unwrap(), Duration::from_millis(500)).unwrap();
+        run(|| {
+            for _ in server.accepted_with(|_| Ok('a')) {}
+            for _ in server.connected() {}
+            for _ in server.closed() {}
+            server.sleep();
+            client.receive().map(|messages| messages.count()).ok();
+            client.sleep();
+            client.state() == ClientState::Connected && server.remotes.len() == 1
+        });
+        client
+    }
+
+    fn already_in_use(result: Result<(), Error>) -> bool {
+        match result {
+            Err(Error::Io(err)) => err.kind() == ErrorKind::AlreadyExists,
+            _ => false
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn remotes_are_imported_by_another_server() {
+
+        let mut old = TcpServer::with_clock(30.0, Arc::new(MockClock::new()));
+        old.bind("127.0.0.1:0").unwrap();
+
+        let mut client = accept(&mut old);
+
+        // This one is still waiting on its first message, with the same id
+        let mut new = TcpServer::with_clock(30.0, Arc::new(MockClock::new()));
+        new.bind("127.0.0.1:0").unwrap();
+
+        let mut waiting: Client<TCP, u8> = Client::with_clock(30.0, Arc::new(MockClock::new()));
+        waiting.connect(new.local_addr().unwrap(), Duration::from_millis(500)).unwrap();
+        run(|| {
+            for _ in new.accepted_with_handshake(1000, |_, _| Some('w')) {}
+            new.sleep();
+            waiting.receive().map(|messages| messages.count()).ok();
+            waiting.sleep();
+            new.pending.len() == 1
+        });
+
+        let exported = old.export_remotes().unwrap();
+        assert_eq!(exported.iter().map(|remote| remote.id()).collect::<Vec<_>>(), vec![ConnectionId(0)]);
+        assert_eq!(old.remotes.len(), 0);
+
+        assert!(already_in_use(unsafe { new.import_remotes(exported.clone()) }));
+        assert_eq!(new.remotes.len(), 0);
+        assert_eq!(new.pending.len(), 1);
+
+        // Neither may the same id appear twice
+        let mut fresh = TcpServer::with_clock(30.0, Arc::new(MockClock::new()));
+        fresh.bind("127.0.0.1:0").unwrap();
+        assert!(already_in_use(unsafe { fresh.import_remotes(vec![exported[0].clone(), exported[0].clone()]) }));
+        assert_eq!(fresh.remotes.len(), 0);
+
+        unsafe { fresh.import_remotes(exported) }.unwrap();
+        assert_eq!(fresh.remote_mut(ConnectionId(0)).map(|entry| entry.1), Some('a'));
+
+        // The client keeps talking to the imported remote over the same connection
+        fresh.remote_mut(ConnectionId(0)).unwrap().0.send(7).unwrap();
+        client.send(9).unwrap();
+
+        let (mut received, mut replied) = (Vec::new(), Vec::new());
+        run(|| {
+            for &mut (ref mut remote, _) in fresh.connected() {
+                received.extend(remote.receive());
+            }
+            for _ in fresh.closed() {}
+            fresh.sleep();
+            if let Ok(messages) = client.receive() {
+                replied.extend(messages);
+            }
+            client.sleep();
+            !received.is_empty() && !replied.is_empty()
+        });
+        assert_eq!(received, vec![9]);
+        assert_eq!(replied, vec![7]);
+        assert_eq!(client.state(), ClientState::Connected);
+        assert_eq!(fresh.next_id, 1);
+
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn remotes_of_a_paused_server_stay_paused_when_they_cannot_be_exported() {
+
+        let mut server = TcpServer::with_clock(30.0, Arc::new(MockClock::new()));
+        server.bind("127.0.0.1:0").unwrap();
+
+        let mut client = accept(&mut server);
+
+        server.pause();
+        run(|| {
+            server.sleep();
+            client.receive().map(|messages| messages.count()).ok();
+            client.sleep();
+            client.server_paused()
+        });
+
+        // The message stays buffered until the server resumes
+        server.remote_mut(ConnectionId(0)).unwrap().0.send(7).unwrap();
+        assert_eq!(server.export_remotes().unwrap().len(), 0);
+        assert!(server.remote_mut(ConnectionId(0)).unwrap().0.timer.paused());
+
+        let mut received = Vec::new();
+        for _ in 0..10 {
+            server.sleep();
+            if let Ok(messages) = client.receive() {
+                received.extend(messages);
+            }
+            client.sleep();
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(client.server_paused());
+        assert!(received.is_empty());
+
+        server.resume();
+        run(|| {
+            for _ in server.connected() {}
+            for _ in server.closed() {}
+            server.sleep();
+            if let Ok(messages) = client.receive() {
+                received.extend(messages);
+            }
+            client.sleep();
+            !received.is_empty()
+        });
+        assert_eq!(received, vec![7]);
+        assert!(!client.server_paused());
+
+    }
+
+    #[test]
+    fn acks_are_reported_across_the_wrap_of_their_ids() {
+
+        let mut server = TcpServer::with_clock(30.0, Arc::new(MockClock::new()));
+        server.bind("127.0.0.1:0").unwrap();
+        let mut client = accept(&mut server);
+
+        {
+            let remote = &mut server.remote_mut(ConnectionId(0)).unwrap().0;
+            // As if all the earlier ids had been used and acked already
+            remote.next_ack = u32::MAX - 1;
+            remote.acks_reported = u32::MAX - 1;
+            for message in 0..3 {
+                remote.send_acked(message).unwrap();
+            }
+        }
+
+        let mut acked = Vec::new();
+        run(|| {
+            for &mut (ref mut remote, _) in server.connected() {
+                acked.extend(remote.acked());
+            }
+            for _ in server.closed() {}
+            server.sleep();
+            client.receive().map(|messages| messages.count()).ok();
+            client.sleep();
+            acked.len() >= 3
+        });
+        assert_eq!(acked, vec![AckId(u32::MAX - 1), AckId(u32::MAX), AckId(0)]);
+
+    }
+
+}
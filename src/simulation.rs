@@ -0,0 +1,336 @@
+// Copyright (c) 2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::cmp;
+use std::marker::PhantomData;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::io::{Error as IOError, IoSlice};
+use std::time::{self, Duration, Instant};
+
+
+// External Dependencies ------------------------------------------------------
+#[cfg(feature = "poll")]
+use mio::{Registry, Token};
+
+
+// Internal Dependencies ------------------------------------------------------
+use ::protocol::{Protocol, Host, Connection};
+use ::message::{frame_length, FRAME_HEADER_SIZE};
+
+
+// Statics --------------------------------------------------------------------
+static DEFAULT_CONFIG: Mutex<Option<SimulationConfig>> = Mutex::new(None);
+
+
+// Simulation Configuration ---------------------------------------------------
+#[derive(Debug, Copy, Clone)]
+struct Conditions {
+    latency: Duration,
+    jitter: Duration,
+    loss: f64,
+    duplication: f64
+}
+
+#[derive(Debug, Clone)]
+pub struct SimulationConfig {
+    conditions: Arc<Mutex<Conditions>>
+}
+
+impl SimulationConfig {
+
+    pub fn new() -> Self {
+        Self {
+            conditions: Arc::new(Mutex::new(Conditions {
+                latency: Duration::from_millis(0),
+                jitter: Duration::from_millis(0),
+                loss: 0.0,
+                duplication: 0.0
+            }))
+        }
+    }
+
+    // Use this configuration for all simulated connections created afterwards
+    pub fn install(&self) {
+        if let Ok(mut config) = DEFAULT_CONFIG.lock() {
+            *config = Some(self.clone());
+        }
+    }
+
+    pub fn set_latency(&self, latency: Duration) {
+        self.update(|c| c.latency = latency);
+    }
+
+    pub fn set_jitter(&self, jitter: Duration) {
+        self.update(|c| c.jitter = jitter);
+    }
+
+    pub fn set_loss(&self, probability: f64) {
+        self.update(|c| c.loss = probability);
+    }
+
+    pub fn set_duplication(&self, probability: f64) {
+        self.update(|c| c.duplication = probability);
+    }
+
+    pub fn latency(&self) -> Duration {
+        self.conditions().latency
+    }
+
+    pub fn jitter(&self) -> Duration {
+        self.conditions().jitter
+    }
+
+    pub fn loss(&self) -> f64 {
+        self.conditions().loss
+    }
+
+    pub fn duplication(&self) -> f64 {
+        self.conditions().duplication
+    }
+
+    fn update<F: FnOnce(&mut Conditions)>(&self, f: F) {
+        if let Ok(mut conditions) = self.conditions.lock() {
+            f(&mut conditions);
+        }
+    }
+
+    fn conditions(&self) -> Conditions {
+        match self.conditions.lock() {
+            Ok(conditions) => *conditions,
+            Err(err) => *err.into_inner()
+        }
+    }
+
+    fn installed() -> Self {
+        match DEFAULT_CONFIG.lock() {
+            Ok(config) => config.clone().unwrap_or_default(),
+            Err(_) => SimulationConfig::new()
+        }
+    }
+
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        SimulationConfig::new()
+    }
+}
+
+
+// Simulated Protocol ---------------------------------------------------------
+pub struct Simulated<P: Protocol> {
+    protocol: PhantomData<P>
+}
+
+impl<P: Protocol> Protocol for Simulated<P> {
+    type Host = SimulatedHost<P::Host>;
+    type Connection = SimulatedConnection<P::Connection>;
+}
+
+pub struct SimulatedHost<H: Host> {
+    host: H
+}
+
+impl<H: Host> Host for SimulatedHost<H> {
+
+    type Connection = SimulatedConnection<H::Connection>;
+
+    fn bind(addrs: &[<H::Connection as Connection>::Addr], config: &<H::Connection as Connection>::Config) -> Result<Self, IOError> where Self: Sized {
+        Ok(Self {
+            host: H::bind(addrs, config)?
+        })
+    }
+
+    fn accept(&mut self) -> Result<Self::Connection, IOError> where Self: Sized {
+        let connection = self.host.accept()?;
+        Ok(SimulatedConnection::new(connection))
+    }
+
+    fn local_addr(&self) -> Result<<H::Connection as Connection>::Addr, IOError> where Self: Sized {
+        self.host.local_addr()
+    }
+
+    #[cfg(feature = "poll")]
+    fn register(&mut self, registry: &Registry, token: Token) -> Result<(), IOError> where Self: Sized {
+        self.host.register(registry, token)
+    }
+
+    fn shutdown(self) -> Result<(), IOError> where Self: Sized {
+        self.host.shutdown()
+    }
+
+}
+
+pub struct SimulatedConnection<C: Connection> {
+    connection: C,
+    config: SimulationConfig,
+    pending: Vec<u8>,
+    delayed: VecDeque<(Instant, Vec<u8>)>,
+    error: Option<IOError>,
+    random: Random
+}
+
+impl<C: Connection> SimulatedConnection<C> {
+
+    fn new(connection: C) -> Self {
+        Self {
+            connection: connection,
+            config: SimulationConfig::installed(),
+            pending: Vec::new(),
+            delayed: VecDeque::new(),
+            error: None,
+            random: Random::new()
+        }
+    }
+
+    fn schedule(&mut self, frame: Vec<u8>) {
+
+        let conditions = self.config.conditions();
+        if self.random.next() < conditions.loss {
+            return;
+        }
+
+        let copies = if self.random.next() < conditions.duplication {
+            2
+
+        } else {
+            1
+        };
+
+        // Frames keep their order, so jitter can only ever delay them further
+        let jitter = duration_ms(conditions.jitter) * (self.random.next() * 2.0 - 1.0);
+        let delay = (duration_ms(conditions.latency) + jitter).max(0.0);
+        let mut due = Instant::now() + Duration::from_micros((delay * 1000.0) as u64);
+        if let Some(&(last, _)) = self.delayed.back() {
+            due = cmp::max(due, last);
+        }
+
+        for _ in 0..copies {
+            self.delayed.push_back((due, frame.clone()));
+        }
+
+    }
+
+}
+
+impl<C: Connection> Connection for SimulatedConnection<C> {
+
+    type Addr = C::Addr;
+    type Config = C::Config;
+
+    fn connect(addrs: &[C::Addr], timeout: Duration, config: &C::Config) -> Result<Self, IOError> where Self: Sized {
+        Ok(SimulatedConnection::new(C::connect(addrs, timeout, config)?))
+    }
+
+    fn connect_start(addrs: &[C::Addr], timeout: Duration, config: &C::Config) -> Result<Self, IOError> where Self: Sized {
+        Ok(SimulatedConnection::new(C::connect_start(addrs, timeout, config)?))
+    }
+
+    fn connect_poll(&mut self) -> Result<bool, IOError> where Self: Sized {
+        self.connection.connect_poll()
+    }
+
+    fn peer_addr(&self) -> Result<C::Addr, IOError> where Self: Sized {
+        self.connection.peer_addr()
+    }
+
+    fn local_addr(&self) -> Result<C::Addr, IOError> where Self: Sized {
+        self.connection.local_addr()
+    }
+
+    fn read(&mut self, buffer: &mut Vec<u8>, max_bytes: usize) -> Result<usize, IOError> where Self: Sized {
+
+        if self.error.is_none() {
+            if let Err(err) = self.connection.read(&mut self.pending, max_bytes) {
+                self.error = Some(err);
+            }
+        }
+
+        // Split received bytes into frames and delay each of them
+        while let Some(length) = frame_length(&self.pending) {
+            if self.pending.len() < FRAME_HEADER_SIZE + length {
+                break;
+            }
+            let frame = self.pending.drain(0..FRAME_HEADER_SIZE + length).collect();
+            self.schedule(frame);
+        }
+
+        // Deliver all frames which are due
+        let now = Instant::now();
+        let mut bytes = 0;
+        while bytes < max_bytes && self.delayed.front().is_some_and(|&(due, _)| due <= now) {
+            if let Some((_, frame)) = self.delayed.pop_front() {
+                bytes += frame.len();
+                buffer.extend(frame);
+            }
+        }
+
+        // Connection errors are only reported once all delayed frames arrived
+        if bytes == 0 && self.delayed.is_empty() {
+            if let Some(err) = self.error.take() {
+                return Err(err);
+            }
+        }
+
+        Ok(bytes)
+
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<usize, IOError> where Self: Sized {
+        self.connection.write(bytes)
+    }
+
+    fn write_vectored(&mut self, slices: &[IoSlice]) -> Result<usize, IOError> where Self: Sized {
+        self.connection.write_vectored(slices)
+    }
+
+    #[cfg(feature = "poll")]
+    fn register(&mut self, registry: &Registry, token: Token) -> Result<(), IOError> where Self: Sized {
+        self.connection.register(registry, token)
+    }
+
+    fn shutdown(&mut self) -> Result<(), IOError> where Self: Sized {
+        self.connection.shutdown()
+    }
+
+}
+
+
+// Utilities ------------------------------------------------------------------
+fn duration_ms(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + f64::from(duration.subsec_nanos()) / 1_000_000.0
+}
+
+struct Random {
+    state: u64
+}
+
+impl Random {
+
+    fn new() -> Self {
+        let seed = match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
+            Ok(dur) => dur.as_secs() ^ u64::from(dur.subsec_nanos()),
+            Err(err) => err.duration().as_secs()
+        };
+        Self {
+            state: seed | 1
+        }
+    }
+
+    // xorshift64, good enough to simulate packet loss
+    fn next(&mut self) -> f64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+}
@@ -0,0 +1,532 @@
+// Copyright (c) 2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::cmp;
+use std::fmt;
+use std::error;
+use std::marker::PhantomData;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use std::io::{Error as IOError, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr};
+
+
+// External Dependencies ------------------------------------------------------
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+
+// Internal Dependencies ------------------------------------------------------
+use ::error::Error;
+use ::time::Clock;
+use ::codec::{Codec, BincodeCodec};
+use ::protocol::{Protocol, Host, Connection, ToAddrs};
+use ::message::{InternalMessage, Incoming, Outgoing, DEFAULT_MAX_MESSAGE_SIZE, PROTOCOL_VERSION, create_message_iterator};
+
+
+// Statics --------------------------------------------------------------------
+static HOSTS: Mutex<Vec<(SocketAddr, Arc<Mutex<VecDeque<MemoryConnection>>>)>> = Mutex::new(Vec::new());
+static NEXT_PORT: Mutex<u16> = Mutex::new(MIN_EPHEMERAL_PORT);
+const MIN_EPHEMERAL_PORT: u16 = 49152;
+const DEFAULT_EXPECT_TICKS: u64 = 600;
+
+
+// Memory Protocol ------------------------------------------------------------
+// Connections within the same process, hosts are registered under their
+// address until they are shut down; port 0 picks a free one
+pub struct Memory;
+impl Protocol for Memory {
+    type Host = MemoryHost;
+    type Connection = MemoryConnection;
+}
+
+pub struct MemoryHost {
+    addr: SocketAddr,
+    pending: Arc<Mutex<VecDeque<MemoryConnection>>>
+}
+
+impl Host for MemoryHost {
+
+    type Connection = MemoryConnection;
+
+    fn bind(addrs: &[SocketAddr], _: &()) -> Result<Self, IOError> where Self: Sized {
+        let mut hosts = lock(&HOSTS)?;
+        let addr = match addrs.first() {
+            Some(addr) if addr.port() == 0 => SocketAddr::new(addr.ip(), ephemeral_port()?),
+            Some(&addr) => addr,
+            None => return Err(IOError::new(ErrorKind::AddrNotAvailable, ""))
+        };
+
+        if hosts.iter().any(|&(a, _)| a == addr) {
+            Err(IOError::new(ErrorKind::AddrInUse, ""))
+
+        } else {
+            let pending = Arc::new(Mutex::new(VecDeque::new()));
+            hosts.push((addr, pending.clone()));
+            Ok(Self {
+                addr: addr,
+                pending: pending
+            })
+        }
+    }
+
+    fn accept(&mut self) -> Result<MemoryConnection, IOError> where Self: Sized {
+        lock(&self.pending)?.pop_front().ok_or_else(|| IOError::new(ErrorKind::WouldBlock, ""))
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, IOError> where Self: Sized {
+        Ok(self.addr)
+    }
+
+    fn shutdown(self) -> Result<(), IOError> where Self: Sized {
+        Ok(())
+    }
+
+}
+
+impl Drop for MemoryHost {
+    fn drop(&mut self) {
+        if let Ok(mut hosts) = lock(&HOSTS) {
+            hosts.retain(|&(addr, _)| addr != self.addr);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Pipe {
+    buffers: [VecDeque<u8>; 2],
+    closed: [bool; 2]
+}
+
+// Each side reads from its own buffer and writes into the one of its peer
+pub struct MemoryConnection {
+    pipe: Arc<Mutex<Pipe>>,
+    side: usize,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr
+}
+
+impl Connection for MemoryConnection {
+
+    type Addr = SocketAddr;
+    type Config = ();
+
+    fn connect(addrs: &[SocketAddr], _: Duration, _: &()) -> Result<Self, IOError> where Self: Sized {
+        let hosts = lock(&HOSTS)?;
+        for addr in addrs {
+            if let Some((_, pending)) = hosts.iter().find(|&&(a, _)| a == *addr) {
+                let pipe = Arc::new(Mutex::new(Pipe::default()));
+                let local_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), ephemeral_port()?);
+                lock(pending)?.push_back(MemoryConnection {
+                    pipe: pipe.clone(),
+                    side: 1,
+                    local_addr: *addr,
+                    peer_addr: local_addr
+                });
+                return Ok(MemoryConnection {
+                    pipe: pipe,
+                    side: 0,
+                    local_addr: local_addr,
+                    peer_addr: *addr
+                });
+            }
+        }
+        Err(IOError::new(ErrorKind::ConnectionRefused, ""))
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr, IOError> where Self: Sized {
+        Ok(self.peer_addr)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, IOError> where Self: Sized {
+        Ok(self.local_addr)
+    }
+
+    // Like a stream the connection reports the close once it has been drained
+    fn read(&mut self, buffer: &mut Vec<u8>, max_bytes: usize) -> Result<usize, IOError> where Self: Sized {
+        let mut pipe = lock(&self.pipe)?;
+        let closed = pipe.closed[1 - self.side] || pipe.closed[self.side];
+        let incoming = &mut pipe.buffers[self.side];
+        if incoming.is_empty() && closed {
+            Err(IOError::new(ErrorKind::UnexpectedEof, "closed by the peer"))
+
+        } else {
+            let bytes = cmp::min(incoming.len(), max_bytes);
+            buffer.extend(incoming.drain(0..bytes));
+            Ok(bytes)
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<usize, IOError> where Self: Sized {
+        let mut pipe = lock(&self.pipe)?;
+        if pipe.closed[0] || pipe.closed[1] {
+            Err(IOError::new(ErrorKind::BrokenPipe, ""))
+
+        } else {
+            pipe.buffers[1 - self.side].extend(bytes);
+            Ok(bytes.len())
+        }
+    }
+
+    fn shutdown(&mut self) -> Result<(), IOError> where Self: Sized {
+        lock(&self.pipe)?.closed[self.side] = true;
+        Ok(())
+    }
+
+}
+
+impl Drop for MemoryConnection {
+    fn drop(&mut self) {
+        self.shutdown().ok();
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> Result<MutexGuard<'_, T>, IOError> {
+    mutex.lock().map_err(|_| IOError::other("memory protocol state poisoned"))
+}
+
+fn ephemeral_port() -> Result<u16, IOError> {
+    let mut port = lock(&NEXT_PORT)?;
+    let next = *port;
+    *port = if next == u16::MAX { MIN_EPHEMERAL_PORT } else { next + 1 };
+    Ok(next)
+}
+
+
+// Script Errors --------------------------------------------------------------
+// Steps are counted from 0 in the order they were added to the script
+#[derive(Debug)]
+pub enum ScriptError {
+    Timeout {
+        step: usize
+    },
+    UnexpectedMessage {
+        step: usize,
+        message: String
+    },
+    NotConnected {
+        step: usize
+    },
+    Failed(Error)
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ScriptError::Timeout { step } => write!(f, "step {} timed out", step),
+            ScriptError::UnexpectedMessage { step, ref message } => write!(f, "step {} received unexpected message {}", step, message),
+            ScriptError::NotConnected { step } => write!(f, "step {} requires a connection", step),
+            ScriptError::Failed(ref err) => write!(f, "peer failed: {}", err)
+        }
+    }
+}
+
+impl error::Error for ScriptError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            ScriptError::Failed(ref err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+
+// Fake Peer ------------------------------------------------------------------
+enum Step<M> {
+    Send(M),
+    Expect(Box<dyn FnMut(&M) -> bool>),
+    ExpectConnect,
+    ExpectClose,
+    Advance(u64),
+    Drop
+}
+
+// Speaks the wire format on the other end of a memory connection, either as
+// the client of a server or as the server a client connects to. Pings are
+// answered and closes acknowledged, everything else follows the script.
+//
+// The scripts are run with a closure which performs a single tick of the
+// server or client under test, including its sleep() so a MockClock shared
+// with the peer advances.
+pub struct FakePeer<M: Serialize + DeserializeOwned + fmt::Debug, E: Codec = BincodeCodec> {
+    host: Option<MemoryHost>,
+    connection: Option<MemoryConnection>,
+    incoming: Incoming<InternalMessage>,
+    outgoing: Outgoing,
+    clock: Arc<dyn Clock>,
+    ticks_per_second: f64,
+    version: u32,
+    ticks: u64,
+    expect_ticks: u64,
+    handshake_done: bool,
+    close_code: Option<u8>,
+    received: VecDeque<M>,
+    steps: Vec<Step<M>>,
+    codec: PhantomData<E>
+}
+
+impl<M: Serialize + DeserializeOwned + fmt::Debug, E: Codec> FakePeer<M, E> {
+
+    pub fn new(ticks_per_second: f64, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            host: None,
+            connection: None,
+            incoming: Incoming::new(DEFAULT_MAX_MESSAGE_SIZE),
+            outgoing: Outgoing::new(),
+            clock: clock,
+            ticks_per_second: ticks_per_second,
+            version: 0,
+            ticks: 0,
+            expect_ticks: DEFAULT_EXPECT_TICKS,
+            handshake_done: false,
+            close_code: None,
+            received: VecDeque::new(),
+            steps: Vec::new(),
+            codec: PhantomData
+        }
+    }
+
+    // Must match the version of the server or client under test
+    pub fn set_version(&mut self, version: u32) {
+        self.version = version;
+    }
+
+    // How many ticks the expect steps wait before the script fails
+    pub fn set_expect_ticks(&mut self, ticks: u64) {
+        self.expect_ticks = ticks;
+    }
+
+    // Connects to a server bound to the memory protocol right away
+    pub fn connect<A: ToAddrs<SocketAddr>>(&mut self, addr: A) -> Result<(), Error> {
+        if self.connection.is_some() {
+            Err(Error::AlreadyConnected)
+
+        } else {
+            let connection = MemoryConnection::connect(&addr.to_addrs()?[..], Duration::from_millis(0), &())?;
+            self.open(connection);
+            self.send_internal(self.hello())?;
+            Ok(())
+        }
+    }
+
+    // Clients connecting to the address are accepted one at a time, a new
+    // connection is only accepted once the previous one was dropped
+    pub fn listen<A: ToAddrs<SocketAddr>>(&mut self, addr: A) -> Result<(), Error> {
+        if self.host.is_some() {
+            Err(Error::AlreadyBound)
+
+        } else {
+            self.host = Some(MemoryHost::bind(&addr.to_addrs()?[..], &())?);
+            Ok(())
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        match (self.host.as_ref(), self.connection.as_ref()) {
+            (Some(host), _) => Ok(host.local_addr()?),
+            (None, Some(connection)) => Ok(connection.local_addr()?),
+            (None, None) => Err(Error::NotConnected)
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    // Ticks the peer went through while running scripts
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    // The code of the last close request received from the other side
+    pub fn close_code(&self) -> Option<u8> {
+        self.close_code
+    }
+
+
+    // Script -----------------------------------------------------------------
+    pub fn send(&mut self, message: M) -> &mut Self {
+        self.steps.push(Step::Send(message));
+        self
+    }
+
+    // The next message received must match, otherwise the script fails
+    pub fn expect_message<F: FnMut(&M) -> bool + 'static>(&mut self, matcher: F) -> &mut Self {
+        self.steps.push(Step::Expect(Box::new(matcher)));
+        self
+    }
+
+    // Waits for the handshake with the other side to complete
+    pub fn expect_connect(&mut self) -> &mut Self {
+        self.steps.push(Step::ExpectConnect);
+        self
+    }
+
+    // Waits for the other side to close or drop the connection
+    pub fn expect_close(&mut self) -> &mut Self {
+        self.steps.push(Step::ExpectClose);
+        self
+    }
+
+    pub fn advance_ticks(&mut self, ticks: u64) -> &mut Self {
+        self.steps.push(Step::Advance(ticks));
+        self
+    }
+
+    // Goes away without a close, like a peer which lost its network
+    pub fn drop_connection(&mut self) -> &mut Self {
+        self.steps.push(Step::Drop);
+        self
+    }
+
+    // Runs all steps added since the last run, stopping at the first one which fails
+    pub fn run<F: FnMut()>(&mut self, mut tick: F) -> Result<(), ScriptError> {
+        let steps = self.steps.drain(0..).collect::<Vec<_>>();
+        for (index, step) in steps.into_iter().enumerate() {
+            match step {
+                Step::Send(message) => {
+                    if self.connection.is_none() {
+                        return Err(ScriptError::NotConnected { step: index });
+                    }
+                    let bytes = self.outgoing.encode::<E, _>(&message, DEFAULT_MAX_MESSAGE_SIZE).map_err(ScriptError::Failed)?;
+                    self.outgoing.push_channel_frame(0, false, bytes);
+                    self.flush();
+                },
+                Step::Expect(mut matcher) => {
+                    let message = self.wait(index, &mut tick, |peer| peer.received.pop_front())?;
+                    if !matcher(&message) {
+                        return Err(ScriptError::UnexpectedMessage {
+                            step: index,
+                            message: format!("{:?}", message)
+                        });
+                    }
+                },
+                Step::ExpectConnect => {
+                    self.wait(index, &mut tick, |peer| if peer.handshake_done { Some(()) } else { None })?;
+                },
+                Step::ExpectClose => {
+                    self.wait(index, &mut tick, |peer| if peer.connection.is_none() { Some(()) } else { None })?;
+                },
+                Step::Advance(ticks) => for _ in 0..ticks {
+                    self.tick(&mut tick);
+                },
+                Step::Drop => self.close()
+            }
+        }
+        Ok(())
+    }
+
+
+    // Internal ---------------------------------------------------------------
+    fn wait<T, F: FnMut(), C: FnMut(&mut Self) -> Option<T>>(&mut self, step: usize, tick: &mut F, mut condition: C) -> Result<T, ScriptError> {
+        for _ in 0..self.expect_ticks {
+            if let Some(result) = condition(self) {
+                return Ok(result);
+            }
+            self.tick(tick);
+        }
+        condition(self).ok_or(ScriptError::Timeout { step: step })
+    }
+
+    fn tick<F: FnMut()>(&mut self, tick: &mut F) {
+
+        tick();
+        self.ticks += 1;
+
+        if self.connection.is_none() {
+            if let Some(connection) = self.host.as_mut().and_then(|host| host.accept().ok()) {
+                self.open(connection);
+            }
+        }
+
+        let read = match self.connection.as_mut() {
+            Some(connection) => connection.read(self.incoming.buffer_mut(), usize::MAX),
+            None => return
+        };
+
+        self.incoming.extract_frames::<E>();
+        for m in self.incoming.drain_internal() {
+            match m {
+                InternalMessage::Ping(seq, time) => {
+                    let pong = InternalMessage::Pong(seq, time, self.clock.now_unix_ms(), self.ticks);
+                    self.send_internal(pong).ok();
+                },
+                InternalMessage::Hello { .. } => {
+                    // Servers only answer once the client introduced itself
+                    if self.host.is_some() {
+                        self.send_internal(self.hello()).ok();
+                    }
+                    self.handshake_done = true;
+                },
+                InternalMessage::Close(code) => {
+                    self.close_code = Some(code);
+                    self.send_internal(InternalMessage::Close(code)).ok();
+                    self.flush();
+                    self.close();
+                    return;
+                },
+                _ => {}
+            }
+        }
+
+        self.received.extend(create_message_iterator::<M, InternalMessage, E>(&mut self.incoming, None));
+        if let Some(id) = self.incoming.take_ack() {
+            self.send_internal(InternalMessage::Ack(id)).ok();
+        }
+
+        if read.is_err() {
+            self.close();
+
+        } else {
+            self.flush();
+        }
+
+    }
+
+    fn open(&mut self, connection: MemoryConnection) {
+        self.connection = Some(connection);
+        self.incoming.clear();
+        self.outgoing.clear();
+        self.handshake_done = false;
+    }
+
+    fn close(&mut self) {
+        if let Some(mut connection) = self.connection.take() {
+            connection.shutdown().ok();
+        }
+        self.handshake_done = false;
+    }
+
+    fn flush(&mut self) {
+        if let Some(connection) = self.connection.as_mut() {
+            self.outgoing.write_to(connection, usize::MAX).ok();
+        }
+    }
+
+    fn send_internal(&mut self, message: InternalMessage) -> Result<(), Error> {
+        let bytes = self.outgoing.encode::<E, _>(&message, DEFAULT_MAX_MESSAGE_SIZE)?;
+        self.outgoing.push_frame(0, bytes);
+        self.flush();
+        Ok(())
+    }
+
+    fn hello(&self) -> InternalMessage {
+        InternalMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            version: self.version,
+            ticks_per_second: self.ticks_per_second,
+            compression: false,
+            stamping: false
+        }
+    }
+
+}
+
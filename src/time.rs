@@ -8,8 +8,10 @@
 
 
 // STD Dependencies -----------------------------------------------------------
+use std::cmp;
 use std::iter;
 use std::thread;
+use std::sync::{Arc, Mutex};
 use std::time::{self, Instant, Duration};
 
 
@@ -18,102 +20,828 @@ use ::message::InternalMessage;
 
 
 // Statics --------------------------------------------------------------------
-static AVERAGE_SIZE: usize = 16;
+static DEFAULT_AVERAGE_SIZE: usize = 16;
+static DEFAULT_CLOCK_RATIO: f64 = 0.5;
+static MAX_PONG_AGE_MS: u64 = 5000;
+static MAX_CLOCK_JUMP_MS: u64 = 1000;
+static DEFAULT_PING_WARMUP_TICKS: u64 = 32;
+static DEFAULT_PING_INTERVAL_TICKS: u64 = 8;
+static DEFAULT_SUSPECT_INTERVALS: u64 = 8;
+static DEFAULT_DEAD_INTERVALS: u64 = 40;
+static PINGS_PER_SECOND: f64 = 4.0;
+pub static MIN_TICKS_PER_SECOND: f64 = 0.01;
+pub static MAX_TICKS_PER_SECOND: f64 = 10_000.0;
+static DEFAULT_EXCELLENT_MS: f64 = 50.0;
+static DEFAULT_GOOD_MS: f64 = 100.0;
+static DEFAULT_POOR_MS: f64 = 250.0;
+static DEFAULT_INITIAL_ALLOWANCE: usize = 4096;
+static DEFAULT_MIN_ALLOWANCE: usize = 512;
+static DEFAULT_MAX_ALLOWANCE: usize = 65536;
+static DEFAULT_ALLOWANCE_INCREASE: usize = 256;
+static DEFAULT_CONGESTION_FACTOR: f64 = 1.5;
+static MIN_CONGESTION_MS: f64 = 5.0;
+
+
+// Clock Abstraction ----------------------------------------------------------
+pub trait Clock: Send + Sync {
+    fn now_instant(&self) -> Instant;
+    fn now_unix_ms(&self) -> u64;
+    fn sleep(&self, duration: Duration);
+
+    // Clocks which only advance when slept on cannot be waited on by polling
+    fn is_realtime(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+
+    fn now_instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn now_unix_ms(&self) -> u64 {
+        let dur = match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
+            Ok(dur) => dur,
+            Err(err) => err.duration(),
+        };
+        dur.as_secs() * 1000 + u64::from(dur.subsec_millis())
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+
+}
+
+// Only advances when slept on, so ticks take no real time at all and always
+// last exactly as long as they should, e.g. for replays
+#[derive(Debug)]
+pub struct MockClock {
+    start: Instant,
+    start_unix_ms: u64,
+    elapsed: Mutex<Duration>
+}
+
+impl MockClock {
+
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            start_unix_ms: SystemClock.now_unix_ms(),
+            elapsed: Mutex::new(Duration::from_millis(0))
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        if let Ok(mut elapsed) = self.elapsed.lock() {
+            *elapsed += duration;
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        match self.elapsed.lock() {
+            Ok(elapsed) => *elapsed,
+            Err(err) => *err.into_inner()
+        }
+    }
+
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+
+    fn now_instant(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn now_unix_ms(&self) -> u64 {
+        self.start_unix_ms + self.elapsed().as_millis() as u64
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+
+    fn is_realtime(&self) -> bool {
+        false
+    }
+
+}
+
+
+// Timing Configuration -------------------------------------------------------
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TimingConfig {
+    // Pings are sent every tick for this many ticks after connecting
+    pub ping_warmup_ticks: u64,
+    // Ticks between two pings once the warm up is over
+    pub ping_interval_ticks: u64,
+    // Number of samples the RTT is averaged over
+    pub rtt_window: usize,
+    // Number of samples the clock shift is averaged over
+    pub clock_window: usize,
+    // Weight of a new clock shift sample against the current average
+    pub clock_ratio: f64,
+    // How the RTT samples are averaged
+    pub rtt_average: Average,
+    // Limits used to grade the connection quality
+    pub quality_thresholds: QualityThresholds,
+    // Ping intervals without a pong after which the peer is suspected to be
+    // gone and probed with an extra ping
+    pub suspect_intervals: u64,
+    // Ping intervals without a pong after which the peer is considered dead,
+    // independent of the connection timeout
+    pub dead_intervals: u64
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Average {
+    Mean,
+    // Middle sample of the window, ignoring any spikes
+    Median,
+    // Mean without the given fraction of the lowest and highest samples
+    TrimmedMean(f64)
+}
+
+impl TimingConfig {
+
+    // Spaces pings out to about a quarter of a second, independent of the
+    // tick rate
+    pub fn for_ticks_per_second(ticks_per_second: f64) -> Self {
+        Self {
+            ping_interval_ticks: cmp::max((tick_rate(ticks_per_second) / PINGS_PER_SECOND) as u64, 1),
+            .. Self::default()
+        }
+    }
+
+}
+
+impl Default for TimingConfig {
+    fn default() -> Self {
+        Self {
+            ping_warmup_ticks: DEFAULT_PING_WARMUP_TICKS,
+            ping_interval_ticks: DEFAULT_PING_INTERVAL_TICKS,
+            rtt_window: DEFAULT_AVERAGE_SIZE,
+            clock_window: DEFAULT_AVERAGE_SIZE,
+            clock_ratio: DEFAULT_CLOCK_RATIO,
+            rtt_average: Average::Mean,
+            quality_thresholds: QualityThresholds::default(),
+            suspect_intervals: DEFAULT_SUSPECT_INTERVALS,
+            dead_intervals: DEFAULT_DEAD_INTERVALS
+        }
+    }
+}
+
+
+// Connection Quality ---------------------------------------------------------
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum ConnectionQuality {
+    Excellent,
+    Good,
+    Poor,
+    Bad
+}
+
+// Upper limits in milliseconds for the RTT plus twice the jitter
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct QualityThresholds {
+    pub excellent: f64,
+    pub good: f64,
+    pub poor: f64
+}
+
+impl QualityThresholds {
+
+    pub fn grade(&self, rtt: f64, jitter: f64) -> ConnectionQuality {
+        let latency = rtt + jitter * 2.0;
+        if latency <= self.excellent {
+            ConnectionQuality::Excellent
+
+        } else if latency <= self.good {
+            ConnectionQuality::Good
+
+        } else if latency <= self.poor {
+            ConnectionQuality::Poor
+
+        } else {
+            ConnectionQuality::Bad
+        }
+    }
+
+}
+
+impl Default for QualityThresholds {
+    fn default() -> Self {
+        Self {
+            excellent: DEFAULT_EXCELLENT_MS,
+            good: DEFAULT_GOOD_MS,
+            poor: DEFAULT_POOR_MS
+        }
+    }
+}
+
+
+// Adaptive Sending -----------------------------------------------------------
+// Byte allowances per tick, grown while the RTT stays near its baseline and
+// halved once it exceeds the baseline by the congestion factor
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AdaptiveSend {
+    pub initial_allowance: usize,
+    pub min_allowance: usize,
+    pub max_allowance: usize,
+    // Bytes added to the allowance every tick without congestion
+    pub increase: usize,
+    pub congestion_factor: f64
+}
+
+impl Default for AdaptiveSend {
+    fn default() -> Self {
+        Self {
+            initial_allowance: DEFAULT_INITIAL_ALLOWANCE,
+            min_allowance: DEFAULT_MIN_ALLOWANCE,
+            max_allowance: DEFAULT_MAX_ALLOWANCE,
+            increase: DEFAULT_ALLOWANCE_INCREASE,
+            congestion_factor: DEFAULT_CONGESTION_FACTOR
+        }
+    }
+}
+
+// Only recommends, the bytes queued above the allowance are counted but sent
+// all the same
+pub struct SendAllowance {
+    config: Option<AdaptiveSend>,
+    allowance: usize,
+    baseline: Option<f64>,
+    hold_ticks: u64,
+    exceeded: u64
+}
+
+impl SendAllowance {
+
+    pub fn new() -> Self {
+        Self {
+            config: None,
+            allowance: usize::MAX,
+            baseline: None,
+            hold_ticks: 0,
+            exceeded: 0
+        }
+    }
+
+    pub fn set(&mut self, config: Option<AdaptiveSend>) {
+        self.config = config.map(|config| {
+            let min_allowance = cmp::max(config.min_allowance, 1);
+            AdaptiveSend {
+                min_allowance: min_allowance,
+                max_allowance: cmp::max(config.max_allowance, min_allowance),
+                initial_allowance: config.initial_allowance.clamp(min_allowance, cmp::max(config.max_allowance, min_allowance)),
+                congestion_factor: config.congestion_factor.max(1.0),
+                .. config
+            }
+        });
+        self.allowance = self.config.map_or(usize::MAX, |config| config.initial_allowance);
+        self.baseline = None;
+        self.hold_ticks = 0;
+    }
+
+    pub fn allowance(&self) -> usize {
+        self.allowance
+    }
+
+    // Lowest RTT seen since adaptive sending was enabled
+    pub fn baseline(&self) -> Option<f64> {
+        self.baseline
+    }
+
+    pub fn exceeded(&self) -> u64 {
+        self.exceeded
+    }
+
+    // The RTT is only passed in once it is stable, until then the allowance
+    // stays where it is
+    pub fn tick(&mut self, queued: usize, rtt: Option<f64>, ticks_per_second: f64) {
+
+        let config = match self.config {
+            Some(config) => config,
+            None => return
+        };
+
+        if queued > self.allowance {
+            self.exceeded += 1;
+        }
+
+        if let Some(rtt) = rtt {
+
+            let baseline = self.baseline.map_or(rtt, |baseline| baseline.min(rtt));
+            self.baseline = Some(baseline);
+
+            // Tiny baselines would turn every bit of jitter into congestion
+            let congested = rtt > baseline * config.congestion_factor && rtt > baseline + MIN_CONGESTION_MS;
+            if self.hold_ticks > 0 {
+                self.hold_ticks -= 1;
+
+            // Backs off at most once per round trip, so the averaged RTT gets a
+            // chance to reflect the reduced rate
+            } else if congested {
+                self.allowance = cmp::max(self.allowance / 2, config.min_allowance);
+                self.hold_ticks = cmp::max((rtt / 1000.0 * tick_rate(ticks_per_second)).ceil() as u64, 1);
+
+            } else {
+                self.allowance = cmp::min(self.allowance.saturating_add(config.increase), config.max_allowance);
+            }
+
+        }
+
+    }
+
+}
+
+
+// Peer Clocks ----------------------------------------------------------------
+// A snapshot of the mapping between the ticks of the peer and the local time
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PeerClock {
+    tick: u64,
+    tick_ms: f64,
+    ms_per_tick: f64,
+    now_ms: f64
+}
+
+impl PeerClock {
+
+    // Milliseconds since the peer was at the given tick, which only carries
+    // the lower bits of the actual tick
+    pub fn age_ms(&self, tick: u32) -> f64 {
+        let ticks = f64::from(tick.wrapping_sub(self.tick as u32) as i32);
+        self.now_ms - (self.tick_ms + ticks * self.ms_per_tick)
+    }
+
+}
+
+
+// Tick Reports ---------------------------------------------------------------
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct TickReport {
+    // Time spent waiting for the end of the tick
+    pub slept: Duration,
+    // Time by which the work done during the tick exceeded its budget
+    pub overrun: Duration,
+    // Whole ticks of work which still have to be caught up with
+    pub ticks_behind: u32
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum CatchUpPolicy {
+    // Skip sleeping until all of the time owed has been caught up with
+    #[default]
+    Compress,
+    // Like Compress, but forget the time owed once it exceeds the number of
+    // ticks so the loop cannot fall further and further behind
+    DropAfter(u32)
+}
 
 
 // Timer Abstraction ----------------------------------------------------------
+// What another process needs to carry on with the pings of a connection, see
+// Server::export_remotes(); the averages restart from their last values
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimerState {
+    ticks: u64,
+    next_ping: u32,
+    rtt: Option<f64>,
+    jitter: Option<f64>,
+    clock_shift: Option<f64>
+}
+
 // TODO rename into state or something else?
 pub struct Timer {
-    tick: u8,
-    ticks_per_second: u8,
+    clock: Arc<dyn Clock>,
+    ticks_per_second: f64,
     clock_shift: MovingAverage,
+    started: Instant,
     last_wait: Instant,
     accumulated_wait: Duration,
-    last_ping: Instant,
-    average_rtt: MovingAverage
+    deadline: Option<Instant>,
+    wait_started: Instant,
+    catch_up: CatchUpPolicy,
+    next_report: TickReport,
+    report: TickReport,
+    overrun_ticks: u64,
+    ticks: u64,
+    ticks_since_ping: u64,
+    timing: TimingConfig,
+    next_ping: u32,
+    pending_pings: Vec<(u32, Instant, Duration)>,
+    ticks_since_pong: u64,
+    probed: bool,
+    paused_at: Option<Instant>,
+    last_pong: Option<(u64, Instant, u64)>,
+    average_rtt: MovingAverage,
+    jitter: MovingAverage,
+    last_rtt: Option<f64>
 }
 
 impl Timer {
 
-    pub fn new(ticks_per_second: u8) -> Self {
+    pub fn new(ticks_per_second: f64, clock: Arc<dyn Clock>) -> Self {
+        let now = clock.now_instant();
+        let timing = TimingConfig::default();
         Self {
-            tick: 0,
-            ticks_per_second: ticks_per_second,
-            clock_shift: MovingAverage::new(AVERAGE_SIZE),
-            last_wait: Instant::now(),
-            last_ping: Instant::now(),
+            clock: clock,
+            ticks_per_second: tick_rate(ticks_per_second),
+            clock_shift: MovingAverage::new(timing.clock_window),
+            started: now,
+            last_wait: now,
+            wait_started: now,
+            catch_up: CatchUpPolicy::default(),
+            next_report: TickReport::default(),
+            report: TickReport::default(),
+            overrun_ticks: 0,
+            ticks: 0,
+            ticks_since_ping: 0,
+            timing: timing,
+            next_ping: 0,
+            pending_pings: Vec::new(),
+            ticks_since_pong: 0,
+            probed: false,
+            paused_at: None,
+            last_pong: None,
             accumulated_wait: Duration::new(0, 0),
-            average_rtt: MovingAverage::new(AVERAGE_SIZE)
+            deadline: None,
+            average_rtt: MovingAverage::with_average(timing.rtt_window, timing.rtt_average),
+            jitter: MovingAverage::new(timing.rtt_window),
+            last_rtt: None
         }
     }
 
+    pub fn now(&self) -> Instant {
+        self.clock.now_instant()
+    }
+
+    // Time passed since the timer was created or last reset
+    pub fn elapsed(&self) -> Duration {
+        self.now().saturating_duration_since(self.started)
+    }
+
     pub fn rtt(&self) -> f64 {
         self.average_rtt.get()
     }
 
+    // Whether the RTT is averaged over a full window of samples
+    pub fn rtt_is_stable(&self) -> bool {
+        self.average_rtt.is_warm()
+    }
+
+    // Average difference between two consecutive RTT samples
+    pub fn jitter(&self) -> f64 {
+        self.jitter.get()
+    }
+
+    // Lowest and highest RTT within the current window
+    pub fn rtt_bounds(&self) -> (f64, f64) {
+        self.average_rtt.bounds()
+    }
+
+    // None until the first RTT sample arrived
+    pub fn quality(&self) -> Option<ConnectionQuality> {
+        if self.average_rtt.is_empty() {
+            None
+
+        } else {
+            Some(self.timing.quality_thresholds.grade(self.average_rtt.get(), self.jitter.get()))
+        }
+    }
+
     pub fn clock(&self) -> f64 {
         self.clock_shift.get()
     }
 
+    pub fn ticks_per_second(&self) -> f64 {
+        self.ticks_per_second
+    }
+
+    // Local wall clock plus the smoothed clock shift, only as accurate as the
+    // asymmetry between the two directions of the round trip
+    pub fn remote_time(&self) -> Option<f64> {
+        if self.clock_shift.is_empty() {
+            None
+
+        } else {
+            Some(self.clock.now_unix_ms() as f64 + self.clock_shift.get())
+        }
+    }
+
+    // Extrapolates the tick of the last pong by the time passed since then and
+    // the one way latency, making it accurate to about one tick; stands still
+    // while paused
+    pub fn remote_tick(&self) -> Option<u64> {
+        self.last_pong.map(|(tick, received, _)| {
+            let elapsed = self.paused_at.unwrap_or_else(|| self.clock.now_instant()).saturating_duration_since(received);
+            let elapsed_ms = elapsed.as_secs() as f64 * 1000.0 + f64::from(elapsed.subsec_micros()) / 1000.0;
+            let latency_ms = elapsed_ms + self.average_rtt.get() / 2.0;
+            tick + (latency_ms * self.ticks_per_second / 1000.0) as u64
+        })
+    }
+
+    // Where the ticks of the peer fall on the local clock, going by the time
+    // the peer reported for the tick of the last pong and the clock shift
+    pub fn peer_clock(&self) -> Option<PeerClock> {
+        self.last_pong.map(|(tick, _, server_time)| PeerClock {
+            tick: tick,
+            tick_ms: server_time as f64 - self.clock_shift.get(),
+            ms_per_tick: 1000.0 / self.ticks_per_second,
+            now_ms: self.clock.now_unix_ms() as f64
+        })
+    }
+
+    // Spread of the clock shift samples in milliseconds, ages derived from the
+    // clock shift cannot be more accurate than this
+    pub fn clock_deviation(&self) -> f64 {
+        self.clock_shift.deviation()
+    }
+
+    // Local ticks until the remote reaches the given tick, negative once it
+    // has passed; both sides run at the same rate after the handshake
+    pub fn ticks_until_remote(&self, tick: u64) -> Option<i64> {
+        self.remote_tick().map(|remote| tick as i64 - remote as i64)
+    }
+
+    pub fn timed_out(&self, timeout: Duration) -> bool {
+        self.ticks_since_pong as f64 > timeout.as_secs_f64() * self.ticks_per_second
+    }
+
+    // Whether the peer missed enough pongs to be suspected of being gone
+    pub fn suspect(&self) -> bool {
+        self.ticks_since_pong > self.timing.suspect_intervals * self.timing.ping_interval_ticks
+    }
+
+    pub fn dead(&self) -> bool {
+        self.ticks_since_pong > self.timing.dead_intervals * self.timing.ping_interval_ticks
+    }
+
+    // Puts the pings on hold while one of the sides stopped ticking, so the
+    // pause counts neither towards the timeout nor the RTT
+    pub fn pause(&mut self) {
+        if self.paused_at.is_none() {
+            self.paused_at = Some(self.now());
+            self.pending_pings.clear();
+        }
+    }
+
+    // Pings are timed from the moment they were written to the connection
+    // instead of when they were queued, so the RTT only covers the network
+    pub fn ping_written(&mut self, seq: u32) {
+        let now = self.clock.now_instant();
+        if let Some(ping) = self.pending_pings.iter_mut().find(|ping| ping.0 == seq) {
+            ping.2 += now.saturating_duration_since(ping.1);
+            ping.1 = now;
+        }
+    }
+
+    // When it was the peer which paused, its ticks stood still and the tick
+    // of the last pong is moved along by the length of the pause
+    pub fn resume(&mut self, peer_paused: bool) {
+        if let Some(paused_at) = self.paused_at.take() {
+            let paused = self.now().saturating_duration_since(paused_at);
+            if peer_paused {
+                self.last_pong = self.last_pong.map(|(tick, received, time)| {
+                    (tick, received + paused, time + paused.as_millis() as u64)
+                });
+            }
+            self.pending_pings.clear();
+            self.ticks_since_ping = 0;
+            self.ticks_since_pong = 0;
+            self.probed = false;
+        }
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused_at.is_some()
+    }
+
+    pub fn set_ticks_per_second(&mut self, ticks_per_second: f64) {
+        self.ticks_per_second = tick_rate(ticks_per_second);
+    }
+
+    pub fn timing(&self) -> TimingConfig {
+        self.timing
+    }
+
+    // Changing a window size discards the samples averaged so far
+    pub fn set_timing(&mut self, timing: TimingConfig) {
+        let timing = TimingConfig {
+            ping_interval_ticks: cmp::max(timing.ping_interval_ticks, 1),
+            rtt_window: cmp::max(timing.rtt_window, 1),
+            clock_window: cmp::max(timing.clock_window, 1),
+            clock_ratio: timing.clock_ratio.clamp(0.0, 1.0),
+            .. timing
+        };
+        if timing.rtt_window != self.timing.rtt_window {
+            self.average_rtt = MovingAverage::with_average(timing.rtt_window, timing.rtt_average);
+            self.jitter = MovingAverage::new(timing.rtt_window);
+            self.last_rtt = None;
+
+        } else if timing.rtt_average != self.timing.rtt_average {
+            self.average_rtt.set_average(timing.rtt_average);
+        }
+        if timing.clock_window != self.timing.clock_window {
+            self.clock_shift = MovingAverage::new(timing.clock_window);
+        }
+        self.timing = timing;
+    }
+
+    pub fn last_tick_report(&self) -> TickReport {
+        self.report
+    }
+
+    pub fn overrun_ticks(&self) -> u64 {
+        self.overrun_ticks
+    }
+
+    pub fn set_catch_up(&mut self, policy: CatchUpPolicy) {
+        self.catch_up = policy;
+    }
+
+    pub fn set_ping_warmup(&mut self, ticks: u64) {
+        self.timing.ping_warmup_ticks = ticks;
+    }
+
+    pub fn set_ping_interval(&mut self, ticks: u64) {
+        self.timing.ping_interval_ticks = cmp::max(ticks, 1);
+    }
+
+    // Synchronized once the clock shift is averaged over a full set of samples
+    // which agree with each other to within a quarter of the round trip
+    pub fn synchronized(&self) -> bool {
+        self.clock_shift.is_warm() && self.clock_shift.deviation() <= (self.average_rtt.get() / 4.0).max(1.0)
+    }
+
     pub fn reset(&mut self) {
-        self.tick = 0;
-        self.last_wait = Instant::now();
+        self.started = self.clock.now_instant();
+        self.last_wait = self.started;
         self.accumulated_wait = Duration::new(0, 0);
-        self.last_ping = Instant::now();
-        self.clock_shift = MovingAverage::new(AVERAGE_SIZE);
-        self.average_rtt = MovingAverage::new(AVERAGE_SIZE);
+        self.deadline = None;
+        self.next_report = TickReport::default();
+        self.report = TickReport::default();
+        self.overrun_ticks = 0;
+        self.ticks = 0;
+        self.ticks_since_ping = 0;
+        self.next_ping = 0;
+        self.pending_pings.clear();
+        self.ticks_since_pong = 0;
+        self.probed = false;
+        self.paused_at = None;
+        self.last_pong = None;
+        self.clock_shift = MovingAverage::new(self.timing.clock_window);
+        self.average_rtt = MovingAverage::with_average(self.timing.rtt_window, self.timing.rtt_average);
+        self.jitter = MovingAverage::new(self.timing.rtt_window);
+        self.last_rtt = None;
+    }
+
+    pub fn state(&self) -> TimerState {
+        TimerState {
+            ticks: self.ticks,
+            next_ping: self.next_ping,
+            rtt: if self.average_rtt.is_empty() { None } else { Some(self.average_rtt.get()) },
+            jitter: if self.jitter.is_empty() { None } else { Some(self.jitter.get()) },
+            clock_shift: if self.clock_shift.is_empty() { None } else { Some(self.clock_shift.get()) }
+        }
+    }
+
+    // Pongs for the pings sent before the state was taken are ignored
+    pub fn restore(&mut self, state: &TimerState) {
+        self.ticks = state.ticks;
+        self.next_ping = state.next_ping;
+        if let Some(rtt) = state.rtt {
+            self.average_rtt.update(rtt, 1.0);
+            self.last_rtt = Some(rtt);
+        }
+        if let Some(jitter) = state.jitter {
+            self.jitter.update(jitter, 1.0);
+        }
+        if let Some(shift) = state.clock_shift {
+            self.clock_shift.update(shift, 1.0);
+        }
     }
 
     pub fn clone(&mut self) -> Self {
+        let now = self.clock.now_instant();
         Self {
-            tick: self.tick,
+            clock: self.clock.clone(),
             ticks_per_second: self.ticks_per_second,
-            clock_shift: MovingAverage::new(AVERAGE_SIZE),
-            last_wait: Instant::now(),
-            last_ping: Instant::now(),
+            clock_shift: MovingAverage::new(self.timing.clock_window),
+            started: now,
+            last_wait: now,
+            wait_started: now,
+            catch_up: self.catch_up,
+            next_report: TickReport::default(),
+            report: TickReport::default(),
+            overrun_ticks: 0,
+            ticks: 0,
+            ticks_since_ping: 0,
+            timing: self.timing,
+            next_ping: 0,
+            pending_pings: Vec::new(),
+            ticks_since_pong: 0,
+            probed: false,
+            paused_at: None,
+            last_pong: None,
             accumulated_wait: Duration::new(0, 0),
-            average_rtt: MovingAverage::new(AVERAGE_SIZE)
+            deadline: None,
+            average_rtt: MovingAverage::with_average(self.timing.rtt_window, self.timing.rtt_average),
+            jitter: MovingAverage::new(self.timing.rtt_window),
+            last_rtt: None
         }
     }
 
     // TODO callback for client side configuration?
-    pub fn receive(&mut self, messages: Vec<InternalMessage>) -> Vec<InternalMessage> {
+    pub fn receive(&mut self, messages: Vec<InternalMessage>, ticks: u64) -> Vec<InternalMessage> {
 
-        let now = precise_time_ms();
+        let now = self.clock.now_unix_ms();
+        let instant = self.clock.now_instant();
+        let max_age = Duration::from_millis(MAX_PONG_AGE_MS);
         let mut outgoing = Vec::new();
 
-        // Requests
-        // TODO improve the logic here to send a lot of updates early on and then space out over
-        // time
-        // TODO work with a multiple of ticks instead of using a time based system
-        let d = 1000 / u64::from(self.ticks_per_second) * 8;
-        if self.last_ping.elapsed() > Duration::from_millis(d) {
-            outgoing.push(InternalMessage::Ping(self.tick, now));
-            self.last_ping = Instant::now();
+        // Requests, sent every tick during the warm up so the averages fill
+        // up quickly and spaced out to the regular interval afterwards
+        self.ticks += 1;
+        self.ticks_since_ping += 1;
+        if self.paused() {
+            self.ticks_since_ping = 0;
+
+        } else if self.ticks <= self.timing.ping_warmup_ticks || self.ticks_since_ping >= self.timing.ping_interval_ticks {
+            outgoing.push(InternalMessage::Ping(self.next_ping, now));
+            self.pending_pings.push((self.next_ping, instant, Duration::new(0, 0)));
+            self.next_ping = self.next_ping.wrapping_add(1);
+            self.ticks_since_ping = 0;
         }
 
+        // Forget about pings which will never be answered
+        self.pending_pings.retain(|&(_, sent, _)| instant.duration_since(sent) <= max_age);
+
         // Responses
         for m in messages {
             match m {
 
-                InternalMessage::Ping(tick, time) => {
-                    outgoing.push(InternalMessage::Pong(tick, time, precise_time_ms()));
+                InternalMessage::Ping(seq, time) => {
+                    outgoing.push(InternalMessage::Pong(seq, time, now, ticks));
                 },
 
-                InternalMessage::Pong(tick, client_time, server_time) => {
+                InternalMessage::Pong(seq, client_time, server_time, server_tick) => {
+
+                    self.ticks_since_pong = 0;
+                    self.probed = false;
+
+                    // Ignore pongs for unknown or expired pings
+                    let (sent, queued) = match self.pending_pings.iter().position(|&(s, _, _)| s == seq) {
+                        Some(index) => {
+                            let (_, sent, queued) = self.pending_pings.remove(index);
+                            (sent, queued)
+                        },
+                        None => continue
+                    };
 
-                    let tick_duration = 1000 / u64::from(self.ticks_per_second);
-                    let tick_diff = u64::from(self.tick.wrapping_sub(tick));
+                    // Measure Round Trip Time, the remote answers pings one tick late
+                    let elapsed = instant.duration_since(sent);
+                    let elapsed_ms = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis());
+                    let tick_ms = 1000.0 / self.ticks_per_second;
+                    let rtt = (elapsed.as_secs_f64() * 1000.0 - tick_ms).max(0.0);
 
-                    // Measure Round Trip Time
-                    let expected_rtt = (tick_diff.saturating_sub(1) * tick_duration) as f64;
-                    let actual_rtt = now.saturating_sub(client_time).saturating_sub(tick_duration) as f64;
-                    let average_rtt = (expected_rtt + actual_rtt) / 2.0;
+                    // Samples are judged against the average from before they were added
+                    let average_rtt = if self.average_rtt.is_empty() {
+                        None
 
-                    self.average_rtt.update(average_rtt, 1.0);
+                    } else {
+                        Some(self.average_rtt.get())
+                    };
+
+                    self.average_rtt.update(rtt, 1.0);
+                    if let Some(last) = self.last_rtt {
+                        self.jitter.update((rtt - last).abs(), 1.0);
+                    }
+                    self.last_rtt = Some(rtt);
+                    self.last_pong = Some((server_tick, instant, server_time));
+
+                    // The time in the ping is from when it was queued up
+                    let client_time = client_time + queued.as_millis() as u64;
+
+                    // Skip the clock shift when the wall clock was adjusted in the meantime
+                    let wall_ms = now.saturating_sub(client_time);
+                    if client_time > now || wall_ms.max(elapsed_ms) - wall_ms.min(elapsed_ms) > MAX_CLOCK_JUMP_MS {
+                        continue;
+                    }
 
                     // Measure clock shift
-                    if average_rtt <= self.average_rtt.get() * 1.5 {
+                    if average_rtt.is_none_or(|average| rtt <= average * 1.5) {
 
                         let diff = (
                             (server_time as f64 - client_time as f64) +
@@ -121,55 +849,316 @@ impl Timer {
 
                         ) / 2.0;
 
-                        self.clock_shift.update(diff, 0.5);
+                        self.clock_shift.update(diff, self.timing.clock_ratio);
 
                     }
 
-                }
+                },
+
+                InternalMessage::Close(_) | InternalMessage::Ack(_) | InternalMessage::Query |
+                InternalMessage::Status(_) | InternalMessage::Hello { .. } |
+                InternalMessage::Pause | InternalMessage::Resume => {}
 
             }
         }
 
         // Internal state
-        self.tick = self.tick.wrapping_add(1);
+        if !self.paused() {
+            self.ticks_since_pong += 1;
+        }
+
+        // Probe suspects right away instead of waiting for the next interval
+        if self.suspect() && !self.probed && !self.paused() {
+            outgoing.push(InternalMessage::Ping(self.next_ping, now));
+            self.pending_pings.push((self.next_ping, instant, Duration::new(0, 0)));
+            self.next_ping = self.next_ping.wrapping_add(1);
+            self.probed = true;
+        }
 
         outgoing
 
     }
 
     pub fn sleep(&mut self) {
+        let deadline = self.deadline();
+        let now = self.clock.now_instant();
+        if deadline > now {
+            self.clock.sleep(deadline - now);
+        }
+        self.complete_tick();
+    }
+
+    #[cfg(feature = "poll")]
+    // Leaves the waiting to the caller, who may return before the tick is over;
+    // returns whether the current tick has been completed
+    pub fn sleep_with<F: FnOnce(Duration)>(&mut self, wait: F) -> bool {
+        if !self.clock.is_realtime() {
+            self.sleep();
+            return true;
+        }
+        let deadline = self.deadline();
+        let now = self.clock.now_instant();
+        if deadline > now {
+            wait(deadline - now);
+        }
+        if self.clock.now_instant() >= deadline {
+            self.complete_tick();
+            true
+
+        } else {
+            false
+        }
+    }
+
+    // Completes the current tick if its deadline has passed, without waiting;
+    // for loops whose timing is controlled by someone else. How late the call
+    // came is counted towards the next tick, so the rate does not depend on
+    // how the frames line up with the ticks
+    pub fn try_tick(&mut self) -> bool {
+        let deadline = self.deadline();
+        if self.clock.now_instant() >= deadline {
+            self.complete_tick();
+            self.last_wait = deadline;
+            true
+
+        } else {
+            false
+        }
+    }
+
+    fn deadline(&mut self) -> Instant {
+
+        if let Some(deadline) = self.deadline {
+            return deadline;
+        }
 
         // Calculate desired wait time
-        let desired_wait = Duration::new(0, 1_000_000_000 / u32::from(self.ticks_per_second));
+        let desired_wait = Duration::from_secs_f64(1.0 / self.ticks_per_second);
 
         // Calculate additional time taken by external logic
-        self.accumulated_wait += self.last_wait.elapsed();
+        let now = self.clock.now_instant();
+        let elapsed = now.duration_since(self.last_wait);
+        self.accumulated_wait += elapsed;
+
+        let overrun = elapsed.saturating_sub(desired_wait);
+        if overrun > Duration::new(0, 0) {
+            self.overrun_ticks += 1;
+        }
 
         // If the accumulated wait is lower than the desired_wait wait, simply subtract it
-        if self.accumulated_wait <= desired_wait {
-            thread::sleep(desired_wait - self.accumulated_wait);
+        let remaining = if self.accumulated_wait <= desired_wait {
+            let remaining = desired_wait - self.accumulated_wait;
             self.accumulated_wait = Duration::new(0, 0);
+            remaining
 
         // Otherwise reduce the accumulated wait by desired_wait and do not sleep at all
         } else {
             self.accumulated_wait -= desired_wait;
+            Duration::new(0, 0)
+        };
+
+        let ticks_behind = (self.accumulated_wait.as_nanos() / desired_wait.as_nanos()) as u32;
+        if let CatchUpPolicy::DropAfter(max_ticks) = self.catch_up {
+            if ticks_behind > max_ticks {
+                self.accumulated_wait = Duration::new(0, 0);
+            }
         }
 
-        self.last_wait = Instant::now();
+        self.next_report = TickReport {
+            slept: Duration::new(0, 0),
+            overrun: overrun,
+            ticks_behind: ticks_behind
+        };
+
+        self.wait_started = now;
+        let deadline = now + remaining;
+        self.deadline = Some(deadline);
+        deadline
 
     }
 
+    fn complete_tick(&mut self) {
+        let now = self.clock.now_instant();
+        self.report = TickReport {
+            slept: now.duration_since(self.wait_started),
+            .. self.next_report
+        };
+        self.deadline = None;
+        self.last_wait = now;
+    }
+
 }
 
 // Utilites -------------------------------------------------------------------
-fn precise_time_ms() -> u64 {
+pub struct Throughput {
+    total: usize,
+    current: usize,
+    average: MovingAverage
+}
+
+impl Throughput {
+
+    pub fn new(ticks_per_second: f64) -> Self {
+        Self {
+            total: 0,
+            current: 0,
+            average: MovingAverage::new(cmp::max(ticks_per_second.round() as usize, 1))
+        }
+    }
 
-    let dur = match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
-        Ok(dur) => dur,
-        Err(err) => err.duration(),
-    };
+    pub fn add(&mut self, bytes: usize) {
+        self.total += bytes;
+        self.current += bytes;
+    }
 
-    dur.as_secs() * 1000 + u64::from(dur.subsec_nanos() / 1_000_000)
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    // Bytes added since the last tick
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    pub fn per_second(&self, ticks_per_second: f64) -> f64 {
+        self.average.get() * ticks_per_second
+    }
+
+    pub fn tick(&mut self) {
+        self.average.update(self.current as f64, 1.0);
+        self.current = 0;
+    }
+
+}
+
+// Token bucket which is refilled every tick, up to one second worth of bytes
+pub struct RateLimit {
+    bytes_per_second: Option<usize>,
+    budget: usize
+}
+
+impl RateLimit {
+
+    pub fn new() -> Self {
+        Self {
+            bytes_per_second: None,
+            budget: 0
+        }
+    }
+
+    pub fn set(&mut self, bytes_per_second: Option<usize>) {
+        self.bytes_per_second = bytes_per_second;
+        self.budget = 0;
+    }
+
+    pub fn budget(&self) -> Option<usize> {
+        self.bytes_per_second.map(|_| self.budget)
+    }
+
+    pub fn available(&self) -> usize {
+        self.budget().unwrap_or(usize::MAX)
+    }
+
+    pub fn consume(&mut self, bytes: usize) {
+        self.budget = self.budget.saturating_sub(bytes);
+    }
+
+    pub fn tick(&mut self, ticks_per_second: f64) {
+        if let Some(limit) = self.bytes_per_second {
+            let refill = cmp::max((limit as f64 / tick_rate(ticks_per_second)) as usize, 1);
+            self.budget = cmp::min(self.budget + refill, cmp::max(limit, refill));
+        }
+    }
+
+}
+
+// Keeps tick durations representable and non-zero
+fn tick_rate(ticks_per_second: f64) -> f64 {
+    if ticks_per_second.is_nan() {
+        MIN_TICKS_PER_SECOND
+
+    } else {
+        ticks_per_second.clamp(MIN_TICKS_PER_SECOND, MAX_TICKS_PER_SECOND)
+    }
+}
+
+// Token bucket for incoming messages, refilled every tick so it does not depend
+// on the wall clock
+pub struct MessageLimit {
+    limit: Option<(u32, u32)>,
+    flood_threshold: Option<u32>,
+    tokens: f64,
+    dropped: u64,
+    dropped_this_tick: u32,
+    flooded: bool
+}
+
+impl MessageLimit {
+
+    pub fn new() -> Self {
+        Self {
+            limit: None,
+            flood_threshold: None,
+            tokens: 0.0,
+            dropped: 0,
+            dropped_this_tick: 0,
+            flooded: false
+        }
+    }
+
+    // Messages per second and the burst allowed on top of them, the bucket
+    // starts out full
+    pub fn set(&mut self, limit: Option<(u32, u32)>, flood_threshold: Option<u32>) {
+        let limit = limit.map(|(per_second, burst)| (per_second, cmp::max(burst, 1)));
+        if limit != self.limit {
+            self.tokens = limit.map_or(0.0, |(_, burst)| f64::from(burst));
+            self.limit = limit;
+        }
+        self.flood_threshold = flood_threshold;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.limit.is_some()
+    }
+
+    // Takes a token for a single message, peers which keep going after they
+    // ran out are flooding once they exceed the threshold within a tick
+    pub fn allow(&mut self) -> bool {
+        if self.limit.is_none() {
+            true
+
+        } else if !self.flooded && self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+
+        } else {
+            self.dropped += 1;
+            self.dropped_this_tick = self.dropped_this_tick.saturating_add(1);
+            if self.flood_threshold.is_some_and(|threshold| self.dropped_this_tick > threshold) {
+                self.flooded = true;
+            }
+            false
+        }
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn dropped_this_tick(&self) -> u32 {
+        self.dropped_this_tick
+    }
+
+    pub fn flooded(&self) -> bool {
+        self.flooded
+    }
+
+    pub fn tick(&mut self, ticks_per_second: f64) {
+        if let Some((per_second, burst)) = self.limit {
+            self.tokens = (self.tokens + f64::from(per_second) / tick_rate(ticks_per_second)).min(f64::from(burst));
+        }
+        self.dropped_this_tick = 0;
+    }
 
 }
 
@@ -178,25 +1167,67 @@ struct MovingAverage {
     index: usize,
     used: usize,
     average: f64,
+    kind: Average,
     values: Vec<f64>
 }
 
 impl MovingAverage {
 
     fn new(size: usize) -> Self {
+        MovingAverage::with_average(size, Average::Mean)
+    }
+
+    fn with_average(size: usize, kind: Average) -> Self {
         Self {
             size: size,
             index: 0,
             used: 0,
             average: 0.0,
-            values: iter::repeat(0.0f64).take(size).collect()
+            kind: kind,
+            values: iter::repeat_n(0.0f64, size).collect()
         }
     }
 
+    fn set_average(&mut self, kind: Average) {
+        self.kind = kind;
+        self.average = self.compute();
+    }
+
     fn get(&self) -> f64 {
         self.average
     }
 
+    fn is_empty(&self) -> bool {
+        self.used == 0
+    }
+
+    // Whether the window has been filled completely
+    fn is_warm(&self) -> bool {
+        self.used == self.size
+    }
+
+    fn bounds(&self) -> (f64, f64) {
+        if self.used == 0 {
+            (0.0, 0.0)
+
+        } else {
+            self.values[..self.used].iter().fold((f64::MAX, f64::MIN), |(min, max), &value| {
+                (min.min(value), max.max(value))
+            })
+        }
+    }
+
+    // Standard deviation of the samples from their average
+    fn deviation(&self) -> f64 {
+        if self.used == 0 {
+            0.0
+
+        } else {
+            let sum: f64 = self.values[..self.used].iter().map(|value| (value - self.average).powi(2)).sum();
+            (sum / self.used as f64).sqrt()
+        }
+    }
+
     fn update(&mut self, value: f64, ratio: f64) {
 
         self.values[self.index] = self.average * (1.0 - ratio) + value * ratio;
@@ -210,8 +1241,33 @@ impl MovingAverage {
             self.index = 0;
         }
 
-        let value: f64 = self.values.iter().sum();
-        self.average = value / self.used as f64
+        self.average = self.compute();
+
+    }
+
+    fn compute(&self) -> f64 {
+
+        if self.used == 0 {
+            return 0.0;
+        }
+
+        let trim = match self.kind {
+            Average::Mean => {
+                let value: f64 = self.values[..self.used].iter().sum();
+                return value / self.used as f64;
+            },
+            Average::Median => (self.used - 1) / 2,
+            Average::TrimmedMean(fraction) => {
+                cmp::min((self.used as f64 * fraction.clamp(0.0, 0.5)) as usize, (self.used - 1) / 2)
+            }
+        };
+
+        // Averaging the middle samples also yields the median of an even window
+        let mut sorted = self.values[..self.used].to_vec();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+
+        let middle = &sorted[trim..self.used - trim];
+        middle.iter().sum::<f64>() / middle.len() as f64
 
     }
 
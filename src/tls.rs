@@ -0,0 +1,266 @@
+// Copyright (c) 2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::time::Duration;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+use std::io::{Read, Write};
+use std::io::{Error as IOError, ErrorKind, IoSlice};
+use std::net::SocketAddr;
+
+
+// External Dependencies ------------------------------------------------------
+use rustls::{self, ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::pki_types::pem::PemObject;
+#[cfg(feature = "poll")]
+use mio::{Registry, Token};
+
+
+// Internal Dependencies ------------------------------------------------------
+use ::error::Error;
+use ::protocol::{Protocol, Host, Connection, TcpConfig, TcpHost, TcpConnection};
+
+
+// Statics --------------------------------------------------------------------
+static ACCEPTOR: Mutex<Option<TlsAcceptor>> = Mutex::new(None);
+static CONNECTOR: Mutex<Option<TlsConnector>> = Mutex::new(None);
+const TLS_MAX_PENDING_SIZE: usize = 64 * 1024;
+
+
+// TLS Configuration ----------------------------------------------------------
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    config: Arc<ServerConfig>
+}
+
+impl TlsAcceptor {
+
+    pub fn from_pem(cert_chain: &[u8], key: &[u8]) -> Result<Self, Error> {
+        let certs = CertificateDer::pem_slice_iter(cert_chain).collect::<Result<Vec<_>, _>>().map_err(invalid_data)?;
+        let key = PrivateKeyDer::from_pem_slice(key).map_err(invalid_data)?;
+        let config = ServerConfig::builder().with_no_client_auth().with_single_cert(certs, key).map_err(invalid_data)?;
+        Ok(Self {
+            config: Arc::new(config)
+        })
+    }
+
+    // Use this configuration for all TLS hosts bound afterwards
+    pub fn install(&self) {
+        if let Ok(mut acceptor) = ACCEPTOR.lock() {
+            *acceptor = Some(self.clone());
+        }
+    }
+
+}
+
+#[derive(Clone)]
+pub struct TlsConnector {
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>
+}
+
+impl TlsConnector {
+
+    pub fn from_pem(root_certs: &[u8], server_name: &str) -> Result<Self, Error> {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_slice_iter(root_certs) {
+            roots.add(cert.map_err(invalid_data)?).map_err(invalid_data)?;
+        }
+        let config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+        Ok(Self {
+            config: Arc::new(config),
+            server_name: ServerName::try_from(server_name.to_string()).map_err(invalid_data)?
+        })
+    }
+
+    // Use this configuration for all TLS connections created afterwards
+    pub fn install(&self) {
+        if let Ok(mut connector) = CONNECTOR.lock() {
+            *connector = Some(self.clone());
+        }
+    }
+
+}
+
+fn invalid_data<E: ToString>(err: E) -> IOError {
+    IOError::new(ErrorKind::InvalidData, err.to_string())
+}
+
+fn installed<T: Clone>(config: &Mutex<Option<T>>) -> Result<T, IOError> {
+    config.lock().ok().and_then(|config| config.clone()).ok_or_else(|| {
+        IOError::new(ErrorKind::NotFound, "no TLS configuration installed")
+    })
+}
+
+
+// TLS Protocol ---------------------------------------------------------------
+pub struct Tls;
+impl Protocol for Tls {
+    type Host = TlsHost;
+    type Connection = TlsConnection;
+}
+
+pub struct TlsHost {
+    host: TcpHost,
+    config: Arc<ServerConfig>
+}
+
+impl Host for TlsHost {
+
+    type Connection = TlsConnection;
+
+    fn bind(addrs: &[SocketAddr], config: &TcpConfig) -> Result<Self, IOError> where Self: Sized {
+        let acceptor = installed(&ACCEPTOR)?;
+        Ok(Self {
+            host: TcpHost::bind(addrs, config)?,
+            config: acceptor.config
+        })
+    }
+
+    fn accept(&mut self) -> Result<TlsConnection, IOError> where Self: Sized {
+        let connection = self.host.accept()?;
+        let session = ServerConnection::new(self.config.clone()).map_err(invalid_data)?;
+        Ok(TlsConnection::new(connection, rustls::Connection::Server(session)))
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, IOError> where Self: Sized {
+        self.host.local_addr()
+    }
+
+    #[cfg(feature = "poll")]
+    fn register(&mut self, registry: &Registry, token: Token) -> Result<(), IOError> where Self: Sized {
+        self.host.register(registry, token)
+    }
+
+    fn shutdown(self) -> Result<(), IOError> where Self: Sized {
+        self.host.shutdown()
+    }
+
+}
+
+pub struct TlsConnection {
+    connection: TcpConnection,
+    session: rustls::Connection,
+    pending: Vec<u8>
+}
+
+impl TlsConnection {
+
+    fn new(connection: TcpConnection, session: rustls::Connection) -> Self {
+        Self {
+            connection: connection,
+            session: session,
+            pending: Vec::new()
+        }
+    }
+
+    // Moves encrypted records from the session onto the socket, as far as it
+    // accepts them
+    fn flush(&mut self) -> Result<(), IOError> {
+        while self.session.wants_write() && self.pending.len() < TLS_MAX_PENDING_SIZE {
+            self.session.write_tls(&mut self.pending)?;
+        }
+        if !self.pending.is_empty() {
+            let bytes = self.connection.write(&self.pending[..])?;
+            self.pending.drain(0..bytes);
+        }
+        Ok(())
+    }
+
+}
+
+impl Connection for TlsConnection {
+
+    type Addr = SocketAddr;
+    type Config = TcpConfig;
+
+    fn connect(addrs: &[SocketAddr], timeout: Duration, config: &TcpConfig) -> Result<Self, IOError> where Self: Sized {
+        let connector = installed(&CONNECTOR)?;
+        let connection = TcpConnection::connect(addrs, timeout, config)?;
+        let session = ClientConnection::new(connector.config, connector.server_name).map_err(invalid_data)?;
+        let mut connection = TlsConnection::new(connection, rustls::Connection::Client(session));
+        connection.flush()?;
+        Ok(connection)
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr, IOError> where Self: Sized {
+        self.connection.peer_addr()
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, IOError> where Self: Sized {
+        self.connection.local_addr()
+    }
+
+    fn read(&mut self, buffer: &mut Vec<u8>, max_bytes: usize) -> Result<usize, IOError> where Self: Sized {
+
+        let mut encrypted = Vec::new();
+        let result = self.connection.read(&mut encrypted, max_bytes);
+
+        // Feed everything that was received into the session before surfacing errors
+        let offset = buffer.len();
+        let mut records = &encrypted[..];
+        loop {
+            if !records.is_empty() {
+                self.session.read_tls(&mut records)?;
+                self.session.process_new_packets().map_err(invalid_data)?;
+            }
+
+            // Drain decrypted data so the session's plaintext buffer never fills up
+            match self.session.reader().read_to_end(buffer) {
+                Ok(_) => {},
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => {},
+                Err(err) => return Err(err)
+            }
+
+            if records.is_empty() {
+                break;
+            }
+        }
+
+        // Handshake responses are sent right away
+        self.flush()?;
+        result?;
+
+        Ok(buffer.len() - offset)
+
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<usize, IOError> where Self: Sized {
+        self.write_vectored(&[IoSlice::new(bytes)])
+    }
+
+    fn write_vectored(&mut self, slices: &[IoSlice]) -> Result<usize, IOError> where Self: Sized {
+
+        // Nothing can be sent until the handshake has completed
+        let written = if self.session.is_handshaking() || self.pending.len() >= TLS_MAX_PENDING_SIZE {
+            0
+
+        } else {
+            self.session.writer().write_vectored(slices)?
+        };
+
+        self.flush()?;
+        Ok(written)
+
+    }
+
+    #[cfg(feature = "poll")]
+    fn register(&mut self, registry: &Registry, token: Token) -> Result<(), IOError> where Self: Sized {
+        self.connection.register(registry, token)
+    }
+
+    fn shutdown(&mut self) -> Result<(), IOError> where Self: Sized {
+        self.session.send_close_notify();
+        self.flush().ok();
+        self.connection.shutdown()
+    }
+
+}
@@ -0,0 +1,287 @@
+// Copyright (c) 2017 Ivo Wetzel
+
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::mem;
+use std::time::Duration;
+use std::io::{Error as IOError, ErrorKind};
+use std::net::{SocketAddr, Shutdown, TcpStream};
+#[cfg(all(feature = "poll", unix))]
+use std::os::unix::io::AsRawFd;
+
+
+// External Dependencies ------------------------------------------------------
+use tungstenite::{self, HandshakeError, Message};
+use tungstenite::client::IntoClientRequest;
+use tungstenite::handshake::MidHandshake;
+use tungstenite::handshake::client::ClientHandshake;
+use tungstenite::handshake::server::{NoCallback, ServerHandshake};
+use tungstenite::protocol::WebSocket as Socket;
+#[cfg(feature = "poll")]
+use mio::{Registry, Token};
+#[cfg(all(feature = "poll", unix))]
+use mio::{Interest, unix::SourceFd};
+
+
+// Internal Dependencies ------------------------------------------------------
+use ::protocol::{Protocol, Host, Connection, TcpConfig, TcpHost, configure_stream};
+use ::message::{frame_length, FRAME_HEADER_SIZE};
+
+
+// WebSocket Protocol ---------------------------------------------------------
+pub struct WebSocket;
+impl Protocol for WebSocket {
+    type Host = WebSocketHost;
+    type Connection = WebSocketConnection;
+}
+
+pub struct WebSocketHost {
+    host: TcpHost
+}
+
+impl Host for WebSocketHost {
+
+    type Connection = WebSocketConnection;
+
+    fn bind(addrs: &[SocketAddr], config: &TcpConfig) -> Result<Self, IOError> where Self: Sized {
+        Ok(Self {
+            host: TcpHost::bind(addrs, config)?
+        })
+    }
+
+    fn accept(&mut self) -> Result<WebSocketConnection, IOError> where Self: Sized {
+        // The upgrade request is answered over the following ticks
+        let (stream, addr) = self.host.accept_stream()?;
+        let handshake = ServerHandshake::start(stream.try_clone()?, NoCallback, None);
+        Ok(WebSocketConnection::new(stream, addr, State::Accepting(Box::new(handshake))))
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, IOError> where Self: Sized {
+        self.host.local_addr()
+    }
+
+    #[cfg(feature = "poll")]
+    fn register(&mut self, registry: &Registry, token: Token) -> Result<(), IOError> where Self: Sized {
+        self.host.register(registry, token)
+    }
+
+    fn shutdown(self) -> Result<(), IOError> where Self: Sized {
+        self.host.shutdown()
+    }
+
+}
+
+enum State {
+    Accepting(Box<MidHandshake<ServerHandshake<TcpStream, NoCallback>>>),
+    Connecting(Box<MidHandshake<ClientHandshake<TcpStream>>>),
+    Open(Box<Socket<TcpStream>>),
+    Closed
+}
+
+pub struct WebSocketConnection {
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    state: State
+}
+
+impl WebSocketConnection {
+
+    fn new(stream: TcpStream, peer_addr: SocketAddr, state: State) -> Self {
+        Self {
+            stream: stream,
+            peer_addr: peer_addr,
+            state: state
+        }
+    }
+
+    fn open_stream(addrs: &[SocketAddr], timeout: Duration, config: &TcpConfig) -> Result<(TcpStream, SocketAddr), IOError> {
+        if let Some(&addr) = addrs.first() {
+            let stream = TcpStream::connect_timeout(&addr, timeout)?;
+            configure_stream(&stream, config)?;
+            Ok((stream, addr))
+
+        } else {
+            Err(IOError::new(ErrorKind::AddrNotAvailable, ""))
+        }
+    }
+
+    // Continues the upgrade handshake, returns whether it has completed
+    fn handshake(&mut self) -> Result<bool, IOError> {
+        self.state = match mem::replace(&mut self.state, State::Closed) {
+            State::Accepting(handshake) => match handshake.handshake() {
+                Ok(socket) => State::Open(Box::new(socket)),
+                Err(HandshakeError::Interrupted(handshake)) => State::Accepting(Box::new(handshake)),
+                Err(HandshakeError::Failure(err)) => return Err(io_error(err))
+            },
+            State::Connecting(handshake) => match handshake.handshake() {
+                Ok((socket, _)) => State::Open(Box::new(socket)),
+                Err(HandshakeError::Interrupted(handshake)) => State::Connecting(Box::new(handshake)),
+                Err(HandshakeError::Failure(err)) => return Err(io_error(err))
+            },
+            State::Open(socket) => State::Open(socket),
+            State::Closed => return Err(IOError::new(ErrorKind::NotConnected, ""))
+        };
+        Ok(matches!(self.state, State::Open(_)))
+    }
+
+}
+
+impl Connection for WebSocketConnection {
+
+    type Addr = SocketAddr;
+    type Config = TcpConfig;
+
+    fn connect(addrs: &[SocketAddr], timeout: Duration, config: &TcpConfig) -> Result<Self, IOError> where Self: Sized {
+
+        // Perform the whole handshake while the stream is still blocking
+        let (stream, addr) = WebSocketConnection::open_stream(addrs, timeout, config)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        let (socket, _) = tungstenite::client(request(addr)?, stream.try_clone()?).map_err(|err| match err {
+            HandshakeError::Interrupted(_) => IOError::new(ErrorKind::TimedOut, ""),
+            HandshakeError::Failure(err) => io_error(err)
+        })?;
+
+        stream.set_read_timeout(None)?;
+        stream.set_write_timeout(None)?;
+        stream.set_nonblocking(true)?;
+        Ok(WebSocketConnection::new(stream, addr, State::Open(Box::new(socket))))
+
+    }
+
+    fn connect_start(addrs: &[SocketAddr], timeout: Duration, config: &TcpConfig) -> Result<Self, IOError> where Self: Sized {
+        let (stream, addr) = WebSocketConnection::open_stream(addrs, timeout, config)?;
+        stream.set_nonblocking(true)?;
+        let handshake = ClientHandshake::start(stream.try_clone()?, request(addr)?, None).map_err(io_error)?;
+        Ok(WebSocketConnection::new(stream, addr, State::Connecting(Box::new(handshake))))
+    }
+
+    fn connect_poll(&mut self) -> Result<bool, IOError> where Self: Sized {
+        self.handshake()
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr, IOError> where Self: Sized {
+        Ok(self.peer_addr)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, IOError> where Self: Sized {
+        self.stream.local_addr()
+    }
+
+    fn read(&mut self, buffer: &mut Vec<u8>, max_bytes: usize) -> Result<usize, IOError> where Self: Sized {
+
+        if !self.handshake()? {
+            return Ok(0);
+        }
+
+        let socket = match self.state {
+            State::Open(ref mut socket) => socket,
+            _ => return Ok(0)
+        };
+
+        // Every binary message carries exactly one frame
+        let mut bytes = 0;
+        while bytes < max_bytes {
+            match socket.read() {
+                Ok(Message::Binary(data)) => {
+                    bytes += data.len();
+                    buffer.extend(data);
+                },
+                Ok(_) => {},
+                Err(tungstenite::Error::Io(ref err)) if err.kind() == ErrorKind::WouldBlock => break,
+                // Deliver what arrived before the connection was closed first
+                Err(_) if bytes > 0 => break,
+                Err(tungstenite::Error::ConnectionClosed) | Err(tungstenite::Error::AlreadyClosed) => {
+                    self.stream.shutdown(Shutdown::Both).ok();
+                    return Err(IOError::new(ErrorKind::UnexpectedEof, "closed by the peer"));
+                },
+                Err(err) => {
+                    self.stream.shutdown(Shutdown::Both).ok();
+                    return Err(io_error(err));
+                }
+            }
+        }
+
+        Ok(bytes)
+
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<usize, IOError> where Self: Sized {
+
+        // Nothing can be sent until the handshake has completed
+        if !self.handshake()? {
+            return Ok(0);
+        }
+
+        let socket = match self.state {
+            State::Open(ref mut socket) => socket,
+            _ => return Ok(0)
+        };
+
+        // Only hand over more frames once the previous ones left the socket
+        match socket.flush() {
+            Ok(()) => {},
+            Err(tungstenite::Error::Io(ref err)) if err.kind() == ErrorKind::WouldBlock => return Ok(0),
+            Err(err) => return Err(io_error(err))
+        }
+
+        let mut written = 0;
+        while let Some(length) = frame_length(&bytes[written..]) {
+            let end = written + FRAME_HEADER_SIZE + length;
+            if bytes.len() < end {
+                break;
+            }
+            match socket.write(Message::Binary(bytes[written..end].to_vec())) {
+                Ok(()) => written = end,
+                // The message was buffered but the socket is full
+                Err(tungstenite::Error::Io(ref err)) if err.kind() == ErrorKind::WouldBlock => {
+                    written = end;
+                    break;
+                },
+                Err(tungstenite::Error::WriteBufferFull(_)) => break,
+                Err(err) => return Err(io_error(err))
+            }
+        }
+
+        match socket.flush() {
+            Ok(()) => Ok(written),
+            Err(tungstenite::Error::Io(ref err)) if err.kind() == ErrorKind::WouldBlock => Ok(written),
+            Err(err) => Err(io_error(err))
+        }
+
+    }
+
+    #[cfg(all(feature = "poll", unix))]
+    fn register(&mut self, registry: &Registry, token: Token) -> Result<(), IOError> where Self: Sized {
+        registry.register(&mut SourceFd(&self.stream.as_raw_fd()), token, Interest::READABLE)
+    }
+
+    fn shutdown(&mut self) -> Result<(), IOError> where Self: Sized {
+        if let State::Open(ref mut socket) = self.state {
+            socket.close(None).ok();
+            socket.flush().ok();
+        }
+        self.state = State::Closed;
+        self.stream.shutdown(Shutdown::Both)
+    }
+
+}
+
+fn request(addr: SocketAddr) -> Result<tungstenite::handshake::client::Request, IOError> {
+    format!("ws://{}/", addr).into_client_request().map_err(io_error)
+}
+
+fn io_error(err: tungstenite::Error) -> IOError {
+    match err {
+        tungstenite::Error::Io(err) => err,
+        err => IOError::new(ErrorKind::InvalidData, err.to_string())
+    }
+}
+
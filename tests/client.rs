@@ -0,0 +1,227 @@
+// Crates ---------------------------------------------------------------------
+extern crate cobalt_two;
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::sync::Arc;
+use std::time::Duration;
+use std::net::SocketAddr;
+use std::io::ErrorKind;
+
+
+// External Dependencies ------------------------------------------------------
+use cobalt_two::{AckId, Client, ClientState, CloseReason, Error, Memory, MockClock, SymmetricServer};
+
+
+// Every client and server runs on its own mock clock, so none of the tests
+// takes any real time
+static TICKS_PER_SECOND: f64 = 30.0;
+static MAX_TICKS: usize = 300;
+
+type Server = SymmetricServer<Memory, u8, ()>;
+
+fn bind() -> Server {
+    let mut server = Server::with_clock(TICKS_PER_SECOND, Arc::new(MockClock::new()));
+    server.bind("127.0.0.1:0").unwrap();
+    server
+}
+
+fn client() -> Client<Memory, u8> {
+    Client::with_clock(TICKS_PER_SECOND, Arc::new(MockClock::new()))
+}
+
+// Collects what the server receives until the condition holds
+fn tick<F: FnMut(&mut Client<Memory, u8>, &[u8]) -> bool>(server: &mut Server, client: &mut Client<Memory, u8>, mut done: F) -> Vec<u8> {
+    let mut received = Vec::new();
+    for _ in 0..MAX_TICKS {
+        client.receive().map(|messages| messages.count()).ok();
+        client.sleep();
+        for _ in server.accepted_with(|_| Ok(())) {}
+        for &mut (ref mut remote, _) in server.connected() {
+            received.extend(remote.receive());
+        }
+        for _ in server.closed() {}
+        if done(client, &received) {
+            return received;
+        }
+        server.sleep();
+    }
+    panic!("condition not met within {} ticks", MAX_TICKS);
+}
+
+#[test]
+fn messages_sent_before_connecting_go_out_first() {
+
+    let mut server = bind();
+    let mut client = client();
+    client.set_preconnect_queue(4);
+
+    // Queued while disconnected and during the handshake
+    client.send(0).unwrap();
+    client.send(1).unwrap();
+    client.connect(server.local_addr().unwrap(), Duration::from_millis(500)).unwrap();
+    assert_eq!(client.state(), ClientState::Connecting);
+    client.send(2).unwrap();
+
+    // Later messages are only sent once the queued ones are out
+    let received = tick(&mut server, &mut client, |client, received| {
+        if client.state() == ClientState::Connected && client.messages_sent() == 3 {
+            client.send(3).unwrap();
+        }
+        received.len() == 4
+    });
+    assert_eq!(received, vec![0, 1, 2, 3]);
+    assert!(client.take_unsent().is_empty());
+
+}
+
+#[test]
+fn messages_over_the_limit_of_the_queue_are_refused() {
+
+    let mut client = client();
+    client.set_preconnect_queue(2);
+    client.send(0).unwrap();
+    client.send(1).unwrap();
+    match client.send(2) {
+        Err(Error::BufferFull) => {},
+        other => panic!("expected BufferFull, got {:?}", other)
+    }
+
+    // Pre-encoded bytes are never queued
+    match client.send_bytes(&[2]) {
+        Err(Error::NotConnected) => {},
+        other => panic!("expected NotConnected, got {:?}", other)
+    }
+
+    // Failed connections leave the queue alone
+    assert!(client.connect("127.0.0.1:1", Duration::from_millis(500)).is_err());
+    assert_eq!(client.take_unsent(), vec![0, 1]);
+    client.send(2).unwrap();
+
+    // Without a queue nothing can be sent before connecting
+    let mut client = self::client();
+    match client.send(0) {
+        Err(Error::NotConnected) => {},
+        other => panic!("expected NotConnected, got {:?}", other)
+    }
+
+}
+
+#[test]
+fn dropped_clients_close_their_connection_in_order() {
+
+    let mut server = bind();
+    {
+        let mut client = client();
+        client.connect(server.local_addr().unwrap(), Duration::from_millis(500)).unwrap();
+        tick(&mut server, &mut client, |client, _| client.state() == ClientState::Connected);
+    }
+
+    let mut reasons = Vec::new();
+    for _ in 0..MAX_TICKS {
+        for _ in server.connected() {}
+        reasons.extend(server.closed().map(|(_, remote, _)| remote.close_reason()));
+        if !reasons.is_empty() {
+            break;
+        }
+        server.sleep();
+    }
+    match reasons.as_slice() {
+        [Some(CloseReason::RemoteClose(_))] => {},
+        other => panic!("expected a single RemoteClose, got {:?}", other)
+    }
+
+}
+
+#[test]
+fn connecting_fails_over_to_the_next_address() {
+
+    let mut server = bind();
+    let dead: SocketAddr = "127.0.0.1:1".parse().unwrap();
+    let live = server.local_addr().unwrap();
+
+    let mut client = client();
+    client.connect_any(&[dead, live], Duration::from_millis(500)).unwrap();
+    assert_eq!(client.connected_addr(), Some(live));
+    tick(&mut server, &mut client, |client, _| client.state() == ClientState::Connected);
+    client.disconnect().unwrap();
+
+    // The same goes for connections established during sleep()
+    let mut client = self::client();
+    client.connect_nonblocking(&[dead, live][..], Duration::from_millis(500)).unwrap();
+    tick(&mut server, &mut client, |client, _| client.state() == ClientState::Connected);
+    assert_eq!(client.connected_addr(), Some(live));
+
+}
+
+#[test]
+fn connecting_reports_the_failure_of_every_address() {
+
+    let dead: Vec<SocketAddr> = vec!["127.0.0.1:1".parse().unwrap(), "127.0.0.1:2".parse().unwrap()];
+    let mut client = client();
+    match client.connect_any(&dead, Duration::from_millis(500)) {
+        Err(Error::ConnectFailed(failures)) => {
+            assert_eq!(failures.iter().map(|(addr, _)| addr.as_str()).collect::<Vec<_>>(), vec!["127.0.0.1:1", "127.0.0.1:2"]);
+            assert!(failures.iter().all(|(_, err)| err.kind() == ErrorKind::ConnectionRefused));
+        },
+        other => panic!("expected ConnectFailed, got {:?}", other)
+    }
+    assert_eq!(client.state(), ClientState::Disconnected);
+    assert_eq!(client.connected_addr(), None);
+
+}
+
+#[test]
+fn acks_only_advance_once_the_server_iterated_the_messages() {
+
+    let mut server = bind();
+    let mut client = client();
+    client.connect(server.local_addr().unwrap(), Duration::from_millis(500)).unwrap();
+    tick(&mut server, &mut client, |client, _| client.state() == ClientState::Connected);
+
+    let ids: Vec<AckId> = (0..3).map(|message| client.send_acked(message).unwrap()).collect();
+    assert_eq!(ids, vec![AckId(0), AckId(1), AckId(2)]);
+
+    // Received by the server, but not yet handed to its application
+    let mut acked = Vec::new();
+    for _ in 0..30 {
+        client.receive().map(|messages| messages.count()).ok();
+        acked.extend(client.acked());
+        client.sleep();
+        for _ in server.accepted_with(|_| Ok(())) {}
+        for _ in server.closed() {}
+        server.sleep();
+    }
+    assert!(acked.is_empty());
+    assert_eq!(client.last_acked(), None);
+
+    let mut received = Vec::new();
+    let mut step = |server: &mut Server, client: &mut Client<Memory, u8>, take: usize, acked: &mut Vec<AckId>| {
+        for &mut (ref mut remote, _) in server.connected() {
+            received.extend(remote.receive().take(take));
+        }
+        for _ in server.closed() {}
+        server.sleep();
+        client.receive().map(|messages| messages.count()).ok();
+        acked.extend(client.acked());
+        client.sleep();
+    };
+
+    // Only the two messages which were iterated are acked
+    step(&mut server, &mut client, 2, &mut acked);
+    for _ in 0..30 {
+        step(&mut server, &mut client, 0, &mut acked);
+    }
+    assert_eq!(acked, vec![AckId(0), AckId(1)]);
+
+    for _ in 0..MAX_TICKS {
+        step(&mut server, &mut client, 1, &mut acked);
+        if acked.len() == 3 {
+            break;
+        }
+    }
+    assert_eq!(acked, ids);
+    assert_eq!(client.last_acked(), Some(AckId(2)));
+    assert_eq!(received, vec![0, 1, 2]);
+
+}
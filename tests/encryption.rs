@@ -0,0 +1,80 @@
+// Crates ---------------------------------------------------------------------
+extern crate cobalt_two;
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::sync::Arc;
+use std::time::Duration;
+
+
+// External Dependencies ------------------------------------------------------
+use cobalt_two::{Client, ClientState, CloseReason, Memory, MockClock, SymmetricServer};
+
+
+// Every client and server runs on its own mock clock, so none of the tests
+// takes any real time
+static TICKS_PER_SECOND: f64 = 30.0;
+static MAX_TICKS: usize = 300;
+
+type Server = SymmetricServer<Memory, String, ()>;
+
+fn connect(server_psk: [u8; 32], client_psk: [u8; 32]) -> (Server, Client<Memory, String>) {
+
+    let mut server = Server::with_clock(TICKS_PER_SECOND, Arc::new(MockClock::new()));
+    server.set_psk(server_psk);
+    server.bind("127.0.0.1:0").unwrap();
+
+    let mut client = Client::with_clock(TICKS_PER_SECOND, Arc::new(MockClock::new()));
+    client.set_psk(client_psk);
+    client.connect(server.local_addr().unwrap(), Duration::from_millis(500)).unwrap();
+
+    (server, client)
+
+}
+
+// Ticks both sides until the server received the message or the client lost
+// its connection, returning the received messages and the accepted remotes
+fn run(server: &mut Server, client: &mut Client<Memory, String>, message: &str) -> (Vec<String>, usize) {
+    let (mut received, mut accepted) = (Vec::new(), 0);
+    for _ in 0..MAX_TICKS {
+        accepted += server.accepted_with(|_| Ok(())).count();
+        for &mut (ref mut remote, _) in server.connected() {
+            received.extend(remote.receive());
+        }
+        for _ in server.closed() {}
+        server.sleep();
+
+        if client.state() == ClientState::Connected && client.messages_sent() == 0 {
+            client.send(message.to_string()).unwrap();
+        }
+        client.receive().map(|messages| messages.count()).ok();
+        client.sleep();
+
+        if !received.is_empty() || client.state() == ClientState::Lost || client.state() == ClientState::Failed {
+            break;
+        }
+    }
+    (received, accepted)
+}
+
+#[test]
+fn messages_round_trip_with_the_same_psk() {
+    let (mut server, mut client) = connect([7; 32], [7; 32]);
+    let (received, accepted) = run(&mut server, &mut client, "secret");
+    assert_eq!(received, vec!["secret".to_string()]);
+    assert_eq!(accepted, 1);
+    assert_eq!(client.state(), ClientState::Connected);
+}
+
+#[test]
+fn mismatched_psks_close_the_connection() {
+    let (mut server, mut client) = connect([7; 32], [8; 32]);
+    let (received, accepted) = run(&mut server, &mut client, "secret");
+
+    // Neither side gets to see anything of the other one
+    assert!(received.is_empty());
+    assert_eq!(accepted, 0);
+    assert!(server.remotes().is_empty());
+    assert_eq!(client.state(), ClientState::Lost);
+    assert_eq!(client.disconnect_reason(), Some(CloseReason::ProtocolError));
+}
@@ -0,0 +1,173 @@
+// Crates ---------------------------------------------------------------------
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+extern crate cobalt_two;
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::sync::Arc;
+use std::time::Duration;
+
+
+// External Dependencies ------------------------------------------------------
+use cobalt_two::{Client, ClientState, ConnectionQuality, FakePeer, Memory, MockClock, ReconnectPolicy, SymmetricServer};
+
+
+// Every test runs on a mock clock shared by the peer and the side under
+// test, so none of them takes any real time
+static TICKS_PER_SECOND: f64 = 30.0;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+enum Message {
+    Ping(u32),
+    Pong(u32),
+    Kick
+}
+
+type Server = SymmetricServer<Memory, Message, u32>;
+
+fn server(clock: Arc<MockClock>) -> Server {
+    let mut server = Server::with_clock(TICKS_PER_SECOND, clock);
+    server.bind("127.0.0.1:0").unwrap();
+    server
+}
+
+// Echoes pings and kicks whoever asks for it, counting the pings of each remote
+fn tick(server: &mut Server, accepted: &mut usize, closed: &mut Vec<u32>) {
+    *accepted += server.accepted_with(|_| Ok(0)).count();
+    for &mut (ref mut remote, ref mut pings) in server.connected() {
+        let messages: Vec<Message> = remote.receive().collect();
+        for m in messages {
+            match m {
+                Message::Ping(n) => {
+                    *pings += 1;
+                    remote.send(Message::Pong(n)).unwrap();
+                },
+                Message::Kick => remote.close_with_code(42).unwrap(),
+                Message::Pong(_) => {}
+            }
+        }
+    }
+    closed.extend(server.closed().map(|(_, _, pings)| pings));
+    server.sleep();
+}
+
+#[test]
+fn server_accepts_peers_and_answers_their_messages() {
+
+    let clock = Arc::new(MockClock::new());
+    let mut server = server(clock.clone());
+
+    let mut peer = FakePeer::<Message>::new(TICKS_PER_SECOND, clock);
+    peer.connect(server.local_addr().unwrap()).unwrap();
+    peer.expect_connect()
+        .send(Message::Ping(1))
+        .expect_message(|m| *m == Message::Pong(1))
+        .send(Message::Ping(2))
+        .expect_message(|m| *m == Message::Pong(2));
+
+    let (mut accepted, mut closed) = (0, Vec::new());
+    peer.run(|| tick(&mut server, &mut accepted, &mut closed)).unwrap();
+
+    assert_eq!(accepted, 1);
+    assert!(closed.is_empty());
+    assert_eq!(server.remotes().iter_mut().map(|(_, &mut pings)| pings).collect::<Vec<_>>(), vec![2]);
+
+}
+
+#[test]
+fn server_kicks_peers_with_a_close_code() {
+
+    let clock = Arc::new(MockClock::new());
+    let mut server = server(clock.clone());
+
+    let mut peer = FakePeer::<Message>::new(TICKS_PER_SECOND, clock);
+    peer.connect(server.local_addr().unwrap()).unwrap();
+    peer.expect_connect()
+        .send(Message::Ping(1))
+        .expect_message(|m| *m == Message::Pong(1))
+        .send(Message::Kick)
+        .expect_close();
+
+    let (mut accepted, mut closed) = (0, Vec::new());
+    peer.run(|| tick(&mut server, &mut accepted, &mut closed)).unwrap();
+    peer.advance_ticks(1).run(|| tick(&mut server, &mut accepted, &mut closed)).unwrap();
+
+    assert_eq!(peer.close_code(), Some(42));
+    assert!(!peer.is_connected());
+    assert_eq!(closed, vec![1]);
+    assert!(server.remotes().is_empty());
+
+}
+
+#[test]
+fn client_measures_the_rtt_to_peers() {
+
+    let clock = Arc::new(MockClock::new());
+    let mut peer = FakePeer::<Message>::new(TICKS_PER_SECOND, clock.clone());
+    peer.listen("127.0.0.1:0").unwrap();
+
+    let mut client = Client::<Memory, Message>::with_clock(TICKS_PER_SECOND, clock);
+    client.connect(peer.local_addr().unwrap(), Duration::from_millis(100)).unwrap();
+    assert_eq!(client.quality(), None);
+
+    // The peer answers within the tick it receives a ping, which the RTT
+    // does not count
+    peer.expect_connect().advance_ticks(30).run(|| {
+        client.receive().map(|messages| messages.count()).ok();
+        client.sleep();
+
+    }).unwrap();
+
+    assert_eq!(client.state(), ClientState::Connected);
+    assert_eq!(client.quality(), Some(ConnectionQuality::Excellent));
+    assert_eq!(client.rtt(), 0.0);
+
+}
+
+#[test]
+fn client_reconnects_after_peers_drop_the_connection() {
+
+    let clock = Arc::new(MockClock::new());
+    let mut peer = FakePeer::<Message>::new(TICKS_PER_SECOND, clock.clone());
+    peer.listen("127.0.0.1:0").unwrap();
+
+    let mut client = Client::<Memory, Message>::with_clock(TICKS_PER_SECOND, clock);
+    client.set_auto_reconnect(Some(ReconnectPolicy {
+        initial_delay: Duration::from_millis(100),
+        ..ReconnectPolicy::default()
+    }));
+    client.connect(peer.local_addr().unwrap(), Duration::from_millis(100)).unwrap();
+
+    let mut states = Vec::new();
+    let mut received = Vec::new();
+    peer.expect_connect()
+        .advance_ticks(2)
+        .drop_connection()
+        .advance_ticks(1)
+        .expect_connect()
+        .send(Message::Ping(7))
+        .advance_ticks(2);
+
+    peer.run(|| {
+        if let Ok(messages) = client.receive() {
+            received.extend(messages);
+        }
+        if states.last() != Some(&client.state()) {
+            states.push(client.state());
+        }
+        client.sleep();
+
+    }).unwrap();
+
+    assert_eq!(states, vec![
+        ClientState::Connecting,
+        ClientState::Connected,
+        ClientState::Lost,
+        ClientState::Connecting,
+        ClientState::Connected
+    ]);
+    assert_eq!(received, vec![Message::Ping(7)]);
+
+}
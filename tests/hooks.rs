@@ -0,0 +1,88 @@
+// Crates ---------------------------------------------------------------------
+extern crate cobalt_two;
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::sync::Arc;
+use std::time::Duration;
+
+
+// External Dependencies ------------------------------------------------------
+use cobalt_two::{Client, ClientState, HookAction, Memory, MockClock, SymmetricServer};
+
+
+// Every client and server runs on its own mock clock, so none of the tests
+// takes any real time
+static TICKS_PER_SECOND: f64 = 30.0;
+static MAX_TICKS: usize = 300;
+
+type Server = SymmetricServer<Memory, u8, ()>;
+
+fn connect() -> (Server, Client<Memory, u8>) {
+
+    let mut server = Server::with_clock(TICKS_PER_SECOND, Arc::new(MockClock::new()));
+    server.bind("127.0.0.1:0").unwrap();
+
+    let mut client = Client::with_clock(TICKS_PER_SECOND, Arc::new(MockClock::new()));
+    client.connect(server.local_addr().unwrap(), Duration::from_millis(500)).unwrap();
+    tick(&mut server, &mut client, |server, client| {
+        for _ in server.accepted_with(|_| Ok(())) {}
+        server.connected().count() == 1 && client.state() == ClientState::Connected
+    });
+
+    (server, client)
+
+}
+
+fn tick<F: FnMut(&mut Server, &Client<Memory, u8>) -> bool>(server: &mut Server, client: &mut Client<Memory, u8>, mut done: F) {
+    for _ in 0..MAX_TICKS {
+        client.receive().map(|messages| messages.count()).ok();
+        client.sleep();
+        if done(server, client) {
+            return;
+        }
+        for _ in server.closed() {}
+        server.sleep();
+    }
+    panic!("condition not met within {} ticks", MAX_TICKS);
+}
+
+#[test]
+fn messages_dropped_by_the_inbound_hook_are_never_yielded() {
+
+    let (mut server, mut client) = connect();
+
+    let mut seen = 0;
+    server.set_inbound_hook(move |_, _| {
+        seen += 1;
+        if seen % 2 == 0 {
+            HookAction::Drop
+
+        } else {
+            HookAction::Pass
+        }
+    });
+
+    for message in 0..10 {
+        client.send(message).unwrap();
+    }
+
+    let mut received = Vec::new();
+    tick(&mut server, &mut client, |server, _| {
+        for &mut (ref mut remote, _) in server.connected() {
+            received.extend(remote.receive());
+        }
+        received.len() >= 5
+    });
+
+    // Later ticks do not bring back any of the dropped ones
+    tick(&mut server, &mut client, |server, _| {
+        for &mut (ref mut remote, _) in server.connected() {
+            received.extend(remote.receive());
+        }
+        server.ticks() >= 20
+    });
+    assert_eq!(received, vec![0, 2, 4, 6, 8]);
+    assert_eq!(client.state(), ClientState::Connected);
+
+}
@@ -0,0 +1,55 @@
+// Crates ---------------------------------------------------------------------
+extern crate cobalt_two;
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::time::Duration;
+use std::net::IpAddr;
+
+
+// External Dependencies ------------------------------------------------------
+use cobalt_two::{Accepted, BincodeCodec, Closed, Connected, ConnectionId, SymmetricServer, TCP, TcpConnection};
+
+
+// Nothing here boxes the iterators, so all of them can be named by the
+// application, be it to store or to return them
+type Server = SymmetricServer<TCP, u32, String>;
+
+struct Tick<'a> {
+    accepted: Accepted<'a, TcpConnection, u32, u32, String, BincodeCodec>
+}
+
+fn connected(server: &mut Server) -> Connected<'_, TcpConnection, u32, u32, String, BincodeCodec> {
+    server.connected()
+}
+
+fn closed(server: &mut Server) -> Closed<TcpConnection, u32, u32, String, BincodeCodec> {
+    server.closed()
+}
+
+fn remote_ids(server: &Server) -> impl Iterator<Item=ConnectionId> + '_ {
+    server.remote_ids()
+}
+
+#[test]
+fn server_iterators_can_be_named() {
+
+    let mut server = Server::new(30);
+    server.bind("127.0.0.1:0").unwrap();
+
+    let tick = Tick {
+        accepted: server.accepted_with(|addr| Ok(addr.to_string()))
+    };
+    assert_eq!(tick.accepted.count(), 0);
+    assert_eq!(connected(&mut server).count(), 0);
+    assert_eq!(closed(&mut server).count(), 0);
+    assert_eq!(remote_ids(&server).count(), 0);
+
+    let addr: IpAddr = "10.0.0.1".parse().unwrap();
+    server.ban(addr, Some(Duration::from_secs(60)));
+    assert_eq!(server.bans().map(|(addr, _)| addr).collect::<Vec<_>>(), vec![addr]);
+
+    let group = server.create_group();
+    assert_eq!(server.group_members(group).count(), 0);
+
+}
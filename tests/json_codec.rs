@@ -0,0 +1,72 @@
+// Crates ---------------------------------------------------------------------
+#[macro_use]
+extern crate serde_derive;
+extern crate cobalt_two;
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::thread;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+
+// External Dependencies ------------------------------------------------------
+use cobalt_two::{Client, ClientState, Error, JsonCodec, Server, TCP};
+
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum Message {
+    Chat(String),
+    Move(i32, i32)
+}
+
+fn client(addr: SocketAddr, messages: Vec<Message>) -> Result<Vec<Message>, Error> {
+
+    let mut client = Client::<TCP, Message, Message, JsonCodec>::new(60);
+    client.connect(addr, Duration::from_millis(500))?;
+
+    let mut sent = false;
+    let mut received = Vec::new();
+    while received.len() < messages.len() && client.ticks() < 300 {
+        received.extend(client.receive()?);
+        if client.state() == ClientState::Connected && !sent {
+            for m in &messages {
+                client.send(m.clone())?;
+            }
+            sent = true;
+        }
+        client.sleep();
+    }
+
+    client.disconnect()?;
+    Ok(received)
+
+}
+
+#[test]
+fn messages_round_trip_over_tcp_as_json() {
+
+    let mut server = Server::<TCP, Message, Message, (), JsonCodec>::new(60);
+    server.bind("127.0.0.1:0").unwrap();
+
+    let messages = vec![Message::Chat("escaped \"json\" ✓".to_string()), Message::Move(1, -2)];
+    let addr = server.local_addr().unwrap();
+    let expected = messages.clone();
+    let handle = thread::spawn(move || client(addr, messages));
+
+    // Echo everything until the client went away
+    while !handle.is_finished() && server.ticks() < 600 {
+        for _ in server.accepted_with(|_| Ok(())) {}
+        for &mut (ref mut remote, _) in server.connected() {
+            let received: Vec<Message> = remote.receive().collect();
+            for m in received {
+                remote.send(m).unwrap();
+            }
+        }
+        for _ in server.closed() {}
+        server.sleep();
+    }
+
+    assert_eq!(handle.join().unwrap().unwrap(), expected);
+
+}
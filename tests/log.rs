@@ -0,0 +1,89 @@
+// Crates ---------------------------------------------------------------------
+extern crate log;
+extern crate cobalt_two;
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+
+// External Dependencies ------------------------------------------------------
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use cobalt_two::{Client, ClientState, Memory, MockClock, SymmetricServer};
+
+
+// Keeps every record, the logger is global so this file holds a single test
+struct Capture {
+    records: Mutex<Vec<(Level, String)>>
+}
+
+impl Log for Capture {
+
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        if let Ok(mut records) = self.records.lock() {
+            records.push((record.level(), record.args().to_string()));
+        }
+    }
+
+    fn flush(&self) {}
+
+}
+
+static LOGGER: Capture = Capture {
+    records: Mutex::new(Vec::new())
+};
+
+static TICKS_PER_SECOND: f64 = 30.0;
+static MAX_TICKS: usize = 300;
+
+#[test]
+fn connection_lifecycle_is_logged() {
+
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(LevelFilter::Trace);
+
+    let mut server = SymmetricServer::<Memory, (), ()>::with_clock(TICKS_PER_SECOND, Arc::new(MockClock::new()));
+    server.bind("127.0.0.1:0").unwrap();
+
+    let mut client = Client::<Memory, ()>::with_clock(TICKS_PER_SECOND, Arc::new(MockClock::new()));
+    client.connect(server.local_addr().unwrap(), Duration::from_millis(500)).unwrap();
+    let addr = client.local_addr().unwrap();
+
+    // The server kicks the client as soon as it is connected
+    let mut closed = 0;
+    for _ in 0..MAX_TICKS {
+        for _ in server.accepted_with(|_| Ok(())) {}
+        for &mut (ref mut remote, _) in server.connected() {
+            remote.close().unwrap();
+        }
+        closed += server.closed().count();
+        server.sleep();
+        client.receive().map(|messages| messages.count()).ok();
+        client.sleep();
+        if closed > 0 && client.state() != ClientState::Connected {
+            break;
+        }
+    }
+    assert_eq!(closed, 1);
+
+    // Every record of the connection carries the address of its peer
+    let needle = format!("from {:?} ", addr);
+    let records: Vec<(Level, String)> = LOGGER.records.lock().unwrap().iter().filter(|record| record.1.contains(&needle)).cloned().collect();
+    let events: Vec<(Level, &str)> = records.iter().map(|&(level, ref message)| {
+        (level, message.rsplit(&needle).next().unwrap())
+
+    }).collect();
+
+    assert_eq!(events, vec![
+        (Level::Debug, "opened"),
+        (Level::Info, "accepted"),
+        (Level::Debug, "closing: closed locally"),
+        (Level::Info, "closed: closed locally")
+    ]);
+
+}
@@ -0,0 +1,257 @@
+// Crates ---------------------------------------------------------------------
+extern crate cobalt_two;
+
+
+// STD Dependencies -----------------------------------------------------------
+use std::sync::Arc;
+use std::time::Duration;
+use std::net::SocketAddr;
+
+
+// External Dependencies ------------------------------------------------------
+use cobalt_two::{Client, ClientState, CloseReason, ConnectionId, Memory, MockClock, SymmetricServer};
+
+
+// Every client and server runs on its own mock clock, so none of the tests
+// takes any real time
+static TICKS_PER_SECOND: f64 = 30.0;
+static MAX_TICKS: usize = 300;
+
+type Server = SymmetricServer<Memory, (), char>;
+
+fn connect(count: usize) -> (Server, Vec<Client<Memory, ()>>) {
+
+    let mut server = Server::with_clock(TICKS_PER_SECOND, Arc::new(MockClock::new()));
+    server.bind("127.0.0.1:0").unwrap();
+
+    let mut clients: Vec<Client<Memory, ()>> = (0..count).map(|_| {
+        let mut client = Client::with_clock(TICKS_PER_SECOND, Arc::new(MockClock::new()));
+        client.connect(server.local_addr().unwrap(), Duration::from_millis(500)).unwrap();
+        client
+    }).collect();
+
+    // Each remote is labeled by the position of its client
+    let addrs: Vec<SocketAddr> = clients.iter().map(|client| client.local_addr().unwrap()).collect();
+    tick(&mut server, &mut clients, |server| {
+        for _ in server.accepted_with(|addr| Ok((b'a' + addrs.iter().position(|&a| a == addr).unwrap() as u8) as char)) {}
+        let connected = server.connected().map(|&mut (ref mut remote, _)| remote.receive().count()).count();
+        for _ in server.closed() {}
+        connected == count
+    });
+    tick(&mut server, &mut clients, |_| true);
+    assert!(clients.iter().all(|client| client.state() == ClientState::Connected));
+
+    (server, clients)
+
+}
+
+fn tick<F: FnMut(&mut Server) -> bool>(server: &mut Server, clients: &mut [Client<Memory, ()>], mut done: F) {
+    for _ in 0..MAX_TICKS {
+        for client in clients.iter_mut() {
+            client.receive().map(|messages| messages.count()).ok();
+            client.sleep();
+        }
+        if done(server) {
+            return;
+        }
+        server.sleep();
+    }
+    panic!("condition not met within {} ticks", MAX_TICKS);
+}
+
+#[test]
+fn remotes_closing_in_the_same_tick_leave_the_others_untouched() {
+
+    let (mut server, mut clients) = connect(4);
+    let addrs: Vec<SocketAddr> = clients.iter().map(|client| client.local_addr().unwrap()).collect();
+
+    for &mut (ref mut remote, data) in server.connected() {
+        if data == 'a' || data == 'c' {
+            remote.close().unwrap();
+        }
+    }
+
+    // Both remotes finish closing within the same tick
+    let mut closed = Vec::new();
+    tick(&mut server, &mut clients, |server| {
+        closed.extend(server.closed().map(|(_, _, data)| data));
+        !closed.is_empty()
+    });
+    assert_eq!(closed, vec!['a', 'c']);
+
+    let remaining: Vec<(SocketAddr, char)> = server.remotes().iter_mut().map(|(remote, data)| (remote.peer_addr(), *data)).collect();
+    assert_eq!(remaining, vec![(addrs[1], 'b'), (addrs[3], 'd')]);
+
+    // Only the clients of the closed remotes lose their connection
+    tick(&mut server, &mut clients, |_| true);
+    assert_eq!(clients.iter().map(|client| client.state()).collect::<Vec<_>>(), vec![
+        ClientState::Lost,
+        ClientState::Connected,
+        ClientState::Lost,
+        ClientState::Connected
+    ]);
+
+}
+
+#[test]
+fn remotes_are_yielded_in_the_order_they_were_accepted() {
+
+    let (mut server, mut clients) = connect(6);
+    assert_eq!(server.connected().map(|entry| entry.1).collect::<String>(), "abcdef");
+
+    // Closing them in reverse does not change the order of closed() either
+    let ids: Vec<ConnectionId> = server.remote_ids().collect();
+    for &id in ids.iter().rev() {
+        if let Some(&mut (ref mut remote, data)) = server.remote_mut(id) {
+            if data == 'b' || data == 'd' || data == 'f' {
+                remote.close().unwrap();
+            }
+        }
+    }
+
+    let mut closed = String::new();
+    tick(&mut server, &mut clients, |server| {
+        closed.extend(server.closed().map(|(_, _, data)| data));
+        !closed.is_empty()
+    });
+    assert_eq!(closed, "bdf");
+    assert_eq!(server.connected().map(|entry| entry.1).collect::<String>(), "ace");
+
+    // Later remotes come after all earlier ones, even though slots were freed
+    let mut client = Client::with_clock(TICKS_PER_SECOND, Arc::new(MockClock::new()));
+    client.connect(server.local_addr().unwrap(), Duration::from_millis(500)).unwrap();
+    clients.push(client);
+
+    let mut accepted = String::new();
+    tick(&mut server, &mut clients, |server| {
+        accepted.extend(server.accepted_with(|_| Ok('g')).map(|entry| entry.1));
+        !accepted.is_empty()
+    });
+    assert_eq!(accepted, "g");
+
+    let mut connected = String::new();
+    tick(&mut server, &mut clients, |server| {
+        connected = server.connected().map(|entry| entry.1).collect();
+        connected.len() == 4
+    });
+    assert_eq!(connected, "aceg");
+
+}
+
+#[test]
+fn bursts_of_connections_are_accepted_over_several_ticks() {
+
+    let mut server = Server::with_clock(TICKS_PER_SECOND, Arc::new(MockClock::new()));
+    server.set_accept_limit_per_tick(8);
+    server.bind("127.0.0.1:0").unwrap();
+
+    // All of them reconnect at once, e.g. after a restart of the server
+    let mut clients: Vec<Client<Memory, ()>> = (0..50).map(|_| {
+        let mut client = Client::with_clock(TICKS_PER_SECOND, Arc::new(MockClock::new()));
+        client.connect(server.local_addr().unwrap(), Duration::from_millis(500)).unwrap();
+        client
+    }).collect();
+
+    let (mut accepted, mut per_tick) = (0, Vec::new());
+    tick(&mut server, &mut clients, |server| {
+        accepted += server.accepted_with(|_| Ok('x')).count();
+        per_tick.push(server.accepted_this_tick());
+        for _ in server.connected() {}
+        for _ in server.closed() {}
+        accepted == 50
+    });
+
+    assert!(per_tick.iter().all(|&count| count <= 8));
+    assert!(per_tick.iter().filter(|&&count| count > 0).count() >= 7);
+    assert_eq!(per_tick.iter().sum::<usize>(), 50);
+
+    // None of the connections waiting in the backlog was lost
+    tick(&mut server, &mut clients, |_| true);
+    assert!(clients.iter().all(|client| client.state() == ClientState::Connected));
+    assert_eq!(server.remotes().len(), 50);
+
+}
+
+#[test]
+fn connections_accepted_while_paused_do_not_time_out() {
+
+    let mut server = Server::with_clock(TICKS_PER_SECOND, Arc::new(MockClock::new()));
+    server.bind("127.0.0.1:0").unwrap();
+    server.pause();
+
+    let connect = |server: &Server| {
+        let mut client = Client::with_clock(TICKS_PER_SECOND, Arc::new(MockClock::new()));
+        client.set_connection_timeout(Duration::from_secs(1));
+        client.connect(server.local_addr().unwrap(), Duration::from_millis(500)).unwrap();
+        vec![client]
+    };
+
+    // Both stay connected for longer than their timeout
+    let mut accepted = connect(&server);
+    let mut ticks = 0;
+    tick(&mut server, &mut accepted, |server| {
+        for _ in server.accepted_with(|_| Ok('a')) {}
+        ticks += 1;
+        ticks == 3 * TICKS_PER_SECOND as usize
+    });
+    assert_eq!(server.remotes().len(), 1);
+    assert_eq!(accepted[0].state(), ClientState::Connected);
+    assert!(accepted[0].server_paused());
+
+    // This one is still waiting on its first message
+    let mut waiting = connect(&server);
+    let mut ticks = 0;
+    tick(&mut server, &mut waiting, |server| {
+        for _ in server.accepted_with_handshake(1000, |_, _| Some('w')) {}
+        ticks += 1;
+        ticks == 3 * TICKS_PER_SECOND as usize
+    });
+    assert_eq!(server.remotes().len(), 1);
+    assert_eq!(waiting[0].state(), ClientState::Connected);
+    assert!(waiting[0].server_paused());
+
+    // And both carry on once the server resumes
+    server.resume();
+    waiting[0].send(()).unwrap();
+    let mut handshaken = Vec::new();
+    tick(&mut server, &mut waiting, |server| {
+        handshaken.extend(server.accepted_with_handshake(1000, |_, _| Some('w')).map(|entry| entry.1));
+        handshaken.contains(&'w')
+    });
+    assert!(!waiting[0].server_paused());
+
+    tick(&mut server, &mut accepted, |server| {
+        for _ in server.connected() {}
+        for _ in server.closed() {}
+        true
+    });
+    assert!(!accepted[0].server_paused());
+    assert_eq!(accepted[0].state(), ClientState::Connected);
+
+}
+
+#[test]
+fn dropped_servers_close_their_connections_in_order() {
+
+    let mut clients = {
+        let (_server, clients) = connect(2);
+        clients
+    };
+
+    for _ in 0..MAX_TICKS {
+        for client in clients.iter_mut() {
+            client.receive().map(|messages| messages.count()).ok();
+            client.sleep();
+        }
+        if clients.iter().all(|client| client.state() != ClientState::Connected) {
+            break;
+        }
+    }
+    for client in &clients {
+        match client.disconnect_reason() {
+            Some(CloseReason::RemoteClose(_)) => {},
+            other => panic!("expected RemoteClose, got {:?}", other)
+        }
+    }
+
+}